@@ -1,6 +1,7 @@
 import os
 import sys
 import tempfile
+import time
 import contextlib
 import subprocess
 from pathlib import Path
@@ -49,6 +50,30 @@ def test_completes_directories(complgen_binary_path: Path):
                 assert completions == sorted(['foo', 'bar'])
 
 
+def test_completes_paths_matching_glob(complgen_binary_path: Path):
+    with completion_script_path(complgen_binary_path, '''cmd <PATH:*.{yml,yaml}>;''') as completions_file_path:
+        with tempfile.TemporaryDirectory() as dir:
+            with set_working_dir(Path(dir)):
+                Path('foo.yml').write_text('dummy')
+                Path('bar.yaml').write_text('dummy')
+                Path('baz.txt').write_text('dummy')
+                os.mkdir('quux')
+                completions = get_sorted_completions(completions_file_path, '''COMP_WORDS=(cmd); COMP_CWORD=1; _cmd; printf '%s\n' "${COMPREPLY[@]}"''')
+                assert completions == sorted(['foo.yml', 'bar.yaml', 'quux'])
+
+
+def test_completes_signals(complgen_binary_path: Path):
+    with completion_script_path(complgen_binary_path, '''cmd <SIGNAL>;''') as completions_file_path:
+        completions = get_sorted_completions(completions_file_path, '''COMP_WORDS=(cmd USR); COMP_CWORD=1; _cmd; printf '%s\n' "${COMPREPLY[@]}"''')
+        assert completions == sorted(['USR1', 'USR2'])
+
+
+def test_completes_environment_variables(complgen_binary_path: Path):
+    with completion_script_path(complgen_binary_path, '''cmd <ENVIRONMENT_VARIABLE>;''') as completions_file_path:
+        completions = get_sorted_completions(completions_file_path, '''export COMPLGEN_TEST_VARIABLE=1; COMP_WORDS=(cmd COMPLGEN_TEST_); COMP_CWORD=1; _cmd; printf '%s\n' "${COMPREPLY[@]}"''')
+        assert completions == ['COMPLGEN_TEST_VARIABLE']
+
+
 def test_bash_uses_correct_transition_with_duplicated_literals(complgen_binary_path: Path):
     GRAMMAR = '''
 cmd <COMMAND> [--help];
@@ -110,6 +135,28 @@ def test_jit_completes_directories_bash(complgen_binary_path: Path):
             assert get_sorted_jit_bash_completions(complgen_binary_path, '''cmd <DIRECTORY> [--help];''', 0, []) == sorted(['bar', 'foo'])
 
 
+def test_jit_completes_paths_matching_glob_bash(complgen_binary_path: Path):
+    with tempfile.TemporaryDirectory() as dir:
+        with set_working_dir(Path(dir)):
+            Path('foo.yml').write_text('dummy')
+            Path('bar.yaml').write_text('dummy')
+            Path('baz.txt').write_text('dummy')
+            os.mkdir('quux')
+            assert get_sorted_jit_bash_completions(complgen_binary_path, '''cmd <PATH:*.{yml,yaml}>;''', 0, []) == sorted(['foo.yml', 'bar.yaml', 'quux'])
+
+
+def test_jit_completes_signals_bash(complgen_binary_path: Path):
+    assert get_sorted_jit_bash_completions(complgen_binary_path, '''cmd <SIGNAL>;''', 0, ['USR']) == sorted(['USR1', 'USR2'])
+
+
+def test_jit_completes_environment_variables_bash(complgen_binary_path: Path):
+    os.environ['COMPLGEN_TEST_VARIABLE'] = '1'
+    try:
+        assert get_sorted_jit_bash_completions(complgen_binary_path, '''cmd <ENVIRONMENT_VARIABLE>;''', 0, ['COMPLGEN_TEST_']) == ['COMPLGEN_TEST_VARIABLE']
+    finally:
+        del os.environ['COMPLGEN_TEST_VARIABLE']
+
+
 def test_specializes_for_bash(complgen_binary_path: Path):
     GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@bash> ::= { echo bash };'''
     with completion_script_path(complgen_binary_path, GRAMMAR) as path:
@@ -120,3 +167,261 @@ def test_specializes_for_bash(complgen_binary_path: Path):
 def test_jit_specializes_for_bash(complgen_binary_path: Path):
     GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@bash> ::= { echo bash };'''
     assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 0, []) == sorted(['bash'])
+
+
+def test_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --col); COMP_CWORD=1; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['--color='])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --color =); COMP_CWORD=2; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['always', 'never', 'auto'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --color = al); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['always'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --color = always --h); COMP_CWORD=4; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['--help'])
+
+
+def test_jit_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''cmd [--color=<WHEN>] [--help]; <WHEN> ::= always | never | auto;'''
+    process = subprocess.run([complgen_binary_path, 'complete', '-', 'bash', '--', '2', '--color', '=', 'al'], input=GRAMMAR.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    assert sorted(process.stdout.decode().splitlines()) == sorted(['always'])
+
+
+def test_completes_short_options_cluster(complgen_binary_path: Path):
+    GRAMMAR = '''
+tar (@cluster(-x | -v | -f <FILE>) | --help)...;
+<FILE> ::= foo.tar | bar.tar;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(tar -xv); COMP_CWORD=1; _tar; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-xv', '-xvf'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(tar -xvf ''); COMP_CWORD=2; _tar; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['foo.tar', 'bar.tar'])
+
+
+def test_skips_given_once_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd @once(-a | -b | --color <WHEN>);
+<WHEN> ::= always | never;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd ''); COMP_CWORD=1; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-a', '-b', '--color'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -a --color always ''); COMP_CWORD=4; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-b'])
+
+
+def test_skips_given_once_options_per_occurrence(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [@once(-v | --color=<WHEN>)]... sub [-v]...;
+<WHEN> ::= always | never;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --color = never -v ''); COMP_CWORD=5; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['sub'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -v sub ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-v'])
+
+
+def test_skips_excluded_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd (@exclusive(--oneline | --format=<FMT>) | -i)...;
+<FMT> ::= short | full;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -i --oneline ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-i', '--oneline'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --format = full ''); COMP_CWORD=4; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-i', '--format='])
+
+def test_skips_excluded_options_per_occurrence(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd (@exclusive(-E | -F) | -i)... sub [-F]...;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -E ''); COMP_CWORD=2; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-E', '-i', 'sub'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -E sub ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-F'])
+
+
+def test_skips_options_after_end_of_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [-v | --color=<WHEN>]... [<END_OF_OPTIONS>] (-1 | first)...;
+<WHEN> ::= always | never;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -v ''); COMP_CWORD=2; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-v', '--color=', '--', '-1', 'first'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd -v -- ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['first'])
+
+def test_offers_options_after_double_dash_option_argument(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [-v | --sep <SEP>]... [<END_OF_OPTIONS>] (-1 | first)...;
+<SEP> ::= -- | ,;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --sep -- ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['-v', '--sep', '--', '-1', 'first'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(cmd --sep -- -- ''); COMP_CWORD=4; _cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['first'])
+
+
+def test_completes_multiple_commands(complgen_binary_path: Path):
+    GRAMMAR = '''
+foo <SUBCOMMAND>;
+foo --version;
+foo-admin (<SUBCOMMAND> | reset);
+<SUBCOMMAND> ::= start | stop;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        assert get_sorted_completions(path, r'''COMP_WORDS=(foo ''); COMP_CWORD=1; _foo; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['--version', 'start', 'stop'])
+        assert get_sorted_completions(path, r'''COMP_WORDS=(foo-admin ''); COMP_CWORD=1; _foo-admin; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['reset', 'start', 'stop'])
+
+
+def test_jit_completes_picked_command(complgen_binary_path: Path):
+    GRAMMAR = '''
+foo --version;
+foo-admin reset;
+'''
+    process = subprocess.run([complgen_binary_path, 'complete', '--command', 'foo-admin', '-', 'bash', '--', '0'], input=GRAMMAR.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    assert process.stdout.decode().splitlines() == ['reset']
+
+
+def test_runs_the_command_of_the_matched_alternative(complgen_binary_path: Path):
+    with completion_script_path(complgen_binary_path, '''cmd (a {{{ echo A }}} | b {{{ echo B }}});''') as path:
+        input = r'''COMP_WORDS=(cmd a ''); COMP_CWORD=2; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['A']
+        input = r'''COMP_WORDS=(cmd b ''); COMP_CWORD=2; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['B']
+
+
+def test_jit_completes_from_compiled_json(complgen_binary_path: Path):
+    GRAMMAR = '''cmd (--help | --version);'''
+    automaton = subprocess.run([complgen_binary_path, 'compile', '--json', '-', '-'], input=GRAMMAR.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout
+    process = subprocess.run([complgen_binary_path, 'complete', '--json', '-', 'bash', '--', '0'], input=automaton, stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    assert sorted(process.stdout.decode().splitlines()) == sorted(['--help', '--version'])
+
+
+@contextlib.contextmanager
+def init_script_path(complgen_binary_path: Path, usage_directory: Path) -> Generator[Path, None, None]:
+    bash_script = subprocess.run([complgen_binary_path, 'init', 'bash', '--dir', usage_directory], stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout
+    with tempfile.NamedTemporaryFile() as f:
+        f.write(bash_script)
+        f.flush()
+        yield Path(f.name)
+
+
+def test_init_completes(complgen_binary_path: Path):
+    with tempfile.TemporaryDirectory() as dir:
+        usage_directory = Path(dir) / 'my grammars'
+        usage_directory.mkdir()
+        (usage_directory / 'cmd.usage').write_text('''cmd (--help | --version) <PATH>;''')
+        with init_script_path(complgen_binary_path, usage_directory) as init_file_path:
+            with set_working_dir(Path(dir)):
+                Path('file one').write_text('dummy')
+                assert get_sorted_completions(init_file_path, '''complete -p cmd''') == ['complete -F _complgen_jit cmd']
+                assert get_sorted_completions(init_file_path, '''COMP_WORDS=(cmd --); COMP_CWORD=1; _complgen_jit cmd; printf '%s\n' "${COMPREPLY[@]}"''') == sorted(['--help', '--version'])
+                assert get_sorted_completions(init_file_path, '''COMP_WORDS=(/usr/bin/cmd --help fi); COMP_CWORD=2; _complgen_jit /usr/bin/cmd; printf '%s\n' "${COMPREPLY[@]}"''') == ['file one']
+
+
+def test_completes_quoted_and_unicode_literals(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd ('pkg@1.2' | 'key:value' | 'foo bar' | 'it\'s' | größe);'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        input = r'''COMP_WORDS=(cmd); COMP_CWORD=1; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == sorted(['pkg@1.2', 'key:value', 'foo bar', "it's", 'größe'])
+
+        # Bash splits words on `:`, just like on `=`
+        input = r'''COMP_WORDS=(cmd key : v); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['value']
+
+
+def test_jit_completes_quoted_and_unicode_literals(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd ('pkg@1.2' | 'key:value' | größe);'''
+    assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 0, []) == sorted(['pkg@1.2', 'key:value', 'größe'])
+    assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 2, ['key', ':', 'v']) == ['value']
+
+
+def test_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        input = r'''COMP_WORDS=(cmd -C /tmp checkout ''); COMP_CWORD=4; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['-C,/tmp,checkout']
+
+
+def test_jit_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 3, ['-C', '/tmp', 'checkout']) == ['-C,/tmp,checkout']
+
+
+def test_passes_bound_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd --repo <repo=PATH> <BRANCH>;
+<BRANCH> ::= {{{ printf '%s\n' "repo:$repo" }}};
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        input = r'''COMP_WORDS=(cmd --repo /tmp ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['repo:/tmp']
+
+
+def test_jit_passes_bound_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd --repo <repo=PATH> <BRANCH>;
+<BRANCH> ::= {{{ printf '%s\n' "repo:$repo" }}};
+'''
+    assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 2, ['--repo', '/tmp']) == ['repo:/tmp']
+
+
+def test_binds_words_to_all_names_sharing_a_transition(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd (<repo=REPO> log | <file=FILE> show | <PATH> diff) <OUT>;
+<REPO> ::= {{{ echo repo }}};
+<FILE> ::= {{{ echo file }}};
+<OUT> ::= {{{ printf '%s\n' "repo:$repo,file:$file" }}};
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        input = r'''COMP_WORDS=(cmd x log ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['repo:x,file:x']
+    assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 2, ['x', 'log']) == ['repo:x,file:x']
+
+
+def test_skips_commands_exceeding_timeout(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd {{{ sleep 5; echo slow }}} @timeout(100ms);'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        input = r'''COMP_WORDS=(cmd ''); COMP_CWORD=1; _cmd; echo "${#COMPREPLY[@]}"'''
+        start = time.monotonic()
+        assert get_sorted_completions(path, input) == ['0']
+        assert time.monotonic() - start < 3
+
+
+def test_jit_skips_commands_exceeding_timeout(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd {{{ sleep 5; echo slow }}} @timeout(100ms);'''
+    start = time.monotonic()
+    assert get_sorted_jit_bash_completions(complgen_binary_path, GRAMMAR, 0, []) == []
+    assert time.monotonic() - start < 3
+
+
+def test_caches_command_output(complgen_binary_path: Path):
+    with tempfile.TemporaryDirectory() as dir:
+        GRAMMAR = r'''cmd {{{{{{ echo run >> {dir}/runs; wc -l < {dir}/runs | tr -d ' ' }}}}}} @cache(60s);'''.format(dir=dir)
+        with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+            input = r'''COMP_WORDS=(cmd ''); COMP_CWORD=1; _cmd; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+            assert get_sorted_completions(path, input) == ['1']
+
+
+def test_caches_command_output_per_directory(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd {{{ echo run >> runs; wc -l < runs | tr -d ' ' }}} @cache(60s);'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        with tempfile.TemporaryDirectory() as first, tempfile.TemporaryDirectory() as second:
+            input = r'''COMP_WORDS=(cmd ''); COMP_CWORD=1; cd {first}; _cmd; cd {second}; _cmd; printf '%s\n' "${{COMPREPLY[@]}}"'''.format(first=first, second=second)
+            assert get_sorted_completions(path, input) == ['1']
+
+
+def test_hides_command_errors_unless_debugging(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd {{{ echo oops >&2; echo ok }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as path:
+        input = r'''COMP_WORDS=(cmd ''); COMP_CWORD=1; _cmd 2>&1; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['ok']
+        input = r'''COMPLGEN_DEBUG=1; COMP_WORDS=(cmd ''); COMP_CWORD=1; _cmd 2>&1; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['ok', 'oops']
+
+
+def test_completes_repeated_words(complgen_binary_path: Path):
+    with completion_script_path(complgen_binary_path, '''cmd (foo | bar){2} --force;''') as path:
+        input = r'''COMP_WORDS=(cmd foo ''); COMP_CWORD=2; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == sorted(['foo', 'bar'])
+        input = r'''COMP_WORDS=(cmd foo bar ''); COMP_CWORD=3; _cmd; printf '%s\n' "${COMPREPLY[@]}"'''
+        assert get_sorted_completions(path, input) == ['--force']
+
+
+def test_rejects_too_large_repeat_counts(complgen_binary_path: Path):
+    process = subprocess.run([complgen_binary_path, 'compile', '--bash-script', '-', '-'], input=b'cmd <PATH>{1,1000000};', stdout=subprocess.PIPE, stderr=subprocess.PIPE, timeout=10)
+    assert process.returncode != 0
+    assert 'count too large' in process.stderr.decode()
@@ -0,0 +1,86 @@
+import sys
+import json
+import tempfile
+import subprocess
+import contextlib
+from pathlib import Path
+from typing import Generator
+
+
+# The edit: module is only there in interactive sessions, so the completer gets registered in a map of our own
+# instead, candidates are collected as maps and the script completes the words passed to it as arguments
+SHIM = '''
+var completers = [&]
+fn complex-candidate {|code &display='' &code-suffix=''| put [&code=$code &display=$display] }
+'''
+
+QUERY = '''
+put [($completers[$args[0]] $@args)] | to-json
+'''
+
+
+@contextlib.contextmanager
+def completion_script_path(complgen_binary_path: Path, grammar: str) -> Generator[Path, None, None]:
+    elvish_script = subprocess.run([complgen_binary_path, 'compile', '--elvish-script', '-', '-'], input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout.decode()
+    elvish_script = elvish_script.replace('set edit:completion:arg-completer[', 'set completers[').replace('edit:complex-candidate ', 'complex-candidate ')
+    with tempfile.NamedTemporaryFile(suffix='.elv') as f:
+        f.write((SHIM + elvish_script + QUERY).encode())
+        f.flush()
+        yield Path(f.name)
+
+
+def get_sorted_completions(completions_file_path: Path, input: str) -> list[tuple[str, str]]:
+    completed_process = subprocess.run(['elvish', '-norc', completions_file_path] + input.split(' '), stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    parsed = []
+    for candidate in json.loads(completed_process.stdout):
+        code, display = candidate['code'], candidate['display']
+        description = display[len(code) + 2:-1] if display != code else ''
+        parsed.append((code, description))
+    parsed.sort(key=lambda pair: pair[0])
+    return parsed
+
+
+def test_elvish_uses_correct_description_with_duplicated_literals(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd <COMMAND> [--help];
+
+<COMMAND> ::= rm           "Remove a project" <RM-OPTION>
+            | remote       "Manage a project's remotes" [<REMOTE-SUBCOMMAND>]
+            ;
+
+<REMOTE-SUBCOMMAND> ::= rm <name>;
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == sorted([('rm', "Remove a project"), ('remote', "Manage a project's remotes")])
+        assert get_sorted_completions(completions_file_path, 'cmd remote ') == sorted([('--help', ''), ('rm', '')])
+
+
+def test_elvish_external_command_produces_description(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd { printf "completion\tdescription\n" };
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == [('completion', 'description')]
+
+
+def test_elvish_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd -C /tmp checkout ') == [('-C,/tmp,checkout', '')]
+
+
+def test_elvish_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd --color=a') == sorted([('--color=always', ''), ('--color=auto', '')])
+
+
+def test_specializes_for_elvish(complgen_binary_path: Path):
+    GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@elvish> ::= { put elvish };'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == [('elvish', '')]
@@ -1,6 +1,7 @@
 import os
 import sys
 import tempfile
+import time
 import subprocess
 import contextlib
 from pathlib import Path
@@ -89,6 +90,19 @@ def test_completes_paths(complgen_binary_path: Path):
                 assert completions == sorted([('bar', ''), ('baz/', ''), ('foo', '')])
 
 
+def test_fish_completes_paths_matching_glob(complgen_binary_path: Path):
+    with completion_script_path(complgen_binary_path, '''cmd <PATH:*.{yml,yaml}>;''') as completions_file_path:
+        with tempfile.TemporaryDirectory() as dir:
+            with set_working_dir(Path(dir)):
+                Path('foo.yml').write_text('dummy')
+                Path('bar.yaml').write_text('dummy')
+                Path('baz.txt').write_text('dummy')
+                os.mkdir('quux')
+                input = 'source {}; complete --command cmd --do-complete "cmd "'.format(completions_file_path)
+                completions = get_sorted_completions(input)
+                assert completions == sorted([('bar.yaml', ''), ('foo.yml', ''), ('quux/', '')])
+
+
 def test_completes_directories(complgen_binary_path: Path):
     with completion_script_path(complgen_binary_path, '''cmd <DIRECTORY> [--help];''') as completions_file_path:
         with tempfile.TemporaryDirectory() as dir:
@@ -136,3 +150,134 @@ def test_specializes_for_fish(complgen_binary_path: Path):
 def test_jit_specializes_for_fish(complgen_binary_path: Path):
     GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@fish> ::= { echo fish };'''
     assert get_sorted_jit_fish_completions(complgen_binary_path, GRAMMAR, 0, []) == sorted([('fish', '')])
+
+
+def test_fish_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd --color="'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('--color=always', ''), ('--color=auto', ''), ('--color=never', '')], key=lambda pair: pair[0])
+
+        input = 'source {}; complete --command cmd --do-complete "cmd --color=always --h"'.format(completions_file_path)
+        assert get_sorted_completions(input) == [('--help', '')]
+
+
+def test_jit_completes_option_value_after_equals_sign_fish(complgen_binary_path: Path):
+    GRAMMAR = '''cmd [--color=<WHEN>] [--help]; <WHEN> ::= always | never | auto;'''
+    assert get_sorted_jit_fish_completions(complgen_binary_path, GRAMMAR, 0, ['--color=al']) == [('--color=always', '')]
+
+
+def test_fish_completes_short_options_cluster(complgen_binary_path: Path):
+    GRAMMAR = '''
+tar (@cluster(-x "extract" | -v | -f <FILE>) | --help)...;
+<FILE> ::= foo.tar | bar.tar;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command tar --do-complete "tar -vx"'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('-vx', 'extract'), ('-vxf', '')], key=lambda pair: pair[0])
+
+        input = 'source {}; complete --command tar --do-complete "tar -vxf "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('bar.tar', ''), ('foo.tar', '')], key=lambda pair: pair[0])
+
+
+def test_init_completes(complgen_binary_path: Path):
+    with tempfile.TemporaryDirectory() as dir:
+        usage_directory = Path(dir) / 'my grammars'
+        usage_directory.mkdir()
+        (usage_directory / 'cmd.usage').write_text('''cmd (--help "show help" | --version) <PATH>;''')
+        init_script = subprocess.run([complgen_binary_path, 'init', 'fish', '--dir', usage_directory], stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout
+        with tempfile.NamedTemporaryFile() as f:
+            f.write(init_script)
+            f.flush()
+            with set_working_dir(Path(dir)):
+                Path('file one').write_text('dummy')
+                input = 'source {}; complete --command cmd --do-complete "cmd --"'.format(f.name)
+                assert get_sorted_completions(input) == sorted([('--help', 'show help'), ('--version', '')])
+                input = 'source {}; complete --command cmd --do-complete "cmd --help fi"'.format(f.name)
+                assert get_sorted_completions(input) == [('file one', '')]
+
+
+def test_fish_completes_quoted_and_unicode_literals(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd ('pkg@1.2' | 'key:value' | größe);'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('pkg@1.2', ''), ('key:value', ''), ('größe', '')], key=lambda pair: pair[0])
+
+
+def test_fish_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd -C /tmp checkout "'.format(completions_file_path)
+        assert get_sorted_completions(input) == [('-C,/tmp,checkout', '')]
+
+
+def test_fish_passes_bound_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd --repo <repo=PATH> <BRANCH>;
+<BRANCH> ::= {{{ printf '%s\n' "repo:$repo" }}};
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd --repo /tmp "'.format(completions_file_path)
+        assert get_sorted_completions(input) == [('repo:/tmp', '')]
+
+
+def test_fish_skips_commands_exceeding_timeout(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd {{{ sleep 5; echo slow }}} @timeout(100ms);'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd "'.format(completions_file_path)
+        start = time.monotonic()
+        assert get_sorted_completions(input) == []
+        assert time.monotonic() - start < 3
+
+
+def test_fish_caches_command_output(complgen_binary_path: Path):
+    with tempfile.TemporaryDirectory() as dir:
+        GRAMMAR = r'''cmd {{{{{{ echo run >> {dir}/runs; wc -l < {dir}/runs | string trim }}}}}} @cache(60s);'''.format(dir=dir)
+        with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+            input = 'source {}; complete --command cmd --do-complete "cmd " >/dev/null; complete --command cmd --do-complete "cmd "'.format(completions_file_path)
+            assert get_sorted_completions(input) == [('1', '')]
+
+
+def test_fish_skips_given_once_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [@once(-v | --color=<WHEN>)]... sub [-v]...;
+<WHEN> ::= always | never;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd --color=never "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('-v', ''), ('sub', '')], key=lambda pair: pair[0])
+
+        input = 'source {}; complete --command cmd --do-complete "cmd -v sub "'.format(completions_file_path)
+        assert get_sorted_completions(input) == [('-v', '')]
+
+
+def test_fish_skips_excluded_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd (@exclusive(-E | --format=<FMT>) | -i)... sub [-F | --format=<FMT>]...;
+<FMT> ::= short | full;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd -E "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('-E', ''), ('-i', ''), ('sub', '')], key=lambda pair: pair[0])
+
+        input = 'source {}; complete --command cmd --do-complete "cmd --format=full "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('--format=', ''), ('-i', ''), ('sub', '')], key=lambda pair: pair[0])
+
+        input = 'source {}; complete --command cmd --do-complete "cmd -E sub "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('--format=', ''), ('-F', '')], key=lambda pair: pair[0])
+
+
+def test_fish_skips_options_after_end_of_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [-v | --sep <SEP>]... [<END_OF_OPTIONS>] (-1 | first)...;
+<SEP> ::= -- | ,;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        input = 'source {}; complete --command cmd --do-complete "cmd --sep -- "'.format(completions_file_path)
+        assert get_sorted_completions(input) == sorted([('-v', ''), ('--sep', ''), ('--', ''), ('-1', ''), ('first', '')], key=lambda pair: pair[0])
+
+        input = 'source {}; complete --command cmd --do-complete "cmd --sep -- -- "'.format(completions_file_path)
+        assert get_sorted_completions(input) == [('first', '')]
@@ -0,0 +1,106 @@
+import sys
+import json
+import tempfile
+import subprocess
+import contextlib
+from pathlib import Path
+from typing import Generator
+
+
+@contextlib.contextmanager
+def completion_script_path(complgen_binary_path: Path, grammar: str) -> Generator[Path, None, None]:
+    nushell_script = subprocess.run([complgen_binary_path, 'compile', '--nushell-script', '-', '-'], input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout
+    with tempfile.NamedTemporaryFile(suffix='.nu') as f:
+        f.write(nushell_script)
+        f.flush()
+        yield Path(f.name)
+
+
+def get_sorted_completions(completions_file_path: Path, command: str, input: str) -> list[tuple[str, str]]:
+    script = '''source {}; 'nu-complete {}' {} | to json'''.format(json.dumps(str(completions_file_path)), command, json.dumps(input))
+    completed_process = subprocess.run(['nu', '--no-config-file', '--commands', script], stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    parsed = [(record['value'], record['description']) for record in json.loads(completed_process.stdout)]
+    parsed.sort(key=lambda pair: pair[0])
+    return parsed
+
+
+def test_nushell_uses_correct_description_with_duplicated_literals(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd <COMMAND> [--help];
+
+<COMMAND> ::= rm           "Remove a project" <RM-OPTION>
+            | remote       "Manage a project's remotes" [<REMOTE-SUBCOMMAND>]
+            ;
+
+<REMOTE-SUBCOMMAND> ::= rm <name>;
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd ') == sorted([('rm', "Remove a project"), ('remote', "Manage a project's remotes")])
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd remote ') == sorted([('--help', ''), ('rm', '')])
+
+
+def test_nushell_external_command_produces_description(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd { printf "completion\tdescription\n" };
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd ') == [('completion', 'description')]
+
+
+def test_nushell_keeps_quoted_words_together(complgen_binary_path: Path):
+    GRAMMAR = '''cmd <WORD> --baz;'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd "foo bar" ') == [('--baz', '')]
+        assert get_sorted_completions(completions_file_path, 'cmd', "cmd 'a b' ") == [('--baz', '')]
+
+
+def test_nushell_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd -C /tmp checkout ') == [('-C,/tmp,checkout', '')]
+
+
+def test_nushell_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd --color=') == sorted([('--color=always', ''), ('--color=auto', ''), ('--color=never', '')])
+
+
+def test_nushell_completes_short_options_cluster(complgen_binary_path: Path):
+    GRAMMAR = '''
+tar @cluster(-x | -v)...;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'tar', 'tar -x') == sorted([('-x', ''), ('-xv', '')])
+
+
+def test_specializes_for_nushell(complgen_binary_path: Path):
+    GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@nushell> ::= { [nushell] };'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd ') == [('nushell', '')]
+
+
+def get_sorted_jit_nushell_completions(complgen_binary_path: Path, grammar: str, completed_word_index: int, words_before_cursor: list[str]) -> list[tuple[str, str]]:
+    process = subprocess.run([complgen_binary_path, 'complete', '-', 'nushell', '--', str(completed_word_index)] + words_before_cursor, input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    parsed = []
+    for line in process.stdout.decode().splitlines():
+        completion, description = line.split('\t', maxsplit=1)
+        parsed.append((completion, description))
+    return sorted(parsed, key=lambda pair: pair[0])
+
+
+def test_jit_completes_nushell(complgen_binary_path: Path):
+    GRAMMAR = '''cmd (--help "Print help" | --version);'''
+    assert get_sorted_jit_nushell_completions(complgen_binary_path, GRAMMAR, 0, []) == sorted([('--help', 'Print help'), ('--version', '')])
+
+
+def test_nushell_passes_prefix_to_specialized_commands(complgen_binary_path: Path):
+    GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@nushell> ::= { [$"($cur)-completed"] };'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd', 'cmd ab') == [('ab-completed', '')]
+    assert get_sorted_jit_nushell_completions(complgen_binary_path, GRAMMAR, 0, ['a\\b"c']) == [('a\\b"c-completed', '')]
@@ -0,0 +1,107 @@
+import sys
+import tempfile
+import subprocess
+import contextlib
+from pathlib import Path
+from typing import Generator
+
+
+def powershell_completions_from_stdout(stdout: str) -> list[tuple[str, str]]:
+    result = []
+    for line in stdout.splitlines():
+        fields = line.split('\t', maxsplit=2)
+        if len(fields) == 1:
+            result.append((fields[0], ''))
+        else:
+            result.append((fields[0], fields[1]))
+    return result
+
+
+@contextlib.contextmanager
+def completion_script_path(complgen_binary_path: Path, grammar: str) -> Generator[Path, None, None]:
+    powershell_script = subprocess.run([complgen_binary_path, 'compile', '--powershell-script', '-', '-'], input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout
+    with tempfile.NamedTemporaryFile(suffix='.ps1') as f:
+        f.write(powershell_script)
+        f.flush()
+        yield Path(f.name)
+
+
+def get_sorted_completions(completions_file_path: Path, input: str) -> list[tuple[str, str]]:
+    script = '''. '{}'; (TabExpansion2 -inputScript '{}' -cursorColumn {}).CompletionMatches | ForEach-Object {{ "$($_.CompletionText)`t$($_.ToolTip)" }}'''.format(completions_file_path, input, len(input))
+    completed_process = subprocess.run(['pwsh', '-NoProfile', '-NonInteractive', '-Command', script], stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    parsed = powershell_completions_from_stdout(completed_process.stdout.decode())
+    parsed.sort(key=lambda pair: pair[0])
+    return parsed
+
+
+def test_powershell_uses_correct_description_with_duplicated_literals(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd <COMMAND> [--help];
+
+<COMMAND> ::= rm           "Remove a project" <RM-OPTION>
+            | remote       "Manage a project's remotes" [<REMOTE-SUBCOMMAND>]
+            ;
+
+<REMOTE-SUBCOMMAND> ::= rm <name>;
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == sorted([('rm', "Remove a project"), ('remote', "Manage a project's remotes")], key=lambda pair: pair[0])
+        assert get_sorted_completions(completions_file_path, 'cmd remote ') == sorted([('--help', '--help'), ('rm', 'rm')], key=lambda pair: pair[0])
+
+
+def test_powershell_external_command_produces_description(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd { printf "completion\tdescription\n" };
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == [('completion', 'description')]
+
+
+def test_powershell_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd -C /tmp checkout ') == [('-C,/tmp,checkout', '-C,/tmp,checkout')]
+
+
+def test_powershell_runs_external_commands_in_posix_shell(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd { for word in "it's" 'say"hi"'; do printf '%s\n' "$word"; done };
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == sorted([("it's", "it's"), ('say"hi"', 'say"hi"')], key=lambda pair: pair[0])
+
+
+def test_powershell_unquotes_words(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd ('foo bar' --baz | <WORD> {{{ printf '%s\n' "$COMPLGEN_WORDS" }}});'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd "foo bar" ') == [('--baz', '--baz')]
+        assert get_sorted_completions(completions_file_path, 'cmd "x" ') == [('x', 'x')]
+
+
+def test_powershell_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd --color=a') == sorted([('--color=always', '--color=always'), ('--color=auto', '--color=auto')], key=lambda pair: pair[0])
+
+
+def test_specializes_for_powershell(complgen_binary_path: Path):
+    GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@powershell> ::= { Write-Output powershell };'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == [('powershell', 'powershell')]
+
+
+def get_sorted_jit_powershell_completions(complgen_binary_path: Path, grammar: str, completed_word_index: int, words_before_cursor: list[str]) -> list[tuple[str, str]]:
+    process = subprocess.run([complgen_binary_path, 'complete', '-', 'powershell', '--', str(completed_word_index)] + words_before_cursor, input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    parsed = powershell_completions_from_stdout(process.stdout.decode())
+    return sorted(parsed, key=lambda pair: pair[0])
+
+
+def test_jit_completes_powershell(complgen_binary_path: Path):
+    GRAMMAR = '''cmd (--help "Print help" | --version);'''
+    assert get_sorted_jit_powershell_completions(complgen_binary_path, GRAMMAR, 0, []) == sorted([('--help', 'Print help'), ('--version', '')])
@@ -0,0 +1,78 @@
+import sys
+import json
+import tempfile
+import subprocess
+import contextlib
+from pathlib import Path
+from typing import Generator
+
+
+@contextlib.contextmanager
+def completion_script_path(complgen_binary_path: Path, grammar: str) -> Generator[Path, None, None]:
+    xonsh_script = subprocess.run([complgen_binary_path, 'compile', '--xonsh-script', '-', '-'], input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout
+    with tempfile.NamedTemporaryFile(suffix='.xsh') as f:
+        f.write(xonsh_script)
+        f.flush()
+        yield Path(f.name)
+
+
+def get_sorted_completions(completions_file_path: Path, input: str) -> list[tuple[str, str]]:
+    script = '''
+import json
+from xonsh.parsers.completion_context import CommandArg, CommandContext
+source {path}
+words = {input}.split(' ')
+context = CommandContext(args=tuple(CommandArg(word) for word in words[:-1]), arg_index=len(words) - 1, prefix=words[-1])
+completions = __xonsh__.completers[words[0]](context)
+print(json.dumps([(str(c), c.description) for c in completions]))
+'''.format(path=json.dumps(str(completions_file_path)), input=json.dumps(input))
+    completed_process = subprocess.run(['xonsh', '--no-rc', '-c', script], stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
+    parsed = [tuple(pair) for pair in json.loads(completed_process.stdout)]
+    parsed.sort(key=lambda pair: pair[0])
+    return parsed
+
+
+def test_xonsh_uses_correct_description_with_duplicated_literals(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd <COMMAND> [--help];
+
+<COMMAND> ::= rm           "Remove a project" <RM-OPTION>
+            | remote       "Manage a project's remotes" [<REMOTE-SUBCOMMAND>]
+            ;
+
+<REMOTE-SUBCOMMAND> ::= rm <name>;
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == sorted([('rm', "Remove a project"), ('remote', "Manage a project's remotes")])
+        assert get_sorted_completions(completions_file_path, 'cmd remote ') == sorted([('--help', ''), ('rm', '')])
+
+
+def test_xonsh_external_command_produces_description(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd { printf "completion\tdescription\n" };
+'''
+
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == [('completion', 'description')]
+
+
+def test_xonsh_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd -C /tmp checkout ') == [('-C,/tmp,checkout', '')]
+
+
+def test_xonsh_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd --color=a') == sorted([('--color=always', ''), ('--color=auto', '')])
+
+
+def test_specializes_for_xonsh(complgen_binary_path: Path):
+    GRAMMAR = '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@xonsh> ::= { ['xonsh'] };'''
+    with completion_script_path(complgen_binary_path, GRAMMAR) as completions_file_path:
+        assert get_sorted_completions(completions_file_path, 'cmd ') == [('xonsh', '')]
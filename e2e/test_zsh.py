@@ -2,6 +2,7 @@ import os
 import sys
 import stat
 import tempfile
+import time
 import contextlib
 import subprocess
 from pathlib import Path
@@ -111,6 +112,17 @@ def test_completes_directories(complgen_binary_path: Path):
                 assert get_sorted_completions(capture_zsh_path, 'cmd ') == sorted([('bar/', ''), ('foo/', '')])
 
 
+def test_zsh_completes_paths_matching_glob(complgen_binary_path: Path):
+    with capture_grammar_completions(complgen_binary_path, '''cmd <PATH:*.{yml,yaml}>;''') as capture_zsh_path:
+        with tempfile.TemporaryDirectory() as dir:
+            with set_working_dir(Path(dir)):
+                Path('foo.yml').write_text('dummy')
+                Path('bar.yaml').write_text('dummy')
+                Path('baz.txt').write_text('dummy')
+                os.mkdir('quux')
+                assert get_sorted_completions(capture_zsh_path, 'cmd ') == sorted([('bar.yaml', ''), ('foo.yml', ''), ('quux/', '')])
+
+
 def get_jit_zsh_completions_expr(complgen_binary_path: Path, grammar: str, completed_word_index: int, words_before_cursor: list[str]) -> str:
     process = subprocess.run([complgen_binary_path, 'complete', '-', 'zsh', '--', str(completed_word_index)] + words_before_cursor, input=grammar.encode(), stdout=subprocess.PIPE, stderr=sys.stderr, check=True)
     return process.stdout.decode()
@@ -144,3 +156,97 @@ def test_specializes_for_zsh(complgen_binary_path: Path):
 def test_jit_specializes_for_zsh(complgen_binary_path: Path):
     expr = get_jit_zsh_completions_expr(complgen_binary_path, '''cmd <FOO>; <FOO> ::= { echo foo }; <FOO@zsh> ::= { compadd zsh };''', 0, [])
     assert expr == 'local -a completions=("zsh")\nlocal -a descriptions=("zsh")\ncompadd -d descriptions -a completions\n'
+
+
+def test_zsh_completes_option_value_after_equals_sign(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [--color=<WHEN>] [--help];
+<WHEN> ::= always | never | auto;
+'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd --color=') == sorted([('always', ''), ('auto', ''), ('never', '')])
+        assert get_sorted_completions(capture_zsh_path, 'cmd --color=always --h') == sorted([('--help', '')])
+
+
+def test_jit_completes_option_value_after_equals_sign_zsh(complgen_binary_path: Path):
+    expr = get_jit_zsh_completions_expr(complgen_binary_path, '''cmd [--color=<WHEN>] [--help]; <WHEN> ::= always | never | auto;''', 0, ['--color=al'])
+    assert expr == 'local -a completions=("--color=always")\nlocal -a descriptions=("--color=always")\ncompadd -d descriptions -a completions\n'
+
+
+def test_zsh_completes_short_options_cluster(complgen_binary_path: Path):
+    GRAMMAR = '''
+tar (@cluster(-x | -v | -f <FILE>) | --help)...;
+<FILE> ::= foo.tar | bar.tar;
+'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'tar -xvf ') == sorted([('bar.tar', ''), ('foo.tar', '')])
+
+
+def test_init_completes(complgen_binary_path: Path):
+    with tempfile.TemporaryDirectory() as dir:
+        usage_directory = Path(dir) / 'my grammars'
+        usage_directory.mkdir()
+        (usage_directory / 'cmd.usage').write_text('''cmd (--help | --version) <PATH>;''')
+        init_script = subprocess.run([complgen_binary_path, 'init', 'zsh', '--dir', usage_directory], stdout=subprocess.PIPE, stderr=sys.stderr, check=True).stdout.decode()
+        with capture_script_path(init_script) as capture_zsh_path:
+            assert get_sorted_completions(capture_zsh_path, 'cmd --') == sorted([('--help', ''), ('--version', '')])
+
+
+def test_zsh_completes_quoted_and_unicode_literals(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd ('pkg@1.2' | 'key:value' | größe);'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd ') == sorted([('pkg@1.2', ''), ('key:value', ''), ('größe', '')])
+
+
+def test_zsh_passes_preceding_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd -C /tmp checkout ') == [('-C,/tmp,checkout', '')]
+
+
+def test_zsh_passes_bound_words_to_commands(complgen_binary_path: Path):
+    GRAMMAR = r'''
+cmd --repo <repo=PATH> <BRANCH>;
+<BRANCH> ::= {{{ printf '%s\n' "repo:$repo" }}};
+'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd --repo /tmp ') == [('repo:/tmp', '')]
+
+
+def test_zsh_skips_commands_exceeding_timeout(complgen_binary_path: Path):
+    GRAMMAR = r'''cmd {{{ sleep 5; echo slow }}} @timeout(100ms);'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        start = time.monotonic()
+        assert get_sorted_completions(capture_zsh_path, 'cmd ') == []
+        assert time.monotonic() - start < 3
+
+
+def test_zsh_skips_given_once_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [@once(-v | --color=<WHEN>)]... sub [-v]...;
+<WHEN> ::= always | never;
+'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd --color=never ') == sorted([('-v', ''), ('sub', '')])
+        assert get_sorted_completions(capture_zsh_path, 'cmd -v sub ') == [('-v', '')]
+
+
+def test_zsh_skips_excluded_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd (@exclusive(-E | --format=<FMT>) | -i)... sub [-F | --format=<FMT>]...;
+<FMT> ::= short | full;
+'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd -E ') == sorted([('-E', ''), ('-i', ''), ('sub', '')])
+        assert get_sorted_completions(capture_zsh_path, 'cmd --format=full ') == sorted([('--format=', ''), ('-i', ''), ('sub', '')])
+        assert get_sorted_completions(capture_zsh_path, 'cmd -E sub ') == sorted([('--format=', ''), ('-F', '')])
+
+
+def test_zsh_skips_options_after_end_of_options(complgen_binary_path: Path):
+    GRAMMAR = '''
+cmd [-v | --sep <SEP>]... [<END_OF_OPTIONS>] (-1 | first)...;
+<SEP> ::= -- | ,;
+'''
+    with capture_grammar_completions(complgen_binary_path, GRAMMAR) as capture_zsh_path:
+        assert get_sorted_completions(capture_zsh_path, 'cmd --sep -- ') == sorted([('-v', ''), ('--sep', ''), ('--', ''), ('-1', ''), ('first', '')])
+        assert get_sorted_completions(capture_zsh_path, 'cmd --sep -- -- ') == [('first', '')]
@@ -6,6 +6,62 @@ use ustr::{UstrMap, Ustr, ustr};
 use crate::dfa::DFA;
 
 
+// Shared between all completion scripts, hence not prefixed with the command name.  Sets `command_output` in the
+// caller, which also provides `cur` and `bound_words`.
+const RUN_FUNCTION: &str = r#"# Runs the function $1 completing $cur, giving up on it after $2 seconds and reusing the output of a successful
+# run for $3 seconds, if given.  Errors are only shown if COMPLGEN_DEBUG is set.
+_complgen_run () {
+    local function_name=$1 timeout=$2 cache_ttl=$3
+    declare -gA _complgen_cached_output _complgen_cached_at
+    # Commands like `git branch` depend on the working directory
+    local cache_key=$PWD$'\x1f'$function_name$'\x1f'$cur$'\x1f'$COMPLGEN_WORDS$'\x1f'${bound_words[*]}
+    local now
+    printf -v now '%(%s)T' -1
+    # Not using [[ -v ]] or arithmetic on the arrays as both evaluate the key once more
+    local cached_at=${_complgen_cached_at[$cache_key]-}
+    if [[ -n $cache_ttl && -n $cached_at ]] && (( now - cached_at < cache_ttl )); then
+        command_output=${_complgen_cached_output[$cache_key]}
+        return
+    fi
+
+    local -a read_timeout=()
+    if [[ -n $timeout ]]; then
+        read_timeout=(-t "$timeout")
+    fi
+    command_output=
+    IFS= read -r -d '' "${read_timeout[@]}" command_output < <(
+        set -- "$function_name" "$cur"
+        [[ ${#bound_words[@]} -eq 0 ]] || export "${bound_words[@]}"
+        [[ -v COMPLGEN_DEBUG ]] || exec 2>/dev/null
+        "$@" && printf '\0'
+    )
+    local status=$?
+    if [[ $status -gt 128 ]]; then
+        kill "$!" 2>/dev/null
+        command_output=
+    elif [[ $status -eq 0 && -n $cache_ttl ]]; then
+        _complgen_cached_output[$cache_key]=$command_output
+        _complgen_cached_at[$cache_key]=$now
+    fi
+}
+"#;
+
+
+/// Everything between single quotes is taken literally, including newlines.  Only the single quote itself needs
+/// to be spelled as `'\''`.
+pub fn quote_bash_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+
+/// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>.  Directories are always offered so that the
+/// matching files in them can be reached.
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let patterns = itertools::join(crate::glob::expand_braces(glob).iter().map(|pattern| crate::glob::escape_pattern(pattern)), "|");
+    format!(r#"compgen -A directory -- "$1"; compgen -A file -- "$1" | while IFS= read -r path; do [[ -d $path ]] || case ${{path##*/}} in {patterns}) printf '%s\n' "$path" ;; esac; done"#)
+}
+
+
 /// `literals`: an associative array used for literals deduplication (interning) where:
 ///   * key: the literal
 ///   * value: literal's id
@@ -17,23 +73,54 @@ use crate::dfa::DFA;
 /// The initializer value above indicates there are 3 transitions from the state of id `key`.
 /// First of which is a transition on word `add`, to the state 23.
 ///
+/// `prefix_transitions`: same as `transitions` but the keys are literals that only match the beginning of a
+/// word, e.g. `--color=`.  The rest of the word is matched starting at the target state.
+///
 /// `match_anything_transitions`: an associative array where:
 ///  * key: state number
 ///  * value: state number
 /// An entry in the `match_anything_transitions` array indicates that there's a fallback transition that
 /// accepts any word
 ///
+/// `bindings`: an associative array where:
+///  * key: source and target state numbers of a match-anything transition, separated by a comma
+///  * value: space-separated names the word matched by the transition gets bound to
+///
+/// `once`: an associative array where:
+///  * key: state number and literal (or prefix) id, separated by a comma
+///  * value: space-separated ids of the @once(...) options the transition stands for
+///
+/// Once a transition has been taken, none of the transitions sharing an id with it is offered anymore
+///
+/// `exclusive`: an associative array where:
+///  * key: state number and literal (or prefix) id, separated by a comma
+///  * value: space-separated tags of the @exclusive(...) alternatives the transition starts
+///
+/// `exclusions`: an associative array where:
+///  * key: tag of an @exclusive(...) alternative
+///  * value: space-separated tags of the other alternatives of the same @exclusive(...)
+///
+/// Once a transition has been taken, none of the transitions starting the other alternatives is offered anymore
+///
+/// `end_of_options_states`: an associative array where:
+///  * key: number of a state reached through <END_OF_OPTIONS>, i.e. after which only positionals follow
+///  * value: 1
+///
+/// `command_timeouts`, `command_cache_ttls`: associative arrays where:
+///  * key: command id
+///  * value: seconds, from @timeout(...) and @cache(...) in the grammar
+///
 fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
     let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id, literal, description.unwrap_or(ustr("")))).collect();
 
     let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, input, description)| ((*input, *description), *id)).collect();
-    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| literal), " ");
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| quote_bash_string(literal)), " ");
     writeln!(buffer, r#"    local -a literals=({literals})"#)?;
     writeln!(buffer, "")?;
 
     writeln!(buffer, r#"    declare -A transitions"#)?;
     for state in dfa.get_all_states() {
-        let transitions = dfa.get_literal_transitions_from(StateId::try_from(state).unwrap());
+        let transitions = dfa.get_literal_transitions_from(state);
         if transitions.is_empty() {
             continue;
         }
@@ -42,12 +129,45 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
         writeln!(buffer, r#"    transitions[{state}]="({state_transitions})""#)?;
     }
 
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    declare -A prefix_transitions"#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(prefix, to)| format!("[{}]={}", literal_id_from_input_description.get(&(prefix, ustr(""))).unwrap(), to)), " ");
+        writeln!(buffer, r#"    prefix_transitions[{state}]="({state_transitions})""#)?;
+    }
+
     writeln!(buffer, "")?;
 
     writeln!(buffer, r#"    declare -A match_anything_transitions"#)?;
     let match_anything_transitions = itertools::join(dfa.get_match_anything_transitions().into_iter().map(|(from, to)| format!("[{from}]={to}")), " ");
     writeln!(buffer, r#"    match_anything_transitions=({match_anything_transitions})"#)?;
 
+    writeln!(buffer, r#"    declare -A bindings"#)?;
+    let bindings = itertools::join(dfa.get_binding_transitions().into_iter().map(|(from, to, names)| format!(r#"[{from},{to}]="{}""#, itertools::join(names, " "))), " ");
+    writeln!(buffer, r#"    bindings=({bindings})"#)?;
+
+    let once = itertools::join(dfa.get_once_transitions().into_iter().map(|(from, literal, description, ids)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!(r#"[{from},{literal_id}]="{}""#, itertools::join(ids, " "))
+    }), " ");
+    writeln!(buffer, r#"    declare -A once=({once})"#)?;
+
+    let exclusive = itertools::join(dfa.get_exclusive_transitions().into_iter().map(|(from, literal, description, tags)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!(r#"[{from},{literal_id}]="{}""#, itertools::join(tags, " "))
+    }), " ");
+    writeln!(buffer, r#"    declare -A exclusive=({exclusive})"#)?;
+    let exclusions = itertools::join(dfa.get_exclusions().into_iter().map(|(tag, others)| format!(r#"[{tag}]="{}""#, itertools::join(others, " "))), " ");
+    writeln!(buffer, r#"    declare -A exclusions=({exclusions})"#)?;
+
+    let end_of_options_states = itertools::join(dfa.end_of_options_states.iter().map(|state| format!("[{state}]=1")), " ");
+    writeln!(buffer, r#"    declare -A end_of_options_states=({end_of_options_states})"#)?;
+
     Ok(())
 }
 
@@ -71,25 +191,61 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
 "#)?;
     }
 
+    writeln!(buffer, "{RUN_FUNCTION}")?;
+
     write!(buffer, r#"_{command} () {{
 "#)?;
 
     write_tables(buffer, dfa)?;
 
+    // Bash splits words on characters from COMP_WORDBREAKS, `=` being one of them by default.  Glue them back
+    // together so that e.g. `--color=al` is a single word.
     write!(buffer, r#"
+    local -a words=()
+    local cword=0
+    local i
+    for ((i = 0; i < ${{#COMP_WORDS[@]}} || i <= COMP_CWORD; i++)); do
+        if [[ $i -gt 0 && ( ${{COMP_WORDS[$i]}} = +([=:]) || ${{COMP_WORDS[$((i - 1))]}} = +([=:]) ) ]]; then
+            words[-1]+=${{COMP_WORDS[$i]}}
+        else
+            words+=("${{COMP_WORDS[$i]}}")
+        fi
+        if [[ $i -eq $COMP_CWORD ]]; then
+            cword=$((${{#words[@]}} - 1))
+        fi
+    done
+
+    local -x COMPLGEN_WORDS
+    printf -v COMPLGEN_WORDS '%s\n' "${{words[@]:1:cword-1}}"
+    COMPLGEN_WORDS=${{COMPLGEN_WORDS%$'\n'}}
+
+    # Words bound to names, e.g. by <repo=PATH>, as assignments to export to external commands
+    local -a bound_words=()
+
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    local -A given_once=()
+    local -A given_exclusive=()
+
     local state={starting_state}
     local word_index=1
-    while [[ $word_index -lt $COMP_CWORD ]]; do
+    while [[ $word_index -lt $cword ]]; do
+        local word=${{words[$word_index]}}
+
         if [[ -v "transitions[$state]" ]]; then
             local state_transitions_initializer=${{transitions[$state]}}
             declare -A state_transitions
             eval "state_transitions=$state_transitions_initializer"
 
-            local word=${{COMP_WORDS[$word_index]}}
             local word_matched=0
-            for literal_id in $(seq 1 ${{#literals[@]}}); do
-                if [[ ${{literals[$literal_id]}} = $word ]]; then
+            for literal_id in "${{!literals[@]}}"; do
+                if [[ ${{literals[$literal_id]}} = "$word" ]]; then
                     if [[ -v "state_transitions[$literal_id]" ]]; then
+                        for id in ${{once[$state,$literal_id]-}}; do
+                            given_once[$id]=1
+                        done
+                        for tag in ${{exclusive[$state,$literal_id]-}}; do
+                            given_exclusive[$tag]=1
+                        done
                         state=${{state_transitions[$literal_id]}}
                         word_index=$((word_index + 1))
                         word_matched=1
@@ -102,8 +258,41 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
             fi
         fi
 
+        if [[ -v "prefix_transitions[$state]" ]]; then
+            local state_prefix_transitions_initializer=${{prefix_transitions[$state]}}
+            declare -A state_prefix_transitions
+            eval "state_prefix_transitions=$state_prefix_transitions_initializer"
+
+            local longest_prefix_id=
+            local longest_prefix=
+            for literal_id in "${{!state_prefix_transitions[@]}}"; do
+                local prefix=${{literals[$literal_id]}}
+                if [[ $word = "$prefix"* && ${{#prefix}} -gt ${{#longest_prefix}} ]]; then
+                    longest_prefix_id=$literal_id
+                    longest_prefix=$prefix
+                fi
+            done
+            if [[ -n $longest_prefix_id ]]; then
+                for id in ${{once[$state,$longest_prefix_id]-}}; do
+                    given_once[$id]=1
+                done
+                for tag in ${{exclusive[$state,$longest_prefix_id]-}}; do
+                    given_exclusive[$tag]=1
+                done
+                state=${{state_prefix_transitions[$longest_prefix_id]}}
+                words[$word_index]=${{word#"$longest_prefix"}}
+                continue
+            fi
+        fi
+
         if [[ -v "match_anything_transitions[$state]" ]]; then
-            state=${{match_anything_transitions[$state]}}
+            local next_state=${{match_anything_transitions[$state]}}
+            if [[ -v "bindings[$state,$next_state]" ]]; then
+                for name in ${{bindings[$state,$next_state]}}; do
+                    bound_words+=("$name=$word")
+                done
+            fi
+            state=$next_state
             word_index=$((word_index + 1))
             continue
         fi
@@ -114,31 +303,96 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
 "#, starting_state = dfa.starting_state)?;
 
     write!(buffer, r#"
+    local cur=${{words[$cword]}}
+    local word_prefix=
     local completions=()
+    while true; do
+        local -a state_literal_ids=()
+
+        if [[ -v "transitions[$state]" ]]; then
+            local state_transitions_initializer=${{transitions[$state]}}
+            declare -A state_transitions
+            eval "state_transitions=$state_transitions_initializer"
+            state_literal_ids+=("${{!state_transitions[@]}}")
+        fi
 
-    if [[ -v "transitions[$state]" ]]; then
-        local state_transitions_initializer=${{transitions[$state]}}
-        declare -A state_transitions
-        eval "state_transitions=$state_transitions_initializer"
+        local longest_prefix_id=
+        local longest_prefix=
+        if [[ -v "prefix_transitions[$state]" ]]; then
+            local state_prefix_transitions_initializer=${{prefix_transitions[$state]}}
+            declare -A state_prefix_transitions
+            eval "state_prefix_transitions=$state_prefix_transitions_initializer"
+            state_literal_ids+=("${{!state_prefix_transitions[@]}}")
+
+            for literal_id in "${{!state_prefix_transitions[@]}}"; do
+                local prefix=${{literals[$literal_id]}}
+                if [[ $cur = "$prefix"* && ${{#prefix}} -gt ${{#longest_prefix}} ]]; then
+                    longest_prefix_id=$literal_id
+                    longest_prefix=$prefix
+                fi
+            done
+        fi
 
-        for literal_id in ${{!state_transitions[@]}}; do
-            completions+=(${{literals[$literal_id]}})
+        for literal_id in "${{state_literal_ids[@]}}"; do
+            local literal=${{literals[$literal_id]}}
+            # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+            if [[ $word_prefix = -[^-]* && ${{#literal}} -eq 1 && ${{word_prefix:1}} = *"$literal"* ]]; then
+                continue
+            fi
+            # Options from @once(...) aren't offered again once given
+            if [[ -z $word_prefix ]]; then
+                local id
+                for id in ${{once[$state,$literal_id]-}}; do
+                    [[ -v "given_once[$id]" ]] && continue 2
+                done
+            fi
+            # Alternatives of an @exclusive(...) aren't offered once another one of it has been given
+            if [[ -z $word_prefix ]]; then
+                local tag excluding_tag
+                for tag in ${{exclusive[$state,$literal_id]-}}; do
+                    for excluding_tag in ${{exclusions[$tag]}}; do
+                        [[ -v "given_exclusive[$excluding_tag]" ]] && continue 3
+                    done
+                done
+            fi
+            # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+            if [[ -z $word_prefix && -v "end_of_options_states[$state]" && $literal = -* ]]; then
+                continue
+            fi
+            completions+=("$word_prefix$literal")
         done
-    fi
+
+        if [[ -z $longest_prefix_id ]]; then
+            break
+        fi
+        state=${{state_prefix_transitions[$longest_prefix_id]}}
+        word_prefix+=$longest_prefix
+        cur=${{cur#"$longest_prefix"}}
+    done
 "#)?;
 
     let command_id_from_state: HashMap<StateId, usize> = dfa.get_command_transitions().into_iter().map(|(state, cmd)| (state, *id_from_command.get(&cmd).unwrap())).collect();
     if !command_id_from_state.is_empty() {
         writeln!(buffer, r#"    declare -A commands"#)?;
         let array_initializer = itertools::join(command_id_from_state.into_iter().map(|(state, id)| format!("[{state}]={id}")), " ");
-        write!(buffer, r#"    commands=({array_initializer})"#)?;
-        write!(buffer, r#"
-    if [[ -v "commands[$state]" ]]; then
+        writeln!(buffer, r#"    commands=({array_initializer})"#)?;
+        let annotations = dfa.get_command_annotations();
+        let timeouts = itertools::join(id_from_command.iter().filter_map(|(cmd, id)| Some(format!("[{id}]={}", annotations.get(cmd)?.get_timeout_seconds()?))), " ");
+        if !timeouts.is_empty() {
+            writeln!(buffer, r#"    declare -A command_timeouts=({timeouts})"#)?;
+        }
+        let cache_ttls = itertools::join(id_from_command.iter().filter_map(|(cmd, id)| Some(format!("[{id}]={}", annotations.get(cmd)?.get_cache_ttl_seconds()?))), " ");
+        if !cache_ttls.is_empty() {
+            writeln!(buffer, r#"    declare -A command_cache_ttls=({cache_ttls})"#)?;
+        }
+        write!(buffer, r#"    if [[ -v "commands[$state]" ]]; then
         local command_id=${{commands[$state]}}
-        IFS=$'\n' read -r -d '' -a command_completions < <( _{command}_${{command_id}} "${{COMP_WORDS[$COMP_CWORD]}}" && printf '\0' )
+        local command_output
+        _complgen_run _{command}_${{command_id}} "${{command_timeouts[$command_id]-}}" "${{command_cache_ttls[$command_id]-}}"
+        IFS=$'\n' read -r -d '' -a command_completions <<< "$command_output"
         for line in "${{command_completions[@]}}"; do
-            local elem=$(echo "$line" | cut -f1)
-            completions+=($elem)
+            local elem=${{line%%$'\t'*}}
+            completions+=("$word_prefix$elem")
         done
     fi
 
@@ -154,10 +408,12 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
         write!(buffer, r#"
     if [[ -v "specialized_commands[$state]" ]]; then
         local command_id=${{specialized_commands[$state]}}
-        IFS=$'\n' read -r -d '' -a command_completions < <( _{command}_spec_${{command_id}} "${{COMP_WORDS[$COMP_CWORD]}}" && printf '\0' )
+        local command_output
+        _complgen_run _{command}_spec_${{command_id}}
+        IFS=$'\n' read -r -d '' -a command_completions <<< "$command_output"
         for line in "${{command_completions[@]}}"; do
-            local elem=$(echo "$line" | cut -f1)
-            completions+=($elem)
+            local elem=${{line%%$'\t'*}}
+            completions+=("$word_prefix$elem")
         done
     fi
 
@@ -165,8 +421,29 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
     }
 
     write!(buffer, r#"
-    completions=${{completions[@]}}
-    COMPREPLY=($(compgen -W "$completions" -- "${{COMP_WORDS[$COMP_CWORD]}}"))
+    # Bash is only going to replace the part of the word after the last `=` or `:`
+    local ltrim=
+    local wordbreaks=${{COMP_WORDBREAKS//[^=:]}}
+    if [[ -n $wordbreaks && ${{words[$cword]}} = *[$wordbreaks]* ]]; then
+        ltrim=${{words[$cword]%"${{words[$cword]##*[$wordbreaks]}}"}}
+    fi
+
+    # Not using `compgen -W` as it would split completions containing whitespace and expand globs
+    COMPREPLY=()
+    local -A seen=()
+    local completion
+    for completion in "${{completions[@]}}"; do
+        [[ $completion = "${{words[$cword]}}"* ]] || continue
+        completion=${{completion#"$ltrim"}}
+        if [[ -n $completion && -z ${{seen[$completion]+set}} ]]; then
+            seen[$completion]=1
+            COMPREPLY+=("$completion")
+        fi
+    done
+
+    if [[ ${{#COMPREPLY[@]}} -eq 1 && ${{COMPREPLY[0]}} = *= ]]; then
+        compopt -o nospace 2>/dev/null
+    fi
     return 0
 }}
 
@@ -174,3 +451,56 @@ complete -F _{command} {command}
 "#)?;
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use crate::grammar::ExprKind;
+    use crate::regex::AugmentedRegex;
+
+    use super::*;
+
+    fn get_completions(literals: &[String]) -> Vec<String> {
+        let expr = ExprKind::Alternative(literals.iter().map(|literal| ExprKind::Terminal(ustr(literal), None).into()).collect());
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &UstrMap::default(), &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let mut script: Vec<u8> = Default::default();
+        write_completion_script(&mut script, "cmd", &dfa).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let output = Command::new("bash").arg("-c").arg(format!(r#"{script}
+COMP_WORDS=(cmd '')
+COMP_CWORD=1
+_cmd
+printf '%s\0' "${{COMPREPLY[@]}}"
+"#)).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut completions: Vec<String> = stdout.split_terminator('\0').map(|s| s.to_string()).collect();
+        completions.sort();
+        completions
+    }
+
+    #[test]
+    fn completes_literals_containing_whitespace_and_globs() {
+        let literals = ["foo bar".to_string(), "*".to_string(), "it's".to_string()];
+        assert_eq!(get_completions(&literals), vec!["*", "foo bar", "it's"]);
+    }
+
+    proptest! {
+        #[test]
+        fn quoting_round_trips(s in "[^\\x00]*") {
+            let output = Command::new("bash").arg("-c").arg(format!("printf %s {}", quote_bash_string(&s))).output().unwrap();
+            prop_assert_eq!(String::from_utf8(output.stdout).unwrap(), s);
+        }
+
+        #[test]
+        fn completes_arbitrary_literals(literal in "[^\\x00]{1,16}") {
+            prop_assert_eq!(get_completions(std::slice::from_ref(&literal)), vec![literal]);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use complgen::Result;
+use serde::{Deserialize, Serialize};
+use ustr::Ustr;
+
+use crate::dfa::DFA;
+use crate::grammar::Grammar;
+use crate::json::Document;
+
+
+// Building the DFA is by far the most expensive part of `complgen complete`.  To avoid paying for it on every
+// TAB press, minimized automata get stored in a binary file next to the grammar, e.g. `darcs.usage.dfa`.  The
+// cache is valid as long as it's been written by the same complgen version and neither the grammar nor any of
+// the files it includes changed since.
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Source {
+    path: PathBuf,
+    hash: u64,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Cache {
+    complgen_version: String,
+    sources: Vec<Source>, // The grammar file first, followed by the included ones
+    document: Document,
+}
+
+
+pub fn get_cache_path(usage_file_path: &Path) -> PathBuf {
+    let mut file_name = usage_file_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".dfa");
+    usage_file_path.with_file_name(file_name)
+}
+
+
+fn hash_file(path: &Path) -> std::io::Result<u64> {
+    let contents = std::fs::read(path)?;
+    Ok(xxhash_rust::xxh3::xxh3_64(&contents))
+}
+
+
+fn is_up_to_date(cache: &Cache) -> bool {
+    if cache.complgen_version != env!("CARGO_PKG_VERSION") {
+        log::debug!("DFA cache written by complgen {}", cache.complgen_version);
+        return false;
+    }
+    cache.sources.iter().all(|source| match hash_file(&source.path) {
+        Ok(hash) if hash == source.hash => true,
+        _ => {
+            log::debug!("DFA cache out of date: {}", source.path.display());
+            false
+        },
+    })
+}
+
+
+/// Returns None if there's no usable cache for the grammar, for whatever reason.
+pub fn load(usage_file_path: &Path) -> Option<Vec<(Ustr, DFA)>> {
+    let cache_path = get_cache_path(usage_file_path);
+    let bytes = std::fs::read(&cache_path).ok()?;
+    let cache: Cache = match rmp_serde::from_slice(&bytes) {
+        Ok(cache) => cache,
+        Err(e) => {
+            log::debug!("Unable to decode {}: {}", cache_path.display(), e);
+            return None;
+        },
+    };
+    match cache.sources.first() {
+        Some(source) if usage_file_path.canonicalize().ok()? == source.path => {},
+        _ => return None,
+    }
+    if !is_up_to_date(&cache) {
+        return None;
+    }
+    cache.document.to_dfas().ok()
+}
+
+
+/// Writes the cache atomically so that concurrent completions never see a partially written file.
+pub fn store(usage_file_path: &Path, grammar: &Grammar, dfas: &[(Ustr, DFA)]) -> Result<()> {
+    let mut sources: Vec<Source> = Default::default();
+    for path in std::iter::once(usage_file_path.canonicalize()?).chain(grammar.get_included_files().iter().cloned()) {
+        let hash = hash_file(&path)?;
+        sources.push(Source { path, hash });
+    }
+    let cache = Cache {
+        complgen_version: env!("CARGO_PKG_VERSION").to_string(),
+        sources,
+        document: Document::from_dfas(dfas),
+    };
+    let bytes = rmp_serde::to_vec_named(&cache)?;
+
+    let cache_path = get_cache_path(usage_file_path);
+    let directory = cache_path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(directory)?;
+    file.write_all(&bytes)?;
+    file.persist(&cache_path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+
+// Created by us and accessible to no one else, so that nobody can plant entries in it.  Anything else found at
+// `path` gets refused.
+fn ensure_private_directory(path: &Path) -> std::io::Result<()> {
+    match std::fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {},
+        Err(e) => return Err(e),
+    }
+    let metadata = std::fs::symlink_metadata(path)?;
+    // SAFETY: getuid() can't fail
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(std::io::Error::new(ErrorKind::PermissionDenied, format!("{} isn't private to the user", path.display())));
+    }
+    Ok(())
+}
+
+
+/// Where output of external commands annotated with @cache(...) gets stored, in files named after a hash of
+/// everything the command gets to see, so that `complgen complete` can reuse it across invocations.  Falls back
+/// to a per-user directory in the shared temporary one if there's no home directory.
+fn get_command_output_cache_directory() -> std::io::Result<PathBuf> {
+    match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(cache_home), _) if !cache_home.is_empty() => Ok(PathBuf::from(cache_home).join("complgen")),
+        (_, Some(home)) if !home.is_empty() => Ok(Path::new(&home).join(".cache").join("complgen")),
+        _ => {
+            // SAFETY: getuid() can't fail
+            let directory = std::env::temp_dir().join(format!("complgen-{}", unsafe { libc::getuid() }));
+            ensure_private_directory(&directory)?;
+            Ok(directory)
+        },
+    }
+}
+
+
+fn get_command_output_path(directory: &Path, key: &str) -> PathBuf {
+    directory.join(format!("{:016x}", xxhash_rust::xxh3::xxh3_64(key.as_bytes())))
+}
+
+
+fn load_command_output_from(directory: &Path, key: &str, ttl: Duration) -> Option<String> {
+    let path = get_command_output_path(directory, key);
+    let age = std::fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age >= ttl {
+        log::debug!("Command output cache expired: {}", path.display());
+        return None;
+    }
+    std::fs::read_to_string(&path).ok()
+}
+
+
+fn store_command_output_in(directory: &Path, key: &str, output: &str) -> Result<()> {
+    std::fs::create_dir_all(directory)?;
+    let mut file = tempfile::NamedTempFile::new_in(directory)?;
+    file.write_all(output.as_bytes())?;
+    file.persist(get_command_output_path(directory, key)).map_err(|e| e.error)?;
+    Ok(())
+}
+
+
+/// Returns None if there's no output stored under `key` within the last `ttl`.  Entries expire based on their
+/// modification time.
+pub fn load_command_output(key: &str, ttl: Duration) -> Option<String> {
+    load_command_output_from(&get_command_output_cache_directory().ok()?, key, ttl)
+}
+
+
+pub fn store_command_output(key: &str, output: &str) -> Result<()> {
+    store_command_output_in(&get_command_output_cache_directory()?, key, output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use bumpalo::Bump;
+    use crate::grammar::ValidGrammar;
+    use crate::regex::AugmentedRegex;
+
+    fn compile(usage_file_path: &Path) -> (Grammar, Vec<(Ustr, DFA)>) {
+        let input = std::fs::read_to_string(usage_file_path).unwrap();
+        let grammar = Grammar::parse(&input).unwrap().resolve_includes(Some(usage_file_path)).unwrap();
+        let validated = ValidGrammar::from_grammar(&grammar).unwrap();
+        let arena = Bump::new();
+        let dfas = validated.commands.iter().map(|command| {
+            let regex = AugmentedRegex::from_expr(&command.expr, &validated.specializations, &arena).unwrap();
+            (command.command, DFA::from_regex(&regex).unwrap().minimize())
+        }).collect();
+        (grammar, dfas)
+    }
+
+    fn to_json(dfas: &[(Ustr, DFA)]) -> String {
+        let mut buffer: Vec<u8> = Default::default();
+        crate::json::write_dfas(&mut buffer, dfas).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn loads_stored_automata() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage_file_path = dir.path().join("grep.usage");
+        std::fs::write(&usage_file_path, "grep --color=(always | never) <PATTERN>;").unwrap();
+        assert!(load(&usage_file_path).is_none());
+
+        let (grammar, dfas) = compile(&usage_file_path);
+        store(&usage_file_path, &grammar, &dfas).unwrap();
+        assert!(dir.path().join("grep.usage.dfa").exists());
+
+        let loaded = load(&usage_file_path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].0, "grep");
+        assert_eq!(to_json(&loaded), to_json(&dfas));
+    }
+
+    #[test]
+    fn invalidates_on_grammar_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage_file_path = dir.path().join("grep.usage");
+        std::fs::write(&usage_file_path, "grep --color;").unwrap();
+        let (grammar, dfas) = compile(&usage_file_path);
+        store(&usage_file_path, &grammar, &dfas).unwrap();
+        std::fs::write(&usage_file_path, "grep --colour;").unwrap();
+        assert!(load(&usage_file_path).is_none());
+    }
+
+    #[test]
+    fn invalidates_on_included_file_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage_file_path = dir.path().join("grep.usage");
+        let included_path = dir.path().join("color.usage");
+        std::fs::write(&usage_file_path, "include \"color.usage\";\ngrep <COLOR>;").unwrap();
+        std::fs::write(&included_path, "<COLOR> ::= always | never;").unwrap();
+        let (grammar, dfas) = compile(&usage_file_path);
+        store(&usage_file_path, &grammar, &dfas).unwrap();
+        assert!(load(&usage_file_path).is_some());
+        std::fs::write(&included_path, "<COLOR> ::= always | never | auto;").unwrap();
+        assert!(load(&usage_file_path).is_none());
+    }
+
+    #[test]
+    fn refuses_shared_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let private = dir.path().join("private");
+        ensure_private_directory(&private).unwrap();
+        assert_eq!(std::fs::metadata(&private).unwrap().mode() & 0o777, 0o700);
+        ensure_private_directory(&private).unwrap();
+
+        let shared = dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(ensure_private_directory(&shared).is_err());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(ensure_private_directory(&file).is_err());
+    }
+
+    #[test]
+    fn expires_command_output() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_command_output_from(dir.path(), "kubectl get ns", Duration::from_secs(60)).is_none());
+        store_command_output_in(dir.path(), "kubectl get ns", "default\nkube-system\n").unwrap();
+        assert_eq!(load_command_output_from(dir.path(), "kubectl get ns", Duration::from_secs(60)).as_deref(), Some("default\nkube-system\n"));
+        assert!(load_command_output_from(dir.path(), "kubectl get pods", Duration::from_secs(60)).is_none());
+        assert!(load_command_output_from(dir.path(), "kubectl get ns", Duration::ZERO).is_none());
+    }
+}
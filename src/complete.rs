@@ -1,38 +1,132 @@
 use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use std::{io::Write, process::Output};
-use std::process::Command;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
 
 use complgen::StateId;
 use hashbrown::HashMap;
 
-use ustr::ustr;
+use roaring::RoaringBitmap;
+use ustr::{Ustr, ustr};
 use anyhow::{anyhow, Context};
 
-use crate::grammar::Specialization;
+use crate::cache;
+use crate::grammar::{CommandAnnotations, Specialization};
+use crate::nushell::escape_nushell_string;
+use crate::zsh::escape_zsh_string;
 use crate::{dfa::DFA, regex::{Input, MatchAnythingInput}};
 
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Shell {
     Bash,
     Fish,
     Zsh,
+    Powershell,
+    Nushell,
 }
 
 
-fn shell_out_bash(command: &str) -> anyhow::Result<Output> {
-    Command::new("bash").arg("-c").arg(command).output().map_err(Into::into)
+// What external commands get to see in their environment, like in the generated scripts: the words preceding
+// the completed one, one per line, and the words bound to names, e.g. by <repo=PATH>.  They run in the shell's
+// working directory, which isn't necessarily ours, e.g. under `complgen serve`.
+struct CommandEnv<'a> {
+    words: &'a [&'a str],
+    bindings: Vec<(Ustr, &'a str)>,
+    cwd: &'a Path,
 }
 
 
-fn shell_out_fish(command: &str) -> anyhow::Result<Output> {
-    Command::new("fish").arg("-c").arg(command).output().map_err(Into::into)
+// Errors from external commands would otherwise end up in the middle of the user's prompt
+fn is_debug_enabled() -> bool {
+    std::env::var_os("COMPLGEN_DEBUG").is_some()
 }
 
 
-fn shell_out_zsh(command: &str) -> anyhow::Result<Output> {
-    Command::new("zsh").arg("-c").arg(command).output().map_err(Into::into)
+// Drains a pipe on a separate thread so that the command never blocks on it being full
+fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut bytes: Vec<u8> = Default::default();
+        let _ = reader.read_to_end(&mut bytes);
+        let _ = sender.send(bytes);
+    });
+    receiver
+}
+
+
+fn run(command: &mut Command, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<Output> {
+    command.current_dir(env.cwd);
+    command.env("COMPLGEN_WORDS", env.words.join("\n"));
+    command.envs(env.bindings.iter().map(|(name, word)| (name.as_str(), word)));
+    let Some(timeout) = timeout else {
+        return command.output().map_err(Into::into);
+    };
+
+    // In a process group of its own so that processes it spawned, e.g. in a pipeline, can be killed along with it
+    let deadline = Instant::now() + timeout;
+    let mut child = command.process_group(0).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+    // Processes spawned by the command may keep the pipes open after it exits, hence the deadline applies to
+    // reading them as well
+    let mut wait = || -> Option<Output> {
+        let stdout = stdout.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok()?;
+        let stderr = stderr.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok()?;
+        loop {
+            if let Some(status) = child.try_wait().ok()? {
+                return Some(Output { status, stdout, stderr });
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    };
+    match wait() {
+        Some(output) => Ok(output),
+        None => {
+            // SAFETY: kill() has no memory safety preconditions.  The group id is the child's pid.
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+            Err(anyhow!("Command timed out after {}ms", timeout.as_millis()))
+        },
+    }
+}
+
+
+fn shell_out_bash(command: &str, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<Output> {
+    run(Command::new("bash").arg("-c").arg(command), env, timeout)
+}
+
+
+fn shell_out_fish(command: &str, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<Output> {
+    run(Command::new("fish").arg("-c").arg(command), env, timeout)
+}
+
+
+fn shell_out_zsh(command: &str, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<Output> {
+    run(Command::new("zsh").arg("-c").arg(command), env, timeout)
+}
+
+
+fn shell_out_powershell(command: &str, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<Output> {
+    run(Command::new("pwsh").arg("-NoProfile").arg("-NonInteractive").arg("-Command").arg(command), env, timeout)
+}
+
+
+fn shell_out_sh(command: &str, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<Output> {
+    run(Command::new("sh").arg("-c").arg(command), env, timeout)
+}
+
+
+fn shell_out_nushell(command: &str, env: &CommandEnv) -> anyhow::Result<Output> {
+    run(Command::new("nu").arg("--no-config-file").arg("--commands").arg(command), env, None)
 }
 
 
@@ -51,28 +145,45 @@ fn stdout_from_output(output: Output) -> anyhow::Result<String> {
 }
 
 
-fn get_bash_command_stdout(command: &str) -> anyhow::Result<String> {
-    let output = shell_out_bash(command).with_context(|| command.to_string())?;
+fn get_bash_command_stdout(command: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    let output = shell_out_bash(command, env, None).with_context(|| command.to_string())?;
     stdout_from_output(output)
 }
 
-fn get_fish_command_stdout(command: &str) -> anyhow::Result<String> {
-    let output = shell_out_fish(command).with_context(|| command.to_string())?;
+fn get_fish_command_stdout(command: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    let output = shell_out_fish(command, env, None).with_context(|| command.to_string())?;
     stdout_from_output(output)
 }
 
-fn get_zsh_command_stdout(command: &str) -> anyhow::Result<String> {
-    let output = shell_out_zsh(command).with_context(|| command.to_string())?;
+fn get_zsh_command_stdout(command: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    let output = shell_out_zsh(command, env, None).with_context(|| command.to_string())?;
     stdout_from_output(output)
 }
 
-fn get_zsh_script_stdout<P: AsRef<OsStr>>(script_path: P, arg: &str) -> anyhow::Result<String> {
-    let output = Command::new("zsh").arg(script_path).arg(arg).output()?;
+fn get_powershell_command_stdout(command: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    let output = shell_out_powershell(command, env, None).with_context(|| command.to_string())?;
     stdout_from_output(output)
 }
 
+fn get_sh_command_stdout(command: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    let output = shell_out_sh(command, env, None).with_context(|| command.to_string())?;
+    stdout_from_output(output)
+}
 
-fn capture_zsh_completions(completion_code: &str, command: &str, user_input: &str) -> anyhow::Result<String> {
+fn get_nushell_command_stdout(command: &str, prefix: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    // Specialized Nushell commands receive the completed word prefix in `$cur` and may produce either a string or a list
+    let script = format!(r#"do {{|cur| {command} }} "{}" | to text"#, escape_nushell_string(prefix));
+    let output = shell_out_nushell(&script, env).with_context(|| command.to_string())?;
+    stdout_from_output(output)
+}
+
+fn get_zsh_script_stdout<P: AsRef<OsStr>>(script_path: P, arg: &str, env: &CommandEnv) -> anyhow::Result<String> {
+    let output = run(Command::new("zsh").arg(script_path).arg(arg), env, None)?;
+    stdout_from_output(output)
+}
+
+
+fn capture_zsh_completions(completion_code: &str, command: &str, user_input: &str, env: &CommandEnv) -> anyhow::Result<String> {
     let preamble = include_str!("../capture_preamble.zsh");
     let postamble = include_str!("../capture_postamble.zsh");
 
@@ -89,7 +200,7 @@ fn capture_zsh_completions(completion_code: &str, command: &str, user_input: &st
 
     capture_script.as_file().flush()?;
 
-    get_zsh_script_stdout(capture_script.path(), user_input)
+    get_zsh_script_stdout(capture_script.path(), user_input, env)
         .with_context(|| completion_code.to_string())
         .with_context(|| command.to_string())
         .with_context(|| user_input.to_string())
@@ -97,39 +208,97 @@ fn capture_zsh_completions(completion_code: &str, command: &str, user_input: &st
 
 
 impl Shell {
-    fn shell_out(&self, command: &str) -> anyhow::Result<String> {
+    fn shell_out(&self, command: &str, env: &CommandEnv, timeout: Option<Duration>) -> anyhow::Result<String> {
         let output = match self {
-            Shell::Bash => shell_out_bash(command)?,
-            Shell::Fish => shell_out_fish(command)?,
-            Shell::Zsh => shell_out_zsh(command)?,
+            Shell::Bash => shell_out_bash(command, env, timeout)?,
+            Shell::Fish => shell_out_fish(command, env, timeout)?,
+            Shell::Zsh => shell_out_zsh(command, env, timeout)?,
+            Shell::Powershell => shell_out_powershell(command, env, timeout)?,
+            // Nushell syntax is too far from POSIX shells to run generic commands with it
+            Shell::Nushell => shell_out_sh(command, env, timeout)?,
         };
 
         stdout_from_output(output)
     }
+
+    // Only successful runs get cached.  The key covers everything the command gets to see, including the
+    // working directory, e.g. for `git branch`.
+    fn get_command_stdout(&self, command: &str, env: &CommandEnv, annotations: &CommandAnnotations) -> anyhow::Result<String> {
+        let Some(ttl) = annotations.cache_ttl else {
+            return self.shell_out(command, env, annotations.timeout);
+        };
+        let mut key = format!("{self:?}\0{}\0{command}\0{}", env.cwd.display(), env.words.join("\n"));
+        for (name, word) in &env.bindings {
+            key.push_str(&format!("\0{name}={word}"));
+        }
+        if let Some(stdout) = cache::load_command_output(&key, ttl) {
+            return Ok(stdout);
+        }
+        let stdout = self.shell_out(command, env, annotations.timeout)?;
+        if let Err(e) = cache::store_command_output(&key, &stdout) {
+            log::debug!("Unable to cache the output of {}: {}", command, e);
+        }
+        Ok(stdout)
+    }
 }
 
 
-pub fn get_match_final_state(dfa: &DFA, inputs: &[&str], completed_word_index: usize) -> Option<StateId> {
-    let mut backtracking_stack = Vec::from_iter([(0, dfa.starting_state)]);
-    while let Some((input_index, current_state)) = backtracking_stack.pop() {
-        if input_index >= inputs.len() {
-            return Some(current_state);
+// What matching the words preceding the completed one arrived at
+#[derive(Clone)]
+pub struct Match<'a> {
+    pub state: StateId,
+    pub bindings: Vec<(Ustr, &'a str)>, // Words bound to names along the way, e.g. by <repo=PATH>
+    pub given_once: RoaringBitmap, // Ids of the @once(...) options given along the way, see DFA::once
+    pub given_exclusive: RoaringBitmap, // Tags of the @exclusive(...) alternatives given along the way, see DFA::exclusive
+}
+
+
+impl<'a> Match<'a> {
+    // Follows a literal or prefix transition, recording what it stands for
+    fn follow(&self, dfa: &DFA, input: &Input, to: StateId) -> Self {
+        let mut result = self.clone();
+        result.given_once.extend(dfa.once.get(&(self.state, *input)).into_iter().flatten());
+        result.given_exclusive.extend(dfa.exclusive.get(&(self.state, *input)).into_iter().flatten());
+        result.state = to;
+        result
+    }
+}
+
+
+pub fn get_match_final_state<'a>(dfa: &DFA, inputs: &[&'a str], completed_word_index: usize) -> Option<Match<'a>> {
+    // (word index, byte offset into that word, what's been matched so far)
+    let starting_match = Match { state: dfa.starting_state, bindings: vec![], given_once: RoaringBitmap::new(), given_exclusive: RoaringBitmap::new() };
+    let mut backtracking_stack = Vec::from_iter([(0, 0, starting_match)]);
+    while let Some((input_index, offset, current)) = backtracking_stack.pop() {
+        if input_index >= inputs.len() || input_index >= completed_word_index {
+            return Some(current);
         }
 
-        if input_index >= completed_word_index {
-            return Some(current_state);
+        let word = &inputs[input_index][offset..];
+
+        for (transition_input, to) in dfa.transitions.get(&current.state).unwrap_or(&HashMap::default()) {
+            if let Input::Any(_) = transition_input {
+                let mut next = current.clone();
+                for name in dfa.bindings.get(&(current.state, *to)).into_iter().flatten() {
+                    next.bindings.push((*name, word));
+                }
+                next.state = *to;
+                backtracking_stack.push((input_index + 1, 0, next));
+            }
         }
 
-        for (transition_input, to) in dfa.transitions.get(&current_state).unwrap_or(&HashMap::default()) {
-            if transition_input.matches_anything() {
-                backtracking_stack.push((input_index + 1, *to));
+        for (transition_input, to) in dfa.transitions.get(&current.state).unwrap_or(&HashMap::default()) {
+            if let Input::Prefix(prefix) = transition_input {
+                if word.starts_with(prefix.as_str()) {
+                    backtracking_stack.push((input_index, offset + prefix.len(), current.follow(dfa, transition_input, *to)));
+                }
             }
         }
 
-        for (transition_input, to) in dfa.transitions.get(&current_state).unwrap_or(&HashMap::default()) {
+        for (transition_input, to) in dfa.transitions.get(&current.state).unwrap_or(&HashMap::default()) {
             if let Input::Literal(s, _) = transition_input {
-                if s.as_str() == inputs[input_index] {
-                    backtracking_stack.push((input_index + 1, *to));
+                if s.as_str() == word {
+                    backtracking_stack.push((input_index + 1, 0, current.follow(dfa, transition_input, *to)));
                 }
             }
         }
@@ -138,26 +307,113 @@ pub fn get_match_final_state(dfa: &DFA, inputs: &[&str], completed_word_index: u
 }
 
 
-fn capture_specialized_completions(shell: Shell, specialization: &Specialization, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+// Consume the longest `Input::Prefix` the remainder of the word being completed starts with, e.g. `--color=` in
+// `--color=al`.
+fn split_completed_word_prefix<'a>(dfa: &DFA, state_id: StateId, word: &'a str) -> Option<(StateId, &'a str, &'a str)> {
+    dfa.get_prefix_transitions_from(state_id).into_iter().find(|(prefix, _)| word.starts_with(prefix.as_str())).map(|(prefix, to)| (to, &word[..prefix.len()], &word[prefix.len()..]))
+}
+
+
+// Within a short options cluster, e.g. `-xv`, only offer the options that aren't part of it yet.
+fn is_repeated_cluster_option(word_prefix: &str, completion: &str) -> bool {
+    if !word_prefix.starts_with('-') || word_prefix.starts_with("--") {
+        return false;
+    }
+    let mut chars = completion.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => word_prefix[1..].contains(c),
+        _ => false,
+    }
+}
+
+
+// Options from @once(...) aren't offered again once the same occurrence of them has been matched and neither are
+// alternatives of an @exclusive(...) once another alternative of it has.  No options at all are offered after
+// <END_OF_OPTIONS>, i.e. `--`.
+fn is_ruled_out_option(dfa: &DFA, state_id: StateId, input: &Input, matched: &Match) -> bool {
+    if dfa.once.get(&(state_id, *input)).into_iter().flatten().any(|id| matched.given_once.contains(*id)) {
+        return true;
+    }
+    for tag in dfa.exclusive.get(&(state_id, *input)).into_iter().flatten() {
+        let group = dfa.exclusive_groups.iter().find(|group| group.contains(tag)).into_iter().flatten();
+        if group.filter(|other| *other != tag).any(|other| matched.given_exclusive.contains(*other)) {
+            return true;
+        }
+    }
+    match input {
+        Input::Literal(literal, _) | Input::Prefix(literal) => dfa.end_of_options_states.contains(state_id) && literal.starts_with('-'),
+        Input::Any(_) => false,
+    }
+}
+
+
+fn is_bash_wordbreak(word: &str) -> bool {
+    !word.is_empty() && word.chars().all(|c| c == '=' || c == ':')
+}
+
+
+// Bash splits words on characters from `COMP_WORDBREAKS`, `=` and `:` being among them by default, so that
+// `--color=al` arrives as `--color`, `=`, `al`.  Glue such words back together so that they can be matched
+// against `Input::Prefix`es and literals like `key:value`.  Returns the joined words and the new index of the
+// completed word.
+pub fn join_bash_wordbreaks(words: &[&str], completed_word_index: usize) -> (Vec<String>, usize) {
+    let mut joined: Vec<String> = Default::default();
+    let mut joined_completed_word_index = None;
+    for index in 0..std::cmp::max(words.len(), completed_word_index + 1) {
+        let word = words.get(index).copied().unwrap_or("");
+        let glue = index > 0 && (is_bash_wordbreak(word) || is_bash_wordbreak(words[index - 1]));
+        match joined.last_mut() {
+            Some(last) if glue => last.push_str(word),
+            _ => joined.push(word.to_string()),
+        }
+        if index == completed_word_index {
+            joined_completed_word_index = Some(joined.len() - 1);
+        }
+    }
+    if completed_word_index >= words.len() && joined.last().map(|s| s.is_empty()).unwrap_or(false) {
+        joined.pop();
+    }
+    (joined, joined_completed_word_index.unwrap())
+}
+
+
+fn capture_specialized_completions(shell: Shell, specialization: &Specialization, prefix: &str, env: &CommandEnv) -> anyhow::Result<Vec<(String, String)>> {
     let stdout = match shell {
         Shell::Bash => {
             let Some(command) = specialization.bash.or(specialization.generic) else {
                 return Ok(vec![]);
             };
-            get_bash_command_stdout(&command)?
+            get_bash_command_stdout(&command, env)?
         },
         Shell::Fish => {
             let Some(command) = specialization.fish.or(specialization.generic) else {
                 return Ok(vec![]);
             };
-            get_fish_command_stdout(&command)?
+            get_fish_command_stdout(&command, env)?
         },
         Shell::Zsh => {
             if let Some(command) = specialization.zsh {
-                capture_zsh_completions(&command, "dummy", &format!("dummy "))?
+                capture_zsh_completions(&command, "dummy", "dummy ", env)?
             }
             else if let Some(command) = specialization.generic {
-                get_zsh_command_stdout(&command)?
+                get_zsh_command_stdout(&command, env)?
+            }
+            else {
+                return Ok(vec![]);
+            }
+        },
+        Shell::Powershell => {
+            let Some(command) = specialization.powershell.or(specialization.generic) else {
+                return Ok(vec![]);
+            };
+            get_powershell_command_stdout(&command, env)?
+        },
+        Shell::Nushell => {
+            if let Some(command) = specialization.nushell {
+                get_nushell_command_stdout(&command, prefix, env)?
+            }
+            else if let Some(command) = specialization.generic {
+                get_sh_command_stdout(&command, env)?
             }
             else {
                 return Ok(vec![]);
@@ -176,7 +432,187 @@ fn capture_specialized_completions(shell: Shell, specialization: &Specialization
 }
 
 
-fn do_get_completions_for_input(input: &Input, prefix: &str, shell: Shell) -> anyhow::Result<Vec<(String, String)>> {
+// Predefined nonterminals like <PATH> are completed right here instead of shelling out.  Compiled scripts can't
+// call into complgen and use the shell equivalents from grammar.rs instead.  The parameter is what follows the
+// colon in e.g. <PATH:*.rs>.  Relative paths are relative to `cwd`.
+type NativeProvider = fn(prefix: &str, parameter: Option<&str>, shell: Shell, cwd: &Path) -> Vec<(String, String)>;
+
+const NATIVE_PROVIDERS: &[(&str, NativeProvider)] = &[
+    ("PATH", complete_paths),
+    ("DIRECTORY", complete_directories),
+    ("USER", complete_users),
+    ("GROUP", complete_groups),
+    ("HOST", complete_hosts),
+    ("ENVIRONMENT_VARIABLE", complete_environment_variables),
+    ("PID", complete_pids),
+    ("SIGNAL", complete_signals),
+];
+
+
+fn get_native_provider(nonterminal: &str) -> Option<NativeProvider> {
+    NATIVE_PROVIDERS.iter().find(|(name, _)| *name == nonterminal).map(|(_, provider)| *provider)
+}
+
+
+fn get_home_directory() -> Option<PathBuf> {
+    std::env::var_os("HOME").filter(|home| !home.is_empty()).map(PathBuf::from)
+}
+
+
+// Hidden files only show up once a dot is typed.  Bash is the odd one out in not marking directories with a
+// trailing slash.
+fn list_directory(prefix: &str, shell: Shell, cwd: &Path, directories_only: bool, glob: Option<&str>) -> Vec<(String, String)> {
+    let (directory, name_prefix) = match prefix.rfind('/') {
+        Some(index) => prefix.split_at(index + 1),
+        None => ("", prefix),
+    };
+    let path = match (directory.strip_prefix("~/"), get_home_directory()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => cwd.join(directory),
+    };
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return vec![];
+    };
+
+    let mut result: Vec<(String, String)> = Default::default();
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !name.starts_with(name_prefix) || (name.starts_with('.') && !name_prefix.starts_with('.')) {
+            continue;
+        }
+        // Symlinks to directories count as directories
+        let is_directory = entry.path().is_dir();
+        if !is_directory && (directories_only || glob.is_some_and(|glob| !crate::glob::is_match(glob, &name))) {
+            continue;
+        }
+        let suffix = if is_directory && shell != Shell::Bash { "/" } else { "" };
+        result.push((format!("{directory}{name}{suffix}"), "".to_string()));
+    }
+    result
+}
+
+
+// Files not matching the glob in <PATH:*.rs> are left out.  Directories never are so that they can be descended into.
+fn complete_paths(prefix: &str, glob: Option<&str>, shell: Shell, cwd: &Path) -> Vec<(String, String)> {
+    list_directory(prefix, shell, cwd, false, glob)
+}
+
+
+// Described the way fish's own __fish_complete_directories describes them
+fn complete_directories(prefix: &str, _: Option<&str>, shell: Shell, cwd: &Path) -> Vec<(String, String)> {
+    let description = if shell == Shell::Fish { "Directory" } else { "" };
+    list_directory(prefix, shell, cwd, true, None).into_iter().map(|(directory, _)| (directory, description.to_string())).collect()
+}
+
+
+// Names are the first field of each line in /etc/passwd and /etc/group
+fn read_account_database(path: &str) -> Vec<Vec<String>> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return vec![];
+    };
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(|field| field.to_owned()).collect())
+        .collect()
+}
+
+
+// Described with the full name, i.e. the first part of the GECOS field
+fn complete_users(_: &str, _: Option<&str>, _: Shell, _: &Path) -> Vec<(String, String)> {
+    read_account_database("/etc/passwd").into_iter().map(|fields| {
+        let full_name = fields.get(4).and_then(|gecos| gecos.split(',').next()).unwrap_or("").to_owned();
+        (fields[0].clone(), full_name)
+    }).collect()
+}
+
+
+fn complete_groups(_: &str, _: Option<&str>, _: Shell, _: &Path) -> Vec<(String, String)> {
+    read_account_database("/etc/group").into_iter().map(|fields| (fields[0].clone(), "".to_string())).collect()
+}
+
+
+// Names from /etc/hosts along with `Host` entries from ~/.ssh/config, except for patterns like `*.example.com`
+fn complete_hosts(_: &str, _: Option<&str>, _: Shell, _: &Path) -> Vec<(String, String)> {
+    let mut hosts: Vec<String> = Default::default();
+    if let Ok(contents) = std::fs::read_to_string("/etc/hosts") {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            hosts.extend(line.split_whitespace().skip(1).map(|host| host.to_owned()));
+        }
+    }
+    if let Some(contents) = get_home_directory().and_then(|home| std::fs::read_to_string(home.join(".ssh").join("config")).ok()) {
+        for line in contents.lines() {
+            let mut words = line.split_whitespace();
+            if !words.next().is_some_and(|keyword| keyword.eq_ignore_ascii_case("host")) {
+                continue;
+            }
+            let patterns = words.take_while(|word| !word.starts_with('#'));
+            hosts.extend(patterns.filter(|pattern| !pattern.contains(['*', '?', '!'])).map(|host| host.to_owned()));
+        }
+    }
+    hosts.into_iter().map(|host| (host, "".to_string())).collect()
+}
+
+
+fn complete_environment_variables(_: &str, _: Option<&str>, _: Shell, _: &Path) -> Vec<(String, String)> {
+    std::env::vars_os().filter_map(|(name, _)| name.into_string().ok()).map(|name| (name, "".to_string())).collect()
+}
+
+
+// Described with the executable name.  Only implemented on systems having /proc.
+fn complete_pids(_: &str, _: Option<&str>, _: Shell, _: &Path) -> Vec<(String, String)> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    entries.flatten().filter_map(|entry| {
+        let pid = entry.file_name().into_string().ok().filter(|name| name.bytes().all(|b| b.is_ascii_digit()))?;
+        let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        Some((pid, name.trim_end().to_owned()))
+    }).collect()
+}
+
+
+// The ones defined by POSIX, without the SIG prefix, the way `kill -s` takes them
+const SIGNALS: &[(&str, &str)] = &[
+    ("HUP", "Hangup"),
+    ("INT", "Interrupt"),
+    ("QUIT", "Quit"),
+    ("ILL", "Illegal instruction"),
+    ("TRAP", "Trace trap"),
+    ("ABRT", "Abort"),
+    ("BUS", "Bus error"),
+    ("FPE", "Floating point exception"),
+    ("KILL", "Kill"),
+    ("USR1", "User defined signal 1"),
+    ("SEGV", "Segmentation fault"),
+    ("USR2", "User defined signal 2"),
+    ("PIPE", "Broken pipe"),
+    ("ALRM", "Alarm clock"),
+    ("TERM", "Terminate"),
+    ("CHLD", "Child status changed"),
+    ("CONT", "Continue"),
+    ("STOP", "Stop"),
+    ("TSTP", "Terminal stop"),
+    ("TTIN", "Background read from terminal"),
+    ("TTOU", "Background write to terminal"),
+    ("URG", "Urgent I/O condition"),
+    ("XCPU", "CPU time limit exceeded"),
+    ("XFSZ", "File size limit exceeded"),
+    ("VTALRM", "Virtual timer expired"),
+    ("PROF", "Profiling timer expired"),
+    ("SYS", "Bad system call"),
+];
+
+
+fn complete_signals(_: &str, _: Option<&str>, _: Shell, _: &Path) -> Vec<(String, String)> {
+    SIGNALS.iter().map(|(name, description)| (name.to_string(), description.to_string())).collect()
+}
+
+
+fn do_get_completions_for_input(input: &Input, prefix: &str, shell: Shell, env: &CommandEnv) -> anyhow::Result<Vec<(String, String)>> {
     let completions = match input {
         Input::Literal(literal, description) => {
             if literal.starts_with(prefix) {
@@ -187,8 +623,17 @@ fn do_get_completions_for_input(input: &Input, prefix: &str, shell: Shell) -> an
             }
         },
 
-        Input::Any(MatchAnythingInput::Command(command)) => {
-            let stdout = shell.shell_out(command.as_str())?;
+        Input::Prefix(literal) => {
+            if literal.starts_with(prefix) {
+                vec![(literal.as_str().to_string(), "".to_string())]
+            }
+            else {
+                vec![]
+            }
+        },
+
+        Input::Any(MatchAnythingInput::Command(command, _, annotations)) => {
+            let stdout = shell.get_command_stdout(command.as_str(), env, annotations)?;
 
             let result: Vec<(String, String)> = stdout.lines().filter(|line| line.starts_with(prefix)).map(|line| match line.split_once("\t") {
                 Some((completion, description)) => (completion.to_owned(), description.to_owned()),
@@ -198,44 +643,154 @@ fn do_get_completions_for_input(input: &Input, prefix: &str, shell: Shell) -> an
             result
         },
 
-        Input::Any(MatchAnythingInput::Nonterminal(_, None)) => vec![],
+        Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => vec![],
 
-        Input::Any(MatchAnythingInput::Nonterminal(_, Some(specialization))) => capture_specialized_completions(shell, specialization, prefix)?
+        Input::Any(MatchAnythingInput::Nonterminal(name, Some(specialization), _)) => {
+            let (name, parameter) = match name.split_once(':') {
+                Some((name, parameter)) => (name, Some(parameter)),
+                None => (name.as_str(), None),
+            };
+            match get_native_provider(name).filter(|_| specialization.native) {
+                Some(provider) => provider(prefix, parameter, shell, env.cwd).into_iter().filter(|(completion, _)| completion.starts_with(prefix)).collect(),
+                None => capture_specialized_completions(shell, specialization, prefix, env)?,
+            }
+        },
     };
     Ok(completions)
 }
 
 
-fn get_completions_for_input(input: &Input, prefix: &str, shell: Shell) -> Vec<(String, String)> {
-    match do_get_completions_for_input(input, prefix, shell) {
+fn get_completions_for_input(input: &Input, prefix: &str, shell: Shell, env: &CommandEnv) -> Vec<(String, String)> {
+    match do_get_completions_for_input(input, prefix, shell, env) {
         Ok(completions) => completions,
         Err(e) => {
-            eprintln!("{:?}", e);
+            if is_debug_enabled() {
+                eprintln!("{:?}", e);
+            }
             return vec![];
         },
     }
 }
 
 
-pub fn get_completions<'a, 'b>(dfa: &DFA, words_before_cursor: &'b [&'a str], completed_word_index: usize, shell: Shell) -> Vec<(String, String)> {
-    let prefix = if completed_word_index < words_before_cursor.len() {
+pub fn get_completions<'a, 'b>(dfa: &DFA, words_before_cursor: &'b [&'a str], completed_word_index: usize, shell: Shell, cwd: &Path) -> Vec<(String, String)> {
+    let mut prefix = if completed_word_index < words_before_cursor.len() {
         words_before_cursor[completed_word_index]
     }
     else {
         ""
     };
 
-    let state_id = match get_match_final_state(dfa, words_before_cursor, completed_word_index) {
-        Some(state_id) => state_id,
-        None => return vec![],
+    let Some(matched) = get_match_final_state(dfa, words_before_cursor, completed_word_index) else {
+        return vec![];
+    };
+    let mut state_id = matched.state;
+
+    let env = CommandEnv {
+        words: &words_before_cursor[..std::cmp::min(completed_word_index, words_before_cursor.len())],
+        bindings: matched.bindings.clone(),
+        cwd,
     };
 
-    let mut completions: Vec<(String, String)> = dfa.transitions.get(&state_id).unwrap_or(&HashMap::default()).iter().map(|(input, _)| get_completions_for_input(input, prefix, shell)).flatten().collect();
-    completions.sort_unstable();
+    // Literals are offered at every prefix split, e.g. both `-x` and `-xv` when completing `-x`.  Commands
+    // only get to complete the final remainder of the word.
+    let no_transitions = HashMap::default();
+    let mut word_prefix = String::default();
+    let mut completions: Vec<(String, String)> = Default::default();
+    loop {
+        let transitions = dfa.transitions.get(&state_id).unwrap_or(&no_transitions);
+        let Some((to, consumed, rest)) = split_completed_word_prefix(dfa, state_id, prefix) else {
+            for (input, _) in transitions {
+                if word_prefix.is_empty() && is_ruled_out_option(dfa, state_id, input, &matched) {
+                    continue;
+                }
+                for (completion, description) in get_completions_for_input(input, prefix, shell, &env) {
+                    if !is_repeated_cluster_option(&word_prefix, &completion) {
+                        completions.push((format!("{word_prefix}{completion}"), description));
+                    }
+                }
+            }
+            break;
+        };
+        for (input, _) in transitions {
+            if word_prefix.is_empty() && is_ruled_out_option(dfa, state_id, input, &matched) {
+                continue;
+            }
+            if matches!(input, Input::Literal(..) | Input::Prefix(..)) {
+                for (completion, description) in get_completions_for_input(input, prefix, shell, &env) {
+                    if !is_repeated_cluster_option(&word_prefix, &completion) {
+                        completions.push((format!("{word_prefix}{completion}"), description));
+                    }
+                }
+            }
+        }
+        word_prefix.push_str(consumed);
+        state_id = to;
+        prefix = rest;
+    }
+
+    // The same word may be reachable both as a literal and as a prefix, e.g. in short options clusters.  Keep
+    // the one having a description.
+    completions.sort_unstable_by(|(left, left_description), (right, right_description)| left.cmp(right).then(right_description.cmp(left_description)));
+    completions.dedup_by(|(left, _), (right, _)| left == right);
     completions
 }
 
 
+/// Outputs completions in the format expected by the shell integration code (see README.md)
+pub fn write_completions<W: Write>(output: &mut W, dfa: &DFA, words_before_cursor: &[&str], completed_word_index: usize, shell: Shell, cwd: &Path) -> anyhow::Result<()> {
+    match shell {
+        Shell::Bash => {
+            let (joined_words, joined_completed_word_index) = join_bash_wordbreaks(words_before_cursor, completed_word_index);
+            let joined_words: Vec<&str> = joined_words.iter().map(|s| s.as_ref()).collect();
+            let completions = get_completions(dfa, &joined_words, joined_completed_word_index, shell, cwd);
+
+            // Bash is only going to replace the part of the word after the last `=` or `:`
+            let completed_word = words_before_cursor.get(completed_word_index).copied().unwrap_or("");
+            let joined_completed_word = joined_words.get(joined_completed_word_index).copied().unwrap_or("");
+            let ltrim = match joined_completed_word.rfind(['=', ':']) {
+                Some(index) if joined_completed_word != completed_word => index + 1,
+                _ => 0,
+            };
+
+            for (completion, _) in completions {
+                writeln!(output, "{}", &completion[ltrim..])?;
+            }
+        },
+        Shell::Fish => {
+            let completions = get_completions(dfa, words_before_cursor, completed_word_index, shell, cwd);
+            for (completion, description) in completions {
+                writeln!(output, "{}\t{}", completion, description)?;
+            }
+        },
+        Shell::Zsh => {
+            let completions = get_completions(dfa, words_before_cursor, completed_word_index, shell, cwd);
+            let completions_array_initializer = itertools::join(completions.iter().map(|(completion, _)| format!(r#""{}""#, escape_zsh_string(completion))), " ");
+            writeln!(output, r#"local -a completions=({completions_array_initializer})"#)?;
+
+            let descriptions_array_initializer = itertools::join(completions.iter().map(|(completion, description)| {
+                if !description.is_empty() {
+                    format!(r#""{} ({})""#, escape_zsh_string(completion), escape_zsh_string(description))
+                }
+                else {
+                    format!(r#""{}""#, escape_zsh_string(completion))
+                }
+            }), " ");
+            writeln!(output, r#"local -a descriptions=({descriptions_array_initializer})"#)?;
+
+            writeln!(output, r#"compadd -d descriptions -a completions"#)?;
+        },
+        Shell::Powershell | Shell::Nushell => {
+            let completions = get_completions(dfa, words_before_cursor, completed_word_index, shell, cwd);
+            for (completion, description) in completions {
+                writeln!(output, "{}\t{}", completion, description)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+
 #[cfg(test)]
 mod tests {
     use bumpalo::Bump;
@@ -247,12 +802,12 @@ mod tests {
 
     fn get_grammar_completions<'a, 'b>(grammar: &str, words_before_cursor: &'b [&'a str], completed_word_index: usize) -> Vec<(String, String)> {
         let g = Grammar::parse(grammar).unwrap();
-        let validated = ValidGrammar::from_grammar(g).unwrap();
+        let validated = ValidGrammar::from_grammar(&g).unwrap();
         let arena = Bump::new();
-        let regex = AugmentedRegex::from_expr(&validated.expr, &validated.specializations, &arena);
-        let dfa = DFA::from_regex(&regex);
+        let regex = AugmentedRegex::from_expr(&validated.commands[0].expr, &validated.specializations, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap();
         let dfa = dfa.minimize();
-        get_completions(&dfa, words_before_cursor, completed_word_index, Shell::Bash)
+        get_completions(&dfa, words_before_cursor, completed_word_index, Shell::Bash, &std::env::current_dir().unwrap())
     }
 
     #[test]
@@ -320,4 +875,275 @@ grep (--help | --version);
         let expected = HashSet::from_iter(["--help"].map(|s| s.to_string()));
         assert_eq!(generated, expected);
     }
+
+    #[test]
+    fn completes_option_value_after_equals_sign() {
+        const GRAMMAR: &str = r#"
+grep [--color=<WHEN>] <FILE>;
+<WHEN> ::= always | never | auto;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--col"], 0).into_iter().map(|(completion, _)| completion));
+        assert_eq!(generated, HashSet::from_iter(["--color=".to_string()]));
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--color=al"], 0).into_iter().map(|(completion, _)| completion));
+        assert_eq!(generated, HashSet::from_iter(["--color=always".to_string()]));
+    }
+
+    #[test]
+    fn matches_option_value_after_equals_sign() {
+        const GRAMMAR: &str = r#"
+grep [--color=<WHEN>] (--help | --version);
+<WHEN> ::= always | never | auto;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--color=auto"], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["--help", "--version"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn joins_words_split_by_bash_on_equals_sign() {
+        let (words, index) = join_bash_wordbreaks(&["--color", "=", "al"], 2);
+        assert_eq!(words, vec!["--color=al".to_string()]);
+        assert_eq!(index, 0);
+
+        let (words, index) = join_bash_wordbreaks(&["--color", "="], 1);
+        assert_eq!(words, vec!["--color=".to_string()]);
+        assert_eq!(index, 0);
+
+        let (words, index) = join_bash_wordbreaks(&["--color", "=", "always", "--he"], 3);
+        assert_eq!(words, vec!["--color=always".to_string(), "--he".to_string()]);
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn passes_preceding_words_to_commands() {
+        const GRAMMAR: &str = r#"cmd -C <DIRECTORY> checkout {{{ printf '%s\n' "$COMPLGEN_WORDS" | paste -s -d , - }}};"#;
+        let generated = get_grammar_completions(GRAMMAR, &["-C", "/tmp", "checkout", ""], 3);
+        assert_eq!(generated, vec![("-C,/tmp,checkout".to_string(), "".to_string())]);
+    }
+
+    #[test]
+    fn passes_bound_words_to_commands() {
+        const GRAMMAR: &str = r#"
+cmd --repo <repo=PATH> <BRANCH>;
+<BRANCH> ::= {{{ printf '%s\n' "repo:$repo" }}};
+"#;
+        let generated = get_grammar_completions(GRAMMAR, &["--repo", "/tmp", ""], 2);
+        assert_eq!(generated, vec![("repo:/tmp".to_string(), "".to_string())]);
+    }
+
+    #[test]
+    fn completes_paths_natively() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("foo"), "").unwrap();
+        std::fs::write(dir.path().join(".hidden"), "").unwrap();
+        std::fs::create_dir(dir.path().join("bar")).unwrap();
+        let prefix = format!("{}/", dir.path().to_str().unwrap());
+        let generated = get_grammar_completions("cmd <PATH>;", &[&prefix], 0);
+        assert_eq!(generated, vec![(format!("{prefix}bar"), "".to_string()), (format!("{prefix}foo"), "".to_string())]);
+        let generated = get_grammar_completions("cmd <DIRECTORY>;", &[&prefix], 0);
+        assert_eq!(generated, vec![(format!("{prefix}bar"), "".to_string())]);
+    }
+
+    #[test]
+    fn describes_directories_for_fish() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("bar")).unwrap();
+        assert_eq!(complete_directories("", None, Shell::Fish, dir.path()), vec![("bar/".to_string(), "Directory".to_string())]);
+        assert_eq!(complete_directories("", None, Shell::Zsh, dir.path()), vec![("bar/".to_string(), "".to_string())]);
+    }
+
+    #[test]
+    fn completes_paths_matching_glob_natively() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.yml"), "").unwrap();
+        std::fs::write(dir.path().join("b.yaml"), "").unwrap();
+        std::fs::write(dir.path().join("c.txt"), "").unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        let prefix = format!("{}/", dir.path().to_str().unwrap());
+        let generated = get_grammar_completions("cmd <PATH:*.{yml,yaml}>;", &[&prefix], 0);
+        let expected: Vec<(String, String)> = ["a.yml", "b.yaml", "src"].iter().map(|name| (format!("{prefix}{name}"), "".to_string())).collect();
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn completes_signals_natively() {
+        let generated = get_grammar_completions("cmd <SIGNAL>;", &["USR"], 0);
+        assert_eq!(generated, vec![("USR1".to_string(), "User defined signal 1".to_string()), ("USR2".to_string(), "User defined signal 2".to_string())]);
+    }
+
+    #[test]
+    fn prefers_grammar_definitions_of_predefined_nonterminals() {
+        let generated = get_grammar_completions("cmd <PATH>; <PATH> ::= foo | bar;", &[], 0);
+        assert_eq!(generated, vec![("bar".to_string(), "".to_string()), ("foo".to_string(), "".to_string())]);
+        let generated = get_grammar_completions("cmd <USER>; <USER@bash> ::= {{{ echo alice }}};", &[], 0);
+        assert_eq!(generated, vec![("alice".to_string(), "".to_string())]);
+    }
+
+    #[test]
+    fn skips_commands_exceeding_timeout() {
+        let start = Instant::now();
+        let generated = get_grammar_completions(r#"cmd {{{ sleep 5; echo slow }}} @timeout(100ms);"#, &[""], 0);
+        assert!(generated.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        let generated = get_grammar_completions(r#"cmd {{{ echo fast }}} @timeout(5s);"#, &[""], 0);
+        assert_eq!(generated, vec![("fast".to_string(), "".to_string())]);
+    }
+
+    #[test]
+    fn kills_processes_spawned_by_commands_exceeding_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_path = dir.path().join("pid");
+        let grammar = format!(r#"cmd {{{{{{ sleep 30 & echo $! > {}; wait }}}}}} @timeout(100ms);"#, pid_path.display());
+        let start = Instant::now();
+        assert!(get_grammar_completions(&grammar, &[""], 0).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(3));
+        let pid = std::fs::read_to_string(&pid_path).unwrap();
+        // Killed processes linger as zombies until their new parent gets around to reaping them
+        let is_running = || std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|stat| !stat.contains(") Z "));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while is_running() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!is_running());
+    }
+
+    #[test]
+    fn caches_command_output_per_directory() {
+        let g = Grammar::parse(r#"cmd {{{ echo run >> runs; wc -l < runs | tr -d ' ' }}} @cache(60s);"#).unwrap();
+        let validated = ValidGrammar::from_grammar(&g).unwrap();
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&validated.commands[0].expr, &validated.specializations, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        for dir in [&first, &second, &first] {
+            let generated = get_completions(&dfa, &[""], 0, Shell::Bash, dir.path());
+            assert_eq!(generated, vec![("1".to_string(), "".to_string())]);
+        }
+    }
+
+    #[test]
+    fn joins_words_split_by_bash_on_colon() {
+        let (words, index) = join_bash_wordbreaks(&["key", ":", "va"], 2);
+        assert_eq!(words, vec!["key:va".to_string()]);
+        assert_eq!(index, 0);
+
+        let (words, index) = join_bash_wordbreaks(&["std", "::", "io"], 2);
+        assert_eq!(words, vec!["std::io".to_string()]);
+        assert_eq!(index, 0);
+    }
+
+    #[test]
+    fn completes_short_options_cluster() {
+        const GRAMMAR: &str = r#"
+tar @cluster(-x | -v | -f <FILE>)... [--help];
+<FILE> ::= foo.tar | bar.tar;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-"], 0).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-x", "-v", "-f"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-xv"], 0).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-xv", "-xvf"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-xvf"], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["foo.tar", "bar.tar"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-xv", "-"], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-x", "-v", "-f", "--help"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn skips_given_once_options() {
+        const GRAMMAR: &str = r#"
+cmd @once(-a | -b | --color <WHEN>);
+<WHEN> ::= always | never;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-a", "--color", "always", ""], 3).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-b"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-b", ""], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-a", "--color"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn skips_given_once_options_per_occurrence() {
+        const GRAMMAR: &str = r#"
+cmd [@once(-v | --color=<WHEN>)]... sub [-v]...;
+<WHEN> ::= always | never;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--color=never", "-v", ""], 2).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["sub"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-v", "sub", ""], 2).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-v"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn skips_options_after_end_of_options() {
+        const GRAMMAR: &str = r#"
+cmd [-v | --color=<WHEN>]... [<END_OF_OPTIONS>] (-1 | first)...;
+<WHEN> ::= always | never;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-v", ""], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-v", "--color=", "--", "-1", "first"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-v", "--", ""], 2).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["first"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn offers_options_after_double_dash_option_argument() {
+        const GRAMMAR: &str = r#"
+cmd [-v | --sep <SEP>]... [<END_OF_OPTIONS>] (-1 | first)...;
+<SEP> ::= -- | ,;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--sep", "--", ""], 2).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-v", "--sep", "--", "-1", "first"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--sep", "--", "--", ""], 3).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["first"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn skips_excluded_options() {
+        const GRAMMAR: &str = r#"
+cmd (@exclusive(--oneline | --format=<FMT>) | -i)...;
+<FMT> ::= short | full;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--oneline", ""], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["--oneline", "-i"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["--format=full", "-i", ""], 2).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["--format=", "-i"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
+
+    #[test]
+    fn skips_excluded_options_per_occurrence() {
+        const GRAMMAR: &str = r#"
+cmd (@exclusive(-E | -F) | -i)... sub [-F]...;
+"#;
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-E", ""], 1).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-E", "-i", "sub"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+
+        let generated: HashSet<_> = HashSet::from_iter(get_grammar_completions(GRAMMAR, &["-E", "sub", ""], 2).into_iter().map(|(completion, _)| completion));
+        let expected = HashSet::from_iter(["-F"].map(|s| s.to_string()));
+        assert_eq!(generated, expected);
+    }
 }
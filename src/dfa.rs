@@ -1,14 +1,14 @@
 use std::{
-    collections::BTreeSet,
-    io::Write, cmp::Ordering, rc::Rc
+    collections::{BTreeSet, VecDeque},
+    io::Write, cmp::Ordering, rc::Rc, time::Duration
 };
 use hashbrown::{HashMap, HashSet};
 
 use roaring::{MultiOps, RoaringBitmap};
-use ustr::{Ustr, ustr};
+use ustr::{Ustr, UstrMap, ustr};
 
-use crate::{regex::{Position, AugmentedRegex, Input, MatchAnythingInput}, grammar::Specialization};
-use complgen::StateId;
+use crate::{regex::{Position, AugmentedRegex, Input, MatchAnythingInput}, grammar::{CommandAnnotations, Specialization}};
+use complgen::{Error, Result, StateId};
 
 
 // Every state in a DFA is formally defined to have a transition on *every* input symbol.  In
@@ -18,29 +18,52 @@ pub const DEAD_STATE_ID: StateId = 0;
 pub const FIRST_STATE_ID: StateId = 1;
 
 
+// See `DFA::get_ambiguities()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ambiguity {
+    pub path: Vec<Input>, // leading from the starting state to the ambiguous state
+    pub literal: Input,
+    pub match_anything: Input,
+}
+
+
 #[derive(Debug, Clone)]
 pub struct DFA {
     pub starting_state: StateId,
     pub transitions: HashMap<StateId, HashMap<Input, StateId>>,
     pub accepting_states: RoaringBitmap,
     pub input_symbols: Rc<HashSet<Input>>,
+    pub bindings: HashMap<(StateId, StateId), Vec<Ustr>>, // Names the word matched by a match-anything transition gets bound to
+    pub once: HashMap<(StateId, Input), Vec<Position>>, // Ids of the @once(...) options a literal or prefix transition stands for, see AugmentedRegex
+    pub exclusive: HashMap<(StateId, Input), Vec<u32>>, // Tags of the @exclusive(...) alternatives a literal or prefix transition starts, see AugmentedRegex
+    pub exclusive_groups: Vec<Vec<u32>>, // See AugmentedRegex
+    pub end_of_options_states: RoaringBitmap, // States reached through <END_OF_OPTIONS>, i.e. after which only positionals follow
 }
 
 
+// A combined state is a set of positions along with whether <END_OF_OPTIONS> has been matched on the way to it.
+// The same positions may be reachable both ways, e.g. in (-v | <END_OF_OPTIONS> | <FILE>)..., in which case they
+// make two states so that options are only ruled out in the one following `--`.
+type CombinedState = (BTreeSet<Position>, bool);
+
 // Reference:
 //  * The Dragon Book: 3.9.5 Converting a Regular Expression Directly to a DFA
-fn dfa_from_regex(regex: &AugmentedRegex) -> DFA {
+// `max_states` is the number of states, including the dead one, past which it gives up
+fn dfa_from_regex(regex: &AugmentedRegex, max_states: StateId) -> Result<DFA> {
     let mut unallocated_state_id = FIRST_STATE_ID;
-    let combined_starting_state: BTreeSet<Position> = regex.firstpos();
+    let combined_starting_state: CombinedState = (regex.firstpos(), false);
     let combined_starting_state_id = unallocated_state_id;
     unallocated_state_id += 1;
 
-    let mut dstates: HashMap<BTreeSet<Position>, StateId> = HashMap::from_iter([(combined_starting_state.clone(), combined_starting_state_id)]);
+    let mut dstates: HashMap<CombinedState, StateId> = HashMap::from_iter([(combined_starting_state.clone(), combined_starting_state_id)]);
 
     let followpos = regex.followpos();
 
     let mut dtran: HashMap<StateId, HashMap<Input, StateId>> = Default::default();
-    let mut unmarked_states: HashSet<BTreeSet<Position>> = Default::default();
+    let mut bindings: HashMap<(StateId, StateId), Vec<Ustr>> = Default::default();
+    let mut once: HashMap<(StateId, Input), Vec<Position>> = Default::default();
+    let mut exclusive: HashMap<(StateId, Input), Vec<u32>> = Default::default();
+    let mut unmarked_states: HashSet<CombinedState> = Default::default();
     unmarked_states.insert(combined_starting_state.clone());
     loop {
         let combined_state = match unmarked_states.iter().next() {
@@ -51,24 +74,56 @@ fn dfa_from_regex(regex: &AugmentedRegex) -> DFA {
         let from_combined_state_id = *dstates.get(&combined_state).unwrap();
         let from_entry = dtran.entry(from_combined_state_id).or_default();
         for input in regex.input_symbols.iter() {
+            // All match-anything inputs compare equal, so `input_symbols` only holds one of them.  Label the
+            // transition with the one actually present in this state instead, e.g. the right command to run.
+            let mut state_input: Option<Input> = None;
+            // For the same reason, the transition stands for all the match-anything inputs in this state at once,
+            // so the matched word gets bound to the names from all of them, e.g. both in (<a=A> x | <b=B> y).
+            let mut state_bindings: Vec<Ustr> = Default::default();
+            let mut once_positions: Vec<Position> = Default::default();
+            let mut exclusive_tags: Vec<u32> = Default::default();
+            let mut end_of_options = combined_state.1;
             let mut u = RoaringBitmap::new();
-            for pos in &combined_state {
+            for pos in &combined_state.0 {
                 let pos_usize = usize::try_from(*pos).unwrap();
                 if regex.input_from_position.get(pos_usize) == Some(input) {
+                    state_input.get_or_insert(regex.input_from_position[pos_usize]);
+                    state_bindings.extend(regex.input_from_position[pos_usize].get_binding());
+                    if regex.once_positions.contains(*pos) && !input.matches_anything() {
+                        once_positions.push(*pos);
+                    }
+                    exclusive_tags.extend(regex.exclusive_tags.get(pos).into_iter().flatten());
+                    end_of_options |= regex.end_of_options_positions.contains(*pos);
                     if let Some(positions) = followpos.get(&pos) {
                         u |= positions;
                     }
                 }
             }
             if !u.is_empty() {
-                let u = BTreeSet::from_iter(u);
+                let u = (BTreeSet::from_iter(u), end_of_options);
                 if !dstates.contains_key(&u) {
+                    if unallocated_state_id >= max_states {
+                        return Err(Error::TooManyStates(max_states.into()));
+                    }
                     dstates.insert(u.clone(), unallocated_state_id);
                     unallocated_state_id += 1;
                     unmarked_states.insert(u.clone());
                 }
                 let to_combined_state_id = dstates.get(&u).unwrap();
-                from_entry.insert(input.clone(), *to_combined_state_id);
+                from_entry.insert(state_input.unwrap_or(*input), *to_combined_state_id);
+                if !state_bindings.is_empty() {
+                    state_bindings.sort_unstable();
+                    state_bindings.dedup();
+                    bindings.insert((from_combined_state_id, *to_combined_state_id), state_bindings);
+                }
+                if !once_positions.is_empty() {
+                    once.insert((from_combined_state_id, *input), once_positions);
+                }
+                if !exclusive_tags.is_empty() {
+                    exclusive_tags.sort_unstable();
+                    exclusive_tags.dedup();
+                    exclusive.insert((from_combined_state_id, *input), exclusive_tags);
+                }
             }
         }
     }
@@ -77,19 +132,26 @@ fn dfa_from_regex(regex: &AugmentedRegex) -> DFA {
     let accepting_states: RoaringBitmap = {
         let mut accepting_states = RoaringBitmap::default();
         for (combined_state, state_id) in &dstates {
-            if combined_state.contains(&regex.endmarker_position) {
-                accepting_states.insert((*state_id).into());
+            if combined_state.0.contains(&regex.endmarker_position) {
+                accepting_states.insert(*state_id);
             }
         }
         accepting_states
     };
 
-    DFA {
+    let end_of_options_states = dstates.iter().filter(|((_, end_of_options), _)| *end_of_options).map(|(_, state_id)| *state_id).collect();
+
+    Ok(DFA {
         starting_state: *dstates.get(&combined_starting_state).unwrap(),
         transitions: dtran,
         accepting_states,
         input_symbols: Rc::clone(&regex.input_symbols),
-    }
+        bindings,
+        once,
+        exclusive,
+        exclusive_groups: regex.exclusive_groups.clone(),
+        end_of_options_states,
+    })
 }
 
 
@@ -154,15 +216,15 @@ struct Transition {
 
 
 fn keep_only_states_with_input_transitions(starting_state: StateId, transitions: &[Transition], accepting_states: &RoaringBitmap) -> (Vec<Transition>, RoaringBitmap) {
-    let states_with_input_transition = RoaringBitmap::from_iter(transitions.iter().map(|transition| u32::from(transition.to)));
+    let states_with_input_transition = RoaringBitmap::from_iter(transitions.iter().map(|transition| transition.to));
 
-    let alive_accepting_states = RoaringBitmap::from_sorted_iter(accepting_states.iter().filter(|state| *state == starting_state.into() || states_with_input_transition.contains(*state))).unwrap();
+    let alive_accepting_states = RoaringBitmap::from_sorted_iter(accepting_states.iter().filter(|state| *state == starting_state || states_with_input_transition.contains(*state))).unwrap();
 
     let alive_transitions: Vec<Transition> = transitions.iter().filter(|transition| {
         if transition.from == starting_state {
             return true;
         }
-        if !states_with_input_transition.contains(transition.from.into()) || !states_with_input_transition.contains(transition.to.into()) {
+        if !states_with_input_transition.contains(transition.from) || !states_with_input_transition.contains(transition.to) {
             return false;
         }
         true
@@ -172,12 +234,13 @@ fn keep_only_states_with_input_transitions(starting_state: StateId, transitions:
 }
 
 fn eliminate_nonaccepting_states_without_output_transitions(transitions: &[Transition], accepting_states: &RoaringBitmap) -> Vec<Transition> {
-    let states_with_output_transition = RoaringBitmap::from_iter(transitions.iter().map(|transition| u32::from(transition.from)));
-    let alive_transitions: Vec<Transition> = transitions.iter().filter(|transition| accepting_states.contains(transition.to.into()) || states_with_output_transition.contains(transition.to.into())).copied().collect();
+    let states_with_output_transition = RoaringBitmap::from_iter(transitions.iter().map(|transition| transition.from));
+    let alive_transitions: Vec<Transition> = transitions.iter().filter(|transition| accepting_states.contains(transition.to) || states_with_output_transition.contains(transition.to)).copied().collect();
     alive_transitions
 }
 
-fn renumber_states(starting_state: StateId, transitions: &[Transition], accepting_states: &RoaringBitmap) -> (StateId, Vec<Transition>, RoaringBitmap) {
+// Also returns the new state ids of the old ones, for use in lookup tables keyed by states
+fn renumber_states(starting_state: StateId, transitions: &[Transition], accepting_states: &RoaringBitmap) -> (StateId, Vec<Transition>, RoaringBitmap, HashMap<StateId, StateId>) {
     let new_from_old_state_id = {
         let mut new_from_old_state_id: HashMap<StateId, StateId> = Default::default();
         let mut unallocated_state_id = 0;
@@ -211,9 +274,9 @@ fn renumber_states(starting_state: StateId, transitions: &[Transition], acceptin
         input: *input,
     }).collect();
 
-    let new_accepting_states: RoaringBitmap = RoaringBitmap::from_iter(accepting_states.iter().map(|old| u32::from(*new_from_old_state_id.get(&u16::try_from(old).unwrap()).unwrap())));
+    let new_accepting_states: RoaringBitmap = RoaringBitmap::from_iter(accepting_states.iter().map(|old| *new_from_old_state_id.get(&old).unwrap()));
 
-    (new_starting_state, new_transitions, new_accepting_states)
+    (new_starting_state, new_transitions, new_accepting_states, new_from_old_state_id)
 }
 
 fn hashmap_transitions_from_vec(transitions: &[Transition]) -> HashMap<StateId, HashMap<Input, StateId>> {
@@ -251,6 +314,43 @@ fn make_inverse_transitions_lookup_table(transitions: &HashMap<StateId, HashMap<
     result
 }
 
+// A prefix gets an empty description, same as in the way backends number literals
+fn get_tagged_transitions(tags: &HashMap<(StateId, Input), Vec<u32>>) -> Vec<(StateId, Ustr, Ustr, Vec<u32>)> {
+    let mut result: Vec<(StateId, Ustr, Ustr, Vec<u32>)> = tags.iter().filter_map(|((from, input), ids)| match input {
+        Input::Literal(literal, description) => Some((*from, *literal, description.unwrap_or(ustr("")), ids.clone())),
+        Input::Prefix(prefix) => Some((*from, *prefix, ustr(""), ids.clone())),
+        Input::Any(_) => None,
+    }).collect();
+    result.sort_unstable();
+    result
+}
+
+// Match-anything inputs all compare equal, so the algorithm below can't tell apart states that only differ in what
+// their match-anything transition completes, e.g. which command it runs, or in the names it binds words to.  They
+// start out in separate groups instead, so that they never get merged, dropping all but one of the commands.  The
+// same goes for states differing in which @once(...) options or @exclusive(...) alternatives their transitions
+// stand for, and in whether they follow <END_OF_OPTIONS>.
+type MatchAnythingSignature<'a> = Option<(MatchAnythingInput, Option<&'a Vec<Ustr>>)>;
+type TagsSignature<'a> = Vec<(Input, Option<&'a Vec<Position>>, Option<&'a Vec<u32>>)>;
+type StateSignature<'a> = (bool, MatchAnythingSignature<'a>, TagsSignature<'a>);
+
+fn get_state_signature(dfa: &DFA, state: StateId) -> StateSignature<'_> {
+    let end_of_options = dfa.end_of_options_states.contains(state);
+    let Some(transitions) = dfa.transitions.get(&state) else {
+        return (end_of_options, None, vec![]);
+    };
+    let match_anything = transitions.iter().find_map(|(input, to)| match input {
+        Input::Any(any) => Some((*any, dfa.bindings.get(&(state, *to)))),
+        Input::Literal(..) | Input::Prefix(_) => None,
+    });
+    let mut tags: TagsSignature = transitions.keys()
+        .map(|input| (*input, dfa.once.get(&(state, *input)), dfa.exclusive.get(&(state, *input))))
+        .filter(|(_, once, exclusive)| once.is_some() || exclusive.is_some())
+        .collect();
+    tags.sort_unstable();
+    (end_of_options, match_anything, tags)
+}
+
 // Hopcroft's DFA minimization algorithm.
 // References:
 //  * The Dragon Book: Minimizing the Number of states of a DFA
@@ -261,17 +361,22 @@ fn make_inverse_transitions_lookup_table(transitions: &HashMap<StateId, HashMap<
 fn do_minimize(dfa: &DFA) -> DFA {
     let mut pool = SetInternPool::default();
     let mut partition: HashSet<SetInternId> = {
-        let dead_state_group = RoaringBitmap::from_iter([u32::from(DEAD_STATE_ID)]);
+        let dead_state_group = RoaringBitmap::from_iter([DEAD_STATE_ID]);
         let all_states = dfa.get_all_states();
         let nonaccepting_states = [&all_states, &dfa.accepting_states, &dead_state_group].difference();
         if nonaccepting_states.is_empty() {
             // Nothing to minimize
             return dfa.clone();
         }
-        let nonaccepting_states_intern_id = pool.intern(nonaccepting_states);
-        let accepting_states_intern_id = pool.intern(dfa.accepting_states.clone());
-        let dead_state_intern_id = pool.intern(dead_state_group);
-        HashSet::from_iter([dead_state_intern_id, accepting_states_intern_id, nonaccepting_states_intern_id])
+        let mut partition: HashSet<SetInternId> = Default::default();
+        for group in [dead_state_group, dfa.accepting_states.clone(), nonaccepting_states] {
+            let mut subgroups: HashMap<StateSignature, RoaringBitmap> = Default::default();
+            for state in &group {
+                subgroups.entry(get_state_signature(dfa, state)).or_default().insert(state);
+            }
+            partition.extend(subgroups.into_values().map(|subgroup| pool.intern(subgroup)));
+        }
+        partition
     };
     let mut worklist = partition.clone();
     let inverse_transitions = make_inverse_transitions_lookup_table(&dfa.transitions, Rc::clone(&dfa.input_symbols));
@@ -285,7 +390,7 @@ fn do_minimize(dfa: &DFA) -> DFA {
         let group_min = group.min().unwrap();
         let group_max = group.max().unwrap();
         let inbetween_index = match inverse_transitions.binary_search_by(|transition| {
-            let to: u32 = transition.to.into();
+            let to = transition.to;
             if to < group_min {
                 return Ordering::Less;
             }
@@ -299,22 +404,22 @@ fn do_minimize(dfa: &DFA) -> DFA {
         };
         let lower_bound = {
             let mut lower_bound = inbetween_index;
-            while lower_bound > 0 && u32::from(inverse_transitions[lower_bound-1].to) >= group_min {
+            while lower_bound > 0 && inverse_transitions[lower_bound-1].to >= group_min {
                 lower_bound -= 1;
             }
             lower_bound
         };
         let upper_bound = {
             let mut upper_bound = inbetween_index;
-            while upper_bound < inverse_transitions.len() - 1 && u32::from(inverse_transitions[upper_bound+1].to) <= group_max {
+            while upper_bound < inverse_transitions.len() - 1 && inverse_transitions[upper_bound+1].to <= group_max {
                 upper_bound += 1;
             }
             upper_bound
         };
 
-        let group_transitions: Vec<Transition> = inverse_transitions[lower_bound..=upper_bound].iter().filter(|transition| group.contains(transition.to.into())).copied().collect();
+        let group_transitions: Vec<Transition> = inverse_transitions[lower_bound..=upper_bound].iter().filter(|transition| group.contains(transition.to)).copied().collect();
         for input in dfa.input_symbols.iter() {
-            let from_states: RoaringBitmap = group_transitions.iter().filter(|transition| transition.input == *input).map(|transition| u32::from(transition.from)).collect();
+            let from_states: RoaringBitmap = group_transitions.iter().filter(|transition| transition.input == *input).map(|transition| transition.from).collect();
             let overlapping_sets: Vec<SetInternId> = partition.iter().filter(|set_id| !pool.get(**set_id).unwrap().is_disjoint(&from_states)).copied().collect();
             for intern_id in overlapping_sets {
                 let states = pool.get(intern_id).unwrap();
@@ -357,7 +462,7 @@ fn do_minimize(dfa: &DFA) -> DFA {
             let partition_element = pool.get(*intern_id).unwrap();
             let representative_state_id = partition_element.min().unwrap();
             for state_id in partition_element.iter() {
-                representative_id_from_state_id.insert(StateId::try_from(state_id).unwrap(), StateId::try_from(representative_state_id).unwrap());
+                representative_id_from_state_id.insert(state_id, representative_state_id);
             }
         }
         representative_id_from_state_id
@@ -368,7 +473,7 @@ fn do_minimize(dfa: &DFA) -> DFA {
     let accepting_states = {
         let mut accepting_states: RoaringBitmap = Default::default();
         for state_id in &dfa.accepting_states {
-            accepting_states.insert((*representative_id_from_state_id.get(&u16::try_from(state_id).unwrap()).unwrap()).into());
+            accepting_states.insert(*representative_id_from_state_id.get(&state_id).unwrap());
         }
         accepting_states
     };
@@ -387,31 +492,56 @@ fn do_minimize(dfa: &DFA) -> DFA {
 
     let (transitions, accepting_states) = keep_only_states_with_input_transitions(starting_state, &transitions, &accepting_states);
     let transitions = eliminate_nonaccepting_states_without_output_transitions(&transitions, &accepting_states);
-    let (starting_state, transitions, accepting_states) = renumber_states(starting_state, &transitions, &accepting_states);
+    let (starting_state, transitions, accepting_states, new_from_old_state_id) = renumber_states(starting_state, &transitions, &accepting_states);
     let transitions = hashmap_transitions_from_vec(&transitions);
+
+    // States sharing a group agree on the bindings, so any of them will do
+    let bindings = dfa.bindings.iter().filter_map(|((from, to), names)| {
+        let from = new_from_old_state_id.get(representative_id_from_state_id.get(from)?)?;
+        let to = new_from_old_state_id.get(representative_id_from_state_id.get(to)?)?;
+        Some(((*from, *to), names.clone()))
+    }).collect();
+    let remap_tags = |tags: &HashMap<(StateId, Input), Vec<u32>>| -> HashMap<(StateId, Input), Vec<u32>> {
+        tags.iter().filter_map(|((from, input), ids)| {
+            let from = new_from_old_state_id.get(representative_id_from_state_id.get(from)?)?;
+            Some(((*from, *input), ids.clone()))
+        }).collect()
+    };
+
     DFA {
         starting_state,
         transitions,
         accepting_states,
         input_symbols: Rc::clone(&dfa.input_symbols),
+        bindings,
+        once: remap_tags(&dfa.once),
+        exclusive: remap_tags(&dfa.exclusive),
+        exclusive_groups: dfa.exclusive_groups.clone(),
+        end_of_options_states: dfa.end_of_options_states.iter().filter_map(|state| new_from_old_state_id.get(representative_id_from_state_id.get(&state)?)).copied().collect(),
     }
 }
 
 
 impl DFA {
-    pub fn from_regex(regex: &AugmentedRegex) -> Self {
-        dfa_from_regex(regex)
+    pub fn from_regex(regex: &AugmentedRegex) -> Result<Self> {
+        dfa_from_regex(regex, StateId::MAX)
     }
 
     pub fn minimize(&self) -> Self {
         do_minimize(self)
     }
 
+    // Prefixes are included as well so that shell scripts can refer to both kinds of inputs through a single
+    // literals table.
     pub fn get_all_literals(&self) -> Vec<(Ustr, Option<Ustr>)> {
-        self.input_symbols.iter().filter_map(|input| match input {
+        let mut result: Vec<(Ustr, Option<Ustr>)> = self.input_symbols.iter().filter_map(|input| match input {
             Input::Literal(input, description) => Some((*input, *description)),
+            Input::Prefix(prefix) => Some((*prefix, None)),
             Input::Any(_) => None,
-        }).collect()
+        }).collect();
+        result.sort_unstable();
+        result.dedup();
+        result
     }
 
     pub fn get_command_transitions(&self) -> Vec<(StateId, Ustr)> {
@@ -419,9 +549,9 @@ impl DFA {
         for (from, tos) in &self.transitions {
             for (input, _) in tos {
                 let cmd = match input {
-                    Input::Any(MatchAnythingInput::Command(cmd)) => *cmd,
+                    Input::Any(MatchAnythingInput::Command(cmd, ..)) => *cmd,
                     Input::Any(MatchAnythingInput::Nonterminal(..)) => continue,
-                    Input::Literal(..) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
                 };
                 result.push((*from, cmd));
             }
@@ -434,11 +564,11 @@ impl DFA {
         for (from, tos) in &self.transitions {
             for (input, _) in tos {
                 let cmd = match input {
-                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { bash: Some(cmd), .. }))) => *cmd,
-                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }))) => continue,
-                    Input::Any(MatchAnythingInput::Nonterminal(_, None)) => continue,
-                    Input::Any(MatchAnythingInput::Command(_)) => continue,
-                    Input::Literal(..) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { bash: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
                 };
                 result.push((*from, cmd));
             }
@@ -451,11 +581,11 @@ impl DFA {
         for (from, tos) in &self.transitions {
             for (input, _) in tos {
                 let cmd = match input {
-                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { fish: Some(cmd), .. }))) => *cmd,
-                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }))) => continue,
-                    Input::Any(MatchAnythingInput::Nonterminal(_, None)) => continue,
-                    Input::Any(MatchAnythingInput::Command(_)) => continue,
-                    Input::Literal(..) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { fish: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
                 };
                 result.push((*from, cmd));
             }
@@ -468,11 +598,79 @@ impl DFA {
         for (from, tos) in &self.transitions {
             for (input, _) in tos {
                 let cmd = match input {
-                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { zsh: Some(cmd), .. }))) => *cmd,
-                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }))) => continue,
-                    Input::Any(MatchAnythingInput::Nonterminal(_, None)) => continue,
-                    Input::Any(MatchAnythingInput::Command(_)) => continue,
-                    Input::Literal(..) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { zsh: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
+                };
+                result.push((*from, cmd));
+            }
+        }
+        result
+    }
+
+    pub fn get_powershell_command_transitions(&self) -> Vec<(StateId, Ustr)> {
+        let mut result: Vec<(StateId, Ustr)> = Default::default();
+        for (from, tos) in &self.transitions {
+            for (input, _) in tos {
+                let cmd = match input {
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { powershell: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
+                };
+                result.push((*from, cmd));
+            }
+        }
+        result
+    }
+
+    pub fn get_nushell_command_transitions(&self) -> Vec<(StateId, Ustr)> {
+        let mut result: Vec<(StateId, Ustr)> = Default::default();
+        for (from, tos) in &self.transitions {
+            for (input, _) in tos {
+                let cmd = match input {
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { nushell: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
+                };
+                result.push((*from, cmd));
+            }
+        }
+        result
+    }
+
+    pub fn get_elvish_command_transitions(&self) -> Vec<(StateId, Ustr)> {
+        let mut result: Vec<(StateId, Ustr)> = Default::default();
+        for (from, tos) in &self.transitions {
+            for (input, _) in tos {
+                let cmd = match input {
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { elvish: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
+                };
+                result.push((*from, cmd));
+            }
+        }
+        result
+    }
+
+    pub fn get_xonsh_command_transitions(&self) -> Vec<(StateId, Ustr)> {
+        let mut result: Vec<(StateId, Ustr)> = Default::default();
+        for (from, tos) in &self.transitions {
+            for (input, _) in tos {
+                let cmd = match input {
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { xonsh: Some(cmd), .. }), _)) => *cmd,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, Some(Specialization { .. }), _)) => continue,
+                    Input::Any(MatchAnythingInput::Nonterminal(_, None, _)) => continue,
+                    Input::Any(MatchAnythingInput::Command(..)) => continue,
+                    Input::Literal(..) | Input::Prefix(_) => continue,
                 };
                 result.push((*from, cmd));
             }
@@ -487,20 +685,34 @@ impl DFA {
         };
         let transitions: Vec<(Ustr, Ustr, StateId)> = map.iter().filter_map(|(input, to)| match input {
             Input::Literal(input, description) => Some((*input, description.unwrap_or(ustr("")), *to)),
-            Input::Any(_) => None,
+            Input::Prefix(_) | Input::Any(_) => None,
+        }).collect();
+        transitions
+    }
+
+    // Longest prefixes first so that taking the first matching one amounts to the longest match
+    pub fn get_prefix_transitions_from(&self, from: StateId) -> Vec<(Ustr, StateId)> {
+        let map = match self.transitions.get(&from) {
+            Some(map) => map,
+            None => return vec![],
+        };
+        let mut transitions: Vec<(Ustr, StateId)> = map.iter().filter_map(|(input, to)| match input {
+            Input::Prefix(prefix) => Some((*prefix, *to)),
+            Input::Literal(..) | Input::Any(_) => None,
         }).collect();
+        transitions.sort_unstable_by(|(left, _), (right, _)| right.len().cmp(&left.len()).then(left.cmp(right)));
         transitions
     }
 
     pub fn get_all_states(&self) -> RoaringBitmap {
         let mut states: RoaringBitmap = Default::default();
         for (from, to) in &self.transitions {
-            states.insert((*from).into());
+            states.insert(*from);
             to.iter().for_each(|(_, to)| {
-                states.insert((*to).into());
+                states.insert(*to);
             });
         }
-        states.insert(DEAD_STATE_ID.into());
+        states.insert(DEAD_STATE_ID);
         states
     }
 
@@ -516,11 +728,129 @@ impl DFA {
         result
     }
 
+    // Match-anything transitions binding the matched word to names, e.g. via <repo=PATH>, as (from, to, names)
+    pub fn get_binding_transitions(&self) -> Vec<(StateId, StateId, Vec<Ustr>)> {
+        let mut result: Vec<(StateId, StateId, Vec<Ustr>)> = self.bindings.iter().map(|((from, to), names)| (*from, *to, names.clone())).collect();
+        result.sort_unstable();
+        result
+    }
+
+    // Literal and prefix transitions standing for @once(...) options, as (from, literal, description, ids)
+    pub fn get_once_transitions(&self) -> Vec<(StateId, Ustr, Ustr, Vec<Position>)> {
+        get_tagged_transitions(&self.once)
+    }
+
+    // Literal and prefix transitions starting @exclusive(...) alternatives, as (from, literal, description, tags)
+    pub fn get_exclusive_transitions(&self) -> Vec<(StateId, Ustr, Ustr, Vec<u32>)> {
+        get_tagged_transitions(&self.exclusive)
+    }
+
+    // The tags of the other alternatives of the @exclusive(...) each tag comes from, as (tag, other tags)
+    pub fn get_exclusions(&self) -> Vec<(u32, Vec<u32>)> {
+        let exclusions = self.exclusive_groups.iter().flat_map(|group| group.iter().map(|tag| (*tag, group.iter().filter(|other| *other != tag).copied().collect())));
+        itertools::sorted(exclusions).collect()
+    }
+
+    // Scripts share a single function between all occurrences of a command, so the same command annotated
+    // differently in several places gets the shortest timeout and cache TTL of all of them
+    pub fn get_command_annotations(&self) -> UstrMap<CommandAnnotations> {
+        fn shortest(left: Option<Duration>, right: Option<Duration>) -> Option<Duration> {
+            match (left, right) {
+                (Some(left), Some(right)) => Some(std::cmp::min(left, right)),
+                _ => left.or(right),
+            }
+        }
+        let mut result: UstrMap<CommandAnnotations> = Default::default();
+        for tos in self.transitions.values() {
+            for (input, _) in tos {
+                let Input::Any(MatchAnythingInput::Command(cmd, _, annotations)) = input else { continue };
+                if *annotations == CommandAnnotations::default() {
+                    continue;
+                }
+                let entry = result.entry(*cmd).or_default();
+                entry.timeout = shortest(entry.timeout, annotations.timeout);
+                entry.cache_ttl = shortest(entry.cache_ttl, annotations.cache_ttl);
+            }
+        }
+        result
+    }
+
+
+    /// @exclusive(...) alternatives that never get offered because every path leading to them passes through
+    /// another alternative of the same @exclusive(...) first, as the literals they start with.
+    pub fn get_unreachable_exclusive_members(&self) -> Vec<Ustr> {
+        let mut result: Vec<Ustr> = Default::default();
+        for (tag, others) in self.get_exclusions() {
+            let is_excluding = |state: StateId, input: &Input| self.exclusive.get(&(state, *input)).is_some_and(|tags| tags.iter().any(|tag| others.contains(tag)));
+            let mut visited: HashSet<StateId> = HashSet::from_iter([self.starting_state]);
+            let mut queue: VecDeque<StateId> = VecDeque::from_iter([self.starting_state]);
+            let mut reachable = false;
+            while let Some(state) = queue.pop_front() {
+                let Some(tos) = self.transitions.get(&state) else { continue };
+                if tos.keys().any(|input| self.exclusive.get(&(state, *input)).is_some_and(|tags| tags.contains(&tag))) {
+                    reachable = true;
+                    break;
+                }
+                for (input, to) in tos {
+                    if !is_excluding(state, input) && visited.insert(*to) {
+                        queue.push_back(*to);
+                    }
+                }
+            }
+            if !reachable {
+                result.extend(self.get_exclusive_transitions().into_iter().filter(|(_, _, _, tags)| tags.contains(&tag)).map(|(_, literal, _, _)| literal));
+            }
+        }
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    // Breadth-first, hence the shortest sequence of inputs leading from the starting state to each state
+    fn get_shortest_paths(&self) -> HashMap<StateId, Vec<Input>> {
+        let mut paths: HashMap<StateId, Vec<Input>> = HashMap::from_iter([(self.starting_state, vec![])]);
+        let mut queue: VecDeque<StateId> = VecDeque::from_iter([self.starting_state]);
+        while let Some(state) = queue.pop_front() {
+            let mut transitions: Vec<(&Input, &StateId)> = self.transitions.get(&state).map(|tos| tos.iter().collect()).unwrap_or_default();
+            transitions.sort_unstable();
+            for (input, to) in transitions {
+                if paths.contains_key(to) {
+                    continue;
+                }
+                let mut path = paths.get(&state).unwrap().clone();
+                path.push(*input);
+                paths.insert(*to, path);
+                queue.push_back(*to);
+            }
+        }
+        paths
+    }
+
+    /// States where a word can be matched both by a literal (or a prefix) and by a match-anything input, i.e. a
+    /// nonterminal or a command, each leading to a different state.  Completion via `complgen complete` tries
+    /// all of them while the generated shell scripts only ever follow the literal, so the two disagree.
+    pub fn get_ambiguities(&self) -> Vec<Ambiguity> {
+        let paths = self.get_shortest_paths();
+        let mut result: Vec<Ambiguity> = Default::default();
+        for (from, tos) in &self.transitions {
+            let Some((match_anything, match_anything_to)) = tos.iter().find(|(input, _)| input.matches_anything()) else { continue };
+            let Some(path) = paths.get(from) else { continue };
+            for (input, to) in tos {
+                if input.matches_anything() || to == match_anything_to {
+                    continue;
+                }
+                result.push(Ambiguity { path: path.clone(), literal: *input, match_anything: *match_anything });
+            }
+        }
+        result.sort_unstable_by(|left, right| (&left.path, left.literal).cmp(&(&right.path, right.literal)));
+        result
+    }
+
     pub fn to_dot<W: Write>(&self, output: &mut W) -> std::result::Result<(), std::io::Error> {
         writeln!(output, "digraph nfa {{")?;
         writeln!(output, "\trankdir=LR;")?;
 
-        if self.accepting_states.contains(self.starting_state.into()) {
+        if self.accepting_states.contains(self.starting_state) {
             writeln!(output, "\tnode [shape = doubleoctagon];")?;
         }
         else {
@@ -530,7 +860,7 @@ impl DFA {
 
         let regular_states = {
             let mut states = [&self.get_all_states(), &self.accepting_states].difference();
-            states.remove(self.starting_state.into());
+            states.remove(self.starting_state);
             states
         };
 
@@ -574,10 +904,10 @@ impl DFA {
 mod tests {
     use std::rc::Rc;
 
-    use crate::grammar::Expr;
+    use crate::grammar::{Expr, ExprKind};
     use crate::grammar::tests::arb_expr_match;
     use crate::regex::AugmentedRegex;
-    use Expr::*;
+    use ExprKind::*;
 
     use super::*;
 
@@ -595,9 +925,10 @@ mod tests {
 
     impl DFA {
         pub fn accepts(&self, inputs: &[&str]) -> bool {
-            let mut backtracking_stack = Vec::from_iter([(0, self.starting_state)]);
-            while let Some((input_index, current_state)) = backtracking_stack.pop() {
-                if input_index == inputs.len() && self.accepting_states.contains(current_state.into()) {
+            // (input index, byte offset into that input, state)
+            let mut backtracking_stack = Vec::from_iter([(0, 0, self.starting_state)]);
+            while let Some((input_index, offset, current_state)) = backtracking_stack.pop() {
+                if input_index == inputs.len() && self.accepting_states.contains(current_state) {
                     return true;
                 }
 
@@ -605,17 +936,19 @@ mod tests {
                     continue;
                 }
 
+                let input = &inputs[input_index][offset..];
+
                 for (transition_input, to) in self.transitions.get(&current_state).unwrap_or(&HashMap::default()) {
                     if transition_input.matches_anything() {
-                        backtracking_stack.push((input_index + 1, *to));
+                        backtracking_stack.push((input_index + 1, 0, *to));
                     }
                 }
 
                 for (transition_input, to) in self.transitions.get(&current_state).unwrap_or(&HashMap::default()) {
-                    if let Input::Literal(s, _) = transition_input {
-                        if s.as_str() == inputs[input_index] {
-                            backtracking_stack.push((input_index + 1, *to));
-                        }
+                    match transition_input {
+                        Input::Literal(s, _) if s.as_str() == input => backtracking_stack.push((input_index + 1, 0, *to)),
+                        Input::Prefix(prefix) if input.starts_with(prefix.as_str()) => backtracking_stack.push((input_index, offset + prefix.len(), *to)),
+                        _ => {},
                     }
                 }
             }
@@ -648,8 +981,8 @@ mod tests {
         let expr = Terminal(ustr("foo"), None);
         let arena = Bump::new();
         let specs = UstrMap::default();
-        let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-        let dfa = DFA::from_regex(&regex);
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap();
         let transitions = dfa.get_transitions();
         assert_eq!(transitions, vec![Transition::new(1, "foo", 2)]);
         assert_eq!(dfa.accepting_states, RoaringBitmap::from_iter([2]));
@@ -666,8 +999,8 @@ mod tests {
             // println!("{:?}", input);
             let arena = Bump::new();
             let specs = UstrMap::default();
-            let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-            let dfa = DFA::from_regex(&regex);
+            let regex = AugmentedRegex::from_expr(&expr, &specs, &arena).unwrap();
+            let dfa = DFA::from_regex(&regex).unwrap();
             let input: Vec<&str> = input.iter().map(|s| {
                 let s: &str = s;
                 s
@@ -681,8 +1014,8 @@ mod tests {
             println!("{:?}", input);
             let arena = Bump::new();
             let specs = UstrMap::default();
-            let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-            let dfa = DFA::from_regex(&regex);
+            let regex = AugmentedRegex::from_expr(&expr, &specs, &arena).unwrap();
+            let dfa = DFA::from_regex(&regex).unwrap();
             let input: Vec<&str> = input.iter().map(|s| {
                 let s: &str = s;
                 s
@@ -695,7 +1028,7 @@ mod tests {
 
     #[test]
     fn accept_hangs() {
-        let expr = Sequence(vec![Rc::new(Alternative(vec![Rc::new(Sequence(vec![Rc::new(Optional(Rc::new(Alternative(vec![Rc::new(Many1(Rc::new(Optional(Rc::new(Many1(Rc::new(Sequence(vec![Rc::new(Terminal(u("foo"), None)), Rc::new(Terminal(u("foo"), None))])))))))), Rc::new(Terminal(u("bar"), None))])))), Rc::new(Nonterminal(u("DIRECTORY")))])), Rc::new(Many1(Rc::new(Terminal(u("--quux"), None))))])), Rc::new(Sequence(vec![Rc::new(Sequence(vec![Rc::new(Many1(Rc::new(Many1(Rc::new(Many1(Rc::new(Terminal(u("bar"), None)))))))), Rc::new(Many1(Rc::new(Sequence(vec![Rc::new(Many1(Rc::new(Many1(Rc::new(Terminal(u("--baz"), None)))))), Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Nonterminal(u("DIRECTORY"))), Rc::new(Nonterminal(u("PATH")))])), Rc::new(Alternative(vec![Rc::new(Terminal(u("--baz"), None)), Rc::new(Sequence(vec![Rc::new(Sequence(vec![Rc::new(Terminal(u("--baz"), None)), Rc::new(Nonterminal(u("FILE")))])), Rc::new(Sequence(vec![Rc::new(Terminal(u("foo"), None)), Rc::new(Nonterminal(u("FILE")))]))]))]))]))]))))])), Rc::new(Terminal(u("bar"), None))]))]);
+        let expr = Sequence(vec![Alternative(vec![Sequence(vec![Optional(Alternative(vec![Many1(Optional(Many1(Sequence(vec![Terminal(u("foo"), None).into(), Terminal(u("foo"), None).into()]).into()).into()).into()).into(), Terminal(u("bar"), None).into()]).into()).into(), Nonterminal(u("DIRECTORY"), None).into()]).into(), Many1(Terminal(u("--quux"), None).into()).into()]).into(), Sequence(vec![Sequence(vec![Many1(Many1(Many1(Terminal(u("bar"), None).into()).into()).into()).into(), Many1(Sequence(vec![Many1(Many1(Terminal(u("--baz"), None).into()).into()).into(), Sequence(vec![Alternative(vec![Nonterminal(u("DIRECTORY"), None).into(), Nonterminal(u("PATH"), None).into()]).into(), Alternative(vec![Terminal(u("--baz"), None).into(), Sequence(vec![Sequence(vec![Terminal(u("--baz"), None).into(), Nonterminal(u("FILE"), None).into()]).into(), Sequence(vec![Terminal(u("foo"), None).into(), Nonterminal(u("FILE"), None).into()]).into()]).into()]).into()]).into()]).into()).into()]).into(), Terminal(u("bar"), None).into()]).into()]);
         let input = [
             "--quux",
             "--quux",
@@ -736,8 +1069,8 @@ mod tests {
         ];
         let arena = Bump::new();
         let specs = UstrMap::default();
-        let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-        let dfa = DFA::from_regex(&regex);
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap();
         let input: Vec<&str> = input.iter().map(|s| {
             let s: &str = s;
             s
@@ -758,7 +1091,7 @@ mod tests {
             transitions.entry(4).or_default().insert(Input::Literal(ustr("e"), None), 5);
             let accepting_states = RoaringBitmap::from_iter([3,5]);
             let input_symbols = Rc::new(HashSet::from_iter([Input::Literal(ustr("f"), None), Input::Literal(ustr("e"), None), Input::Literal(ustr("i"), None)]));
-            DFA { starting_state, transitions, accepting_states, input_symbols }
+            DFA { starting_state, transitions, accepting_states, input_symbols, bindings: Default::default(), once: Default::default(), exclusive: Default::default(), exclusive_groups: Default::default(), end_of_options_states: Default::default() }
         };
         let minimized = dfa.minimize();
         assert_eq!(minimized.starting_state, 0);
@@ -771,13 +1104,13 @@ mod tests {
 
     #[test]
     fn minimization_fails() {
-        let (expr, input) = (Alternative(vec![Rc::new(Many1(Rc::new(Alternative(vec![Rc::new(Terminal(u("--quux"), None)), Rc::new(Sequence(vec![Rc::new(Optional(Rc::new(Sequence(vec![Rc::new(Many1(Rc::new(Many1(Rc::new(Alternative(vec![Rc::new(Terminal(u("--baz"), None)), Rc::new(Nonterminal(u("FILE")))])))))), Rc::new(Nonterminal(u("FILE")))])))), Rc::new(Sequence(vec![Rc::new(Nonterminal(u("FILE"))), Rc::new(Terminal(u("foo"), None))]))]))])))), Rc::new(Nonterminal(u("FILE")))]), [u("--quux"), u("--baz"), u("anything"), u("anything"), u("foo")]);
+        let (expr, input) = (Alternative(vec![Many1(Alternative(vec![Terminal(u("--quux"), None).into(), Sequence(vec![Optional(Sequence(vec![Many1(Many1(Alternative(vec![Terminal(u("--baz"), None).into(), Nonterminal(u("FILE"), None).into()]).into()).into()).into(), Nonterminal(u("FILE"), None).into()]).into()).into(), Sequence(vec![Nonterminal(u("FILE"), None).into(), Terminal(u("foo"), None).into()]).into()]).into()]).into()).into(), Nonterminal(u("FILE"), None).into()]), [u("--quux"), u("--baz"), u("anything"), u("anything"), u("foo")]);
         dbg!(&expr);
         dbg!(&input);
         let arena = Bump::new();
         let specs = UstrMap::default();
-        let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-        let dfa = DFA::from_regex(&regex);
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap();
         let input: Vec<&str> = input.iter().map(|s| {
             let s: &str = s;
             s
@@ -789,12 +1122,12 @@ mod tests {
 
     #[test]
     fn minimization_counterexample1() {
-        let (expr, input) = (Alternative(vec![Rc::new(Many1(Rc::new(Sequence(vec![Rc::new(Nonterminal(u("FILE"))), Rc::new(Nonterminal(u("FILE")))])))), Rc::new(Nonterminal(u("FILE")))]), [u("anything"), u("anything"), u("anything"), u("anything"), u("anything"), u("anything")]);
+        let (expr, input) = (Alternative(vec![Many1(Sequence(vec![Nonterminal(u("FILE"), None).into(), Nonterminal(u("FILE"), None).into()]).into()).into(), Nonterminal(u("FILE"), None).into()]), [u("anything"), u("anything"), u("anything"), u("anything"), u("anything"), u("anything")]);
         dbg!(&expr);
         let arena = Bump::new();
         let specs = UstrMap::default();
-        let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-        let dfa = DFA::from_regex(&regex);
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap();
         let input: Vec<&str> = input.iter().map(|s| {
             let s: &str = s;
             s
@@ -806,12 +1139,12 @@ mod tests {
 
     #[test]
     fn minimization_counterexample2() {
-        let (expr, input) = (Sequence(vec![Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Many1(Rc::new(Many1(Rc::new(Terminal(u("--baz"), None)))))), Rc::new(Nonterminal(u("FILE")))])), Rc::new(Terminal(u("--baz"), None))])), Rc::new(Many1(Rc::new(Alternative(vec![Rc::new(Nonterminal(u("FILE"))), Rc::new(Nonterminal(u("FILE")))]))))]), [u("anything"), u("--baz"), u("anything"), u("anything")]);
+        let (expr, input) = (Sequence(vec![Sequence(vec![Alternative(vec![Many1(Many1(Terminal(u("--baz"), None).into()).into()).into(), Nonterminal(u("FILE"), None).into()]).into(), Terminal(u("--baz"), None).into()]).into(), Many1(Alternative(vec![Nonterminal(u("FILE"), None).into(), Nonterminal(u("FILE"), None).into()]).into()).into()]), [u("anything"), u("--baz"), u("anything"), u("anything")]);
         dbg!(&expr);
         let arena = Bump::new();
         let specs = UstrMap::default();
-        let regex = AugmentedRegex::from_expr(&expr, &specs, &arena);
-        let dfa = DFA::from_regex(&regex);
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap();
         let input: Vec<&str> = input.iter().map(|s| {
             let s: &str = s;
             s
@@ -820,4 +1153,189 @@ mod tests {
         let minimal_dfa = dfa.minimize();
         assert!(minimal_dfa.accepts(&input));
     }
+
+    #[test]
+    fn labels_match_anything_transitions_with_their_own_input() {
+        let expr = Sequence(vec![Command(u("echo a"), None, Default::default()).into(), Terminal(u("foo"), None).into(), Command(u("echo b"), None, Default::default()).into()]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let mut commands: Vec<Ustr> = dfa.get_command_transitions().into_iter().map(|(_, cmd)| cmd).collect();
+        commands.sort_unstable();
+        assert_eq!(commands, vec![u("echo a"), u("echo b")]);
+    }
+
+    #[test]
+    fn keeps_states_running_different_commands_apart() {
+        // (a {{{ echo a }}} | b {{{ echo b }}})
+        let expr = Alternative(vec![
+            Sequence(vec![Terminal(u("a"), None).into(), Command(u("echo a"), None, Default::default()).into()]).into(),
+            Sequence(vec![Terminal(u("b"), None).into(), Command(u("echo b"), None, Default::default()).into()]).into(),
+        ]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let command_transitions = dfa.get_command_transitions();
+        for (literal, _, to) in dfa.get_literal_transitions_from(dfa.starting_state) {
+            let commands: Vec<Ustr> = command_transitions.iter().filter(|(from, _)| *from == to).map(|(_, cmd)| *cmd).collect();
+            assert_eq!(commands, vec![u(&format!("echo {}", literal))]);
+        }
+    }
+
+    #[test]
+    fn binds_words_to_all_the_names_of_a_match_anything_transition() {
+        // (<repo=REPO> log | <file=FILE> show | <PATH> diff) where <REPO> and <FILE> are external commands
+        let expr = Alternative(vec![
+            Sequence(vec![Command(u("echo r"), Some(u("repo")), Default::default()).into(), Terminal(u("log"), None).into()]).into(),
+            Sequence(vec![Command(u("echo f"), Some(u("file")), Default::default()).into(), Terminal(u("show"), None).into()]).into(),
+            Sequence(vec![Nonterminal(u("PATH"), None).into(), Terminal(u("diff"), None).into()]).into(),
+        ]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let (from, to) = dfa.get_match_anything_transitions()[0];
+        assert_eq!(from, dfa.starting_state);
+        assert_eq!(dfa.get_binding_transitions(), vec![(from, to, vec![u("file"), u("repo")])]);
+    }
+
+    #[test]
+    fn reports_too_many_states() {
+        let expr = Sequence(vec![Terminal(u("a"), None).into(), Terminal(u("b"), None).into(), Terminal(u("c"), None).into()]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        assert!(matches!(dfa_from_regex(&regex, 4), Err(Error::TooManyStates(4))));
+        assert!(dfa_from_regex(&regex, 5).is_ok());
+    }
+
+    #[test]
+    fn handles_more_states_than_fit_in_u16() {
+        // (x x ... x) repeated in nested sequences so that the regex isn't too deep to recurse into
+        let x: Rc<Expr> = Terminal(u("x"), None).into();
+        let row: Rc<Expr> = Sequence(vec![x; 255]).into();
+        let block: Rc<Expr> = Sequence(vec![row; 255]).into();
+        let expr: Rc<Expr> = Sequence(vec![block; 2]).into();
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr, &specs, &arena).unwrap();
+        // Not minimized, as that takes a while with that many states
+        let dfa = DFA::from_regex(&regex).unwrap();
+        assert!(dfa.get_all_states().len() > u64::from(u16::MAX));
+        let words = vec!["x"; 255 * 255 * 2];
+        assert!(dfa.accepts(&words));
+        assert!(!dfa.accepts(&words[1..]));
+    }
+
+    #[test]
+    fn detects_ambiguities() {
+        // (build --release | <TARGET> --verbose)
+        let expr = Alternative(vec![
+            Sequence(vec![Terminal(u("build"), None).into(), Terminal(u("--release"), None).into()]).into(),
+            Sequence(vec![Nonterminal(u("TARGET"), None).into(), Terminal(u("--verbose"), None).into()]).into(),
+        ]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let ambiguities = dfa.get_ambiguities();
+        assert_eq!(ambiguities.len(), 1);
+        assert!(ambiguities[0].path.is_empty());
+        assert_eq!(ambiguities[0].literal, Input::Literal(u("build"), None));
+        assert!(matches!(ambiguities[0].match_anything, Input::Any(MatchAnythingInput::Nonterminal(name, ..)) if name == "TARGET"));
+    }
+
+    #[test]
+    fn literal_and_nonterminal_leading_to_the_same_state_are_not_ambiguous() {
+        // --color (always | <WHEN>)
+        let expr = Sequence(vec![Terminal(u("--color"), None).into(), Alternative(vec![Terminal(u("always"), None).into(), Nonterminal(u("WHEN"), None).into()]).into()]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        assert!(dfa.get_ambiguities().is_empty());
+    }
+
+    #[test]
+    fn counts_bounded_repetitions() {
+        // cp <PATH>{2} | ln --force{0,1} <PATH>{1,}
+        let expr = Alternative(vec![
+            Sequence(vec![Terminal(u("cp"), None).into(), Repeat(Nonterminal(u("PATH"), None).into(), 2, Some(2)).into()]).into(),
+            Sequence(vec![
+                Terminal(u("ln"), None).into(),
+                Repeat(Terminal(u("--force"), None).into(), 0, Some(1)).into(),
+                Repeat(Nonterminal(u("PATH"), None).into(), 1, None).into(),
+            ]).into(),
+        ]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        assert!(!dfa.accepts(&["cp", "a"]));
+        assert!(dfa.accepts(&["cp", "a", "b"]));
+        assert!(!dfa.accepts(&["cp", "a", "b", "c"]));
+        assert!(dfa.accepts(&["ln", "a"]));
+        assert!(dfa.accepts(&["ln", "--force", "a", "b", "c"]));
+        assert!(!dfa.accepts(&["ln"]));
+    }
+
+    #[test]
+    fn detects_unreachable_exclusive_members() {
+        // -F can only be reached through -E, which belongs to the same @exclusive(...)
+        let dfa = {
+            let transitions: HashMap<StateId, HashMap<Input, StateId>> = HashMap::from_iter([
+                (0, HashMap::from_iter([(Input::Literal(u("-E"), None), 1)])),
+                (1, HashMap::from_iter([(Input::Literal(u("-F"), None), 2)])),
+            ]);
+            let exclusive = HashMap::from_iter([((0, Input::Literal(u("-E"), None)), vec![0]), ((1, Input::Literal(u("-F"), None)), vec![1])]);
+            let input_symbols = Rc::new(HashSet::from_iter([Input::Literal(u("-E"), None), Input::Literal(u("-F"), None)]));
+            DFA { starting_state: 0, transitions, accepting_states: RoaringBitmap::from_iter([1, 2]), input_symbols, bindings: Default::default(), once: Default::default(), exclusive, exclusive_groups: vec![vec![0, 1]], end_of_options_states: Default::default() }
+        };
+        assert_eq!(dfa.get_exclusions(), vec![(0, vec![1]), (1, vec![0])]);
+        assert_eq!(dfa.get_unreachable_exclusive_members(), vec![u("-F")]);
+    }
+
+    #[test]
+    fn scopes_exclusive_alternatives_to_their_occurrence() {
+        // @exclusive(-E | -F) [sub @exclusive(-E | -F)]
+        let expr = Sequence(vec![
+            Exclusive(vec![Terminal(u("-E"), None).into(), Terminal(u("-F"), None).into()]).into(),
+            Optional(Sequence(vec![
+                Terminal(u("sub"), None).into(),
+                Exclusive(vec![Terminal(u("-E"), None).into(), Terminal(u("-F"), None).into()]).into(),
+            ]).into()).into(),
+        ]);
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        assert_eq!(dfa.exclusive_groups, vec![vec![0, 1], vec![2, 3]]);
+        let mut tags: Vec<(Ustr, Vec<u32>)> = dfa.get_exclusive_transitions().into_iter().map(|(_, literal, _, tags)| (literal, tags)).collect();
+        tags.sort_unstable();
+        assert_eq!(tags, vec![(u("-E"), vec![0]), (u("-E"), vec![2]), (u("-F"), vec![1]), (u("-F"), vec![3])]);
+        assert!(dfa.get_unreachable_exclusive_members().is_empty());
+    }
+
+    #[test]
+    fn tells_apart_states_after_end_of_options() {
+        // (-v | <END_OF_OPTIONS> | x)...
+        let expr = Many1(Alternative(vec![
+            Terminal(u("-v"), None).into(),
+            Nonterminal(u("END_OF_OPTIONS"), None).into(),
+            Terminal(u("x"), None).into(),
+        ]).into());
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &specs, &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let follow = |state: StateId, literal: &str| *dfa.transitions.get(&state).unwrap().get(&Input::Literal(u(literal), None)).unwrap();
+        let after_x = follow(dfa.starting_state, "x");
+        let after_double_dash = follow(dfa.starting_state, "--");
+        assert!(!dfa.end_of_options_states.contains(dfa.starting_state));
+        assert!(!dfa.end_of_options_states.contains(after_x));
+        assert!(dfa.end_of_options_states.contains(after_double_dash));
+        assert!(dfa.end_of_options_states.contains(follow(after_double_dash, "x")));
+    }
 }
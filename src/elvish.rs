@@ -0,0 +1,412 @@
+use std::io::Write;
+
+use complgen::{StateId, Result};
+use hashbrown::HashMap;
+use ustr::{Ustr, UstrMap, ustr};
+use crate::dfa::DFA;
+
+
+// Elvish doesn't interpret anything within single quotes.  The only character needing escaping is the single
+// quote itself.
+pub fn escape_elvish_string(s: &str) -> String {
+    s.replace('\'', "''")
+}
+
+
+// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>, directories included
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let regex = crate::glob::to_regex(glob);
+    format!("edit:complete-filename $cur | each {{|c| if (or (path:is-dir $c[stem]) (re:match '{}' (path:base $c[stem]))) {{ put $c[stem] }} }}", escape_elvish_string(&format!("^(?:{regex})$")))
+}
+
+
+// Elvish map literals need a lone `&` when they're empty, i.e. `[&]`
+fn make_map_initializer(entries: &[String]) -> String {
+    if entries.is_empty() {
+        return "[&]".to_string();
+    }
+    format!("[{}]", itertools::join(entries, " "))
+}
+
+
+/// `literals`: a list of all literals; a literal's id is its index
+///
+/// `descriptions`: a map where:
+///   * key: literal's id
+///   * value: literal's description
+///
+/// `transitions`: a map where:
+///   * key: source state number
+///   * value: a map from a literal id to the destination state number
+///
+/// `prefix_transitions`: a map where:
+///   * key: source state number
+///   * value: a list of `[literal id, destination state number]` pairs, longer prefixes first
+///
+/// Prefix literals like `--color=` only match the beginning of a word.  The rest of the word is matched starting
+/// at the destination state.
+///
+/// `match_anything_transitions`: a map where:
+///   * key: state number
+///   * value: state number
+///
+/// An entry in the `match_anything_transitions` map indicates that there's a fallback transition that accepts
+/// any word
+///
+/// `bindings`: a map where:
+///   * key: source and target state numbers of a fallback transition, separated by a comma
+///   * value: a list of names the word matched by the transition is bound to
+///
+/// `once`: a map where:
+///   * key: state number and literal (or prefix) id, separated by a comma
+///   * value: a list of ids of the @once(...) options the transition stands for
+///
+/// Once a transition has been taken, none of the transitions sharing an id with it is offered anymore
+///
+/// `exclusive`: a map where:
+///   * key: state number and literal (or prefix) id, separated by a comma
+///   * value: a list of tags of the @exclusive(...) alternatives the transition starts
+///
+/// `exclusions`: a map where:
+///   * key: tag of an @exclusive(...) alternative
+///   * value: a list of tags of the other alternatives of the same @exclusive(...)
+///
+/// Once a transition has been taken, none of the transitions starting the other alternatives is offered anymore
+///
+/// `end_of_options_states`: a map where:
+///   * key: number of a state reached through <END_OF_OPTIONS>, i.e. after which only positionals follow
+///   * value: $true
+///
+fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
+    let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id, literal, description.unwrap_or(ustr("")))).collect();
+
+    let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, literal, description)| ((*literal, *description), *id)).collect();
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| format!("'{}'", escape_elvish_string(literal))), " ");
+    writeln!(buffer, r#"    var literals = [{literals}]"#)?;
+
+    let descriptions: Vec<String> = all_literals.iter().filter(|(_, _, description)| !description.is_empty()).map(|(id, _, description)| format!("&{id}='{}'", escape_elvish_string(description))).collect();
+    writeln!(buffer, r#"    var descriptions = {}"#, make_map_initializer(&descriptions))?;
+    writeln!(buffer)?;
+
+    let mut transitions_entries: Vec<String> = Default::default();
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_literal_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(input, description, to)| format!("&{}={}", literal_id_from_input_description.get(&(input, description)).unwrap(), to)), " ");
+        transitions_entries.push(format!("&{state}=[{state_transitions}]"));
+    }
+    writeln!(buffer, r#"    var transitions = {}"#, make_map_initializer(&transitions_entries))?;
+    writeln!(buffer)?;
+
+    let mut prefix_transitions_entries: Vec<String> = Default::default();
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(prefix, to)| format!("[{} {}]", literal_id_from_input_description.get(&(prefix, ustr(""))).unwrap(), to)), " ");
+        prefix_transitions_entries.push(format!("&{state}=[{state_transitions}]"));
+    }
+    writeln!(buffer, r#"    var prefix_transitions = {}"#, make_map_initializer(&prefix_transitions_entries))?;
+    writeln!(buffer)?;
+
+    let match_anything_transitions: Vec<String> = dfa.get_match_anything_transitions().into_iter().map(|(from, to)| format!("&{from}={to}")).collect();
+    writeln!(buffer, r#"    var match_anything_transitions = {}"#, make_map_initializer(&match_anything_transitions))?;
+
+    let bindings: Vec<String> = dfa.get_binding_transitions().into_iter().map(|(from, to, names)| format!("&{from},{to}=[{}]", itertools::join(names, " "))).collect();
+    writeln!(buffer, r#"    var bindings = {}"#, make_map_initializer(&bindings))?;
+
+    let once: Vec<String> = dfa.get_once_transitions().into_iter().map(|(from, literal, description, ids)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!("&{from},{literal_id}=[{}]", itertools::join(ids, " "))
+    }).collect();
+    writeln!(buffer, r#"    var once = {}"#, make_map_initializer(&once))?;
+
+    let exclusive: Vec<String> = dfa.get_exclusive_transitions().into_iter().map(|(from, literal, description, tags)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!("&{from},{literal_id}=[{}]", itertools::join(tags, " "))
+    }).collect();
+    writeln!(buffer, r#"    var exclusive = {}"#, make_map_initializer(&exclusive))?;
+    let exclusions: Vec<String> = dfa.get_exclusions().into_iter().map(|(tag, others)| format!("&{tag}=[{}]", itertools::join(others, " "))).collect();
+    writeln!(buffer, r#"    var exclusions = {}"#, make_map_initializer(&exclusions))?;
+    let end_of_options_states: Vec<String> = dfa.end_of_options_states.iter().map(|state| format!("&{state}=$true")).collect();
+    writeln!(buffer, r#"    var end_of_options_states = {}"#, make_map_initializer(&end_of_options_states))?;
+
+    Ok(())
+}
+
+
+fn write_commands_completion_code<W: Write>(buffer: &mut W, table_name: &str, function_prefix: &str, command_transitions: &[(StateId, Ustr)], id_from_command: &UstrMap<usize>) -> Result<()> {
+    if command_transitions.is_empty() {
+        return Ok(());
+    }
+
+    let function_from_state = itertools::join(command_transitions.iter().map(|(state, cmd)| format!("&{state}=${function_prefix}{}~", id_from_command.get(cmd).unwrap())), " ");
+    write!(buffer, r#"
+    var {table_name} = [{function_from_state}]
+    if (has-key ${table_name} $state) {{
+        for line [(${table_name}[$state] $cur $complgen_words $bound_words)] {{
+            var fields = [(str:split &max=2 "\t" $line)]
+            var description = ''
+            if (> (count $fields) 1) {{
+                set description = $fields[1]
+            }}
+            add-completion $word_prefix$fields[0] $description
+        }}
+    }}
+"#)?;
+
+    Ok(())
+}
+
+
+pub fn write_preamble<W: Write>(buffer: &mut W) -> Result<()> {
+    writeln!(buffer, "use path")?;
+    writeln!(buffer, "use re")?;
+    writeln!(buffer, "use str")?;
+    writeln!(buffer)?;
+    // Runs $f with the environment variables from the $bound map set, restoring the previous values afterwards
+    write!(buffer, r#"fn _complgen_with_env {{|bound f|
+    var saved = [&]
+    for name [(keys $bound)] {{
+        if (has-env $name) {{
+            set saved[$name] = (get-env $name)
+        }}
+        set-env $name $bound[$name]
+    }}
+    try {{
+        $f
+    }} finally {{
+        for name [(keys $bound)] {{
+            if (has-key $saved $name) {{
+                set-env $name $saved[$name]
+            }} else {{
+                unset-env $name
+            }}
+        }}
+    }}
+}}
+
+"#)?;
+    Ok(())
+}
+
+
+pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DFA) -> Result<()> {
+    // Elvish syntax is too far from POSIX shells to run generic commands with it
+    let command_transitions = dfa.get_command_transitions();
+    let id_from_command: UstrMap<usize> = command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_command {
+        write!(buffer, r#"fn _{command}_{id} {{|cur words bound|
+    tmp E:COMPLGEN_WORDS = $words
+    _complgen_with_env $bound {{ e:sh -c '{}' _ $cur }}
+}}
+
+"#, escape_elvish_string(cmd))?;
+    }
+
+    let specialized_command_transitions = dfa.get_elvish_command_transitions();
+    let id_from_specialized_command: UstrMap<usize> = specialized_command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_specialized_command {
+        write!(buffer, r#"fn _{command}_spec_{id} {{|cur words bound|
+    tmp E:COMPLGEN_WORDS = $words
+    _complgen_with_env $bound {{ {cmd} }}
+}}
+
+"#)?;
+    }
+
+    writeln!(buffer, r#"set edit:completion:arg-completer['{}'] = {{|@words|"#, escape_elvish_string(command))?;
+
+    write_tables(buffer, dfa)?;
+
+    write!(buffer, r#"
+    var cword = (- (count $words) 1)
+
+    var complgen_words = (str:join "\n" $words[1..$cword])
+
+    # Words matched by bound nonterminals, e.g. <repo=PATH>, exported to external commands under their names
+    var bound_words = [&]
+
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    var given_once = [&]
+    var given_exclusive = [&]
+
+    var state = '{starting_state}'
+    var word_index = 1
+    while (< $word_index $cword) {{
+        var word = $words[$word_index]
+
+        var word_matched = $false
+        if (has-key $transitions $state) {{
+            var state_transitions = $transitions[$state]
+            for literal_id [(keys $state_transitions)] {{
+                if (==s $literals[$literal_id] $word) {{
+                    if (has-key $once $state','$literal_id) {{
+                        for id $once[$state','$literal_id] {{
+                            set given_once[$id] = $true
+                        }}
+                    }}
+                    if (has-key $exclusive $state','$literal_id) {{
+                        for tag $exclusive[$state','$literal_id] {{
+                            set given_exclusive[$tag] = $true
+                        }}
+                    }}
+                    set state = $state_transitions[$literal_id]
+                    set word_index = (+ $word_index 1)
+                    set word_matched = $true
+                    break
+                }}
+            }}
+        }}
+        if $word_matched {{
+            continue
+        }}
+
+        if (has-key $prefix_transitions $state) {{
+            for transition $prefix_transitions[$state] {{
+                var prefix = $literals[$transition[0]]
+                if (str:has-prefix $word $prefix) {{
+                    if (has-key $once $state','$transition[0]) {{
+                        for id $once[$state','$transition[0]] {{
+                            set given_once[$id] = $true
+                        }}
+                    }}
+                    if (has-key $exclusive $state','$transition[0]) {{
+                        for tag $exclusive[$state','$transition[0]] {{
+                            set given_exclusive[$tag] = $true
+                        }}
+                    }}
+                    set state = $transition[1]
+                    set words[$word_index] = (str:trim-prefix $word $prefix)
+                    set word_matched = $true
+                    break
+                }}
+            }}
+        }}
+        if $word_matched {{
+            continue
+        }}
+
+        if (has-key $match_anything_transitions $state) {{
+            var next_state = $match_anything_transitions[$state]
+            if (has-key $bindings $state','$next_state) {{
+                for name $bindings[$state','$next_state] {{
+                    set bound_words[$name] = $word
+                }}
+            }}
+            set state = $next_state
+            set word_index = (+ $word_index 1)
+            continue
+        }}
+
+        return
+    }}
+
+    # Completion text -> description.  The same completion may be reachable both as a literal and as a prefix,
+    # e.g. in short options clusters, in which case the one having a description wins.
+    var completions = [&]
+    fn add-completion {{|completion description|
+        if (or (not (has-key $completions $completion)) (!=s $description '')) {{
+            set completions[$completion] = $description
+        }}
+    }}
+"#, starting_state = dfa.starting_state)?;
+
+    // Literals like `--color=` only match the beginning of a word.  Strip them off the completed word one by one
+    // so that the remainder can be completed and put them back in front of every completion.
+    write!(buffer, r#"
+    var cur = $words[$cword]
+    var word_prefix = ''
+    while $true {{
+        var state_literal_ids = []
+        if (has-key $transitions $state) {{
+            set state_literal_ids = [(keys $transitions[$state])]
+        }}
+
+        var longest_prefix_transition = $nil
+        if (has-key $prefix_transitions $state) {{
+            for transition $prefix_transitions[$state] {{
+                set state_literal_ids = [$@state_literal_ids $transition[0]]
+                if (and (eq $longest_prefix_transition $nil) (str:has-prefix $cur $literals[$transition[0]])) {{
+                    set longest_prefix_transition = $transition
+                }}
+            }}
+        }}
+
+        for literal_id $state_literal_ids {{
+            var literal = $literals[$literal_id]
+            # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+            if (and (re:match '^-[^-]' $word_prefix) (== (count $literal) 1) (str:contains $word_prefix[1..] $literal)) {{
+                continue
+            }}
+            # Options from @once(...) aren't offered again once given
+            if (and (==s $word_prefix '') (has-key $once $state','$literal_id)) {{
+                var once_given = $false
+                for id $once[$state','$literal_id] {{
+                    if (has-key $given_once $id) {{
+                        set once_given = $true
+                    }}
+                }}
+                if $once_given {{
+                    continue
+                }}
+            }}
+            # Alternatives of an @exclusive(...) aren't offered once another one of it has been given
+            if (and (==s $word_prefix '') (has-key $exclusive $state','$literal_id)) {{
+                var excluded = $false
+                for tag $exclusive[$state','$literal_id] {{
+                    for excluding_tag $exclusions[$tag] {{
+                        if (has-key $given_exclusive $excluding_tag) {{
+                            set excluded = $true
+                        }}
+                    }}
+                }}
+                if $excluded {{
+                    continue
+                }}
+            }}
+            # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+            if (and (==s $word_prefix '') (has-key $end_of_options_states $state) (str:has-prefix $literal '-')) {{
+                continue
+            }}
+            var description = ''
+            if (has-key $descriptions $literal_id) {{
+                set description = $descriptions[$literal_id]
+            }}
+            add-completion $word_prefix$literal $description
+        }}
+
+        if (eq $longest_prefix_transition $nil) {{
+            break
+        }}
+        var prefix = $literals[$longest_prefix_transition[0]]
+        set state = $longest_prefix_transition[1]
+        set word_prefix = $word_prefix$prefix
+        set cur = (str:trim-prefix $cur $prefix)
+    }}
+"#)?;
+
+    write_commands_completion_code(buffer, "commands", &format!("_{command}_"), &command_transitions, &id_from_command)?;
+    write_commands_completion_code(buffer, "specialized_commands", &format!("_{command}_spec_"), &specialized_command_transitions, &id_from_specialized_command)?;
+
+    // Completions ending with `=` expect a value right after them, hence no trailing space
+    write!(buffer, r#"
+    for completion [(keys $completions)] {{
+        var display = $completion
+        if (!=s $completions[$completion] '') {{
+            set display = $completion' ('$completions[$completion]')'
+        }}
+        var suffix = ' '
+        if (str:has-suffix $completion '=') {{
+            set suffix = ''
+        }}
+        edit:complex-candidate $completion &display=$display &code-suffix=$suffix
+    }}
+}}
+"#)?;
+
+    Ok(())
+}
@@ -4,13 +4,96 @@ use complgen::{StateId, Result};
 use hashbrown::HashMap;
 use ustr::{Ustr, UstrMap, ustr};
 use crate::dfa::DFA;
+use crate::grammar::CommandAnnotations;
 
 
 // array indices start at 1 in fish , not 0 (!)
 
 
+// Shared between all completion scripts, hence not prefixed with the command name.  Functions can't run in the
+// background, so the ones having a timeout run in a separate fish process, without the user's configuration,
+// that a watchdog kills in time.  Its output goes to a file so that processes it spawned can't hold up the
+// command substitution.
+const RUN_FUNCTION: &str = r#"# Runs the function $argv[3] with the remaining arguments, giving up on it after $timeout seconds and reusing the
+# output of a successful run for $cache_ttl seconds, if not empty.  Errors are only shown if COMPLGEN_DEBUG is set.
+function __complgen_run --argument-names timeout cache_ttl
+    set --local call (string escape -- $argv[3..] | string join ' ')
+    # Commands like `git branch` depend on the working directory
+    set --local cache_key (string escape -- $PWD $argv[3..] | string join ' ')
+    set --local now
+    if test -n "$cache_ttl"
+        set now (date +%s)
+        set --local index (contains --index -- $cache_key $__complgen_cache_keys)
+        if test -n "$index"; and test (math $now - $__complgen_cache_times[$index]) -lt $cache_ttl
+            string split --no-empty \x1e -- $__complgen_cache_outputs[$index]
+            return 0
+        end
+    end
+
+    set --local stderr /dev/null
+    set --query COMPLGEN_DEBUG; and set stderr /dev/stderr
+    set --local lines
+    set --local run_status
+    if test -z "$timeout"
+        set lines (eval $call 2>$stderr)
+        set run_status $status
+    else
+        set --local output_file (mktemp)
+        sh -c 'fish --no-config --command "$1" >"$2" & child=$!
+(sleep "$3"; kill $child) >/dev/null 2>&1 & watchdog=$!
+wait $child; child_status=$?; kill $watchdog 2>/dev/null; exit $child_status' sh (functions $argv[3] | string collect)\n$call $output_file $timeout 2>$stderr
+        set run_status $status
+        set lines (cat $output_file)
+        rm -f $output_file
+        if test $run_status -gt 128
+            return $run_status
+        end
+    end
+
+    if test $run_status -eq 0; and test -n "$cache_ttl"
+        set --local index (contains --index -- $cache_key $__complgen_cache_keys)
+        if test -z "$index"
+            set --global --append __complgen_cache_keys $cache_key
+            set index (count $__complgen_cache_keys)
+        end
+        set --global __complgen_cache_outputs[$index] (string join \x1e -- '' $lines)
+        set --global __complgen_cache_times[$index] $now
+    end
+    if test (count $lines) -gt 0
+        printf '%s\n' $lines
+    end
+end
+"#;
+
+
+/// For use within double quotes, where only `\`, `"` and `$` are special
 pub fn escape_fish_string(s: &str) -> String {
-    s.replace("\"", "\\\"").replace("$", "\\$")
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$")
+}
+
+
+/// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>, directories included
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let regex = crate::glob::to_regex(glob);
+    format!(r#"__fish_complete_path "$1" | string match --regex -- "{}""#, escape_fish_string(&format!("(^|/)({regex})$|/$")))
+}
+
+
+// Names the words matched by match-anything transitions get bound to, e.g. `repo` in <repo=PATH>.  Their
+// positions are what identifies them in the scripts.
+fn get_binding_names(dfa: &DFA) -> Vec<Ustr> {
+    let mut names: Vec<Ustr> = dfa.get_binding_transitions().into_iter().flat_map(|(_, _, names)| names).collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+
+// The bound words are passed to command functions as arguments following $cur and $COMPLGEN_WORDS, an empty
+// one meaning the name hasn't been bound.  `and` doesn't open a new block, unlike `if`, so the variables stay
+// in scope.
+fn make_binding_exports(binding_names: &[Ustr]) -> String {
+    binding_names.iter().enumerate().map(|(index, name)| format!("    test -n \"$argv[{index}]\"; and set --local --export {name} $argv[{index}]\n", index = index + 3)).collect()
 }
 
 
@@ -18,7 +101,7 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
     let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id + 1, literal, description.unwrap_or(ustr("")))).collect();
 
     let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, literal, description)| ((*literal, *description), *id)).collect();
-    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| literal), " ");
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| format!(r#""{}""#, escape_fish_string(literal))), " ");
     writeln!(buffer, r#"    set --local literals {literals}"#)?;
     writeln!(buffer, "")?;
 
@@ -28,7 +111,7 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
     writeln!(buffer, "")?;
 
     for state in dfa.get_all_states() {
-        let transitions = dfa.get_literal_transitions_from(StateId::try_from(state).unwrap());
+        let transitions = dfa.get_literal_transitions_from(state);
         if transitions.is_empty() {
             continue;
         }
@@ -44,12 +127,57 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
 
     writeln!(buffer, "")?;
 
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_inputs: String = itertools::join(transitions.iter().map(|(prefix, _)| format!("{}", literal_id_from_input_description.get(&(*prefix, ustr(""))).unwrap())), " ");
+        let state_tos: String = itertools::join(transitions.iter().map(|(_, to)| format!("{}", to + 1)), " ");
+        writeln!(buffer, r#"    set prefix_transitions[{}] "set prefix_inputs {state_inputs}; set prefix_tos {state_tos}""#, state + 1)?;
+    }
+
+    writeln!(buffer)?;
+
+    // One element per literal (or prefix) transition standing for @once(...) options, as "state,literal_id", along
+    // with the ids of those options.  Once a transition has been taken, none of the transitions sharing an id with it
+    // is offered anymore.
+    let once_transitions = dfa.get_once_transitions();
+    let once_transitions_from_literal = itertools::join(once_transitions.iter().map(|(from, literal, description, _)| format!("{},{}", from + 1, literal_id_from_input_description.get(&(*literal, *description)).unwrap())), " ");
+    writeln!(buffer, r#"    set --local once_transitions {once_transitions_from_literal}"#)?;
+    let once_ids = itertools::join(once_transitions.iter().map(|(_, _, _, ids)| format!(r#""{}""#, itertools::join(ids, " "))), " ");
+    writeln!(buffer, r#"    set --local once_ids {once_ids}"#)?;
+
+    // Likewise for literal (or prefix) transitions starting @exclusive(...) alternatives, along with the
+    // (1-based) tags of those alternatives.  Indexed by tag, `exclusions` holds the tags of the other alternatives of
+    // the same @exclusive(...).  Once a transition has been taken, none of the transitions starting the other
+    // alternatives is offered anymore.
+    let exclusive_transitions = dfa.get_exclusive_transitions();
+    let exclusive_transitions_from_literal = itertools::join(exclusive_transitions.iter().map(|(from, literal, description, _)| format!("{},{}", from + 1, literal_id_from_input_description.get(&(*literal, *description)).unwrap())), " ");
+    writeln!(buffer, r#"    set --local exclusive_transitions {exclusive_transitions_from_literal}"#)?;
+    let exclusive_tags = itertools::join(exclusive_transitions.iter().map(|(_, _, _, tags)| format!(r#""{}""#, itertools::join(tags.iter().map(|tag| tag + 1), " "))), " ");
+    writeln!(buffer, r#"    set --local exclusive_tags {exclusive_tags}"#)?;
+    let exclusions = itertools::join(dfa.get_exclusions().into_iter().map(|(_, others)| format!(r#""{}""#, itertools::join(others.iter().map(|tag| tag + 1), " "))), " ");
+    writeln!(buffer, r#"    set --local exclusions {exclusions}"#)?;
+
+    // States reached through <END_OF_OPTIONS>, i.e. after which only positionals follow
+    let end_of_options_states = itertools::join(dfa.end_of_options_states.iter().map(|state| state + 1), " ");
+    writeln!(buffer, r#"    set --local end_of_options_states {end_of_options_states}"#)?;
+
     let match_anything_transitions = dfa.get_match_anything_transitions();
     let match_anything_transitions_from = itertools::join(match_anything_transitions.iter().map(|(from, _)| format!("{}", from + 1)), " ");
     writeln!(buffer, r#"    set --local match_anything_transitions_from {match_anything_transitions_from}"#)?;
     let match_anything_transitions_to = itertools::join(match_anything_transitions.iter().map(|(_, to)| format!("{}", to + 1)), " ");
     writeln!(buffer, r#"    set --local match_anything_transitions_to {match_anything_transitions_to}"#)?;
 
+    let binding_names = get_binding_names(dfa);
+    // One element per bound name, with the match-anything transition binding it as "from,to"
+    let binding_transitions: Vec<(StateId, StateId, Ustr)> = dfa.get_binding_transitions().into_iter().flat_map(|(from, to, names)| names.into_iter().map(move |name| (from, to, name))).collect();
+    let binding_transitions_from_to = itertools::join(binding_transitions.iter().map(|(from, to, _)| format!("{},{}", from + 1, to + 1)), " ");
+    writeln!(buffer, r#"    set --local binding_transitions {binding_transitions_from_to}"#)?;
+    let binding_ids = itertools::join(binding_transitions.iter().map(|(_, _, name)| format!("{}", binding_names.binary_search(name).unwrap() + 1)), " ");
+    writeln!(buffer, r#"    set --local binding_ids {binding_ids}"#)?;
+
     Ok(())
 }
 
@@ -70,10 +198,12 @@ fn write_specialized_commands<W: Write>(buffer: &mut W, command: &str, dfa: &DFA
         }
     }
 
+    let binding_exports = make_binding_exports(&get_binding_names(dfa));
     for (cmd, id) in &specialized_id_from_command {
         write!(buffer, r#"function _{command}_spec_{id}
     set 1 $argv[1]
-    {cmd}
+    set --local --export COMPLGEN_WORDS $argv[2]
+{binding_exports}    {cmd}
 end
 
 "#)?;
@@ -94,9 +224,9 @@ fn write_specialized_commands_completion_code<W: Write>(buffer: &mut W, command:
         set --local function_name _{command}_spec_$function_id
         set --local --erase inputs
         set --local --erase tos
-        set --local lines (eval $function_name $COMP_WORDS[$COMP_CWORD])
+        set --local lines (__complgen_run "" "" $function_name "$cur" "$complgen_words" $bound_words)
         for line in $lines
-            printf '%s\n' $line
+            printf '%s%s\n' $word_prefix $line
         end
     end
 "#)?;
@@ -121,10 +251,12 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
         }
     }
 
+    let binding_exports = make_binding_exports(&get_binding_names(dfa));
     for (cmd, id) in &id_from_command {
         write!(buffer, r#"function _{command}_{id}
     set 1 $argv[1]
-    {cmd}
+    set --local --export COMPLGEN_WORDS $argv[2]
+{binding_exports}    {cmd}
 end
 
 "#)?;
@@ -132,6 +264,8 @@ end
 
     let (specialized_command_transitions, specialized_id_from_state) = write_specialized_commands(buffer, command, dfa)?;
 
+    writeln!(buffer, "{RUN_FUNCTION}")?;
+
     write!(buffer, r#"function _{command}"#)?;
 
     write!(buffer, r#"
@@ -147,40 +281,102 @@ end
         set COMP_CWORD (count $COMP_WORDS)
     end
 
+    set --local complgen_words
+    if test $COMP_CWORD -gt 2
+        set complgen_words (string join \n -- $COMP_WORDS[2..(math $COMP_CWORD - 1)] | string collect)
+    end
+
 "#)?;
 
+    // One element per binding name, e.g. by <repo=PATH>, empty until the name gets bound
+    let bound_words = itertools::join(get_binding_names(dfa).iter().map(|_| r#""""#), " ");
+    writeln!(buffer, r#"    set --local bound_words {bound_words}"#)?;
+
     write_tables(buffer, dfa)?;
 
     write!(buffer, r#"
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    set --local given_once
+    set --local given_exclusive
+
     set --local state {starting_state}
     set --local word_index 2
     while test $word_index -lt $COMP_CWORD
+        set --local -- word $COMP_WORDS[$word_index]
+
         if set --query transitions[$state] && test -n $transitions[$state]
             set --local --erase inputs
             set --local --erase tos
             eval $transitions[$state]
 
-            set --local -- word $COMP_WORDS[$word_index]
             if contains -- $word $literals
                 set --local word_matched 0
-                for literal_id in (seq 1 (count $literals))
-                    if test $literals[$literal_id] = $word
-                        set --local index (contains --index -- $literal_id $inputs)
+                for index in (seq 1 (count $inputs))
+                    if test "$literals[$inputs[$index]]" = "$word"
+                        for once_index in (seq (count $once_transitions))
+                            if test $once_transitions[$once_index] = "$state,$inputs[$index]"
+                                set --append given_once (string split ' ' -- $once_ids[$once_index])
+                            end
+                        end
+                        for exclusive_index in (seq (count $exclusive_transitions))
+                            if test $exclusive_transitions[$exclusive_index] = "$state,$inputs[$index]"
+                                set --append given_exclusive (string split ' ' -- $exclusive_tags[$exclusive_index])
+                            end
+                        end
                         set state $tos[$index]
                         set word_index (math $word_index + 1)
                         set word_matched 1
                         break
                     end
                 end
-                if test word_matched -ne 0
+                if test $word_matched -ne 0
                     continue
                 end
             end
         end
 
+        if set --query prefix_transitions[$state] && test -n $prefix_transitions[$state]
+            set --local --erase prefix_inputs
+            set --local --erase prefix_tos
+            eval $prefix_transitions[$state]
+
+            set --local prefix_matched 0
+            for index in (seq 1 (count $prefix_inputs))
+                set --local -- prefix $literals[$prefix_inputs[$index]]
+                set --local prefix_length (string length -- $prefix)
+                set --local -- head (string sub --length $prefix_length -- "$word")
+                if test "$head" = "$prefix"
+                    for once_index in (seq (count $once_transitions))
+                        if test $once_transitions[$once_index] = "$state,$prefix_inputs[$index]"
+                            set --append given_once (string split ' ' -- $once_ids[$once_index])
+                        end
+                    end
+                    for exclusive_index in (seq (count $exclusive_transitions))
+                        if test $exclusive_transitions[$exclusive_index] = "$state,$prefix_inputs[$index]"
+                            set --append given_exclusive (string split ' ' -- $exclusive_tags[$exclusive_index])
+                        end
+                    end
+                    set state $prefix_tos[$index]
+                    set --local -- rest (string sub --start (math $prefix_length + 1) -- "$word")
+                    set COMP_WORDS[$word_index] "$rest"
+                    set prefix_matched 1
+                    break
+                end
+            end
+            if test $prefix_matched -ne 0
+                continue
+            end
+        end
+
         if test -n $match_anything_transitions_from[$state]
             set --local index (contains --index -- $state $match_anything_transitions_from)
-            set state $match_anything_transitions_to[$index]
+            set --local next_state $match_anything_transitions_to[$index]
+            for binding_index in (seq (count $binding_transitions))
+                if test $binding_transitions[$binding_index] = "$state,$next_state"
+                    set bound_words[$binding_ids[$binding_index]] $word
+                end
+            end
+            set state $next_state
             set word_index (math $word_index + 1)
             continue
         end
@@ -189,25 +385,100 @@ end
     end
 "#, starting_state = dfa.starting_state + 1)?;
 
+    // Literals like `--color=` only match the beginning of a word.  Strip them off the completed word one by one
+    // so that the remainder can be completed and put them back in front of every completion.
     write!(buffer, r#"
-    if set --query transitions[$state] && test -n $transitions[$state]
-        set --local --erase inputs
-        set --local --erase tos
-        eval $transitions[$state]
-        for literal_id in $inputs
-            if test -n $descriptions[$literal_id]
-                printf '%s\t%s\n' $literals[$literal_id] $descriptions[$literal_id]
+    set --local -- cur $COMP_WORDS[$COMP_CWORD]
+    set --local word_prefix ""
+    while true
+        set inputs
+        set tos
+        set prefix_inputs
+        set prefix_tos
+        set --local state_literal_ids
+
+        if set --query transitions[$state] && test -n $transitions[$state]
+            eval $transitions[$state]
+            set --append state_literal_ids $inputs
+        end
+
+        if set --query prefix_transitions[$state] && test -n $prefix_transitions[$state]
+            eval $prefix_transitions[$state]
+            set --append state_literal_ids $prefix_inputs
+        end
+
+        for literal_id in $state_literal_ids
+            set --local -- literal $literals[$literal_id]
+            # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+            if string match --quiet --regex -- '^-[^-]' "$word_prefix" && test (string length -- $literal) -eq 1 && contains -- $literal (string split '' -- (string sub --start 2 -- "$word_prefix"))
+                continue
+            end
+            # Options from @once(...) aren't offered again once given, and neither are alternatives of an
+            # @exclusive(...) once another one of it has been given
+            if test -z "$word_prefix"
+                set --local ruled_out 0
+                for once_index in (seq (count $once_transitions))
+                    if test $once_transitions[$once_index] = "$state,$literal_id"
+                        for id in (string split ' ' -- $once_ids[$once_index])
+                            contains -- $id $given_once; and set ruled_out 1
+                        end
+                    end
+                end
+                for exclusive_index in (seq (count $exclusive_transitions))
+                    if test $exclusive_transitions[$exclusive_index] = "$state,$literal_id"
+                        for tag in (string split ' ' -- $exclusive_tags[$exclusive_index])
+                            for excluding_tag in (string split --no-empty ' ' -- $exclusions[$tag])
+                                contains -- $excluding_tag $given_exclusive; and set ruled_out 1
+                            end
+                        end
+                    end
+                end
+                if test $ruled_out -ne 0
+                    continue
+                end
+            end
+            # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+            if test -z "$word_prefix" && contains -- $state $end_of_options_states && string match --quiet -- '-*' $literal
+                continue
+            end
+            if test -n "$descriptions[$literal_id]"
+                printf '%s%s\t%s\n' $word_prefix $literal $descriptions[$literal_id]
             else
-                printf '%s\n' $literals[$literal_id]
+                printf '%s%s\n' $word_prefix $literal
             end
         end
+
+        # Prefixes are ordered from the longest so the first match is the longest one
+        set --local longest_prefix_index
+        for index in (seq 1 (count $prefix_inputs))
+            set --local -- prefix $literals[$prefix_inputs[$index]]
+            set --local -- head (string sub --length (string length -- $prefix) -- "$cur")
+            if test "$head" = "$prefix"
+                set longest_prefix_index $index
+                break
+            end
+        end
+        if test -z "$longest_prefix_index"
+            break
+        end
+
+        set --local -- prefix $literals[$prefix_inputs[$longest_prefix_index]]
+        set state $prefix_tos[$longest_prefix_index]
+        set word_prefix "$word_prefix$prefix"
+        set cur (string sub --start (math (string length -- $prefix) + 1) -- "$cur")
     end
 
 "#)?;
 
     if !command_transitions.is_empty() {
         writeln!(buffer, r#"    set command_states {}"#, itertools::join(id_from_state.iter().map(|(state, _)| state + 1), " "))?;
-        write!(buffer, r#"    set command_ids {}"#, itertools::join(id_from_state.iter().map(|(_, id)| id), " "))?;
+        writeln!(buffer, r#"    set command_ids {}"#, itertools::join(id_from_state.iter().map(|(_, id)| id), " "))?;
+        // Seconds, from @timeout(...) and @cache(...) in the grammar, empty if not annotated
+        let annotations = dfa.get_command_annotations();
+        let command_from_id: HashMap<usize, Ustr> = id_from_command.iter().map(|(cmd, id)| (*id, *cmd)).collect();
+        let state_annotations: Vec<CommandAnnotations> = id_from_state.values().map(|id| annotations.get(&command_from_id[id]).copied().unwrap_or_default()).collect();
+        writeln!(buffer, r#"    set command_timeouts {}"#, itertools::join(state_annotations.iter().map(|annotations| format!(r#""{}""#, annotations.get_timeout_seconds().unwrap_or_default())), " "))?;
+        write!(buffer, r#"    set command_cache_ttls {}"#, itertools::join(state_annotations.iter().map(|annotations| format!(r#""{}""#, annotations.get_cache_ttl_seconds().map(|ttl| ttl.to_string()).unwrap_or_default())), " "))?;
         write!(buffer, r#"
     if contains $state $command_states
         set --local index (contains --index $state $command_states)
@@ -215,9 +486,9 @@ end
         set --local function_name _{command}_$function_id
         set --local --erase inputs
         set --local --erase tos
-        set --local lines (eval $function_name $COMP_WORDS[$COMP_CWORD])
+        set --local lines (__complgen_run "$command_timeouts[$index]" "$command_cache_ttls[$index]" $function_name "$cur" "$complgen_words" $bound_words)
         for line in $lines
-            printf '%s\n' $line
+            printf '%s%s\n' $word_prefix $line
         end
     end
 "#)?;
@@ -234,3 +505,61 @@ complete --command {command} --no-files --arguments "(_{command})"
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use crate::grammar::ExprKind;
+    use crate::regex::AugmentedRegex;
+
+    use super::*;
+
+    // `commandline` only works in interactive sessions, hence the stub
+    fn get_completions(literals: &[String]) -> Vec<String> {
+        let expr = ExprKind::Alternative(literals.iter().map(|literal| ExprKind::Terminal(ustr(literal), None).into()).collect());
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &UstrMap::default(), &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let mut script: Vec<u8> = Default::default();
+        write_completion_script(&mut script, "cmd", &dfa).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let output = Command::new("fish").arg("--no-config").arg("-c").arg(format!(r#"{script}
+function commandline
+    printf '%s\n' 'cmd '
+end
+_cmd
+"#)).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut completions: Vec<String> = stdout.lines().map(|line| line.split('\t').next().unwrap().to_string()).collect();
+        completions.sort();
+        completions
+    }
+
+    #[test]
+    #[ignore = "requires fish"]
+    fn completes_literals_containing_whitespace_and_globs() {
+        let literals = ["foo bar".to_string(), "*".to_string(), "it's".to_string(), "$HOME".to_string()];
+        assert_eq!(get_completions(&literals), vec!["$HOME", "*", "foo bar", "it's"]);
+    }
+
+    proptest! {
+        #[test]
+        #[ignore = "requires fish"]
+        fn escaping_round_trips(s in "[^\\x00]*") {
+            let output = Command::new("fish").arg("--no-config").arg("-c").arg(format!(r#"printf %s "{}""#, escape_fish_string(&s))).output().unwrap();
+            prop_assert_eq!(String::from_utf8(output.stdout).unwrap(), s);
+        }
+
+        // Completions are printed one per line, separated from their descriptions by a tab
+        #[test]
+        #[ignore = "requires fish"]
+        fn completes_arbitrary_literals(literal in "[^\\x00\\t\\n\\r]{1,16}") {
+            prop_assert_eq!(get_completions(std::slice::from_ref(&literal)), vec![literal]);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use itertools::Itertools;
+
+
+// Shell-like patterns restricting completions of <PATH:*.rs>: `*`, `?`, `[abc]`, `[!a-z]` and `{yml,yaml}`
+// alternatives.  They're matched against file names, never against whole paths.
+
+
+// Index of the `]` closing the set opened at `start`.  A `]` right after the opening bracket is part of the set.
+fn find_set_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut index = start + 1;
+    if matches!(chars.get(index), Some('!' | '^')) {
+        index += 1;
+    }
+    if chars.get(index) == Some(&']') {
+        index += 1;
+    }
+    chars.get(index..)?.iter().position(|c| *c == ']').map(|offset| index + offset)
+}
+
+
+// Unbalanced brackets or braces would make for syntax errors in the generated scripts
+pub fn is_valid(glob: &str) -> bool {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut depth: usize = 0;
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '[' => match find_set_end(&chars, index) {
+                Some(end) => index = end,
+                None => return false,
+            },
+            '{' => depth += 1,
+            '}' => match depth.checked_sub(1) {
+                Some(outer) => depth = outer,
+                None => return false,
+            },
+            c if c.is_control() => return false,
+            _ => {},
+        }
+        index += 1;
+    }
+    !chars.is_empty() && depth == 0
+}
+
+
+// E.g. `*.{yml,yaml}` becomes `*.yml` and `*.yaml`.  Expects a valid glob.
+pub fn expand_braces(glob: &str) -> Vec<String> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut open = 0;
+    let mut commas: Vec<usize> = Default::default();
+    let mut depth: usize = 0;
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '[' => index = find_set_end(&chars, index).unwrap_or(index),
+            '{' => {
+                if depth == 0 {
+                    open = index;
+                }
+                depth += 1;
+            },
+            ',' if depth == 1 => commas.push(index),
+            '}' if depth == 1 => {
+                let prefix: String = chars[..open].iter().collect();
+                let suffix: String = chars[index + 1..].iter().collect();
+                let bounds = std::iter::once(open).chain(commas).chain(std::iter::once(index));
+                return bounds.tuple_windows().flat_map(|(from, to)| {
+                    let alternative: String = chars[from + 1..to].iter().collect();
+                    expand_braces(&format!("{prefix}{alternative}{suffix}"))
+                }).collect();
+            },
+            '}' => depth = depth.saturating_sub(1),
+            _ => {},
+        }
+        index += 1;
+    }
+    vec![glob.to_string()]
+}
+
+
+fn set_contains(set: &[char], c: char) -> bool {
+    let (negated, set) = match set.first() {
+        Some('!' | '^') => (true, &set[1..]),
+        _ => (false, set),
+    };
+    let mut index = 0;
+    let mut found = false;
+    while index < set.len() {
+        if set.get(index + 1) == Some(&'-') && index + 2 < set.len() {
+            found |= (set[index]..=set[index + 2]).contains(&c);
+            index += 3;
+        }
+        else {
+            found |= set[index] == c;
+            index += 1;
+        }
+    }
+    found != negated
+}
+
+
+fn do_is_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skipped| do_is_match(&pattern[1..], &name[skipped..])),
+        Some('?') => !name.is_empty() && do_is_match(&pattern[1..], &name[1..]),
+        Some('[') => match (find_set_end(pattern, 0), name.first()) {
+            (Some(end), Some(c)) => set_contains(&pattern[1..end], *c) && do_is_match(&pattern[end + 1..], &name[1..]),
+            (Some(_), None) => false,
+            (None, _) => name.first() == Some(&'[') && do_is_match(&pattern[1..], &name[1..]),
+        },
+        Some(c) => name.first() == Some(c) && do_is_match(&pattern[1..], &name[1..]),
+    }
+}
+
+
+pub fn is_match(glob: &str, name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    expand_braces(glob).iter().any(|pattern| {
+        let pattern: Vec<char> = pattern.chars().collect();
+        do_is_match(&pattern, &name)
+    })
+}
+
+
+// For shells that can only match arbitrary strings against regular expressions.  Unanchored.  Restricted to the
+// syntax that PCRE, .NET, Python, Go and Rust all agree on.
+pub fn to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::default();
+    let mut depth: usize = 0;
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => match find_set_end(&chars, index) {
+                Some(end) => {
+                    regex.push('[');
+                    let mut set = &chars[index + 1..end];
+                    if let Some('!' | '^') = set.first() {
+                        regex.push('^');
+                        set = &set[1..];
+                    }
+                    for c in set {
+                        if matches!(c, '\\' | '[' | ']' | '^') {
+                            regex.push('\\');
+                        }
+                        regex.push(*c);
+                    }
+                    regex.push(']');
+                    index = end;
+                },
+                None => regex.push_str(r"\["),
+            },
+            '{' => {
+                depth += 1;
+                regex.push_str("(?:");
+            },
+            ',' if depth > 0 => regex.push('|'),
+            '}' if depth > 0 => {
+                depth -= 1;
+                regex.push(')');
+            },
+            c => {
+                if r"\.+*?()|[]{}^$".contains(c) {
+                    regex.push('\\');
+                }
+                regex.push(c);
+            },
+        }
+        index += 1;
+    }
+    regex
+}
+
+
+// For shells matching with `case` or their own globs: everything but the wildcards gets backslash-escaped
+pub fn escape_pattern(pattern: &str) -> String {
+    let mut result = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if !c.is_alphanumeric() && !matches!(c, '*' | '?' | '[' | ']' | '!' | '^' | '-' | '_') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_globs() {
+        assert!(is_valid("*.rs"));
+        assert!(is_valid("*.{yml,yaml}"));
+        assert!(is_valid("[]x]*"));
+        assert!(!is_valid("*.{yml,yaml"));
+        assert!(!is_valid("*.yml}"));
+        assert!(!is_valid("[abc"));
+        assert!(!is_valid(""));
+    }
+
+    #[test]
+    fn expands_braces() {
+        assert_eq!(expand_braces("*.rs"), vec!["*.rs"]);
+        assert_eq!(expand_braces("*.{yml,yaml}"), vec!["*.yml", "*.yaml"]);
+        assert_eq!(expand_braces("{a,b{c,d}}.{x,y}"), vec!["a.x", "a.y", "bc.x", "bc.y", "bd.x", "bd.y"]);
+        assert_eq!(expand_braces("[{]x"), vec!["[{]x"]);
+    }
+
+    #[test]
+    fn matches_file_names() {
+        assert!(is_match("*.rs", "main.rs"));
+        assert!(!is_match("*.rs", "main.rsx"));
+        assert!(is_match("*.{yml,yaml}", "config.yaml"));
+        assert!(is_match("Cargo.???l", "Cargo.toml"));
+        assert!(is_match("[a-c]*", "bar"));
+        assert!(!is_match("[!a-c]*", "bar"));
+        assert!(is_match("[]]", "]"));
+    }
+
+    #[test]
+    fn converts_to_regex() {
+        assert_eq!(to_regex("*.rs"), r".*\.rs");
+        assert_eq!(to_regex("*.{yml,yaml}"), r".*\.(?:yml|yaml)");
+        assert_eq!(to_regex("[!a-c]?"), r"[^a-c].");
+        assert_eq!(to_regex("a(b)"), r"a\(b\)");
+    }
+}
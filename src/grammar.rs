@@ -1,26 +1,72 @@
-use std::{rc::Rc, debug_assert, borrow::Borrow};
+use std::{rc::Rc, debug_assert, path::{Path, PathBuf}, time::Duration};
 
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take_while1, escaped, take_till, take_while, take_until},
-    character::{complete::{char, multispace1, one_of}, is_alphanumeric},
+    bytes::complete::{is_not, tag, take_while1, escaped, escaped_transform, take_till, take_while, take_until},
+    character::complete::{char, digit1, multispace1, none_of, one_of, u64},
     multi::many0,
-    IResult, combinator::{fail, opt}, error::context,
+    IResult, Offset, combinator::{fail, map, opt, consumed, value}, error::context,
 };
 
 use complgen::{Error, Result};
+use hashbrown::{HashMap, HashSet};
 use ustr::{Ustr, ustr, UstrMap, UstrSet};
 
 // Can't use an arena here until proptest supports non-owned types: https://github.com/proptest-rs/proptest/issues/9
+#[derive(Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Option<Span>, // None if the expression doesn't come from a grammar file, e.g. when scraped from --help
+}
+
 #[derive(Clone, PartialEq)]
-pub enum Expr {
+pub enum ExprKind {
     Terminal(Ustr, Option<Ustr>),
-    Nonterminal(Ustr), // e.g. <PATH>, <DIRECTORY>, etc.
-    Command(Ustr), // e.g. { ls }
+    Nonterminal(Ustr, Option<Ustr>), // e.g. <PATH>, <DIRECTORY>, etc. along with the binding in <repo=PATH>
+    Command(Ustr, Option<Ustr>, CommandAnnotations), // e.g. { ls } @timeout(1s); bound if it comes from the definition of a bound nonterminal
+    Prefix(Ustr, Rc<Expr>), // e.g. --color=<WHEN>; the literal and what follows it share a single shell word
+    Cluster(Vec<Rc<Expr>>), // e.g. @cluster(-x | -v | -f <FILE>); short options that can be grouped as in -xvf
+    Once(Rc<Expr>), // e.g. @once(-a | -b | --c <X>); options in any order, each offered only until it's been given
+    Exclusive(Vec<Rc<Expr>>), // e.g. @exclusive(-E | -F | -G); alternatives, none offered once another one's been given
     Sequence(Vec<Rc<Expr>>),
     Alternative(Vec<Rc<Expr>>),
     Optional(Rc<Expr>),
     Many1(Rc<Expr>),
+    Repeat(Rc<Expr>, u32, Option<u32>), // e.g. <PATH>{2}, <PATH>{1,3} or <PATH>{2,}; minimum and maximum (if any) count
+}
+
+// e.g. { kubectl get ns } @timeout(500ms) @cache(60s)
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CommandAnnotations {
+    pub timeout: Option<Duration>, // Completions are skipped if the command doesn't finish in time
+    pub cache_ttl: Option<Duration>, // How long the output of a successful run gets reused for
+}
+
+impl std::fmt::Debug for CommandAnnotations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn millis(duration: Option<Duration>) -> String {
+            match duration {
+                Some(duration) => format!("Some(Duration::from_millis({}))", duration.as_millis()),
+                None => "None".to_string(),
+            }
+        }
+        if *self == Self::default() {
+            return f.write_str("CommandAnnotations::default()");
+        }
+        f.write_fmt(format_args!("CommandAnnotations {{ timeout: {}, cache_ttl: {} }}", millis(self.timeout), millis(self.cache_ttl)))
+    }
+}
+
+impl CommandAnnotations {
+    // As accepted by `read -t`, `sleep`, etc., e.g. 0.500
+    pub fn get_timeout_seconds(&self) -> Option<String> {
+        self.timeout.map(|timeout| format!("{}.{:03}", timeout.as_secs(), timeout.subsec_millis()))
+    }
+
+    // Scripts measure time in whole seconds.  Rounded up so that e.g. @cache(500ms) still has an effect.
+    pub fn get_cache_ttl_seconds(&self) -> Option<u64> {
+        self.cache_ttl.map(|ttl| ttl.as_millis().div_ceil(1000) as u64)
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -28,43 +74,114 @@ pub struct Specialization {
     pub bash: Option<Ustr>,
     pub fish: Option<Ustr>,
     pub zsh: Option<Ustr>,
+    pub powershell: Option<Ustr>,
+    pub nushell: Option<Ustr>,
+    pub elvish: Option<Ustr>,
+    pub xonsh: Option<Ustr>,
     pub generic: Option<Ustr>,
+    // Predefined nonterminals like <PATH> get completed in Rust by `complgen complete` (see complete.rs)
+    pub native: bool,
 }
 
 
+// Where an expression comes from doesn't change its meaning
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Self { kind, span: None }
+    }
+}
+
+impl From<ExprKind> for Rc<Expr> {
+    fn from(kind: ExprKind) -> Self {
+        Rc::new(kind.into())
+    }
+}
+
 impl std::fmt::Debug for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl Expr {
+    // Keeps pointing at the same part of the grammar, e.g. after nonterminals within the expression got resolved
+    fn with_kind(&self, kind: ExprKind) -> Self {
+        Self { kind, span: self.span.clone() }
+    }
+}
+
+impl std::fmt::Debug for ExprKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Terminal(term, Some(descr)) => f.write_fmt(format_args!(r#"Rc::new(Terminal(ustr("{term}"), Some(ustr("{}"))))"#, descr)),
-            Self::Terminal(term, None) => f.write_fmt(format_args!(r#"Rc::new(Terminal(ustr("{term}"), None))"#)),
-            Self::Nonterminal(nonterm) => f.write_fmt(format_args!(r#"Rc::new(Nonterminal(ustr("{nonterm}")))"#)),
-            Self::Command(arg0) => f.write_fmt(format_args!(r#"Rc::new(Command(ustr("{}")))"#, arg0)),
-            Self::Sequence(arg0) => f.write_fmt(format_args!(r#"Rc::new(Sequence(vec!{:?}))"#, arg0)),
-            Self::Alternative(arg0) => f.write_fmt(format_args!(r#"Rc::new(Alternative(vec!{:?}))"#, arg0)),
-            Self::Optional(arg0) => f.write_fmt(format_args!(r#"Rc::new(Optional({:?}))"#, arg0)),
-            Self::Many1(arg0) => f.write_fmt(format_args!(r#"Rc::new(Many1({:?}))"#, arg0)),
+            Self::Terminal(term, Some(descr)) => f.write_fmt(format_args!(r#"Terminal(ustr("{term}"), Some(ustr("{}"))).into()"#, descr)),
+            Self::Terminal(term, None) => f.write_fmt(format_args!(r#"Terminal(ustr("{term}"), None).into()"#)),
+            Self::Nonterminal(nonterm, Some(binding)) => f.write_fmt(format_args!(r#"Nonterminal(ustr("{nonterm}"), Some(ustr("{binding}"))).into()"#)),
+            Self::Nonterminal(nonterm, None) => f.write_fmt(format_args!(r#"Nonterminal(ustr("{nonterm}"), None).into()"#)),
+            Self::Command(arg0, Some(binding), annotations) => f.write_fmt(format_args!(r#"Command(ustr("{}"), Some(ustr("{binding}")), {annotations:?}).into()"#, arg0)),
+            Self::Command(arg0, None, annotations) => f.write_fmt(format_args!(r#"Command(ustr("{}"), None, {annotations:?}).into()"#, arg0)),
+            Self::Prefix(prefix, arg0) => f.write_fmt(format_args!(r#"Prefix(ustr("{prefix}"), {:?}).into()"#, arg0)),
+            Self::Cluster(arg0) => f.write_fmt(format_args!(r#"Cluster(vec!{:?}).into()"#, arg0)),
+            Self::Once(arg0) => f.write_fmt(format_args!(r#"Once({:?}).into()"#, arg0)),
+            Self::Exclusive(arg0) => f.write_fmt(format_args!(r#"Exclusive(vec!{:?}).into()"#, arg0)),
+            Self::Sequence(arg0) => f.write_fmt(format_args!(r#"Sequence(vec!{:?}).into()"#, arg0)),
+            Self::Alternative(arg0) => f.write_fmt(format_args!(r#"Alternative(vec!{:?}).into()"#, arg0)),
+            Self::Optional(arg0) => f.write_fmt(format_args!(r#"Optional({:?}).into()"#, arg0)),
+            Self::Many1(arg0) => f.write_fmt(format_args!(r#"Many1({:?}).into()"#, arg0)),
+            Self::Repeat(arg0, min, max) => f.write_fmt(format_args!(r#"Repeat({:?}, {}, {:?}).into()"#, arg0, min, max)),
         }
     }
 }
 
 fn do_to_railroad_diagram(expr: Rc<Expr>) -> Box<dyn railroad::Node> {
-    match expr.as_ref() {
-        Expr::Terminal(s, _) => Box::new(railroad::Terminal::new(s.as_str().to_string())),
-        Expr::Nonterminal(s) => Box::new(railroad::NonTerminal::new(s.as_str().to_string())),
-        Expr::Command(s) => Box::new(railroad::Comment::new(s.as_str().to_string())),
-        Expr::Sequence(subexprs) => {
+    match &expr.kind {
+        ExprKind::Terminal(s, _) => Box::new(railroad::Terminal::new(s.as_str().to_string())),
+        ExprKind::Nonterminal(s, Some(binding)) => Box::new(railroad::NonTerminal::new(format!("{binding}={s}"))),
+        ExprKind::Nonterminal(s, None) => Box::new(railroad::NonTerminal::new(s.as_str().to_string())),
+        ExprKind::Command(s, ..) => Box::new(railroad::Comment::new(s.as_str().to_string())),
+        ExprKind::Prefix(prefix, subexpr) => {
+            let subnodes: Vec<Box<dyn railroad::Node>> = vec![Box::new(railroad::Terminal::new(prefix.as_str().to_string())), do_to_railroad_diagram(Rc::clone(subexpr))];
+            Box::new(railroad::Sequence::new(subnodes))
+        },
+        ExprKind::Cluster(members) => {
+            let subnodes: Vec<Box<dyn railroad::Node>> = members.iter().map(|e| do_to_railroad_diagram(Rc::clone(e))).collect();
+            Box::new(railroad::LabeledBox::new(railroad::Choice::new(subnodes), railroad::Comment::new("cluster".to_string())))
+        },
+        ExprKind::Once(subexpr) => {
+            let subnode = do_to_railroad_diagram(Rc::clone(subexpr));
+            Box::new(railroad::LabeledBox::new(railroad::Repeat::new(subnode, Box::new(railroad::Empty)), railroad::Comment::new("once".to_string())))
+        },
+        ExprKind::Exclusive(members) => {
+            let subnodes: Vec<Box<dyn railroad::Node>> = members.iter().map(|e| do_to_railroad_diagram(Rc::clone(e))).collect();
+            Box::new(railroad::LabeledBox::new(railroad::Choice::new(subnodes), railroad::Comment::new("exclusive".to_string())))
+        },
+        ExprKind::Sequence(subexprs) => {
             let subnodes: Vec<Box<dyn railroad::Node>> = subexprs.iter().map(|e| do_to_railroad_diagram(Rc::clone(e))).collect();
             Box::new(railroad::Sequence::new(subnodes))
         },
-        Expr::Alternative(subexprs) => {
+        ExprKind::Alternative(subexprs) => {
             let subnodes: Vec<Box<dyn railroad::Node>> = subexprs.iter().map(|e| do_to_railroad_diagram(Rc::clone(e))).collect();
             Box::new(railroad::Choice::new(subnodes))
         },
-        Expr::Optional(subexpr) => Box::new(railroad::Optional::new(do_to_railroad_diagram(Rc::clone(subexpr)))),
-        Expr::Many1(subexpr) => {
+        ExprKind::Optional(subexpr) => Box::new(railroad::Optional::new(do_to_railroad_diagram(Rc::clone(subexpr)))),
+        ExprKind::Many1(subexpr) => {
             let subnode = do_to_railroad_diagram(Rc::clone(subexpr));
             Box::new(railroad::Repeat::new(subnode, Box::new(railroad::Empty)))
         },
+        ExprKind::Repeat(subexpr, min, max) => {
+            let subnode = do_to_railroad_diagram(Rc::clone(subexpr));
+            let label = match max {
+                Some(max) if max == min => format!("{min} times"),
+                Some(max) => format!("{min}-{max} times"),
+                None => format!("{min}+ times"),
+            };
+            Box::new(railroad::Repeat::new(subnode, Box::new(railroad::Comment::new(label))))
+        },
     }
 }
 
@@ -115,9 +232,11 @@ fn multiblanks1(input: &str) -> IResult<&str, ()> {
     Ok((input, ()))
 }
 
-fn terminal(input: &str) -> IResult<&str, &str> {
+// Letters and digits of any script, e.g. `größe`, and a handful of punctuation characters.  Anything else, e.g.
+// `pkg@1.2` or `key=value`, needs to be quoted.
+fn bare_terminal(input: &str) -> IResult<&str, &str> {
     fn is_terminal_char(c: char) -> bool {
-        c.is_ascii() && (is_alphanumeric(c as u8) || c == '-' || c == '+' || c == '_' || c == '.')
+        c.is_alphanumeric() || matches!(c, '-' | '+' | '_' | '.' | ':' | '/' | ',')
     }
     let (input, term) = escaped(take_while1(is_terminal_char), '\\', one_of(r#"()[]<>.|;"#))(input)?;
     if term.is_empty() {
@@ -126,6 +245,22 @@ fn terminal(input: &str) -> IResult<&str, &str> {
     Ok((input, term))
 }
 
+// 'foo bar', 'it\'s', 'C:\\'.  Within single quotes, `\'` and `\\` are the only escape sequences; every other
+// character stands for itself.
+fn quoted_terminal(input: &str) -> IResult<&str, String> {
+    let (input, _) = char('\'')(input)?;
+    let (input, term) = escaped_transform(none_of("\\'"), '\\', alt((value("\\", char('\\')), value("'", char('\'')))))(input)?;
+    let (input, _) = char('\'')(input)?;
+    if term.is_empty() {
+        return fail(input);
+    }
+    Ok((input, term))
+}
+
+fn terminal(input: &str) -> IResult<&str, Ustr> {
+    alt((map(quoted_terminal, |term| ustr(&term)), map(bare_terminal, ustr)))(input)
+}
+
 fn description(input: &str) -> IResult<&str, &str> {
     let (input, _) = char('"')(input)?;
     let (input, descr) = escaped(take_till(|c| c == '"'), '\\', char('"'))(input)?;
@@ -139,10 +274,10 @@ fn multiblanks1_description(input: &str) -> IResult<&str, &str> {
     Ok((input, descr))
 }
 
-fn terminal_opt_description_expr(input: &str) -> IResult<&str, Expr> {
+fn terminal_opt_description_expr(input: &str) -> IResult<&str, ExprKind> {
     let (input, term) = terminal(input)?;
     let (input, descr) = opt(multiblanks1_description)(input)?;
-    let expr = Expr::Terminal(ustr(term), descr.map(ustr));
+    let expr = ExprKind::Terminal(term, descr.map(ustr));
     Ok((input, expr))
 }
 
@@ -153,9 +288,20 @@ fn nonterminal(input: &str) -> IResult<&str, &str> {
     Ok((input, name))
 }
 
-fn nonterminal_expr(input: &str) -> IResult<&str, Expr> {
+// Lowercase so that e.g. <KEY=VALUE> remains a plain nonterminal name
+fn is_binding_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some('a'..='z' | '_')) && chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_'))
+}
+
+// <repo=PATH> binds the word matched by <PATH> to `repo`, visible to external commands as $repo
+fn nonterminal_expr(input: &str) -> IResult<&str, ExprKind> {
     let (input, nonterm) = context("nonterminal", nonterminal)(input)?;
-    Ok((input, Expr::Nonterminal(ustr(nonterm))))
+    let expr = match nonterm.split_once('=') {
+        Some((binding, name)) if is_binding_name(binding) && !name.is_empty() => ExprKind::Nonterminal(ustr(name), Some(ustr(binding))),
+        _ => ExprKind::Nonterminal(ustr(nonterm), None),
+    };
+    Ok((input, expr))
 }
 
 fn single_bracket_command(input: &str) -> IResult<&str, &str> {
@@ -180,18 +326,139 @@ fn command(input: &str) -> IResult<&str, &str> {
     alt((triple_bracket_command, single_bracket_command))(input)
 }
 
-fn command_expr(input: &str) -> IResult<&str, Expr> {
+// 500ms, 2s, 1m, 1h
+fn duration(input: &str) -> IResult<&str, Duration> {
+    let (input, amount) = u64(input)?;
+    let (input, unit) = alt((tag("ms"), tag("s"), tag("m"), tag("h")))(input)?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(amount),
+        "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount.saturating_mul(60)),
+        "h" => Duration::from_secs(amount.saturating_mul(3600)),
+        _ => unreachable!(),
+    };
+    Ok((input, duration))
+}
+
+fn command_annotation(input: &str) -> IResult<&str, (&str, Duration)> {
+    let (input, _) = multiblanks0(input)?;
+    let (input, name) = alt((tag("@timeout"), tag("@cache")))(input)?;
+    let (input, _) = multiblanks0(input)?;
+    let (input, _) = char('(')(input)?;
+    let (input, _) = multiblanks0(input)?;
+    let (input, duration) = duration(input)?;
+    let (input, _) = multiblanks0(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, (name, duration)))
+}
+
+fn command_expr(input: &str) -> IResult<&str, ExprKind> {
     let (input, cmd) = command(input)?;
-    Ok((input, Expr::Command(ustr(cmd))))
+    let (input, annotations) = many0(command_annotation)(input)?;
+    let mut result = CommandAnnotations::default();
+    for (name, duration) in annotations {
+        match name {
+            "@timeout" => result.timeout = Some(duration),
+            "@cache" => result.cache_ttl = Some(duration),
+            _ => unreachable!(),
+        }
+    }
+    Ok((input, ExprKind::Command(ustr(cmd), None, result)))
+}
+
+// --color=<WHEN>, --format=(short | long), etc.  No whitespace is allowed around `=` as the option and its
+// value form a single shell word.
+fn prefix_expr(input: &str) -> IResult<&str, ExprKind> {
+    let (input, term) = terminal(input)?;
+    let (input, _) = char('=')(input)?;
+    let (input, value) = alt((
+        spanned(nonterminal_expr),
+        parenthesized_expr,
+        spanned(command_expr),
+        spanned(terminal_opt_description_expr),
+    ))(input)?;
+    let prefix = format!("{term}=");
+    Ok((input, ExprKind::Prefix(ustr(&prefix), Rc::new(value))))
+}
+
+// The option character, its description and its argument, if any
+pub type ClusterMember = (char, Option<Ustr>, Option<Rc<Expr>>);
+
+// A member of a short options cluster is either a single short option, e.g. `-x`, or a short option followed by
+// its argument, e.g. `-f <FILE>`.
+pub fn get_cluster_member(expr: &Expr) -> Option<ClusterMember> {
+    fn short_option(expr: &Expr) -> Option<(char, Option<Ustr>)> {
+        let ExprKind::Terminal(term, description) = &expr.kind else { return None };
+        let mut chars = term.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('-'), Some(c), None) if c != '-' => Some((c, *description)),
+            _ => None,
+        }
+    }
+
+    match &expr.kind {
+        ExprKind::Terminal(..) => {
+            let (c, description) = short_option(expr)?;
+            Some((c, description, None))
+        },
+        ExprKind::Sequence(children) => {
+            let (c, description) = short_option(&children[0])?;
+            let argument = match &children[1..] {
+                [argument] => Rc::clone(argument),
+                arguments => ExprKind::Sequence(arguments.to_vec()).into(),
+            };
+            Some((c, description, Some(argument)))
+        },
+        _ => None,
+    }
+}
+
+// @cluster(-x | -v | -f <FILE>) matches -x, -xv, -vxf <FILE>, etc.  An option taking an argument can only
+// appear at the end of a cluster.
+fn cluster_expr(input: &str) -> IResult<&str, ExprKind> {
+    let (input, _) = tag("@cluster")(input)?;
+    let (input, _) = multiblanks0(input)?;
+    let (input, e) = parenthesized_expr(input)?;
+    let members = match e.kind {
+        ExprKind::Alternative(members) => members,
+        _ => vec![Rc::new(e)],
+    };
+    if members.iter().any(|member| get_cluster_member(member).is_none()) {
+        return fail(input);
+    }
+    Ok((input, ExprKind::Cluster(members)))
+}
+
+// @once(-a | -b | --c <X>) matches one or more of the alternatives, in any order.  Options that have already been
+// given don't get offered again.  That's tracked at completion time, not in the automaton, which would otherwise
+// need a state for every subset of the options.
+fn once_expr(input: &str) -> IResult<&str, ExprKind> {
+    let (input, _) = tag("@once")(input)?;
+    let (input, _) = multiblanks0(input)?;
+    let (input, e) = parenthesized_expr(input)?;
+    Ok((input, ExprKind::Once(Rc::new(e))))
 }
 
-fn optional_expr(input: &str) -> IResult<&str, Expr> {
+// @exclusive(-E | -F | -G) matches any one of the alternatives.  Once one of them has been given, the others don't
+// get offered anymore, e.g. within (@exclusive(-E | -F | -G) | -i)...
+fn exclusive_expr(input: &str) -> IResult<&str, ExprKind> {
+    let (input, _) = tag("@exclusive")(input)?;
+    let (input, _) = multiblanks0(input)?;
+    let (input, e) = parenthesized_expr(input)?;
+    let members = match e.kind {
+        ExprKind::Alternative(members) => members,
+        _ => vec![Rc::new(e)],
+    };
+    Ok((input, ExprKind::Exclusive(members)))
+}
+
+fn optional_expr(input: &str) -> IResult<&str, ExprKind> {
     let (input, _) = char('[')(input)?;
     let (input, _) = multiblanks0(input)?;
     let (input, expr) = expr(input)?;
     let (input, _) = multiblanks0(input)?;
     let (input, _) = char(']')(input)?;
-    Ok((input, Expr::Optional(Rc::new(expr))))
+    Ok((input, ExprKind::Optional(Rc::new(expr))))
 }
 
 fn parenthesized_expr(input: &str) -> IResult<&str, Expr> {
@@ -209,17 +476,76 @@ fn many1_tag(input: &str) -> IResult<&str, ()> {
     Ok((input, ()))
 }
 
+// Every repetition gets positions of its own in the automaton (see regex.rs), so that a typo like {1,1000000}
+// would take forever to compile.  Larger counts are unlikely to be useful for completion anyway.
+pub const MAX_REPEAT_COUNT: u32 = 255;
+
+
+// Counts that don't even fit into u32 are too large just the same, rather than not a count at all
+fn repeat_count(input: &str) -> IResult<&str, u32> {
+    let (rest, digits) = digit1(input)?;
+    match digits.parse::<u32>() {
+        Ok(count) if count <= MAX_REPEAT_COUNT => Ok((rest, count)),
+        _ => Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::TooLarge))),
+    }
+}
+
+
+// {2}, {1,3} or {2,} right after an expression, without whitespace in between as `x { ... }` is followed by an
+// external command instead.  An empty range, e.g. {3,1}, or a count above MAX_REPEAT_COUNT is a hard failure
+// rather than a command.
+fn repeat_range(input: &str) -> IResult<&str, (u32, Option<u32>)> {
+    let start = input;
+    let (input, _) = char('{')(input)?;
+    let (input, min) = repeat_count(input)?;
+    let (input, max) = match opt(char(','))(input)? {
+        (input, Some(_)) => opt(repeat_count)(input)?,
+        (input, None) => (input, Some(min)),
+    };
+    let (input, _) = char('}')(input)?;
+    if max.is_some_and(|max| max < min || max == 0) {
+        return Err(nom::Err::Failure(nom::error::Error::new(start, nom::error::ErrorKind::Verify)));
+    }
+    Ok((input, (min, max)))
+}
+
+
+// Parsers only get to see the rest of the input, which is always a suffix of the whole text.  Spans therefore
+// start out as distances from the end of the text and get turned into offsets by locate_expr() once the whole
+// text is known.
+fn make_unlocated_span(input: &str, rest: &str) -> Span {
+    Span { source: None, text: Rc::from(""), start: input.len(), end: rest.len() }
+}
+
+fn spanned<'a>(mut parser: impl FnMut(&'a str) -> IResult<&'a str, ExprKind>) -> impl FnMut(&'a str) -> IResult<&'a str, Expr> {
+    move |input| {
+        let (rest, kind) = parser(input)?;
+        Ok((rest, Expr { kind, span: Some(make_unlocated_span(input, rest)) }))
+    }
+}
+
 fn unary_expr(input: &str) -> IResult<&str, Expr> {
+    let start = input;
     let (input, e) = alt((
-        nonterminal_expr,
-        optional_expr,
+        spanned(nonterminal_expr),
+        spanned(optional_expr),
         parenthesized_expr,
-        command_expr,
-        terminal_opt_description_expr,
+        spanned(command_expr),
+        spanned(cluster_expr),
+        spanned(once_expr),
+        spanned(exclusive_expr),
+        spanned(prefix_expr),
+        spanned(terminal_opt_description_expr),
     ))(input)?;
 
+    match repeat_range(input) {
+        Ok((input, (min, max))) => return Ok((input, Expr { kind: ExprKind::Repeat(Rc::new(e), min, max), span: Some(make_unlocated_span(start, input)) })),
+        Err(nom::Err::Failure(e)) => return Err(nom::Err::Failure(e)),
+        Err(_) => {},
+    }
+
     if let Ok((input, ())) = many1_tag(input) {
-        return Ok((input, Expr::Many1(Rc::new(e))));
+        return Ok((input, Expr { kind: ExprKind::Many1(Rc::new(e)), span: Some(make_unlocated_span(start, input)) }));
     }
 
     Ok((input, e))
@@ -232,6 +558,7 @@ fn sequence_expr(input: &str) -> IResult<&str, Expr> {
         Ok((input, right))
     }
 
+    let start = input;
     let (mut input, left) = unary_expr(input)?;
     let mut factors: Vec<Expr> = vec![left];
     loop {
@@ -242,7 +569,7 @@ fn sequence_expr(input: &str) -> IResult<&str, Expr> {
     let result = if factors.len() == 1 {
         factors.drain(..).next().unwrap()
     } else {
-        Expr::Sequence(factors.into_iter().map(Rc::new).collect())
+        Expr { kind: ExprKind::Sequence(factors.into_iter().map(Rc::new).collect()), span: Some(make_unlocated_span(start, input)) }
     };
     Ok((input, result))
 }
@@ -256,6 +583,7 @@ fn alternative_expr(input: &str) -> IResult<&str, Expr> {
         Ok((input, right))
     }
 
+    let start = input;
     let (mut input, left) = sequence_expr(input)?;
     let mut elems: Vec<Expr> = vec![left];
     loop {
@@ -266,7 +594,7 @@ fn alternative_expr(input: &str) -> IResult<&str, Expr> {
     let result = if elems.len() == 1 {
         elems.drain(..).next().unwrap()
     } else {
-        Expr::Alternative(elems.into_iter().map(Rc::new).collect())
+        Expr { kind: ExprKind::Alternative(elems.into_iter().map(Rc::new).collect()), span: Some(make_unlocated_span(start, input)) }
     };
     Ok((input, result))
 }
@@ -287,11 +615,18 @@ pub enum Statement {
         shell: Option<Ustr>,
         expr: Rc<Expr>,
     },
+    Include(Ustr), // e.g. include "common.usage";
 }
 
 
+// The command name ends up in shell function and variable names, hence the narrower character set than for
+// terminals
+fn command_name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '_' | '.'))(input)
+}
+
 fn call_variant(input: &str) -> IResult<&str, Statement> {
-    let (input, name) = terminal(input)?;
+    let (input, name) = command_name(input)?;
     let (input, _) = multiblanks1(input)?;
     let (input, expr) = expr(input)?;
     let (input, _) = multiblanks0(input)?;
@@ -344,13 +679,23 @@ fn nonterminal_definition(input: &str) -> IResult<&str, Statement> {
     Ok((input, stmt))
 }
 
-fn statement(input: &str) -> IResult<&str, Statement> {
-    let (input, stmt) = alt((call_variant, nonterminal_definition))(input)?;
+fn include_statement(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = tag("include")(input)?;
+    let (input, _) = multiblanks1(input)?;
+    let (input, path) = description(input)?;
     let (input, _) = multiblanks0(input)?;
-    Ok((input, stmt))
+    let (input, _) = char(';')(input)?;
+    Ok((input, Statement::Include(ustr(path))))
+}
+
+// Also returns the statement's text so that it can be pointed at in error messages
+fn statement(input: &str) -> IResult<&str, (&str, Statement)> {
+    let (input, (text, stmt)) = consumed(alt((include_statement, call_variant, nonterminal_definition)))(input)?;
+    let (input, _) = multiblanks0(input)?;
+    Ok((input, (text, stmt)))
 }
 
-fn grammar(input: &str) -> IResult<&str, Vec<Statement>> {
+fn grammar(input: &str) -> IResult<&str, Vec<(&str, Statement)>> {
     let (input, _) = multiblanks0(input)?;
     let (input, statements) = many0(statement)(input)?;
     let (input, _) = multiblanks0(input)?;
@@ -358,34 +703,195 @@ fn grammar(input: &str) -> IResult<&str, Vec<Statement>> {
 }
 
 
+// A fragment of a grammar file, used to point at the offending part of the grammar in error messages
+#[derive(Debug, PartialEq, Clone)]
+pub struct Span {
+    pub source: Option<Ustr>, // None if it's not known, e.g. stdin
+    pub text: Rc<str>, // the whole file
+    pub start: usize, // byte offsets into `text`
+    pub end: usize,
+}
+
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Grammar {
     statements: Vec<Statement>,
+    spans: Vec<Span>, // where each statement comes from
+    includes: Vec<PathBuf>, // canonical paths of the included files, in the order of inclusion
 }
 
 
-pub struct ValidGrammar {
+pub struct ValidCommand {
     pub command: Ustr,
     pub expr: Rc<Expr>,
+}
+
+
+pub struct ValidGrammar {
+    pub commands: Vec<ValidCommand>, // in the order of first appearance in the grammar
     pub undefined_nonterminals: UstrSet,
     pub specializations: UstrMap<Specialization>,
 }
 
 
+fn source_name(source: Option<Ustr>) -> String {
+    match source {
+        Some(path) => path.as_str().to_string(),
+        None => "<stdin>".to_string(),
+    }
+}
+
+
+// Definitions may come from different files via `include`.  Report both of them in such a case.
+fn check_duplicate_definitions(statements: &[Statement], spans: &[Span]) -> Result<()> {
+    let mut source_from_definition: HashMap<(Ustr, Option<Ustr>), Option<Ustr>> = Default::default();
+    for (statement, source) in statements.iter().zip(spans.iter().map(|span| &span.source)) {
+        let Statement::NonterminalDefinition { symbol, shell, .. } = statement else { continue };
+        match source_from_definition.get(&(*symbol, *shell)) {
+            Some(first_source) if first_source == source => {
+                return Err(Error::DuplicateNonterminalDefinition(*symbol, *shell));
+            },
+            Some(first_source) => {
+                return Err(Error::ConflictingNonterminalDefinitions(*symbol, *shell, source_name(*first_source), source_name(*source)));
+            },
+            None => {
+                source_from_definition.insert((*symbol, *shell), *source);
+            },
+        }
+    }
+    Ok(())
+}
+
+
+// Predefined, unless the grammar defines it in any way.  Matches `--`, after which options aren't offered anymore.
+pub const END_OF_OPTIONS: &str = "END_OF_OPTIONS";
+
+
+// Used as is in compiled scripts.  `complgen complete` has native implementations of these (see complete.rs).
+fn make_builtin_specializations() -> Vec<(Ustr, Specialization)> {
+    vec![
+        (ustr("PATH"), Specialization {
+            bash: Some(ustr(r#"compgen -A file "$1""#)),
+            fish: Some(ustr(r#"__fish_complete_path "$1""#)),
+            zsh: Some(ustr("_path_files")),
+            powershell: Some(ustr(r#"[System.Management.Automation.CompletionCompleters]::CompleteFilename("$1") | ForEach-Object { $_.CompletionText }"#)),
+            nushell: Some(ustr(r#"try { ls --all ($"($cur)*" | into glob) | get name } catch { [] }"#)),
+            elvish: Some(ustr("edit:complete-filename $cur | each {|c| put $c[stem] }")),
+            xonsh: Some(ustr("[p + os.sep if os.path.isdir(p) else p for p in glob.glob(glob.escape(cur) + '*')]")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("DIRECTORY"), Specialization {
+            bash: Some(ustr(r#"compgen -A directory "$1""#)),
+            fish: Some(ustr(r#"__fish_complete_directories "$1""#)),
+            zsh: Some(ustr("_path_files -/")),
+            powershell: Some(ustr(r#"[System.Management.Automation.CompletionCompleters]::CompleteFilename("$1") | Where-Object { $_.ResultType -eq 'ProviderContainer' } | ForEach-Object { $_.CompletionText }"#)),
+            nushell: Some(ustr(r#"try { ls --all ($"($cur)*" | into glob) | where type == dir | get name } catch { [] }"#)),
+            elvish: Some(ustr("edit:complete-filename $cur | each {|c| if (path:is-dir $c[stem]) { put $c[stem] } }")),
+            xonsh: Some(ustr("[p + os.sep for p in glob.glob(glob.escape(cur) + '*') if os.path.isdir(p)]")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("USER"), Specialization {
+            bash: Some(ustr(r#"compgen -A user "$1""#)),
+            fish: Some(ustr("__fish_complete_users")),
+            zsh: Some(ustr("_users")),
+            powershell: Some(ustr(r#"Get-Content /etc/passwd -ErrorAction SilentlyContinue | ForEach-Object { ($_ -split ':')[0] } | Where-Object { $_.StartsWith($1) }"#)),
+            nushell: Some(ustr("try { open /etc/passwd | lines | split column ':' | get column1 } catch { [] }")),
+            elvish: Some(ustr("e:cut -d: -f1 /etc/passwd")),
+            xonsh: Some(ustr("$(cut -d: -f1 /etc/passwd)")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("GROUP"), Specialization {
+            bash: Some(ustr(r#"compgen -A group "$1""#)),
+            fish: Some(ustr("__fish_complete_groups")),
+            zsh: Some(ustr("_groups")),
+            powershell: Some(ustr(r#"Get-Content /etc/group -ErrorAction SilentlyContinue | ForEach-Object { ($_ -split ':')[0] } | Where-Object { $_.StartsWith($1) }"#)),
+            nushell: Some(ustr("try { open /etc/group | lines | split column ':' | get column1 } catch { [] }")),
+            elvish: Some(ustr("e:cut -d: -f1 /etc/group")),
+            xonsh: Some(ustr("$(cut -d: -f1 /etc/group)")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("HOST"), Specialization {
+            bash: Some(ustr(r#"compgen -A hostname "$1""#)),
+            fish: Some(ustr("__fish_print_hostnames")),
+            zsh: Some(ustr("_hosts")),
+            powershell: Some(ustr(r#"Get-Content /etc/hosts -ErrorAction SilentlyContinue | ForEach-Object { -split ($_ -replace '#.*') | Select-Object -Skip 1 } | Where-Object { $_.StartsWith($1) }"#)),
+            nushell: Some(ustr(r#"try { open /etc/hosts | lines | each {|line| $line | str replace --regex '#.*' '' | split row --regex '\s+' | where $it != '' | skip 1 } | flatten } catch { [] }"#)),
+            elvish: Some(ustr("e:awk '!/^[[:space:]]*#/ { for (i = 2; i <= NF && $i !~ /^#/; i++) print $i }' /etc/hosts")),
+            xonsh: Some(ustr("[host for line in open('/etc/hosts') for host in line.split('#')[0].split()[1:]]")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("ENVIRONMENT_VARIABLE"), Specialization {
+            bash: Some(ustr(r#"compgen -e "$1""#)),
+            fish: Some(ustr("set --names --export")),
+            zsh: Some(ustr("_parameters -g '*export*'")),
+            powershell: Some(ustr("Get-ChildItem Env: | ForEach-Object Name | Where-Object { $_.StartsWith($1) }")),
+            nushell: Some(ustr("$env | columns")),
+            elvish: Some(ustr("e:env | e:cut -d= -f1")),
+            xonsh: Some(ustr("list(os.environ)")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("PID"), Specialization {
+            bash: Some(ustr(r#"compgen -W "$(ps -A -o pid=)" -- "$1""#)),
+            fish: Some(ustr("__fish_complete_pids")),
+            zsh: Some(ustr("_pids")),
+            powershell: Some(ustr(r#"Get-Process | ForEach-Object { "$($_.Id)`t$($_.ProcessName)" } | Where-Object { $_.StartsWith($1) }"#)),
+            nushell: Some(ustr(r#"ps | each {|p| $"($p.pid)\t($p.name)" }"#)),
+            elvish: Some(ustr("e:ps -A -o pid= | from-lines | each {|pid| str:trim-space $pid }")),
+            xonsh: Some(ustr("$(ps -A -o pid=).split()")),
+            generic: None,
+            native: true,
+        }),
+        (ustr("SIGNAL"), Specialization {
+            bash: Some(ustr(r#"compgen -A signal "SIG$1" | cut -c 4-"#)),
+            fish: Some(ustr("command kill -l | string split ' '")),
+            zsh: Some(ustr("_signals")),
+            powershell: Some(ustr(r#"-split (& (Get-Command -CommandType Application kill | Select-Object -First 1) -l) | Where-Object { $_.StartsWith($1) }"#)),
+            nushell: Some(ustr(r#"^kill -l | split row --regex '\s+' | where $it != ''"#)),
+            elvish: Some(ustr(r#"e:kill -l | e:tr -s ' ' "\n""#)),
+            xonsh: Some(ustr("$(kill -l).split()")),
+            generic: None,
+            native: true,
+        }),
+    ]
+}
+
+
+fn make_glob_path_specialization(glob: &str) -> Specialization {
+    Specialization {
+        bash: Some(ustr(&crate::bash::make_glob_completion_command(glob))),
+        fish: Some(ustr(&crate::fish::make_glob_completion_command(glob))),
+        zsh: Some(ustr(&crate::zsh::make_glob_completion_command(glob))),
+        powershell: Some(ustr(&crate::powershell::make_glob_completion_command(glob))),
+        nushell: Some(ustr(&crate::nushell::make_glob_completion_command(glob))),
+        elvish: Some(ustr(&crate::elvish::make_glob_completion_command(glob))),
+        xonsh: Some(ustr(&crate::xonsh::make_glob_completion_command(glob))),
+        generic: None,
+        native: true,
+    }
+}
+
+
 fn make_specializations_map(statements: &[Statement]) -> Result<UstrMap<Specialization>> {
     let mut specializations: UstrMap<Specialization> = Default::default();
     for definition in statements {
         let (name, shell, expr) = match definition {
             Statement::NonterminalDefinition { symbol, shell: Some(shell), expr } => (*symbol, shell, expr),
             Statement::NonterminalDefinition { shell: None, .. } => continue,
-            Statement::CallVariant { .. } => continue,
+            Statement::CallVariant { .. } | Statement::Include(_) => continue,
         };
-        let command = match expr.borrow() {
-            Expr::Command(cmd) => cmd,
+        let command = match &expr.kind {
+            ExprKind::Command(cmd, _, annotations) if *annotations == CommandAnnotations::default() => cmd,
+            ExprKind::Command(..) => return Err(Error::AnnotatedSpecialization(name, Some(*shell))),
             _ => return Err(Error::NonCommandSpecialization(name, Some(*shell))),
         };
         let known_shell = match shell.as_str() {
-            "bash" | "fish" | "zsh" => true,
+            "bash" | "fish" | "zsh" | "powershell" | "nushell" | "elvish" | "xonsh" => true,
             _ => false,
         };
         if !known_shell {
@@ -411,6 +917,30 @@ fn make_specializations_map(statements: &[Statement]) -> Result<UstrMap<Speciali
                 }
                 spec.zsh = Some(*command);
             },
+            "powershell" => {
+                if spec.powershell.is_some() {
+                    return Err(Error::DuplicateNonterminalDefinition(name, Some(*shell)));
+                }
+                spec.powershell = Some(*command);
+            },
+            "nushell" => {
+                if spec.nushell.is_some() {
+                    return Err(Error::DuplicateNonterminalDefinition(name, Some(*shell)));
+                }
+                spec.nushell = Some(*command);
+            },
+            "elvish" => {
+                if spec.elvish.is_some() {
+                    return Err(Error::DuplicateNonterminalDefinition(name, Some(*shell)));
+                }
+                spec.elvish = Some(*command);
+            },
+            "xonsh" => {
+                if spec.xonsh.is_some() {
+                    return Err(Error::DuplicateNonterminalDefinition(name, Some(*shell)));
+                }
+                spec.xonsh = Some(*command);
+            },
             _ => unreachable!(),
         }
     }
@@ -421,77 +951,71 @@ fn make_specializations_map(statements: &[Statement]) -> Result<UstrMap<Speciali
             _ => continue,
         };
         let Some(spec) = specializations.get_mut(name) else { continue };
-        let Expr::Command(command) = expr.borrow() else {
+        let ExprKind::Command(command, _, annotations) = &expr.kind else {
             return Err(Error::NonCommandSpecialization(*name, None));
         };
+        if *annotations != CommandAnnotations::default() {
+            return Err(Error::AnnotatedSpecialization(*name, None));
+        }
         if let Some(_) = spec.generic {
             return Err(Error::DuplicateNonterminalDefinition(*name, None));
         }
         spec.generic = Some(*command);
     }
 
-    specializations.entry(ustr("PATH")).or_insert_with(||
-        Specialization {
-            bash: Some(ustr(&format!(r#"compgen -A file "$1""#))),
-            fish: Some(ustr(&format!(r#"__fish_complete_path "$1""#))),
-            zsh: Some(ustr("_path_files")),
-            generic: None,
+    // Predefined nonterminals lose their meaning as soon as the grammar defines them in any way
+    let defined: UstrSet = statements.iter().filter_map(|statement| match statement {
+        Statement::NonterminalDefinition { symbol, .. } => Some(*symbol),
+        Statement::CallVariant { .. } | Statement::Include(_) => None,
+    }).collect();
+    for (name, builtin) in make_builtin_specializations() {
+        if !defined.contains(&name) {
+            specializations.insert(name, builtin);
         }
-    );
+    }
 
-    specializations.entry(ustr("DIRECTORY")).or_insert_with(||
-        Specialization {
-            bash: Some(ustr(&format!(r#"compgen -A directory "$1""#))),
-            fish: Some(ustr(&format!(r#"__fish_complete_directories "$1""#))),
-            zsh: Some(ustr("_path_files -/")),
-            generic: None,
+    // <PATH:*.rs> and the like get a specialization of their own for each glob
+    for statement in statements {
+        let expr = match statement {
+            Statement::CallVariant { expr, .. } | Statement::NonterminalDefinition { expr, .. } => expr,
+            Statement::Include(_) => continue,
+        };
+        for name in get_expression_nonterminals(Rc::clone(expr)) {
+            let Some(glob) = name.strip_prefix("PATH:") else { continue };
+            if !crate::glob::is_valid(glob) {
+                return Err(Error::InvalidGlob(ustr(glob)));
+            }
+            specializations.entry(name).or_insert_with(|| make_glob_path_specialization(glob));
         }
-    );
+    }
 
     Ok(specializations)
 }
 
 
 impl ValidGrammar {
-    pub fn from_grammar(grammar: Grammar) -> Result<Self> {
-        let command = {
-            let mut commands: Vec<Ustr> = grammar.statements.iter().filter_map(|v|
+    pub fn from_grammar(grammar: &Grammar) -> Result<Self> {
+        Self::do_from_grammar(grammar).map_err(|e| grammar.locate_error(e))
+    }
+
+    fn do_from_grammar(grammar: &Grammar) -> Result<Self> {
+        let command_names = {
+            let mut seen: UstrSet = Default::default();
+            let commands: Vec<Ustr> = grammar.statements.iter().filter_map(|v|
                 match v {
                     Statement::CallVariant { head: lhs, .. } => Some(*lhs),
-                    Statement::NonterminalDefinition { .. } => None,
+                    Statement::NonterminalDefinition { .. } | Statement::Include(_) => None,
                 }
-            ).collect();
+            ).filter(|command| seen.insert(*command)).collect();
 
             if commands.is_empty() {
                 return Err(Error::MissingCallVariants);
             }
 
-            commands.sort_unstable();
-            commands.dedup();
-
-            if commands.len() > 1 {
-                return Err(Error::VaryingCommandNames(
-                    commands.into_iter().collect(),
-                ));
-            }
-            commands[0]
+            commands
         };
 
-        let expr = {
-            let call_variants: Vec<Rc<Expr>> = grammar.statements.iter().filter_map(|v|
-                match v {
-                    Statement::CallVariant { expr: rhs, .. } => Some(rhs.clone()),
-                    Statement::NonterminalDefinition { .. } => None,
-                }
-            ).collect();
-
-            if call_variants.len() == 1 {
-                Rc::clone(&call_variants[0])
-            }
-            else {
-                Rc::new(Expr::Alternative(call_variants))
-            }
-        };
+        check_duplicate_definitions(&grammar.statements, &grammar.spans)?;
 
         let specializations = make_specializations_map(&grammar.statements)?;
 
@@ -512,122 +1036,250 @@ impl ValidGrammar {
 
         for nonterminal in get_nonterminals_resolution_order(&nonterminal_definitions)? {
             let e = Rc::clone(nonterminal_definitions.get(&nonterminal).unwrap());
-            *nonterminal_definitions.get_mut(&nonterminal).unwrap() = resolve_nonterminals(e, &nonterminal_definitions, &specializations);
+            *nonterminal_definitions.get_mut(&nonterminal).unwrap() = resolve_nonterminals(e, &nonterminal_definitions, &specializations)?;
         }
-        let expr = resolve_nonterminals(expr, &nonterminal_definitions, &specializations);
+
+        // Nonterminal definitions are shared by all the commands and get resolved only once above
+        let commands: Vec<ValidCommand> = command_names.into_iter().map(|command| -> Result<ValidCommand> {
+            let call_variants: Vec<Rc<Expr>> = grammar.statements.iter().filter_map(|v|
+                match v {
+                    Statement::CallVariant { head, expr: rhs } if *head == command => Some(rhs.clone()),
+                    _ => None,
+                }
+            ).collect();
+
+            let expr = if call_variants.len() == 1 {
+                Rc::clone(&call_variants[0])
+            }
+            else {
+                ExprKind::Alternative(call_variants).into()
+            };
+
+            let expr = resolve_nonterminals(expr, &nonterminal_definitions, &specializations)?;
+            Ok(ValidCommand { command, expr })
+        }).collect::<Result<_>>()?;
 
         let undefined_nonterminals = {
-            let mut nonterms = get_expression_nonterminals(Rc::clone(&expr));
-            nonterms.retain(|n| !specializations.contains_key(n));
+            let mut nonterms: UstrSet = Default::default();
+            for command in &commands {
+                nonterms.extend(get_expression_nonterminals(Rc::clone(&command.expr)));
+            }
+            nonterms.retain(|n| !specializations.contains_key(n) && *n != END_OF_OPTIONS);
             nonterms
         };
 
         let g = ValidGrammar {
-            command,
-            expr,
+            commands,
             undefined_nonterminals,
             specializations,
         };
         Ok(g)
     }
+
+    #[cfg(test)]
+    pub fn get_command(&self, command: &str) -> Option<&ValidCommand> {
+        self.commands.iter().find(|c| c.command == command)
+    }
+
+    // A single command is drawn as is while multiple ones become alternatives, each starting with its name
+    pub fn to_railroad_expr(&self) -> Rc<Expr> {
+        if self.commands.len() == 1 {
+            return Rc::clone(&self.commands[0].expr);
+        }
+        let alternatives = self.commands.iter().map(|c| ExprKind::Sequence(vec![ExprKind::Terminal(c.command, None).into(), Rc::clone(&c.expr)]).into()).collect();
+        ExprKind::Alternative(alternatives).into()
+    }
 }
 
 
-fn resolve_nonterminals(expr: Rc<Expr>, vars: &UstrMap<Rc<Expr>>, specializations: &UstrMap<Specialization>) -> Rc<Expr> {
-    match expr.as_ref() {
-        Expr::Terminal(..) => Rc::clone(&expr),
-        Expr::Nonterminal(name) => {
+fn resolve_nonterminals(expr: Rc<Expr>, vars: &UstrMap<Rc<Expr>>, specializations: &UstrMap<Specialization>) -> Result<Rc<Expr>> {
+    match &expr.kind {
+        ExprKind::Terminal(..) => Ok(Rc::clone(&expr)),
+        ExprKind::Nonterminal(name, binding) => {
             if specializations.contains_key(name) {
-                return Rc::clone(&expr);
+                return Ok(Rc::clone(&expr));
             }
-            match vars.get(&name) {
-                Some(replacement) => {
-                    Rc::clone(&replacement)
+            match (vars.get(&name), binding) {
+                (Some(replacement), Some(binding)) => {
+                    bind(Rc::clone(replacement), *binding).ok_or(Error::NonCommandBinding(*name, *binding))
+                },
+                (Some(replacement), None) => {
+                    Ok(Rc::clone(&replacement))
                 },
-                None => {
-                    Rc::clone(&expr)
+                (None, _) => {
+                    Ok(Rc::clone(&expr))
                 },
             }
         },
-        Expr::Command(_) => Rc::clone(&expr),
-        Expr::Sequence(children) => {
+        ExprKind::Command(..) => Ok(Rc::clone(&expr)),
+        ExprKind::Prefix(prefix, child) => {
+            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
+            if Rc::ptr_eq(child, &new_child) {
+                Ok(Rc::clone(&expr))
+            }
+            else {
+                Ok(Rc::new(expr.with_kind(ExprKind::Prefix(*prefix, new_child))))
+            }
+        },
+        ExprKind::Cluster(children) => {
+            let mut new_children: Vec<Rc<Expr>> = Default::default();
+            let mut any_child_replaced = false;
+            for child in children {
+                let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
+                if !Rc::ptr_eq(child, &new_child) {
+                    any_child_replaced = true;
+                }
+                new_children.push(new_child);
+            }
+            if any_child_replaced {
+                Ok(Rc::new(expr.with_kind(ExprKind::Cluster(new_children))))
+            } else {
+                Ok(Rc::clone(&expr))
+            }
+        },
+        ExprKind::Exclusive(children) => {
+            let mut new_children: Vec<Rc<Expr>> = Default::default();
+            let mut any_child_replaced = false;
+            for child in children {
+                let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
+                if !Rc::ptr_eq(child, &new_child) {
+                    any_child_replaced = true;
+                }
+                new_children.push(new_child);
+            }
+            if any_child_replaced {
+                Ok(Rc::new(expr.with_kind(ExprKind::Exclusive(new_children))))
+            } else {
+                Ok(Rc::clone(&expr))
+            }
+        },
+        ExprKind::Sequence(children) => {
             let mut new_children: Vec<Rc<Expr>> = Default::default();
             let mut any_child_replaced = false;
             for child in children {
-                let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations);
+                let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
                 if !Rc::ptr_eq(&child, &new_child) {
                     any_child_replaced = true;
                 }
                 new_children.push(new_child);
             }
             if any_child_replaced {
-                Rc::new(Expr::Sequence(new_children))
+                Ok(Rc::new(expr.with_kind(ExprKind::Sequence(new_children))))
             } else {
-                Rc::clone(&expr)
+                Ok(Rc::clone(&expr))
             }
         },
-        Expr::Alternative(children) => {
+        ExprKind::Alternative(children) => {
             let mut new_children: Vec<Rc<Expr>> = Default::default();
             let mut any_child_replaced = false;
             for child in children {
-                let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations);
+                let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
                 if !Rc::ptr_eq(&child, &new_child) {
                     any_child_replaced = true;
                 }
                 new_children.push(new_child);
             }
             if any_child_replaced {
-                Rc::new(Expr::Alternative(new_children))
+                Ok(Rc::new(expr.with_kind(ExprKind::Alternative(new_children))))
             } else {
-                Rc::clone(&expr)
+                Ok(Rc::clone(&expr))
             }
         },
-        Expr::Optional(child) => {
-            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations);
+        ExprKind::Optional(child) => {
+            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
             if Rc::ptr_eq(&child, &new_child) {
-                Rc::clone(&expr)
+                Ok(Rc::clone(&expr))
             }
             else {
-                Rc::new(Expr::Optional(new_child))
+                Ok(Rc::new(expr.with_kind(ExprKind::Optional(new_child))))
             }
         },
-        Expr::Many1(child) => {
-            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations);
+        ExprKind::Many1(child) => {
+            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
             if Rc::ptr_eq(&child, &new_child) {
-                Rc::clone(&expr)
+                Ok(Rc::clone(&expr))
             }
             else {
-                Rc::new(Expr::Many1(new_child))
+                Ok(Rc::new(expr.with_kind(ExprKind::Many1(new_child))))
+            }
+        },
+        ExprKind::Once(child) => {
+            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
+            if Rc::ptr_eq(child, &new_child) {
+                Ok(Rc::clone(&expr))
+            }
+            else {
+                Ok(Rc::new(expr.with_kind(ExprKind::Once(new_child))))
+            }
+        },
+        ExprKind::Repeat(child, min, max) => {
+            let new_child = resolve_nonterminals(Rc::clone(child), vars, specializations)?;
+            if Rc::ptr_eq(child, &new_child) {
+                Ok(Rc::clone(&expr))
+            }
+            else {
+                Ok(Rc::new(expr.with_kind(ExprKind::Repeat(new_child, *min, *max))))
             }
         },
     }
 }
 
 
+// Pushes the binding of e.g. <repo=REPO> down to the words the definition of <REPO> consists of.  Only words
+// matched by (nonterminals completed by) external commands can be bound, hence None if there's a literal.
+fn bind(expr: Rc<Expr>, binding: Ustr) -> Option<Rc<Expr>> {
+    let bind_all = |children: &[Rc<Expr>]| -> Option<Vec<Rc<Expr>>> {
+        children.iter().map(|child| bind(Rc::clone(child), binding)).collect()
+    };
+    let result = match &expr.kind {
+        ExprKind::Terminal(..) | ExprKind::Prefix(..) | ExprKind::Cluster(..) => return None,
+        ExprKind::Nonterminal(name, None) => ExprKind::Nonterminal(*name, Some(binding)),
+        ExprKind::Command(cmd, None, annotations) => ExprKind::Command(*cmd, Some(binding), *annotations),
+        ExprKind::Nonterminal(_, Some(_)) | ExprKind::Command(_, Some(_), _) => return Some(expr),
+        ExprKind::Sequence(children) => ExprKind::Sequence(bind_all(children)?),
+        ExprKind::Alternative(children) => ExprKind::Alternative(bind_all(children)?),
+        ExprKind::Exclusive(children) => ExprKind::Exclusive(bind_all(children)?),
+        ExprKind::Optional(child) => ExprKind::Optional(bind(Rc::clone(child), binding)?),
+        ExprKind::Many1(child) => ExprKind::Many1(bind(Rc::clone(child), binding)?),
+        ExprKind::Once(child) => ExprKind::Once(bind(Rc::clone(child), binding)?),
+        ExprKind::Repeat(child, min, max) => ExprKind::Repeat(bind(Rc::clone(child), binding)?, *min, *max),
+    };
+    Some(Rc::new(expr.with_kind(result)))
+}
+
+
 fn do_get_expression_nonterminals(expr: Rc<Expr>, deps: &mut UstrSet) {
-    match expr.as_ref() {
-        Expr::Terminal(..) => {},
-        Expr::Nonterminal(varname) => {
+    match &expr.kind {
+        ExprKind::Terminal(..) => {},
+        ExprKind::Nonterminal(varname, _) => {
             deps.insert(*varname);
         },
-        Expr::Command(_) => {},
-        Expr::Sequence(children) => {
+        ExprKind::Command(..) => {},
+        ExprKind::Prefix(_, child) => { do_get_expression_nonterminals(Rc::clone(child), deps); },
+        ExprKind::Cluster(children) | ExprKind::Exclusive(children) => {
+            for child in children {
+                do_get_expression_nonterminals(Rc::clone(child), deps);
+            }
+        },
+        ExprKind::Sequence(children) => {
             for child in children {
                 do_get_expression_nonterminals(Rc::clone(&child), deps);
             }
         },
-        Expr::Alternative(children) => {
+        ExprKind::Alternative(children) => {
             for child in children {
                 do_get_expression_nonterminals(Rc::clone(&child), deps);
             }
         },
-        Expr::Optional(child) => { do_get_expression_nonterminals(Rc::clone(&child), deps); }
-        Expr::Many1(child) => { do_get_expression_nonterminals(Rc::clone(&child), deps); }
+        ExprKind::Optional(child) => { do_get_expression_nonterminals(Rc::clone(&child), deps); }
+        ExprKind::Many1(child) => { do_get_expression_nonterminals(Rc::clone(&child), deps); }
+        ExprKind::Once(child) => { do_get_expression_nonterminals(Rc::clone(child), deps); }
+        ExprKind::Repeat(child, ..) => { do_get_expression_nonterminals(Rc::clone(child), deps); }
     }
 }
 
 
-fn get_expression_nonterminals(expr: Rc<Expr>) -> UstrSet {
+pub fn get_expression_nonterminals(expr: Rc<Expr>) -> UstrSet {
     let mut result: UstrSet = Default::default();
     do_get_expression_nonterminals(expr, &mut result);
     result
@@ -713,23 +1365,331 @@ fn get_nonterminals_resolution_order(nonterminal_definitions: &UstrMap<Rc<Expr>>
 }
 
 
+impl Span {
+    fn line_bounds(&self) -> (usize, usize) {
+        let line_start = self.text[..self.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = self.text[self.start..].find('\n').map(|i| self.start + i).unwrap_or(self.text.len());
+        (line_start, line_end)
+    }
+
+    pub fn line_column(&self) -> (usize, usize) {
+        let (line_start, _) = self.line_bounds();
+        let line = self.text[..self.start].matches('\n').count() + 1;
+        let column = self.text[line_start..self.start].chars().count() + 1;
+        (line, column)
+    }
+
+    /// `file:line:column`, the way compilers report locations
+    pub fn location(&self) -> String {
+        let (line, column) = self.line_column();
+        format!("{}:{line}:{column}", source_name(self.source))
+    }
+
+    // Only the first line of a multiline span gets underlined
+    fn render(&self, gutter_width: usize, label: &str) -> String {
+        let (line_start, line_end) = self.line_bounds();
+        let (line, column) = self.line_column();
+        let gutter = " ".repeat(gutter_width);
+        let source_line = self.text[line_start..line_end].trim_end_matches('\r');
+        // Keep tabs so that the carets line up with the source line
+        let indent: String = self.text[line_start..self.start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        let carets = "^".repeat(self.text[self.start..self.end.min(line_end)].chars().count().max(1));
+        format!("{gutter}--> {}:{line}:{column}\n{gutter} |\n{line:>gutter_width$} | {source_line}\n{gutter} | {indent}{carets} {label}", source_name(self.source)).trim_end().to_string()
+    }
+}
+
+
+// Renders something along the lines of:
+//
+//  --> grep.usage:3:1
+//   |
+// 3 | <OPTION> ::= --color;
+//   | ^^^^^^^^ defined again here
+//   = help: remove one of the definitions
+fn render_diagnostic(labels: &[(Span, String)], hint: &str) -> String {
+    let gutter_width = labels.iter().map(|(span, _)| span.line_column().0.to_string().len()).max().unwrap_or(1);
+    let mut lines: Vec<String> = labels.iter().map(|(span, label)| span.render(gutter_width, label)).collect();
+    lines.push(format!("{} = help: {hint}", " ".repeat(gutter_width)));
+    lines.join("\n")
+}
+
+
+fn make_located_error(error: Error, labels: &[(Span, String)], hint: &str) -> Error {
+    Error::Located(Box::new(error), render_diagnostic(labels, hint))
+}
+
+
+// The parser only tells where the last successfully parsed statement ends.  Try every kind of statement on the
+// rest of the input to point at where the parsing actually got stuck.
+fn make_parsing_error(text: &Rc<str>, source: Option<Ustr>, statement_start: usize) -> Error {
+    let rest = &text[statement_start..];
+    let start = [include_statement(rest), call_variant(rest), nonterminal_definition(rest)].into_iter().filter_map(|result| match result {
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Some(statement_start + rest.offset(e.input)),
+        _ => None,
+    }).max().unwrap_or(statement_start);
+
+    // Point right past the last token rather than at trailing blank lines
+    let start = if text[start..].trim().is_empty() { text.trim_end().len() } else { start };
+    let token_len = text[start..].find(char::is_whitespace).unwrap_or(text.len() - start);
+    let span = Span { source, text: Rc::clone(text), start, end: start + token_len };
+    if token_len == 0 {
+        let hint = "statements need to be terminated with `;`";
+        return make_located_error(Error::ParsingError("end of input".to_string()), &[(span, "unexpected end of input".to_string())], hint);
+    }
+
+    let hint = if rest.contains(';') {
+        "expected a command line, e.g. `grep [<OPTION>]... <PATTERNS>;` or a nonterminal definition, e.g. `<OPTION> ::= --color;`"
+    } else {
+        "statements need to be terminated with `;`"
+    };
+    make_located_error(Error::ParsingError(text[start..start + token_len].to_string()), &[(span, "unexpected input".to_string())], hint)
+}
+
+
+// Points at the whole range, e.g. {3,1}, or just the offending count, e.g. 1000000 in {1,1000000}
+fn make_repeat_range_error(text: &Rc<str>, source: Option<Ustr>, start: usize, kind: nom::error::ErrorKind) -> Error {
+    let (end, error, label, hint) = match kind {
+        nom::error::ErrorKind::TooLarge => {
+            let end = start + text[start..].find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len() - start);
+            let error = Error::RepeatCountTooLarge(text[start..end].to_string(), MAX_REPEAT_COUNT);
+            (end, error, "count too large", format!("repetition counts can be at most {MAX_REPEAT_COUNT}, use ... for an unbounded number of repetitions"))
+        },
+        nom::error::ErrorKind::Verify => {
+            let end = start + text[start..].find('}').map_or(1, |i| i + 1);
+            let error = Error::EmptyRepeatRange(text[start..end].to_string());
+            (end, error, "empty repetition range", "the maximum count needs to be at least 1 and no less than the minimum one, e.g. {1,3}".to_string())
+        },
+        _ => unreachable!("repeat_range() fails hard on empty ranges and too large counts only"),
+    };
+    let span = Span { source, text: Rc::clone(text), start, end };
+    make_located_error(error, &[(span, label.to_string())], &hint)
+}
+
+
+// Turns the spans recorded while parsing into offsets into `text`, see make_unlocated_span()
+fn locate_expr(expr: &mut Rc<Expr>, text: &Rc<str>, source: Option<Ustr>) {
+    let expr = Rc::make_mut(expr);
+    if let Some(span) = &mut expr.span {
+        *span = Span { source, text: Rc::clone(text), start: text.len() - span.start, end: text.len() - span.end };
+    }
+    match &mut expr.kind {
+        ExprKind::Terminal(..) | ExprKind::Nonterminal(..) | ExprKind::Command(..) => {},
+        ExprKind::Prefix(_, child) | ExprKind::Optional(child) | ExprKind::Many1(child) | ExprKind::Once(child) | ExprKind::Repeat(child, ..) => locate_expr(child, text, source),
+        ExprKind::Cluster(children) | ExprKind::Exclusive(children) | ExprKind::Sequence(children) | ExprKind::Alternative(children) => {
+            for child in children {
+                locate_expr(child, text, source);
+            }
+        },
+    }
+}
+
+
+// Only the part before `::=`, i.e. the nonterminal being defined, e.g. `<FILE@bash>`
+fn get_definition_head(span: &Span) -> Span {
+    let statement = &span.text[span.start..span.end];
+    let end = statement.find("::=").map(|i| span.start + statement[..i].trim_end().len()).unwrap_or(span.end);
+    Span { end, ..span.clone() }
+}
+
+
 impl Grammar {
     pub fn parse(input: &str) -> Result<Self> {
-        let (input, statements) = match grammar(input) {
-            Ok((input, statements)) => (input, statements),
+        Self::parse_source(input, None)
+    }
+
+    /// `source` is the file `input` comes from, if any.  It's only used in error messages.
+    pub fn parse_source(input: &str, source: Option<Ustr>) -> Result<Self> {
+        let text: Rc<str> = Rc::from(input);
+        let (rest, statements) = match grammar(input) {
+            Ok((rest, statements)) => (rest, statements),
+            // Only invalid repetition ranges fail hard, see repeat_range()
+            Err(nom::Err::Failure(e)) if matches!(e.code, nom::error::ErrorKind::Verify | nom::error::ErrorKind::TooLarge) => return Err(make_repeat_range_error(&text, source, input.offset(e.input), e.code)),
             Err(e) => return Err(Error::ParsingError(e.to_string())),
         };
 
-        if !input.is_empty() {
-            return Err(Error::ParsingError(input.to_owned()));
+        if !rest.is_empty() {
+            return Err(make_parsing_error(&text, source, input.offset(rest)));
         }
 
+        let spans: Vec<Span> = statements.iter().map(|(statement_text, _)| {
+            let start = input.offset(statement_text);
+            Span { source, text: Rc::clone(&text), start, end: start + statement_text.len() }
+        }).collect();
+
+        let statements = statements.into_iter().map(|(_, mut statement)| {
+            match &mut statement {
+                Statement::CallVariant { expr, .. } | Statement::NonterminalDefinition { expr, .. } => locate_expr(expr, &text, source),
+                Statement::Include(_) => {},
+            }
+            statement
+        }).collect();
+
         let g = Grammar {
             statements,
+            spans,
+            includes: Default::default(),
         };
 
         Ok(g)
     }
+
+    /// Replaces `include` statements with the contents of the included files.  Relative paths are resolved
+    /// against the directory of the including file (or the current directory if `path` is None, e.g. for stdin).
+    /// Every file gets included at most once.
+    pub fn resolve_includes(self, path: Option<&Path>) -> Result<Self> {
+        let directory = path.and_then(|p| p.parent()).map(Path::to_path_buf).unwrap_or_default();
+        let mut include_stack: Vec<PathBuf> = path.and_then(|p| p.canonicalize().ok()).into_iter().collect();
+        let mut included: HashSet<PathBuf> = include_stack.iter().cloned().collect();
+        let mut output = Grammar { statements: Default::default(), spans: Default::default(), includes: Default::default() };
+        do_resolve_includes(self, &directory, &mut include_stack, &mut included, &mut output)?;
+        Ok(output)
+    }
+
+    /// Files pulled in by `include` statements, i.e. everything besides the top level file the grammar depends on
+    pub fn get_included_files(&self) -> &[PathBuf] {
+        &self.includes
+    }
+
+    fn get_definition_spans(&self, symbol: Ustr, shell: Option<Ustr>) -> Vec<Span> {
+        self.statements.iter().zip(&self.spans).filter_map(|(statement, span)| match statement {
+            Statement::NonterminalDefinition { symbol: s, shell: sh, .. } if *s == symbol && *sh == shell => Some(get_definition_head(span)),
+            _ => None,
+        }).collect()
+    }
+
+    // The resolution order only tells there's a cycle in some cases.  Find out which nonterminals can reach
+    // themselves, in the order of their definitions.
+    fn get_cyclic_nonterminals(&self) -> Vec<Ustr> {
+        let dependency_graph: UstrMap<UstrSet> = self.statements.iter().filter_map(|statement| match statement {
+            Statement::NonterminalDefinition { symbol, shell: None, expr } => Some((*symbol, get_expression_nonterminals(Rc::clone(expr)))),
+            _ => None,
+        }).collect();
+        let reaches_itself = |start: Ustr| {
+            let mut visited: UstrSet = Default::default();
+            let mut stack: Vec<Ustr> = dependency_graph.get(&start).into_iter().flatten().copied().collect();
+            while let Some(vertex) = stack.pop() {
+                if vertex == start {
+                    return true;
+                }
+                if visited.insert(vertex) {
+                    stack.extend(dependency_graph.get(&vertex).into_iter().flatten());
+                }
+            }
+            false
+        };
+        self.statements.iter().filter_map(|statement| match statement {
+            Statement::NonterminalDefinition { symbol, shell: None, .. } if reaches_itself(*symbol) => Some(*symbol),
+            _ => None,
+        }).collect()
+    }
+
+    // Points at the statements responsible for a validation error, if it's possible to tell which ones they are
+    fn locate_error(&self, error: Error) -> Error {
+        let (labels, hint): (Vec<(Span, String)>, String) = match &error {
+            Error::DuplicateNonterminalDefinition(symbol, shell) | Error::ConflictingNonterminalDefinitions(symbol, shell, _, _) => {
+                let spans = self.get_definition_spans(*symbol, *shell);
+                let labels = spans.into_iter().zip(["first defined here", "defined again here"]).map(|(span, label)| (span, label.to_string())).collect();
+                (labels, "remove one of the definitions".to_string())
+            },
+            Error::NonterminalDefinitionsCycle(path) => {
+                let symbols = match path {
+                    Some(path) => {
+                        let mut seen: UstrSet = Default::default();
+                        path.iter().copied().filter(|symbol| seen.insert(*symbol)).collect()
+                    },
+                    None => self.get_cyclic_nonterminals(),
+                };
+                let labels = symbols.iter().flat_map(|symbol| self.get_definition_spans(*symbol, None)).map(|span| (span, "part of the cycle".to_string())).collect();
+                (labels, "a nonterminal can't refer to itself, neither directly nor through other nonterminals".to_string())
+            },
+            Error::NonCommandSpecialization(symbol, shell) => {
+                let labels = self.get_definition_spans(*symbol, *shell).into_iter().take(1).map(|span| (span, "not defined as an external command".to_string())).collect();
+                (labels, format!("nonterminals having specializations need to be external commands, e.g. <{symbol}> ::= {{ ls }};"))
+            },
+            Error::NonCommandBinding(symbol, binding) => {
+                let reference = self.find_expr_span(|kind| matches!(kind, ExprKind::Nonterminal(name, Some(b)) if name == symbol && b == binding));
+                let labels = reference.map(|span| (span, "bound here".to_string())).into_iter().collect();
+                (labels, format!("<{symbol}> needs to consist of nonterminals or external commands only, e.g. <{symbol}> ::= {{ ls }};"))
+            },
+            Error::AnnotatedSpecialization(symbol, shell) => {
+                let labels = self.get_definition_spans(*symbol, *shell).into_iter().take(1).map(|span| (span, "annotated command".to_string())).collect();
+                (labels, format!("@timeout and @cache only apply to commands in nonterminals without shell-specific definitions, unlike <{symbol}>"))
+            },
+            Error::InvalidGlob(glob) => {
+                let name = format!("PATH:{glob}");
+                let reference = self.find_expr_span(|kind| matches!(kind, ExprKind::Nonterminal(n, _) if *n == name));
+                // Without the angle brackets
+                let labels = reference.map(|span| (Span { start: span.start + 1, end: span.end - 1, ..span }, "invalid glob".to_string())).into_iter().collect();
+                (labels, "globs may use *, ?, [abc] and {a,b}, e.g. <PATH:*.{yml,yaml}>".to_string())
+            },
+            Error::UnknownShell(shell) => {
+                let labels = self.statements.iter().zip(&self.spans).find(|(statement, _)| matches!(statement, Statement::NonterminalDefinition { shell: Some(s), .. } if s == shell)).map(|(_, span)| (get_definition_head(span), "unknown shell".to_string())).into_iter().collect();
+                (labels, "supported shells are: bash, fish, zsh, powershell, nushell, elvish, xonsh".to_string())
+            },
+            _ => return error,
+        };
+        if labels.is_empty() {
+            return error;
+        }
+        make_located_error(error, &labels, &hint)
+    }
+
+    // The first expression of the kind `predicate` accepts, in the order of the grammar
+    fn find_expr_span(&self, predicate: impl Fn(&ExprKind) -> bool) -> Option<Span> {
+        fn do_find_expr_span(expr: &Expr, predicate: &impl Fn(&ExprKind) -> bool) -> Option<Span> {
+            if predicate(&expr.kind) {
+                return expr.span.clone();
+            }
+            match &expr.kind {
+                ExprKind::Terminal(..) | ExprKind::Nonterminal(..) | ExprKind::Command(..) => None,
+                ExprKind::Prefix(_, child) | ExprKind::Optional(child) | ExprKind::Many1(child) | ExprKind::Once(child) | ExprKind::Repeat(child, ..) => do_find_expr_span(child, predicate),
+                ExprKind::Cluster(children) | ExprKind::Exclusive(children) | ExprKind::Sequence(children) | ExprKind::Alternative(children) => {
+                    children.iter().find_map(|child| do_find_expr_span(child, predicate))
+                },
+            }
+        }
+        self.statements.iter().find_map(|statement| match statement {
+            Statement::CallVariant { expr, .. } | Statement::NonterminalDefinition { expr, .. } => do_find_expr_span(expr, &predicate),
+            Statement::Include(_) => None,
+        })
+    }
+
+    /// Points at the first use of `<nonterminal>` in the grammar, e.g. for warnings about undefined nonterminals
+    pub fn locate_nonterminal_reference(&self, nonterminal: Ustr) -> Option<String> {
+        let span = self.find_expr_span(|kind| matches!(kind, ExprKind::Nonterminal(name, _) if *name == nonterminal))?;
+        Some(render_diagnostic(&[(span, "used here".to_string())], &format!("define it, e.g. <{nonterminal}> ::= {{ ls }};")))
+    }
+}
+
+
+fn do_resolve_includes(input: Grammar, directory: &Path, include_stack: &mut Vec<PathBuf>, included: &mut HashSet<PathBuf>, output: &mut Grammar) -> Result<()> {
+    for (statement, span) in input.statements.into_iter().zip(input.spans) {
+        let Statement::Include(include_path) = statement else {
+            output.statements.push(statement);
+            output.spans.push(span);
+            continue;
+        };
+
+        let located = |error: Error| make_located_error(error, &[(span.clone(), "included here".to_string())], "check the path of the included file");
+        let include_path = directory.join(include_path.as_str());
+        let canonical = include_path.canonicalize().map_err(|e| located(Error::IncludeError(include_path.to_string_lossy().to_string(), e)))?;
+        if let Some(index) = include_stack.iter().position(|p| *p == canonical) {
+            let cycle: Vec<String> = include_stack[index..].iter().chain(std::iter::once(&canonical)).map(|p| p.to_string_lossy().to_string()).collect();
+            return Err(located(Error::IncludeCycle(cycle)));
+        }
+        if !included.insert(canonical.clone()) {
+            continue;
+        }
+
+        let input = std::fs::read_to_string(&include_path).map_err(|e| located(Error::IncludeError(include_path.to_string_lossy().to_string(), e)))?;
+        let grammar = Grammar::parse_source(&input, Some(ustr(&include_path.to_string_lossy())))?;
+        output.includes.push(canonical.clone());
+        include_stack.push(canonical);
+        let include_directory = include_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        do_resolve_includes(grammar, &include_directory, include_stack, included, output)?;
+        include_stack.pop();
+    }
+    Ok(())
 }
 
 
@@ -739,37 +1699,37 @@ pub mod tests {
     use proptest::{strategy::BoxedStrategy, test_runner::TestRng};
     use proptest::prelude::*;
     use ustr::ustr as u;
-    use Expr::*;
+    use ExprKind::*;
 
     use super::*;
 
     fn arb_literal(inputs: Rc<Vec<Ustr>>) -> BoxedStrategy<Rc<Expr>> {
-        (0..inputs.len()).prop_map(move |index| Rc::new(Terminal(ustr(&inputs[index]), None))).boxed()
+        (0..inputs.len()).prop_map(move |index| Terminal(ustr(&inputs[index]), None).into()).boxed()
     }
 
     fn arb_nonterminal(nonterminals: Rc<Vec<Ustr>>) -> BoxedStrategy<Rc<Expr>> {
-        (0..nonterminals.len()).prop_map(move |index| Rc::new(Nonterminal(ustr(&nonterminals[index])))).boxed()
+        (0..nonterminals.len()).prop_map(move |index| Nonterminal(ustr(&nonterminals[index]), None).into()).boxed()
     }
 
     fn arb_optional(inputs: Rc<Vec<Ustr>>, nonterminals: Rc<Vec<Ustr>>, remaining_depth: usize, max_width: usize) -> BoxedStrategy<Rc<Expr>> {
-        arb_expr(inputs, nonterminals, remaining_depth-1, max_width).prop_map(|e| Rc::new(Optional(e))).boxed()
+        arb_expr(inputs, nonterminals, remaining_depth-1, max_width).prop_map(|e| Optional(e).into()).boxed()
     }
 
     fn arb_many1(inputs: Rc<Vec<Ustr>>, nonterminals: Rc<Vec<Ustr>>, remaining_depth: usize, max_width: usize) -> BoxedStrategy<Rc<Expr>> {
-        arb_expr(inputs, nonterminals, remaining_depth-1, max_width).prop_map(|e| Rc::new(Many1(e))).boxed()
+        arb_expr(inputs, nonterminals, remaining_depth-1, max_width).prop_map(|e| Many1(e).into()).boxed()
     }
 
     fn arb_sequence(inputs: Rc<Vec<Ustr>>, nonterminals: Rc<Vec<Ustr>>, remaining_depth: usize, max_width: usize) -> BoxedStrategy<Rc<Expr>> {
         (2..max_width).prop_flat_map(move |width| {
             let e = arb_expr(inputs.clone(), nonterminals.clone(), remaining_depth-1, max_width);
-            prop::collection::vec(e, width).prop_map(|v| Rc::new(Sequence(v)))
+            prop::collection::vec(e, width).prop_map(|v| Sequence(v).into())
         }).boxed()
     }
 
     fn arb_alternative(inputs: Rc<Vec<Ustr>>, nonterminals: Rc<Vec<Ustr>>, remaining_depth: usize, max_width: usize) -> BoxedStrategy<Rc<Expr>> {
         (2..max_width).prop_flat_map(move |width| {
             let e = arb_expr(inputs.clone(), nonterminals.clone(), remaining_depth-1, max_width);
-            prop::collection::vec(e, width).prop_map(|v| Rc::new(Alternative(v)))
+            prop::collection::vec(e, width).prop_map(|v| Alternative(v).into())
         }).boxed()
     }
 
@@ -793,16 +1753,35 @@ pub mod tests {
     }
 
     pub fn do_arb_match(e: Rc<Expr>, rng: &mut TestRng, max_width: usize, output: &mut Vec<Ustr>) {
-        match e.as_ref() {
+        match &e.kind {
             Terminal(s, _) => output.push(*s),
-            Nonterminal(_) => output.push(ustr("anything")),
-            Command(_) => output.push(ustr("anything")),
+            Nonterminal(..) => output.push(ustr("anything")),
+            Command(..) => output.push(ustr("anything")),
+            Prefix(prefix, subexpr) => {
+                let mut suboutput: Vec<Ustr> = Default::default();
+                do_arb_match(Rc::clone(subexpr), rng, max_width, &mut suboutput);
+                match suboutput.split_first() {
+                    Some((first, rest)) => {
+                        output.push(ustr(&format!("{prefix}{first}")));
+                        output.extend(rest);
+                    },
+                    None => output.push(*prefix),
+                }
+            },
+            Cluster(members) => {
+                let chosen_member = usize::try_from(rng.next_u64().rem(u64::try_from(members.len()).unwrap())).unwrap();
+                let (c, _, argument) = get_cluster_member(&members[chosen_member]).unwrap();
+                output.push(ustr(&format!("-{c}")));
+                if let Some(argument) = argument {
+                    do_arb_match(argument, rng, max_width, output);
+                }
+            },
             Sequence(v) => {
                 for subexpr in v {
                     do_arb_match(Rc::clone(&subexpr), rng, max_width, output);
                 }
             },
-            Alternative(v) => {
+            Alternative(v) | Exclusive(v) => {
                 let chosen_alternative = usize::try_from(rng.next_u64().rem(u64::try_from(v.len()).unwrap())).unwrap();
                 do_arb_match(Rc::clone(&v[chosen_alternative]), rng, max_width, output);
             },
@@ -811,13 +1790,20 @@ pub mod tests {
                     do_arb_match(Rc::clone(&subexpr), rng, max_width, output);
                 }
             },
-            Many1(subexpr) => {
+            Many1(subexpr) | Once(subexpr) => {
                 let n = rng.next_u64();
                 let chosen_len = n % u64::try_from(max_width).unwrap() + 1;
                 for _ in 0..chosen_len {
                     do_arb_match(Rc::clone(&subexpr), rng, max_width, output);
                 }
             },
+            Repeat(subexpr, min, max) => {
+                let (min, max) = (*min, max.unwrap_or(min + u32::try_from(max_width).unwrap()));
+                let chosen_len = u64::from(min) + rng.next_u64() % u64::from(max - min + 1);
+                for _ in 0..chosen_len {
+                    do_arb_match(Rc::clone(subexpr), rng, max_width, output);
+                }
+            },
         }
     }
 
@@ -854,39 +1840,181 @@ pub mod tests {
         assert_eq!(e, Terminal(u("--foo"), None));
     }
 
+    #[test]
+    fn parses_unicode_terminal() {
+        const INPUT: &str = r#"größe"#;
+        let ("", e) = terminal_opt_description_expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Terminal(u("größe"), None));
+
+        const PUNCTUATION_INPUT: &str = r#"key:value,path/to"#;
+        let ("", e) = terminal_opt_description_expr(PUNCTUATION_INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Terminal(u("key:value,path/to"), None));
+    }
+
+    #[test]
+    fn parses_quoted_terminal() {
+        const INPUT: &str = r#"'pkg@1.2 (beta)' "description""#;
+        let ("", e) = terminal_opt_description_expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Terminal(u("pkg@1.2 (beta)"), Some(u("description"))));
+
+        const ESCAPES_INPUT: &str = r#"'it\'s C:\\'"#;
+        let ("", e) = terminal_opt_description_expr(ESCAPES_INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Terminal(u(r"it's C:\"), None));
+
+        assert!(terminal_opt_description_expr("''").is_err());
+        assert!(terminal_opt_description_expr("'unterminated").is_err());
+    }
+
+    #[test]
+    fn parses_quoted_prefix() {
+        const INPUT: &str = r#"'--key@host'=<VALUE>"#;
+        let ("", e) = prefix_expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Prefix(u("--key@host="), Nonterminal(u("VALUE"), None).into()));
+    }
+
     #[test]
     fn parses_symbol() {
         const INPUT: &str = "<FILE>";
         let ("", e) = nonterminal_expr(INPUT).unwrap() else { panic!("parsing error"); };
-        assert_eq!(e, Nonterminal(u("FILE")));
+        assert_eq!(e, Nonterminal(u("FILE"), None));
+    }
+
+    #[test]
+    fn parses_bound_symbol() {
+        let ("", e) = nonterminal_expr("<repo=PATH>").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Nonterminal(u("PATH"), Some(u("repo"))));
+
+        let ("", e) = nonterminal_expr("<KEY=VALUE>").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Nonterminal(u("KEY=VALUE"), None));
     }
 
     #[test]
     fn parses_command() {
         const INPUT: &str = "{ rustup toolchain list | cut -d' ' -f1 }";
         let ("", e) = command_expr(INPUT).unwrap() else { panic!("parsing error"); };
-        assert_eq!(e, Command(u("rustup toolchain list | cut -d' ' -f1")));
+        assert_eq!(e, Command(u("rustup toolchain list | cut -d' ' -f1"), None, Default::default()));
+    }
+
+    #[test]
+    fn parses_command_annotations() {
+        const INPUT: &str = "{ kubectl get ns } @timeout(500ms)\n    @cache( 2m )";
+        let ("", e) = command_expr(INPUT).unwrap() else { panic!("parsing error"); };
+        let annotations = CommandAnnotations { timeout: Some(Duration::from_millis(500)), cache_ttl: Some(Duration::from_secs(120)) };
+        assert_eq!(e, Command(u("kubectl get ns"), None, annotations));
+
+        assert!(Grammar::parse("cmd { ls } @timeout(5);").is_err());
     }
 
     #[test]
     fn parses_triple_brackets_command() {
         const INPUT: &str = "{{{ rad patch list | awk '{print $3}' | grep . | grep -vw ID }}}";
         let ("", e) = command_expr(INPUT).unwrap() else { panic!("parsing error"); };
-        assert_eq!(e, Command(u("rad patch list | awk '{print $3}' | grep . | grep -vw ID")));
+        assert_eq!(e, Command(u("rad patch list | awk '{print $3}' | grep . | grep -vw ID"), None, Default::default()));
+    }
+
+    #[test]
+    fn parses_option_with_value_expr() {
+        const INPUT: &str = "--color=<WHEN>";
+        let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Prefix(u("--color="), Nonterminal(u("WHEN"), None).into()).into());
+    }
+
+    #[test]
+    fn parses_option_with_alternative_value_expr() {
+        const INPUT: &str = "--format=(short | long)";
+        let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(
+            e,
+            Prefix(u("--format="), Alternative(vec![
+                Terminal(u("short"), None).into(),
+                Terminal(u("long"), None).into(),
+            ]).into()).into()
+        );
+    }
+
+    #[test]
+    fn parses_cluster_expr() {
+        const INPUT: &str = "@cluster(-x | -f <FILE>)";
+        let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(
+            e,
+            Cluster(vec![
+                Terminal(u("-x"), None).into(),
+                Sequence(vec![Terminal(u("-f"), None).into(), Nonterminal(u("FILE"), None).into()]).into(),
+            ]).into()
+        );
+    }
+
+    #[test]
+    fn rejects_long_option_in_cluster() {
+        const INPUT: &str = "@cluster(-x | --verbose)";
+        assert!(cluster_expr(INPUT).is_err());
+    }
+
+    #[test]
+    fn parses_once_expr() {
+        const INPUT: &str = "@once(-a | --color <WHEN>)";
+        let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(
+            e,
+            Once(Alternative(vec![
+                Terminal(u("-a"), None).into(),
+                Sequence(vec![
+                    Terminal(u("--color"), None).into(),
+                    Nonterminal(u("WHEN"), None).into(),
+                ]).into(),
+            ]).into()).into()
+        );
+    }
+
+    #[test]
+    fn parses_exclusive_expr() {
+        const INPUT: &str = "@exclusive(-E | --format=<FMT>)";
+        let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
+        assert_eq!(
+            e,
+            Exclusive(vec![
+                Terminal(u("-E"), None).into(),
+                Prefix(u("--format="), Nonterminal(u("FMT"), None).into()).into(),
+            ]).into()
+        );
     }
 
     #[test]
     fn parses_optional_expr() {
         const INPUT: &str = "[<foo>]";
         let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
-        assert_eq!(e, Optional(Rc::new(Nonterminal(u("foo")))));
+        assert_eq!(e, Optional(Nonterminal(u("foo"), None).into()).into());
     }
 
     #[test]
     fn parses_one_or_more_expr() {
         const INPUT: &str = "<foo>...";
         let ("", e) = expr(INPUT).unwrap() else { panic!("parsing error"); };
-        assert_eq!(e, Many1(Rc::new(Nonterminal(u("foo")))));
+        assert_eq!(e, Many1(Nonterminal(u("foo"), None).into()).into());
+    }
+
+    #[test]
+    fn parses_repeat_expr() {
+        let ("", e) = expr("<PATH>{2}").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Repeat(Nonterminal(u("PATH"), None).into(), 2, Some(2)).into());
+
+        let ("", e) = expr("(-v | -q){1,3}").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Repeat(Alternative(vec![Terminal(u("-v"), None).into(), Terminal(u("-q"), None).into()]).into(), 1, Some(3)).into());
+
+        let ("", e) = expr("<PATH>{2,}").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Repeat(Nonterminal(u("PATH"), None).into(), 2, None).into());
+
+        assert!(matches!(expr("<PATH>{3,1}"), Err(nom::Err::Failure(_))));
+        assert!(matches!(expr("<PATH>{1,1000000}"), Err(nom::Err::Failure(_))));
+        assert!(matches!(expr("<PATH>{99999999999}"), Err(nom::Err::Failure(_))));
+
+        let ("", e) = expr("<PATH>{255}").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Repeat(Nonterminal(u("PATH"), None).into(), 255, Some(255)).into());
+
+        // Whitespace before `{` makes it an external command
+        let ("", e) = expr("<PATH> {2}").unwrap() else { panic!("parsing error"); };
+        assert_eq!(e, Sequence(vec![Nonterminal(u("PATH"), None).into(), Command(u("2"), None, CommandAnnotations::default()).into()]).into());
     }
 
     #[test]
@@ -896,9 +2024,9 @@ pub mod tests {
         assert_eq!(
             e,
             Sequence(vec![
-                Rc::new(Nonterminal(u("first-symbol"))),
-                Rc::new(Nonterminal(u("second symbol"))),
-            ])
+                Nonterminal(u("first-symbol"), None).into(),
+                Nonterminal(u("second symbol"), None).into(),
+            ]).into()
         );
     }
 
@@ -909,9 +2037,9 @@ pub mod tests {
         assert_eq!(
             e,
             Alternative(vec![
-                Rc::new(Sequence(vec![Rc::new(Terminal(u("a"), None)), Rc::new(Terminal(u("b"), None))])),
-                Rc::new(Terminal(u("c"), None))
-            ])
+                Sequence(vec![Terminal(u("a"), None).into(), Terminal(u("b"), None).into()]).into(),
+                Terminal(u("c"), None).into()
+            ]).into()
         );
     }
 
@@ -922,9 +2050,9 @@ pub mod tests {
         assert_eq!(
             e,
             Sequence(vec![
-                Rc::new(Terminal(u("a"), None)),
-                Rc::new(Alternative(vec![Rc::new(Terminal(u("b"), None)), Rc::new(Terminal(u("c"), None))])),
-            ])
+                Terminal(u("a"), None).into(),
+                Alternative(vec![Terminal(u("b"), None).into(), Terminal(u("c"), None).into()]).into(),
+            ]).into()
         );
     }
 
@@ -936,7 +2064,7 @@ pub mod tests {
             v,
             Statement::CallVariant {
                 head: u("foo"),
-                expr: Rc::new(Terminal(u("bar"), None))
+                expr: Terminal(u("bar"), None).into()
             }
         );
     }
@@ -949,40 +2077,45 @@ foo baz;
 "#;
         let g = Grammar::parse(INPUT).unwrap();
         assert_eq!(
-            g,
-            Grammar {
-                statements: vec![
-                    Statement::CallVariant { head: u("foo"), expr: Rc::new(Terminal(u("bar"), None)) },
-                    Statement::CallVariant { head: u("foo"), expr: Rc::new(Terminal(u("baz"), None)) }
-                ],
-            }
+            g.statements,
+            vec![
+                Statement::CallVariant { head: u("foo"), expr: Terminal(u("bar"), None).into() },
+                Statement::CallVariant { head: u("foo"), expr: Terminal(u("baz"), None).into() }
+            ],
         );
     }
 
+    #[test]
+    fn restricts_command_names_to_ascii() {
+        let g = Grammar::parse("g++ --version;").unwrap();
+        assert_eq!(g.statements, vec![Statement::CallVariant { head: u("g++"), expr: Terminal(u("--version"), None).into() }]);
+        assert!(Grammar::parse("größe --version;").is_err());
+        assert!(Grammar::parse("git/foo --version;").is_err());
+        assert!(Grammar::parse("foo:bar --version;").is_err());
+    }
+
     #[test]
     fn bug1() {
         // Did not consider whitespace before ...
         const INPUT: &str = "darcs help ( ( -v | --verbose ) | ( -q | --quiet ) ) ... [<DARCS_COMMAND> [DARCS_SUBCOMMAND]]  ;";
         let g = Grammar::parse(INPUT).unwrap();
         assert_eq!(
-            g,
-            Grammar {
-                statements: vec![
-                    Statement::CallVariant { head: u("darcs"), expr: Rc::new(Sequence(vec![
-                    Rc::new(Terminal(u("help"), None)),
-                    Rc::new(Sequence(vec![
-                        Rc::new(Many1(Rc::new(Alternative(vec![
-                            Rc::new(Alternative(vec![Rc::new(Terminal(u("-v"), None)), Rc::new(Terminal(u("--verbose"), None))])),
-                            Rc::new(Alternative(vec![Rc::new(Terminal(u("-q"), None)), Rc::new(Terminal(u("--quiet"), None))])),
-                        ],)),)),
-                        Rc::new(Optional(Rc::new(Sequence(vec![
-                            Rc::new(Nonterminal(u("DARCS_COMMAND"))),
-                            Rc::new(Optional(Rc::new(Terminal(u("DARCS_SUBCOMMAND"), None)))),
-                        ])))),
-                    ])),
-                ])) },
-                ],
-            }
+            g.statements,
+            vec![
+                Statement::CallVariant { head: u("darcs"), expr: Sequence(vec![
+                Terminal(u("help"), None).into(),
+                Sequence(vec![
+                    Many1(Alternative(vec![
+                        Alternative(vec![Terminal(u("-v"), None).into(), Terminal(u("--verbose"), None).into()]).into(),
+                        Alternative(vec![Terminal(u("-q"), None).into(), Terminal(u("--quiet"), None).into()]).into(),
+                    ],).into(),).into(),
+                    Optional(Sequence(vec![
+                        Nonterminal(u("DARCS_COMMAND"), None).into(),
+                        Optional(Terminal(u("DARCS_SUBCOMMAND"), None).into()).into(),
+                    ]).into()).into(),
+                ]).into(),
+            ]).into() },
+            ],
         );
     }
 
@@ -1036,14 +2169,12 @@ grep [<OPTION>]... <PATTERNS> [<FILE>]...;
 "#;
         let g = Grammar::parse(INPUT).unwrap();
         assert_eq!(
-            g,
-            Grammar {
-                statements: vec![
-                    Statement::CallVariant { head: u("grep"), expr: Rc::new(Sequence(vec![Rc::new(Many1(Rc::new(Optional(Rc::new(Nonterminal(ustr("OPTION"))))))), Rc::new(Sequence(vec![Rc::new(Nonterminal(ustr("PATTERNS"))), Rc::new(Many1(Rc::new(Optional(Rc::new(Nonterminal(ustr("FILE")))))))]))])) },
-                    Statement::NonterminalDefinition { symbol: u("OPTION"), shell: None, expr: Rc::new(Sequence(vec![Rc::new(Terminal(ustr("--color"), None)), Rc::new(Nonterminal(ustr("WHEN")))])) },
-                    Statement::NonterminalDefinition { symbol: u("WHEN"), shell: None, expr: Rc::new(Alternative(vec![Rc::new(Terminal(ustr("always"), None)), Rc::new(Terminal(ustr("never"), None)), Rc::new(Terminal(ustr("auto"), None))])) },
-                ],
-            }
+            g.statements,
+            vec![
+                Statement::CallVariant { head: u("grep"), expr: Sequence(vec![Many1(Optional(Nonterminal(ustr("OPTION"), None).into()).into()).into(), Sequence(vec![Nonterminal(ustr("PATTERNS"), None).into(), Many1(Optional(Nonterminal(ustr("FILE"), None).into()).into()).into()]).into()]).into() },
+                Statement::NonterminalDefinition { symbol: u("OPTION"), shell: None, expr: Sequence(vec![Terminal(ustr("--color"), None).into(), Nonterminal(ustr("WHEN"), None).into()]).into() },
+                Statement::NonterminalDefinition { symbol: u("WHEN"), shell: None, expr: Alternative(vec![Terminal(ustr("always"), None).into(), Terminal(ustr("never"), None).into(), Terminal(ustr("auto"), None).into()]).into() },
+            ],
         );
     }
 
@@ -1066,25 +2197,23 @@ grep [<OPTION>]... <PATTERNS> [<FILE>]...;
 "#;
         let g = Grammar::parse(INPUT).unwrap();
         assert_eq!(
-            g,
-            Grammar {
-                statements: vec![
-                    Statement::NonterminalDefinition { symbol: u("OPTION"), shell: None, expr: Rc::new(Alternative(vec![
-                        Rc::new(Terminal(u("--extended-regexp"), None)),
-                        Rc::new(Terminal(u("--fixed-strings"), None)),
-                        Rc::new(Terminal(u("--basic-regexp"), None)),
-                        Rc::new(Terminal(u("--perl-regexp"), None)),
-                    ]))},
-                ],
-            }
+            g.statements,
+            vec![
+                Statement::NonterminalDefinition { symbol: u("OPTION"), shell: None, expr: Alternative(vec![
+                    Terminal(u("--extended-regexp"), None).into(),
+                    Terminal(u("--fixed-strings"), None).into(),
+                    Terminal(u("--basic-regexp"), None).into(),
+                    Terminal(u("--perl-regexp"), None).into(),
+                ]).into()},
+            ],
         );
     }
 
     #[test]
     fn nonterminal_resolution_order_detects_trivial_cycle() {
         let nonterminal_definitions = UstrMap::from_iter([
-            (u("FOO"), Rc::new(Nonterminal(u("BAR")))),
-            (u("BAR"), Rc::new(Nonterminal(u("FOO")))),
+            (u("FOO"), Nonterminal(u("BAR"), None).into()),
+            (u("BAR"), Nonterminal(u("FOO"), None).into()),
         ]);
         assert!(matches!(get_nonterminals_resolution_order(&nonterminal_definitions), Err(Error::NonterminalDefinitionsCycle(None))));
     }
@@ -1092,8 +2221,8 @@ grep [<OPTION>]... <PATTERNS> [<FILE>]...;
     #[test]
     fn nonterminal_resolution_order_detects_simple_cycle() {
         let nonterminal_definitions = UstrMap::from_iter([
-            (u("FOO"), Rc::new(Nonterminal(u("BAR")))),
-            (u("BAR"), Rc::new(Nonterminal(u("BAR")))),
+            (u("FOO"), Nonterminal(u("BAR"), None).into()),
+            (u("BAR"), Nonterminal(u("BAR"), None).into()),
         ]);
         assert!(matches!(&get_nonterminals_resolution_order(&nonterminal_definitions), Err(Error::NonterminalDefinitionsCycle(Some(path))) if path == &[u("BAR"), u("BAR")]));
     }
@@ -1101,9 +2230,9 @@ grep [<OPTION>]... <PATTERNS> [<FILE>]...;
     #[test]
     fn computes_nonterminals_resolution_order() {
         let nonterminal_definitions = UstrMap::from_iter([
-            (u("WHEN"), Rc::new(Alternative(vec![Rc::new(Terminal(u("always"), None)), Rc::new(Terminal(u("never"), None)), Rc::new(Terminal(u("auto"), None))]))),
-            (u("FOO"), Rc::new(Nonterminal(u("WHEN")))),
-            (u("OPTION"), Rc::new(Sequence(vec![Rc::new(Terminal(u("--color"), None)), Rc::new(Nonterminal(u("FOO")))]))),
+            (u("WHEN"), Alternative(vec![Terminal(u("always"), None).into(), Terminal(u("never"), None).into(), Terminal(u("auto"), None).into()]).into()),
+            (u("FOO"), Nonterminal(u("WHEN"), None).into()),
+            (u("OPTION"), Sequence(vec![Terminal(u("--color"), None).into(), Nonterminal(u("FOO"), None).into()]).into()),
         ]);
         assert_eq!(get_nonterminals_resolution_order(&nonterminal_definitions).unwrap(), vec![u("FOO"), u("OPTION")]);
     }
@@ -1115,15 +2244,13 @@ cargo [+{ rustup toolchain list | cut -d' ' -f1 }] [<OPTIONS>] [<COMMAND>];
 "#;
         let g = Grammar::parse(INPUT).unwrap();
         assert_eq!(
-            g,
-            Grammar {
-                statements: vec![
-                    Statement::CallVariant {
-                        head: u("cargo"),
-                        expr: Rc::new(Sequence(vec![Rc::new(Optional(Rc::new(Sequence(vec![Rc::new(Terminal(ustr("+"), None)), Rc::new(Command(u("rustup toolchain list | cut -d' ' -f1")))])))), Rc::new(Sequence(vec![Rc::new(Optional(Rc::new(Nonterminal(ustr("OPTIONS"))))), Rc::new(Optional(Rc::new(Nonterminal(ustr("COMMAND")))))]))])),
-                    },
-                ],
-            }
+            g.statements,
+            vec![
+                Statement::CallVariant {
+                    head: u("cargo"),
+                    expr: Sequence(vec![Optional(Sequence(vec![Terminal(ustr("+"), None).into(), Command(u("rustup toolchain list | cut -d' ' -f1"), None, Default::default()).into()]).into()).into(), Sequence(vec![Optional(Nonterminal(ustr("OPTIONS"), None).into()).into(), Optional(Nonterminal(ustr("COMMAND"), None).into()).into()]).into()]).into(),
+                },
+            ],
         );
     }
 
@@ -1135,20 +2262,18 @@ cargo [+<toolchain>] [<OPTIONS>] [<COMMAND>];
 "#;
         let g = Grammar::parse(INPUT).unwrap();
         assert_eq!(
-            g,
-            Grammar {
-                statements: vec![
-                    Statement::CallVariant {
-                        head: u("cargo"),
-                        expr: Rc::new(Sequence(vec![Rc::new(Optional(Rc::new(Sequence(vec![Rc::new(Terminal(ustr("+"), None)), Rc::new(Nonterminal(ustr("toolchain")))])))), Rc::new(Sequence(vec![Rc::new(Optional(Rc::new(Nonterminal(ustr("OPTIONS"))))), Rc::new(Optional(Rc::new(Nonterminal(ustr("COMMAND")))))]))])),
-                    },
-                    Statement::NonterminalDefinition {
-                        symbol: u("toolchain"),
-                        shell: None,
-                        expr: Rc::new(Command(u("rustup toolchain list | cut -d' ' -f1"))),
-                    },
-                ],
-            }
+            g.statements,
+            vec![
+                Statement::CallVariant {
+                    head: u("cargo"),
+                    expr: Sequence(vec![Optional(Sequence(vec![Terminal(ustr("+"), None).into(), Nonterminal(ustr("toolchain"), None).into()]).into()).into(), Sequence(vec![Optional(Nonterminal(ustr("OPTIONS"), None).into()).into(), Optional(Nonterminal(ustr("COMMAND"), None).into()).into()]).into()]).into(),
+                },
+                Statement::NonterminalDefinition {
+                    symbol: u("toolchain"),
+                    shell: None,
+                    expr: Command(u("rustup toolchain list | cut -d' ' -f1"), None, Default::default()).into(),
+                },
+            ],
         );
     }
 
@@ -1163,7 +2288,7 @@ cargo [+<toolchain>] [<OPTIONS>] [<COMMAND>];
     fn parses_term_descr() {
         const INPUT: &str = r#"--extended-regexp "PATTERNS are extended regular expressions""#;
         let ("", e) = terminal_opt_description_expr(INPUT).unwrap() else { panic!("parsing error"); };
-        assert_eq!(e, Expr::Terminal(ustr("--extended-regexp"), Some(ustr("PATTERNS are extended regular expressions"))));
+        assert_eq!(e, ExprKind::Terminal(ustr("--extended-regexp"), Some(ustr("PATTERNS are extended regular expressions"))));
     }
 
     #[test]
@@ -1175,7 +2300,7 @@ grep --extended-regexp "PATTERNS are extended regular expressions";
         assert_eq!(
             g.statements,
             vec![
-                Statement::CallVariant { head: u("grep"), expr: Rc::new(Terminal(ustr("--extended-regexp"), Some(u("PATTERNS are extended regular expressions")))) }
+                Statement::CallVariant { head: u("grep"), expr: Terminal(ustr("--extended-regexp"), Some(u("PATTERNS are extended regular expressions"))).into() }
             ],
         );
     }
@@ -1188,7 +2313,133 @@ grep [<OPTION>]... <PATTERNS> [<FILE>]...;
 <OPTION> ::= always | never | auto;
 "#;
         let g = Grammar::parse(INPUT).unwrap();
-        assert!(matches!(ValidGrammar::from_grammar(g), Err(Error::DuplicateNonterminalDefinition(nonterm, None)) if nonterm == "OPTION"));
+        let Err(Error::Located(error, snippet)) = ValidGrammar::from_grammar(&g) else { panic!() };
+        assert!(matches!(*error, Error::DuplicateNonterminalDefinition(nonterm, None) if nonterm == "OPTION"));
+        assert_eq!(snippet, r#" --> <stdin>:3:1
+  |
+3 | <OPTION> ::= --color <WHEN>;
+  | ^^^^^^^^ first defined here
+ --> <stdin>:4:1
+  |
+4 | <OPTION> ::= always | never | auto;
+  | ^^^^^^^^ defined again here
+  = help: remove one of the definitions"#);
+    }
+
+    #[test]
+    fn reports_parsing_error_location() {
+        const INPUT: &str = "grep --color;\n\tgrep [--help;\n";
+        let Err(Error::Located(error, snippet)) = Grammar::parse_source(INPUT, Some(u("grep.usage"))) else { panic!() };
+        assert!(matches!(*error, Error::ParsingError(ref fragment) if fragment == "[--help;"));
+        assert_eq!(snippet, " --> grep.usage:2:7\n  |\n2 | \tgrep [--help;\n  | \t     ^^^^^^^^ unexpected input\n  = help: expected a command line, e.g. `grep [<OPTION>]... <PATTERNS>;` or a nonterminal definition, e.g. `<OPTION> ::= --color;`");
+    }
+
+    #[test]
+    fn reports_invalid_repeat_range_location() {
+        let Err(Error::Located(error, snippet)) = Grammar::parse_source("cp <PATH>{1,1000000};\n", Some(u("cp.usage"))) else { panic!() };
+        assert!(matches!(*error, Error::RepeatCountTooLarge(ref count, 255) if count == "1000000"));
+        assert_eq!(snippet, " --> cp.usage:1:13\n  |\n1 | cp <PATH>{1,1000000};\n  |             ^^^^^^^ count too large\n  = help: repetition counts can be at most 255, use ... for an unbounded number of repetitions");
+
+        let Err(Error::Located(error, snippet)) = Grammar::parse_source("cp <PATH>{3,1};\n", Some(u("cp.usage"))) else { panic!() };
+        assert!(matches!(*error, Error::EmptyRepeatRange(ref range) if range == "{3,1}"));
+        assert!(snippet.contains("^^^^^ empty repetition range"));
+    }
+
+    #[test]
+    fn reports_missing_semicolon() {
+        let Err(Error::Located(_, snippet)) = Grammar::parse("grep --color") else { panic!() };
+        assert!(snippet.starts_with(" --> <stdin>:1:13\n"));
+        assert!(snippet.contains("^ unexpected end of input"));
+        assert!(snippet.ends_with("= help: statements need to be terminated with `;`"));
+    }
+
+    #[test]
+    fn records_expression_spans() {
+        const INPUT: &str = "# grep\ngrep --color=<WHEN> [-v | -q]...;\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let Statement::CallVariant { expr, .. } = &g.statements[0] else { panic!() };
+        let text = |e: &Expr| {
+            let span = e.span.as_ref().unwrap();
+            INPUT[span.start..span.end].to_string()
+        };
+        assert_eq!(text(expr), "--color=<WHEN> [-v | -q]...");
+        let Sequence(children) = &expr.kind else { panic!() };
+        assert_eq!(text(&children[0]), "--color=<WHEN>");
+        let Prefix(_, value) = &children[0].kind else { panic!() };
+        assert_eq!(text(value), "<WHEN>");
+        assert_eq!(value.span.as_ref().unwrap().line_column(), (2, 14));
+        assert_eq!(text(&children[1]), "[-v | -q]...");
+        let Many1(optional) = &children[1].kind else { panic!() };
+        let Optional(alternative) = &optional.kind else { panic!() };
+        assert_eq!(text(alternative), "-v | -q");
+    }
+
+    #[test]
+    fn locates_nonterminal_references() {
+        const INPUT: &str = "cmd --file \"takes a <FILE>\" <FILE>;\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let snippet = g.locate_nonterminal_reference(u("FILE")).unwrap();
+        assert!(snippet.starts_with(" --> <stdin>:1:29\n"));
+        assert!(g.locate_nonterminal_reference(u("DIRECTORY")).is_none());
+    }
+
+    #[test]
+    fn binds_command_definitions() {
+        const INPUT: &str = "cmd <branch=BRANCH>;\n<BRANCH> ::= { git branch } | <REMOTE>;\n<REMOTE> ::= { git remote };\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let validated = ValidGrammar::from_grammar(&g).unwrap();
+        assert_eq!(validated.commands[0].expr, Alternative(vec![
+            Command(u("git branch"), Some(u("branch")), Default::default()).into(),
+            Command(u("git remote"), Some(u("branch")), Default::default()).into(),
+        ]).into());
+    }
+
+    #[test]
+    fn reports_non_command_binding_location() {
+        const INPUT: &str = "cmd <branch=BRANCH>;\n<BRANCH> ::= main | { git branch };\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let Err(Error::Located(error, snippet)) = ValidGrammar::from_grammar(&g) else { panic!() };
+        assert!(matches!(*error, Error::NonCommandBinding(nonterm, binding) if nonterm == "BRANCH" && binding == "branch"));
+        assert!(snippet.contains("bound here"));
+    }
+
+    #[test]
+    fn rejects_annotated_specializations() {
+        const INPUT: &str = "cmd <FILE>;\n<FILE@bash> ::= { compgen -A file } @timeout(1s);\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let Err(Error::Located(error, _)) = ValidGrammar::from_grammar(&g) else { panic!() };
+        assert!(matches!(*error, Error::AnnotatedSpecialization(nonterm, Some(shell)) if nonterm == "FILE" && shell == "bash"));
+    }
+
+    #[test]
+    fn specializes_paths_by_glob() {
+        const INPUT: &str = "cmd <PATH:*.{yml,yaml}> <src=PATH:*.rs>;";
+        let g = Grammar::parse(INPUT).unwrap();
+        let validated = ValidGrammar::from_grammar(&g).unwrap();
+        let spec = validated.specializations.get(&u("PATH:*.{yml,yaml}")).unwrap();
+        assert!(spec.native);
+        assert_eq!(spec.zsh, Some(u("_path_files -/; _path_files -g '(*\\.yml|*\\.yaml)'")));
+        assert!(validated.specializations.contains_key(&u("PATH:*.rs")));
+        assert!(validated.undefined_nonterminals.is_empty());
+    }
+
+    #[test]
+    fn reports_invalid_glob_location() {
+        const INPUT: &str = "cmd <PATH:*.{yml>;";
+        let g = Grammar::parse(INPUT).unwrap();
+        let Err(Error::Located(error, snippet)) = ValidGrammar::from_grammar(&g) else { panic!() };
+        assert!(matches!(*error, Error::InvalidGlob(glob) if glob == "*.{yml"));
+        assert!(snippet.contains("1 | cmd <PATH:*.{yml>;\n  |      ^^^^^^^^^^^ invalid glob"));
+    }
+
+    #[test]
+    fn reports_nonterminal_cycle_location() {
+        const INPUT: &str = "cmd <FOO>;\n<FOO> ::= <BAR>;\n<BAR> ::= <FOO>;\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let Err(Error::Located(error, snippet)) = ValidGrammar::from_grammar(&g) else { panic!() };
+        assert!(matches!(*error, Error::NonterminalDefinitionsCycle(_)));
+        assert!(snippet.contains("2 | <FOO> ::= <BAR>;\n  | ^^^^^ part of the cycle"));
+        assert!(snippet.contains("3 | <BAR> ::= <FOO>;\n  | ^^^^^ part of the cycle"));
     }
 
     #[test]
@@ -1198,7 +2449,7 @@ grep [<OPTION>]... <PATTERNS> [<FILE>]...;
         assert_eq!(g.statements, vec![
             Statement::CallVariant {
                 head: u("foo.sh"),
-                expr: Rc::new(Optional(Rc::new(Terminal(ustr("-h"), None)))),
+                expr: Optional(Terminal(ustr("-h"), None).into()).into(),
             },
         ]);
     }
@@ -1225,16 +2476,100 @@ ls <FILE>;
             vec![
                 Statement::CallVariant {
                     head: u("ls"),
-                    expr: Rc::new(Nonterminal(ustr("FILE"))), // should not get expanded because it's specialized
+                    expr: Nonterminal(ustr("FILE"), None).into(), // should not get expanded because it's specialized
                 },
-                NonterminalDefinition { symbol: ustr("FILE"), shell: Some(ustr("bash")), expr: Rc::new(Command(ustr(r#"compgen -A file "$1""#))) },
-                NonterminalDefinition { symbol: ustr("FILE"), shell: Some(ustr("fish")), expr: Rc::new(Command(ustr(r#"__fish_complete_path "$1""#))) },
+                NonterminalDefinition { symbol: ustr("FILE"), shell: Some(ustr("bash")), expr: Command(ustr(r#"compgen -A file "$1""#), None, Default::default()).into() },
+                NonterminalDefinition { symbol: ustr("FILE"), shell: Some(ustr("fish")), expr: Command(ustr(r#"__fish_complete_path "$1""#), None, Default::default()).into() },
             ],
         );
-        let v = ValidGrammar::from_grammar(g).unwrap();
+        let v = ValidGrammar::from_grammar(&g).unwrap();
         let spec = v.specializations.get(&ustr("FILE")).unwrap();
         assert_eq!(spec.bash, Some(ustr(r#"compgen -A file "$1""#)));
         assert_eq!(spec.fish, Some(ustr(r#"__fish_complete_path "$1""#)));
         assert_eq!(spec.zsh, None);
     }
+
+    #[test]
+    fn parses_nushell_specialization() {
+        const INPUT: &str = r#"
+ls <FILE>;
+<FILE@nushell> ::= { ls | get name };
+"#;
+        let g = Grammar::parse(INPUT).unwrap();
+        let v = ValidGrammar::from_grammar(&g).unwrap();
+        let spec = v.specializations.get(&ustr("FILE")).unwrap();
+        assert_eq!(spec.nushell, Some(ustr("ls | get name")));
+        assert_eq!(spec.bash, None);
+    }
+
+    #[test]
+    fn parses_include_statement() {
+        let g = Grammar::parse(r#"include "common.usage"; cmd <USER>;"#).unwrap();
+        assert_eq!(g.statements, vec![
+            Statement::Include(u("common.usage")),
+            Statement::CallVariant { head: u("cmd"), expr: Nonterminal(u("USER"), None).into() },
+        ]);
+    }
+
+    #[test]
+    fn resolves_includes_relative_to_including_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("lib")).unwrap();
+        std::fs::write(dir.path().join("lib/common.usage"), r#"include "signals.usage"; <USER> ::= root;"#).unwrap();
+        std::fs::write(dir.path().join("lib/signals.usage"), r#"<SIGNAL> ::= HUP;"#).unwrap();
+        let main_path = dir.path().join("main.usage");
+        std::fs::write(&main_path, r#"include "lib/common.usage"; cmd <SIGNAL> <USER>;"#).unwrap();
+
+        let g = Grammar::parse(&std::fs::read_to_string(&main_path).unwrap()).unwrap().resolve_includes(Some(&main_path)).unwrap();
+        let v = ValidGrammar::from_grammar(&g).unwrap();
+        assert_eq!(v.commands[0].expr, Sequence(vec![Terminal(u("HUP"), None).into(), Terminal(u("root"), None).into()]).into());
+    }
+
+    #[test]
+    fn detects_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.usage"), r#"include "b.usage";"#).unwrap();
+        std::fs::write(dir.path().join("b.usage"), r#"include "a.usage";"#).unwrap();
+        let main_path = dir.path().join("a.usage");
+        let g = Grammar::parse(&std::fs::read_to_string(&main_path).unwrap()).unwrap();
+        let Err(Error::Located(error, _)) = g.resolve_includes(Some(&main_path)) else { panic!() };
+        assert!(matches!(*error, Error::IncludeCycle(cycle) if cycle.len() == 3));
+    }
+
+    #[test]
+    fn reports_both_files_of_duplicate_definition() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("common.usage"), r#"<USER> ::= root;"#).unwrap();
+        let main_path = dir.path().join("main.usage");
+        std::fs::write(&main_path, r#"include "common.usage"; cmd <USER>; <USER> ::= nobody;"#).unwrap();
+        let source = Some(ustr(&main_path.to_string_lossy()));
+        let g = Grammar::parse_source(&std::fs::read_to_string(&main_path).unwrap(), source).unwrap().resolve_includes(Some(&main_path)).unwrap();
+        let Err(Error::Located(error, _)) = ValidGrammar::from_grammar(&g) else { panic!() };
+        let Error::ConflictingNonterminalDefinitions(nonterminal, None, first, second) = *error else { panic!() };
+        assert_eq!(nonterminal, "USER");
+        assert!(first.ends_with("common.usage"));
+        assert!(second.ends_with("main.usage"));
+    }
+
+    #[test]
+    fn parses_multiple_commands() {
+        const INPUT: &str = r#"
+foo <SUBCOMMAND>;
+foo --version;
+foo-admin (<SUBCOMMAND> | reset);
+<SUBCOMMAND> ::= start | stop;
+"#;
+        let g = Grammar::parse(INPUT).unwrap();
+        let v = ValidGrammar::from_grammar(&g).unwrap();
+        assert_eq!(v.commands.iter().map(|c| c.command).collect::<Vec<_>>(), vec![ustr("foo"), ustr("foo-admin")]);
+        assert_eq!(v.get_command("foo").unwrap().expr, Alternative(vec![
+            Alternative(vec![Terminal(ustr("start"), None).into(), Terminal(ustr("stop"), None).into()]).into(),
+            Terminal(ustr("--version"), None).into(),
+        ]).into());
+        assert_eq!(v.get_command("foo-admin").unwrap().expr, Alternative(vec![
+            Alternative(vec![Terminal(ustr("start"), None).into(), Terminal(ustr("stop"), None).into()]).into(),
+            Terminal(ustr("reset"), None).into(),
+        ]).into());
+        assert!(v.undefined_nonterminals.is_empty());
+    }
 }
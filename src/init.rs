@@ -0,0 +1,106 @@
+use std::io::Write;
+
+use complgen::Result;
+
+use crate::bash::quote_bash_string;
+use crate::fish::escape_fish_string;
+
+
+// `complgen init <shell>` outputs shell code that makes every grammar in a directory complete the command
+// named after its file stem, e.g. `grep.usage` completes `grep`.  Registering a command is cheap: its grammar
+// only gets read once completion is actually requested, by `complgen complete`.
+
+
+// `directory` defaults to ~/.config/complgen, expanded by the shell at startup
+pub fn write_bash_init<W: Write>(buffer: &mut W, complgen: &str, directory: Option<&str>) -> Result<()> {
+    let directory = directory.map(quote_bash_string).unwrap_or_else(|| r#""$HOME/.config/complgen""#.to_string());
+    let complgen = quote_bash_string(complgen);
+    write!(buffer, r#"_complgen_dir={directory}
+
+_complgen_jit () {{
+    local stem=${{1##*/}}
+    local line
+    COMPREPLY=()
+    while IFS= read -r line; do
+        COMPREPLY+=("$line")
+    done < <({complgen} complete "$_complgen_dir/$stem.usage" bash -- "$((COMP_CWORD - 1))" "${{COMP_WORDS[@]:1}}")
+    return 0
+}}
+
+_complgen_register () {{
+    local path stem
+    for path in "$_complgen_dir"/*.usage; do
+        [[ -e $path ]] || continue
+        stem=${{path##*/}}
+        complete -F _complgen_jit "${{stem%.usage}}"
+    done
+}}
+_complgen_register
+unset -f _complgen_register
+"#)?;
+    Ok(())
+}
+
+
+pub fn write_fish_init<W: Write>(buffer: &mut W, complgen: &str, directory: Option<&str>) -> Result<()> {
+    let directory = directory.map(|directory| format!(r#""{}""#, escape_fish_string(directory))).unwrap_or_else(|| "$HOME/.config/complgen".to_string());
+    let complgen = format!(r#""{}""#, escape_fish_string(complgen));
+    write!(buffer, r#"set --global _complgen_dir {directory}
+
+function _complgen_jit
+    set --local tokens (commandline --tokenize --current-process --cut-at-cursor)
+    set --local current (commandline --current-token --cut-at-cursor)
+    set --local stem (string replace --regex '.*/' '' -- $tokens[1])
+    {complgen} complete $_complgen_dir/$stem.usage fish -- (math (count $tokens) - 1) $tokens[2..-1] $current
+end
+
+for path in $_complgen_dir/*.usage
+    complete --command (string replace --regex '^.*/(.*)\.usage$' '$1' -- $path) --no-files --arguments '(_complgen_jit)'
+end
+"#)?;
+    Ok(())
+}
+
+
+// Needs to come after `compinit` as it relies on `compdef`
+pub fn write_zsh_init<W: Write>(buffer: &mut W, complgen: &str, directory: Option<&str>) -> Result<()> {
+    let directory = directory.map(quote_bash_string).unwrap_or_else(|| r#""$HOME/.config/complgen""#.to_string());
+    let complgen = quote_bash_string(complgen);
+    write!(buffer, r#"_complgen_dir={directory}
+
+_complgen_jit () {{
+    local stem=${{words[1]:t}}
+    local -a w=("${{(@Q)words[2,CURRENT]}}")
+    eval "$({complgen} complete "$_complgen_dir/$stem.usage" zsh -- $((CURRENT - 2)) "${{w[@]}}")"
+    return 0
+}}
+
+() {{
+    local f
+    for f in "$_complgen_dir"/*.usage(N); do
+        compdef _complgen_jit ${{f:t:r}}
+    done
+}}
+"#)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_the_directory() {
+        let mut buffer: Vec<u8> = Default::default();
+        write_bash_init(&mut buffer, "/usr/bin/complgen", Some("/tmp/my grammars")).unwrap();
+        let script = String::from_utf8(buffer).unwrap();
+        assert!(script.starts_with("_complgen_dir='/tmp/my grammars'\n"));
+        assert!(script.contains("'/usr/bin/complgen' complete "));
+
+        let mut buffer: Vec<u8> = Default::default();
+        write_fish_init(&mut buffer, "complgen", None).unwrap();
+        let script = String::from_utf8(buffer).unwrap();
+        assert!(script.starts_with("set --global _complgen_dir $HOME/.config/complgen\n"));
+    }
+}
@@ -0,0 +1,413 @@
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use complgen::{Error, Result, StateId};
+use hashbrown::{HashMap, HashSet};
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use ustr::{Ustr, ustr};
+
+use crate::dfa::DFA;
+use crate::grammar::{CommandAnnotations, Specialization};
+use crate::regex::{Input, MatchAnythingInput, Position};
+
+
+/// Bumped whenever documents change meaning.  Readers reject documents of any other version as well as fields they
+/// don't know about, rather than offering completions a newer document rules out.
+pub const FORMAT_VERSION: u32 = 1;
+
+
+/// The top level JSON object:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "commands": [
+///     {
+///       "command": "grep",
+///       "starting_state": 1,
+///       "accepting_states": [2, 3],
+///       "transitions": [
+///         {"from": 1, "to": 2, "input": {"type": "literal", "literal": "--color", "description": "use markers to highlight the matching strings"}, "once": [0]},
+///         {"from": 1, "to": 2, "input": {"type": "prefix", "prefix": "--color="}, "once": [1], "exclusive": [0]},
+///         {"from": 1, "to": 2, "input": {"type": "literal", "literal": "--no-color"}, "exclusive": [1]},
+///         {"from": 2, "to": 3, "input": {"type": "nonterminal", "name": "PATH", "specialization": {"bash": "compgen -A file \"$1\"", "native": true}}},
+///         {"from": 2, "to": 3, "input": {"type": "command", "command": "ls", "binding": "file", "timeout_ms": 500, "cache_ttl_ms": 60000}, "bindings": ["file"]}
+///       ],
+///       "exclusive_groups": [[0, 1]],
+///       "end_of_options_states": [3]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// There's one automaton per command defined in the grammar.  States are integers.  A word is matched by
+/// following the transition whose input matches it:
+///   * `literal`: the word is equal to `literal`.  `description` is optional.
+///   * `prefix`: the word starts with `prefix`.  The rest of the word is matched starting at the `to` state.
+///   * `nonterminal`: any word.  Completions come from the `specialization` for the shell in question, if any.
+///     Keys are shell names (`bash`, `fish`, `zsh`, `powershell`, `nushell`, `elvish`, `xonsh`) and `generic`.
+///     `native` marks predefined nonterminals like `PATH` that `complgen complete` completes without a shell.
+///   * `command`: any word.  Completions are the lines output by running `command` in a POSIX shell.
+///
+/// `nonterminal` and `command` inputs may have a `binding`: the name of the environment variable the matched
+/// word is passed to external commands in, e.g. `repo` for `<repo=PATH>` in the grammar.  A state has at most one
+/// transition matching any word, even if several nonterminals or commands can match a word there, so the
+/// transition's `bindings` list all the names the word gets bound to and are the ones to go by.
+///
+/// `command` inputs may also have a `timeout_ms` after which the command is abandoned and a `cache_ttl_ms` for
+/// how long its output may be reused, from `@timeout(...)` and `@cache(...)` in the grammar.
+///
+/// Input is accepted if matching ends in one of the `accepting_states`.
+///
+/// `literal` and `prefix` transitions may have a `once` list.  They come from `@once(...)` in the grammar: once a
+/// transition has been followed while matching the preceding words, transitions sharing any of its `once` ids
+/// shouldn't be offered as completions anymore.
+///
+/// They may also have an `exclusive` list, the tags of the `@exclusive(...)` alternatives they start.  Each
+/// `@exclusive(...)` in the grammar has one entry in `exclusive_groups`, listing the tags of its alternatives: once
+/// a transition has been followed, transitions tagged with the other tags of its group shouldn't be offered anymore.
+/// Both are optional.
+///
+/// `end_of_options_states` is optional too.  It lists the states reached through `<END_OF_OPTIONS>` in the
+/// grammar, in which literals starting with `-` shouldn't be offered.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Document {
+    version: u32,
+    commands: Vec<Automaton>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Automaton {
+    command: String,
+    starting_state: StateId,
+    accepting_states: Vec<StateId>,
+    transitions: Vec<Transition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclusive_groups: Vec<Vec<u32>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    end_of_options_states: Vec<StateId>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Transition {
+    from: StateId,
+    to: StateId,
+    input: TransitionInput,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bindings: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    once: Vec<Position>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclusive: Vec<u32>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TransitionInput {
+    Literal { literal: String, description: Option<String> },
+    Prefix { prefix: String },
+    Nonterminal {
+        name: String,
+        specialization: Option<SpecializationShells>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binding: Option<String>,
+    },
+    Command {
+        command: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binding: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_ttl_ms: Option<u64>,
+    },
+}
+
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecializationShells {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fish: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    zsh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    powershell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nushell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    elvish: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    xonsh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    generic: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    native: bool,
+}
+
+
+fn to_millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+
+fn to_string(s: Option<Ustr>) -> Option<String> {
+    s.map(|s| s.to_string())
+}
+
+
+fn to_ustr(s: &Option<String>) -> Option<Ustr> {
+    s.as_deref().map(ustr)
+}
+
+
+impl From<&Specialization> for SpecializationShells {
+    fn from(spec: &Specialization) -> Self {
+        Self {
+            bash: to_string(spec.bash),
+            fish: to_string(spec.fish),
+            zsh: to_string(spec.zsh),
+            powershell: to_string(spec.powershell),
+            nushell: to_string(spec.nushell),
+            elvish: to_string(spec.elvish),
+            xonsh: to_string(spec.xonsh),
+            generic: to_string(spec.generic),
+            native: spec.native,
+        }
+    }
+}
+
+
+impl From<&SpecializationShells> for Specialization {
+    fn from(spec: &SpecializationShells) -> Self {
+        Self {
+            bash: to_ustr(&spec.bash),
+            fish: to_ustr(&spec.fish),
+            zsh: to_ustr(&spec.zsh),
+            powershell: to_ustr(&spec.powershell),
+            nushell: to_ustr(&spec.nushell),
+            elvish: to_ustr(&spec.elvish),
+            xonsh: to_ustr(&spec.xonsh),
+            generic: to_ustr(&spec.generic),
+            native: spec.native,
+        }
+    }
+}
+
+
+impl From<&Input> for TransitionInput {
+    fn from(input: &Input) -> Self {
+        match input {
+            Input::Literal(literal, description) => Self::Literal { literal: literal.to_string(), description: to_string(*description) },
+            Input::Prefix(prefix) => Self::Prefix { prefix: prefix.to_string() },
+            Input::Any(MatchAnythingInput::Nonterminal(name, specialization, binding)) => Self::Nonterminal { name: name.to_string(), specialization: specialization.as_ref().map(SpecializationShells::from), binding: to_string(*binding) },
+            Input::Any(MatchAnythingInput::Command(command, binding, annotations)) => Self::Command {
+                command: command.to_string(),
+                binding: to_string(*binding),
+                timeout_ms: annotations.timeout.map(to_millis),
+                cache_ttl_ms: annotations.cache_ttl.map(to_millis),
+            },
+        }
+    }
+}
+
+
+impl From<&TransitionInput> for Input {
+    fn from(input: &TransitionInput) -> Self {
+        match input {
+            TransitionInput::Literal { literal, description } => Input::Literal(ustr(literal), to_ustr(description)),
+            TransitionInput::Prefix { prefix } => Input::Prefix(ustr(prefix)),
+            TransitionInput::Nonterminal { name, specialization, binding } => Input::Any(MatchAnythingInput::Nonterminal(ustr(name), specialization.as_ref().map(Specialization::from), to_ustr(binding))),
+            TransitionInput::Command { command, binding, timeout_ms, cache_ttl_ms } => {
+                let annotations = CommandAnnotations {
+                    timeout: timeout_ms.map(Duration::from_millis),
+                    cache_ttl: cache_ttl_ms.map(Duration::from_millis),
+                };
+                Input::Any(MatchAnythingInput::Command(ustr(command), to_ustr(binding), annotations))
+            },
+        }
+    }
+}
+
+
+fn automaton_from_dfa(command: Ustr, dfa: &DFA) -> Automaton {
+    let mut transitions: Vec<(StateId, Input, StateId)> = dfa.transitions.iter().flat_map(|(from, tos)| tos.iter().map(|(input, to)| (*from, *input, *to))).collect();
+    transitions.sort_unstable();
+    Automaton {
+        command: command.to_string(),
+        starting_state: dfa.starting_state,
+        accepting_states: dfa.accepting_states.iter().collect(),
+        transitions: transitions.iter().map(|(from, input, to)| {
+            let bindings = match input {
+                Input::Any(_) => dfa.bindings.get(&(*from, *to)).map(|names| names.iter().map(|name| name.to_string()).collect()).unwrap_or_default(),
+                Input::Literal(..) | Input::Prefix(_) => vec![],
+            };
+            let once = dfa.once.get(&(*from, *input)).cloned().unwrap_or_default();
+            let exclusive = dfa.exclusive.get(&(*from, *input)).cloned().unwrap_or_default();
+            Transition { from: *from, to: *to, input: TransitionInput::from(input), bindings, once, exclusive }
+        }).collect(),
+        exclusive_groups: dfa.exclusive_groups.clone(),
+        end_of_options_states: dfa.end_of_options_states.iter().collect(),
+    }
+}
+
+
+fn dfa_from_automaton(automaton: &Automaton) -> (Ustr, DFA) {
+    let mut transitions: HashMap<StateId, HashMap<Input, StateId>> = Default::default();
+    let mut input_symbols: HashSet<Input> = Default::default();
+    let mut bindings: HashMap<(StateId, StateId), Vec<Ustr>> = Default::default();
+    let mut once: HashMap<(StateId, Input), Vec<Position>> = Default::default();
+    let mut exclusive: HashMap<(StateId, Input), Vec<u32>> = Default::default();
+    for transition in &automaton.transitions {
+        let input = Input::from(&transition.input);
+        transitions.entry(transition.from).or_default().insert(input, transition.to);
+        input_symbols.insert(input);
+        if input.matches_anything() && !transition.bindings.is_empty() {
+            bindings.insert((transition.from, transition.to), transition.bindings.iter().map(|name| ustr(name)).collect());
+        }
+        if !input.matches_anything() && !transition.once.is_empty() {
+            once.insert((transition.from, input), transition.once.clone());
+        }
+        if !input.matches_anything() && !transition.exclusive.is_empty() {
+            exclusive.insert((transition.from, input), transition.exclusive.clone());
+        }
+    }
+    let dfa = DFA {
+        starting_state: automaton.starting_state,
+        transitions,
+        accepting_states: RoaringBitmap::from_iter(automaton.accepting_states.iter().copied()),
+        input_symbols: Rc::new(input_symbols),
+        bindings,
+        once,
+        exclusive,
+        exclusive_groups: automaton.exclusive_groups.clone(),
+        end_of_options_states: RoaringBitmap::from_iter(automaton.end_of_options_states.iter().copied()),
+    };
+    (ustr(&automaton.command), dfa)
+}
+
+
+impl Document {
+    pub fn from_dfas(dfas: &[(Ustr, DFA)]) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            commands: dfas.iter().map(|(command, dfa)| automaton_from_dfa(*command, dfa)).collect(),
+        }
+    }
+
+    pub fn to_dfas(&self) -> Result<Vec<(Ustr, DFA)>> {
+        if self.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedFormatVersion(self.version, FORMAT_VERSION));
+        }
+        Ok(self.commands.iter().map(dfa_from_automaton).collect())
+    }
+}
+
+
+pub fn write_dfas<W: Write>(buffer: &mut W, dfas: &[(Ustr, DFA)]) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *buffer, &Document::from_dfas(dfas))?;
+    writeln!(buffer)?;
+    Ok(())
+}
+
+
+pub fn read_dfas<R: Read>(reader: R) -> Result<Vec<(Ustr, DFA)>> {
+    let document: Document = serde_json::from_reader(reader)?;
+    document.to_dfas()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bumpalo::Bump;
+    use ustr::UstrMap;
+    use crate::grammar::{Grammar, ValidGrammar};
+    use crate::regex::AugmentedRegex;
+
+    fn to_json(dfas: &[(Ustr, DFA)]) -> String {
+        let mut buffer: Vec<u8> = Default::default();
+        write_dfas(&mut buffer, dfas).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        const INPUT: &str = r#"
+grep [@once(-i | -v)] [<OPTION>]... [@exclusive(-E | -F)] [<END_OF_OPTIONS>] <PATTERN> [<FILE>]...;
+<OPTION> ::= --color=(always | never) | --context "print NUM lines of context" <NUM>;
+<NUM> ::= { seq 1 9 };
+<FILE@bash> ::= { compgen -A file "$1" };
+"#;
+        let g = Grammar::parse(INPUT).unwrap();
+        let validated = ValidGrammar::from_grammar(&g).unwrap();
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&validated.commands[0].expr, &validated.specializations, &arena).unwrap();
+        let dfas = vec![(ustr("grep"), DFA::from_regex(&regex).unwrap().minimize())];
+
+        let json = to_json(&dfas);
+        let read = read_dfas(json.as_bytes()).unwrap();
+        assert_eq!(to_json(&read), json);
+        assert_eq!(read[0].0, "grep");
+        assert_eq!(read[0].1.once, dfas[0].1.once);
+        let once_literals: Vec<Ustr> = read[0].1.get_once_transitions().into_iter().map(|(_, literal, _, _)| literal).collect();
+        assert_eq!(once_literals, vec![ustr("-i"), ustr("-v")]);
+        assert_eq!(read[0].1.exclusive, dfas[0].1.exclusive);
+        assert_eq!(read[0].1.exclusive_groups, vec![vec![0, 1]]);
+        assert_eq!(read[0].1.end_of_options_states, dfas[0].1.end_of_options_states);
+        assert!(!read[0].1.end_of_options_states.is_empty());
+        assert_eq!(read[0].1.get_command_transitions().len(), 1);
+        // <FILE> follows <PATTERN> both with and without `--` before it
+        assert_eq!(read[0].1.get_bash_command_transitions().len(), 2);
+    }
+
+    #[test]
+    fn round_trips_command_annotations() {
+        const INPUT: &str = "kubectl get pods --namespace <NAMESPACE>;\n<NAMESPACE> ::= { kubectl get ns } @timeout(500ms) @cache(1m);\n";
+        let g = Grammar::parse(INPUT).unwrap();
+        let validated = ValidGrammar::from_grammar(&g).unwrap();
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&validated.commands[0].expr, &validated.specializations, &arena).unwrap();
+        let dfas = vec![(ustr("kubectl"), DFA::from_regex(&regex).unwrap().minimize())];
+
+        let json = to_json(&dfas);
+        assert!(json.contains(r#""timeout_ms": 500"#));
+        assert!(json.contains(r#""cache_ttl_ms": 60000"#));
+        let read = read_dfas(json.as_bytes()).unwrap();
+        let annotations = read[0].1.get_command_annotations();
+        assert_eq!(annotations[&ustr("kubectl get ns")], CommandAnnotations { timeout: Some(Duration::from_millis(500)), cache_ttl: Some(Duration::from_secs(60)) });
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&crate::grammar::ExprKind::Terminal(ustr("foo"), None).into(), &specs, &arena).unwrap();
+        let json = to_json(&[(ustr("cmd"), DFA::from_regex(&regex).unwrap())]).replace(r#""version": 1"#, r#""version": 999"#);
+        assert!(matches!(read_dfas(json.as_bytes()), Err(Error::UnsupportedFormatVersion(999, 1))));
+    }
+
+    // Documents written by later versions may use fields that rule out completions
+    #[test]
+    fn rejects_unknown_fields() {
+        let arena = Bump::new();
+        let specs = UstrMap::default();
+        let regex = AugmentedRegex::from_expr(&crate::grammar::ExprKind::Terminal(ustr("foo"), None).into(), &specs, &arena).unwrap();
+        let json = to_json(&[(ustr("cmd"), DFA::from_regex(&regex).unwrap())]);
+        assert!(read_dfas(json.as_bytes()).is_ok());
+        for (field, replacement) in [(r#""command": "cmd""#, r#""command": "cmd", "unknown": []"#), (r#""from""#, r#""unknown": [], "from""#), (r#""type""#, r#""unknown": [], "type""#)] {
+            assert!(json.contains(field));
+            assert!(read_dfas(json.replacen(field, replacement, 1).as_bytes()).is_err(), "{replacement}");
+        }
+    }
+}
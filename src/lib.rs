@@ -6,24 +6,61 @@ pub enum Error {
     #[error("Parsing error: {:?}", .0)]
     ParsingError(String),
 
+    #[error("Empty repetition range: {}", .0)]
+    EmptyRepeatRange(String),
+
+    #[error("Repetition count too large: {} (at most {} allowed)", .0, .1)]
+    RepeatCountTooLarge(String, u32),
+
     #[error("Grammar needs to contain at least one call variant, e.g. grep;")]
     MissingCallVariants,
 
-    #[error("One one command is allowed in completions definition")]
-    VaryingCommandNames(Vec<Ustr>),
-
     #[error("Nonterminal definitions depend on each other cyclically")]
     NonterminalDefinitionsCycle(Option<Vec<Ustr>>),
 
-    #[error("Duplicate nonterminal definition")]
+    #[error("Duplicate nonterminal definition: <{}{}>", .0, .1.map(|shell| format!("@{shell}")).unwrap_or_default())]
     DuplicateNonterminalDefinition(Ustr, Option<Ustr>),
 
+    #[error("Nonterminal <{}{}> is defined both in {} and {}", .0, .1.map(|shell| format!("@{shell}")).unwrap_or_default(), .2, .3)]
+    ConflictingNonterminalDefinitions(Ustr, Option<Ustr>, String, String),
+
+    #[error("Cannot include {}: {}", .0, .1)]
+    IncludeError(String, std::io::Error),
+
+    #[error("Cyclic include: {}", .0.join(" -> "))]
+    IncludeCycle(Vec<String>),
+
     #[error("Unknown shell: {}", .0)]
     UnknownShell(Ustr),
 
     #[error("Can only specialize external commands: {}@{:?}", .0, .1)]
     NonCommandSpecialization(Ustr, Option<Ustr>),
 
+    #[error("Can only annotate commands in nonterminals without shell-specific definitions: {}{}", .0, .1.map(|shell| format!("@{shell}")).unwrap_or_default())]
+    AnnotatedSpecialization(Ustr, Option<Ustr>),
+
+    #[error("Can only bind words completed by external commands: <{}={}>", .1, .0)]
+    NonCommandBinding(Ustr, Ustr),
+
+    #[error("Invalid glob in <PATH:{}>: brackets and braces need to be balanced", .0)]
+    InvalidGlob(Ustr),
+
+    #[error("Grammar too large: its automaton would need more than {} states", .0)]
+    TooManyStates(u64),
+
+    // The error along with a rustc-like snippet of the grammar pointing at its cause
+    #[error("{}\n{}", .0, .1)]
+    Located(Box<Error>, String),
+
+    #[error("Unsupported format version {} (expected {}), please recompile", .0, .1)]
+    UnsupportedFormatVersion(u32, u32),
+
+    #[error("JSON error: {}", .0)]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("DFA cache encoding error: {}", .0)]
+    CacheEncodeError(#[from] rmp_serde::encode::Error),
+
     #[error("UTF-8 conversion error")]
     FromUtf8Error(#[from] FromUtf8Error),
 
@@ -36,4 +73,4 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub type StateId = u16;
+pub type StateId = u32;
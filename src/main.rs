@@ -1,23 +1,32 @@
 use std::io::{BufWriter, Write, Read};
+use std::path::Path;
 use std::rc::Rc;
 
 use anyhow::Context;
 use bumpalo::Bump;
 use clap::Parser;
 
-use complete::get_completions;
 use grammar::{ValidGrammar, Grammar};
+use ustr::{Ustr, ustr};
 
 use crate::dfa::DFA;
-use crate::regex::AugmentedRegex;
-use crate::zsh::escape_zsh_string;
+use crate::regex::{AugmentedRegex, Input, MatchAnythingInput};
 
 mod grammar;
 mod dfa;
 mod bash;
 mod zsh;
 mod fish;
+mod powershell;
+mod nushell;
+mod elvish;
+mod xonsh;
+mod json;
+mod cache;
+mod serve;
+mod init;
 mod complete;
+mod glob;
 mod regex;
 mod scrape;
 
@@ -33,6 +42,9 @@ struct Cli {
 enum Mode {
     Complete(CompleteArgs),
     Compile(CompileArgs),
+    Check(CheckArgs),
+    Serve(ServeArgs),
+    Init(InitArgs),
     Scrape,
 }
 
@@ -47,6 +59,8 @@ enum Shell {
     Bash(CompleteShellArgs),
     Fish(CompleteShellArgs),
     Zsh(CompleteShellArgs),
+    Powershell(CompleteShellArgs),
+    Nushell(CompleteShellArgs),
 }
 
 #[derive(clap::Args)]
@@ -54,6 +68,14 @@ struct CompleteArgs {
     #[clap(long)]
     railroad_svg: Option<String>,
 
+    /// Needed only if the grammar defines more than one command
+    #[clap(long)]
+    command: Option<String>,
+
+    /// Read an automaton written by `complgen compile --json` instead of a grammar
+    #[clap(long)]
+    json: bool,
+
     usage_file_path: String,
 
     #[clap(subcommand)]
@@ -61,6 +83,44 @@ struct CompleteArgs {
 }
 
 
+/// Reports grammar ambiguities.  Exits with a non-zero status if there are any, e.g. to fail a CI job.
+#[derive(clap::Args)]
+struct CheckArgs {
+    usage_file_path: String,
+}
+
+
+/// Answers completion requests over a Unix domain socket, see src/serve.rs for the protocol
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Directory containing the grammars, e.g. ~/.config/complgen
+    grammars_directory: String,
+
+    /// Defaults to $XDG_RUNTIME_DIR/complgen.sock.  Required if XDG_RUNTIME_DIR isn't set.
+    #[clap(long)]
+    socket: Option<String>,
+}
+
+
+#[derive(Clone, clap::ValueEnum)]
+enum InitShell {
+    Bash,
+    Fish,
+    Zsh,
+}
+
+
+/// Outputs shell code completing every command having a grammar in a directory, e.g. eval "$(complgen init bash)"
+#[derive(clap::Args)]
+struct InitArgs {
+    shell: InitShell,
+
+    /// Directory containing the grammars, ~/.config/complgen by default.  `grep.usage` completes `grep`.
+    #[clap(long)]
+    dir: Option<String>,
+}
+
+
 #[derive(clap::Args)]
 struct CompileArgs {
     usage_file_path: String,
@@ -74,9 +134,29 @@ struct CompileArgs {
     #[clap(long)]
     zsh_script: Option<String>,
 
+    #[clap(long)]
+    powershell_script: Option<String>,
+
+    #[clap(long)]
+    nushell_script: Option<String>,
+
+    #[clap(long)]
+    elvish_script: Option<String>,
+
+    #[clap(long)]
+    xonsh_script: Option<String>,
+
     #[clap(long)]
     dfa_dot: Option<String>,
 
+    /// Minimized automata in a versioned JSON format, see src/json.rs
+    #[clap(long)]
+    json: Option<String>,
+
+    /// Precompiled automata for `complgen complete`, stored next to the grammar file
+    #[clap(long)]
+    dfa_bin: bool,
+
     #[clap(long)]
     railroad_svg: Option<String>,
 }
@@ -92,61 +172,115 @@ fn get_file_or_stdin(path: &str) -> anyhow::Result<Box<dyn Read>> {
 }
 
 
-fn complete(args: &CompleteArgs) -> anyhow::Result<()> {
-    let mut input_file = get_file_or_stdin(&args.usage_file_path).context(args.usage_file_path.to_owned())?;
+fn get_path_unless_stdin(path: &str) -> Option<&Path> {
+    if path == "-" {
+        None
+    } else {
+        Some(Path::new(path))
+    }
+}
+
+
+fn parse_grammar_file(usage_file_path: &str) -> anyhow::Result<Grammar> {
+    let mut input_file = get_file_or_stdin(usage_file_path).context(usage_file_path.to_owned())?;
     let mut input: String = Default::default();
-    input_file.read_to_string(&mut input)?;
-    let grammar = Grammar::parse(&input)?;
-    let validated = ValidGrammar::from_grammar(grammar)?;
+    input_file.read_to_string(&mut input).context(usage_file_path.to_owned())?;
+    let path = get_path_unless_stdin(usage_file_path);
+    let grammar = match path {
+        Some(path) => Grammar::parse_source(&input, Some(ustr(&path.to_string_lossy())))?,
+        None => Grammar::parse(&input)?,
+    };
+    let grammar = grammar.resolve_includes(path)?;
+    Ok(grammar)
+}
+
+
+// One minimized DFA per command, in the order of the grammar
+fn get_dfas(validated: &ValidGrammar) -> complgen::Result<Vec<(Ustr, DFA)>> {
+    let arena = Bump::new();
+    let mut dfas: Vec<(Ustr, DFA)> = Default::default();
+    for command in &validated.commands {
+        log::debug!("Grammar -> Regex ({})", command.command);
+        let regex = AugmentedRegex::from_expr(&command.expr, &validated.specializations, &arena)?;
+
+        log::debug!("Regex -> DFA ({})", command.command);
+        let dfa = DFA::from_regex(&regex)?;
+
+        log::debug!("Minimizing DFA ({})", command.command);
+        let dfa = dfa.minimize();
+
+        dfas.push((command.command, dfa));
+    }
+    Ok(dfas)
+}
+
+
+fn pick_command<'a, T>(items: &'a [T], get_name: impl Fn(&T) -> Ustr, requested: &Option<String>) -> anyhow::Result<&'a T> {
+    match requested {
+        Some(name) => items.iter().find(|item| get_name(item) == name.as_str()).ok_or_else(|| anyhow::anyhow!("Command not found in the grammar: {}", name)),
+        None if items.len() == 1 => Ok(&items[0]),
+        None => anyhow::bail!("The grammar defines multiple commands, please pick one with --command"),
+    }
+}
+
+
+// Automata compiled with `complgen compile --json` are used as is.  Grammars go through the on-disk cache.
+fn get_completed_dfa(args: &CompleteArgs) -> anyhow::Result<DFA> {
+    if args.json {
+        if args.railroad_svg.is_some() {
+            anyhow::bail!("--railroad-svg needs a grammar, not a compiled automaton");
+        }
+        let json_file = get_file_or_stdin(&args.usage_file_path)?;
+        let dfas = json::read_dfas(json_file).context(args.usage_file_path.to_owned())?;
+        let (_, dfa) = pick_command(&dfas, |(command, _)| *command, &args.command)?;
+        return Ok(dfa.clone());
+    }
+
+    let path = get_path_unless_stdin(&args.usage_file_path);
+    if let (Some(path), None) = (path, &args.railroad_svg) {
+        if let Some(dfas) = cache::load(path) {
+            log::debug!("Using cached DFA: {}", cache::get_cache_path(path).display());
+            let (_, dfa) = pick_command(&dfas, |(command, _)| *command, &args.command)?;
+            return Ok(dfa.clone());
+        }
+    }
+
+    let grammar = parse_grammar_file(&args.usage_file_path)?;
+    let validated = ValidGrammar::from_grammar(&grammar)?;
+    let command = pick_command(&validated.commands, |command| command.command, &args.command)?;
 
     if let Some(railroad_svg_path) = &args.railroad_svg {
         let mut railroad_svg = get_file_or_stdout(&railroad_svg_path)?;
-        grammar::to_railroad_diagram(Rc::clone(&validated.expr), &mut railroad_svg)?;
+        grammar::to_railroad_diagram(Rc::clone(&command.expr), &mut railroad_svg)?;
     }
 
-    let arena = Bump::new();
-    let regex = AugmentedRegex::from_expr(&validated.expr, &validated.specializations, &arena);
-    let dfa = DFA::from_regex(&regex);
+    let dfas = get_dfas(&validated)?;
+    if let Some(path) = path {
+        // A read-only directory shouldn't break completion, it only makes it slower
+        if let Err(e) = cache::store(path, &grammar, &dfas) {
+            log::debug!("Unable to write {}: {}", cache::get_cache_path(path).display(), e);
+        }
+    }
+    let (_, dfa) = pick_command(&dfas, |(command, _)| *command, &args.command)?;
+    Ok(dfa.clone())
+}
+
+
+fn complete(args: &CompleteArgs) -> anyhow::Result<()> {
+    let dfa = get_completed_dfa(args)?;
 
     let (shell, completed_word_index, words) = match &args.shell {
         Shell::Bash(a) => (complete::Shell::Bash, a.completed_word_index, &a.words),
         Shell::Fish(a) => (complete::Shell::Fish, a.completed_word_index, &a.words),
         Shell::Zsh(a) => (complete::Shell::Zsh, a.completed_word_index, &a.words),
+        Shell::Powershell(a) => (complete::Shell::Powershell, a.completed_word_index, &a.words),
+        Shell::Nushell(a) => (complete::Shell::Nushell, a.completed_word_index, &a.words),
     };
 
     let words_before_cursor: Vec<&str> = words.iter().map(|s| s.as_ref()).collect();
-
-    let completions = get_completions(&dfa, &words_before_cursor, completed_word_index, shell);
-
-    match args.shell {
-        Shell::Bash(_) => {
-            for (completion, _) in completions {
-                println!("{}", completion);
-            }
-        },
-        Shell::Fish(_) => {
-            for (completion, description) in completions {
-                println!("{}\t{}", completion, description);
-            }
-        },
-        Shell::Zsh(_) => {
-            let completions_array_initializer = itertools::join(completions.iter().map(|(completion, _)| format!(r#""{}""#, escape_zsh_string(completion))), " ");
-            println!(r#"local -a completions=({completions_array_initializer})"#);
-
-            let descriptions_array_initializer = itertools::join(completions.iter().map(|(completion, description)| {
-                if !description.is_empty() {
-                    format!(r#""{} ({})""#, escape_zsh_string(completion), escape_zsh_string(description))
-                }
-                else {
-                    format!(r#""{}""#, escape_zsh_string(completion))
-                }
-            }), " ");
-            println!(r#"local -a descriptions=({descriptions_array_initializer})"#);
-
-            println!(r#"compadd -d descriptions -a completions"#);
-        },
-    }
-
+    let cwd = std::env::current_dir()?;
+    let mut stdout = std::io::stdout().lock();
+    complete::write_completions(&mut stdout, &dfa, &words_before_cursor, completed_word_index, shell, &cwd)?;
     Ok(())
 }
 
@@ -162,74 +296,208 @@ fn get_file_or_stdout(path: &str) -> anyhow::Result<Box<dyn Write>> {
 
 
 fn compile(args: &CompileArgs) -> anyhow::Result<()> {
-    match (&args.railroad_svg, &args.dfa_dot, &args.bash_script, &args.fish_script, &args.zsh_script) {
-        (None, None, None, None, None) => {
-            eprintln!("Please specify at least one of --railroad-svg, --dfa-dot, --bash-script, --fish-script, --zsh-script options");
+    match (&args.railroad_svg, &args.dfa_dot, &args.json, args.dfa_bin, &args.bash_script, &args.fish_script, &args.zsh_script, &args.powershell_script, &args.nushell_script, &args.elvish_script, &args.xonsh_script) {
+        (None, None, None, false, None, None, None, None, None, None, None) => {
+            eprintln!("Please specify at least one of --railroad-svg, --dfa-dot, --json, --dfa-bin, --bash-script, --fish-script, --zsh-script, --powershell-script, --nushell-script, --elvish-script, --xonsh-script options");
             std::process::exit(1);
         },
         _ => {},
     }
 
-    let input = {
-        let mut usage_file = get_file_or_stdin(&args.usage_file_path)?;
-        let mut input = String::default();
-        usage_file.read_to_string(&mut input).context(args.usage_file_path.to_owned())?;
-        input
-    };
-
-    let grammar = Grammar::parse(&input)?;
-    let validated = ValidGrammar::from_grammar(grammar)?;
+    let grammar = parse_grammar_file(&args.usage_file_path)?;
+    let validated = ValidGrammar::from_grammar(&grammar)?;
 
-    if !validated.undefined_nonterminals.is_empty() {
-        let joined = itertools::join(validated.undefined_nonterminals, " ");
-        eprintln!("Warning: Undefined nonterminal(s): {}", joined);
+    let mut undefined_nonterminals: Vec<&Ustr> = validated.undefined_nonterminals.iter().collect();
+    undefined_nonterminals.sort_by_key(|nonterminal| nonterminal.as_str());
+    for nonterminal in undefined_nonterminals {
+        match grammar.locate_nonterminal_reference(*nonterminal) {
+            Some(snippet) => eprintln!("Warning: Undefined nonterminal <{nonterminal}>\n{snippet}"),
+            None => eprintln!("Warning: Undefined nonterminal <{nonterminal}>"),
+        }
     }
 
-    let arena = Bump::new();
-
     if let Some(railroad_svg_path) = &args.railroad_svg {
-        grammar::to_railroad_diagram_file(Rc::clone(&validated.expr), railroad_svg_path).context(railroad_svg_path.clone())?;
+        grammar::to_railroad_diagram_file(validated.to_railroad_expr(), railroad_svg_path).context(railroad_svg_path.clone())?;
     }
 
-    log::debug!("Grammar -> Regex");
-    let regex = AugmentedRegex::from_expr(&validated.expr, &validated.specializations, &arena);
-
-    log::debug!("Regex -> DFA");
-    let dfa = DFA::from_regex(&regex);
-
-    log::debug!("Minimizing DFA");
-    let dfa = dfa.minimize();
+    let dfas = get_dfas(&validated)?;
 
     if let Some(dot_file_path) = &args.dfa_dot {
         let mut dot_file = get_file_or_stdout(&dot_file_path)?;
-        dfa.to_dot(&mut dot_file).context(dot_file_path.clone())?;
+        for (_, dfa) in &dfas {
+            dfa.to_dot(&mut dot_file).context(dot_file_path.clone())?;
+        }
+    }
+
+    if let Some(path) = &args.json {
+        log::debug!("Writing JSON");
+        let json_file = get_file_or_stdout(path)?;
+        let mut writer = BufWriter::new(json_file);
+        json::write_dfas(&mut writer, &dfas).context(path.clone())?;
+    }
+
+    if args.dfa_bin {
+        let Some(path) = get_path_unless_stdin(&args.usage_file_path) else {
+            anyhow::bail!("--dfa-bin needs a grammar file, not stdin");
+        };
+        log::debug!("Writing DFA cache");
+        cache::store(path, &grammar, &dfas).context(cache::get_cache_path(path).to_string_lossy().to_string())?;
     }
 
     if let Some(path) = &args.bash_script {
         log::debug!("Writing Bash completion script");
         let script_file = get_file_or_stdout(path)?;
         let mut writer = BufWriter::new(script_file);
-        bash::write_completion_script(&mut writer, &validated.command, &dfa)?;
+        for (command, dfa) in &dfas {
+            bash::write_completion_script(&mut writer, command, dfa)?;
+        }
     }
 
     if let Some(path) = &args.fish_script {
         log::debug!("Writing Fish completion script");
         let script_file = get_file_or_stdout(path)?;
         let mut writer = BufWriter::new(script_file);
-        fish::write_completion_script(&mut writer, &validated.command, &dfa)?;
+        for (command, dfa) in &dfas {
+            fish::write_completion_script(&mut writer, command, dfa)?;
+        }
     }
 
     if let Some(path) = &args.zsh_script {
         log::debug!("Writing Zsh completion script");
         let script_file = get_file_or_stdout(path)?;
         let mut writer = BufWriter::new(script_file);
-        zsh::write_completion_script(&mut writer, &validated.command, &dfa)?;
+        for (command, dfa) in &dfas {
+            zsh::write_completion_script(&mut writer, command, dfa)?;
+        }
+    }
+
+    if let Some(path) = &args.powershell_script {
+        log::debug!("Writing PowerShell completion script");
+        let script_file = get_file_or_stdout(path)?;
+        let mut writer = BufWriter::new(script_file);
+        for (command, dfa) in &dfas {
+            powershell::write_completion_script(&mut writer, command, dfa)?;
+        }
+    }
+
+    if let Some(path) = &args.nushell_script {
+        log::debug!("Writing Nushell completion script");
+        let script_file = get_file_or_stdout(path)?;
+        let mut writer = BufWriter::new(script_file);
+        for (command, dfa) in &dfas {
+            nushell::write_completion_script(&mut writer, command, dfa)?;
+        }
+    }
+
+    if let Some(path) = &args.elvish_script {
+        log::debug!("Writing Elvish completion script");
+        let script_file = get_file_or_stdout(path)?;
+        let mut writer = BufWriter::new(script_file);
+        elvish::write_preamble(&mut writer)?;
+        for (command, dfa) in &dfas {
+            elvish::write_completion_script(&mut writer, command, dfa)?;
+        }
+    }
+
+    if let Some(path) = &args.xonsh_script {
+        log::debug!("Writing xonsh completion script");
+        let script_file = get_file_or_stdout(path)?;
+        let mut writer = BufWriter::new(script_file);
+        xonsh::write_preamble(&mut writer)?;
+        for (command, dfa) in &dfas {
+            xonsh::write_completion_script(&mut writer, command, dfa)?;
+        }
     }
 
     Ok(())
 }
 
 
+fn format_input(input: &Input) -> String {
+    match input {
+        Input::Literal(literal, _) => literal.to_string(),
+        Input::Prefix(prefix) => prefix.to_string(),
+        Input::Any(MatchAnythingInput::Nonterminal(name, ..)) => format!("<{name}>"),
+        Input::Any(MatchAnythingInput::Command(cmd, ..)) => format!("{{{cmd}}}"),
+    }
+}
+
+
+// Prefixes, e.g. `--color=`, share a single shell word with whatever follows them
+fn format_path(command: Ustr, path: &[Input]) -> String {
+    let mut result = command.to_string();
+    let mut previous_is_prefix = false;
+    for input in path {
+        if !previous_is_prefix {
+            result.push(' ');
+        }
+        result.push_str(&format_input(input));
+        previous_is_prefix = matches!(input, Input::Prefix(_));
+    }
+    result
+}
+
+
+fn check(args: &CheckArgs) -> anyhow::Result<()> {
+    let grammar = parse_grammar_file(&args.usage_file_path)?;
+    let validated = ValidGrammar::from_grammar(&grammar)?;
+
+    let arena = Bump::new();
+    let mut num_ambiguities = 0;
+    let mut num_unreachable = 0;
+    for command in &validated.commands {
+        let regex = AugmentedRegex::from_expr(&command.expr, &validated.specializations, &arena)?;
+        let dfa = DFA::from_regex(&regex)?.minimize();
+        let followpos = regex.followpos();
+        for ambiguity in dfa.get_ambiguities() {
+            // Point at the grammar elements clashing in the ambiguous state
+            let positions = regex.get_positions_after(&followpos, &ambiguity.path);
+            let locate = |input: &Input| -> Vec<String> {
+                let mut locations: Vec<String> = Default::default();
+                for pos in &positions {
+                    let pos = usize::try_from(pos).unwrap();
+                    if regex.input_from_position.get(pos) != Some(input) {
+                        continue;
+                    }
+                    if let Some(location) = regex.span_from_position[pos].as_ref().map(|span| span.location()) {
+                        if !locations.contains(&location) {
+                            locations.push(location);
+                        }
+                    }
+                }
+                locations
+            };
+            let literal_locations = locate(&ambiguity.literal);
+            let match_anything_locations = locate(&ambiguity.match_anything);
+            let literal = match ambiguity.literal {
+                Input::Prefix(prefix) => format!("{prefix}*"),
+                input => format_input(&input),
+            };
+            let location = literal_locations.first().cloned().unwrap_or_else(|| args.usage_file_path.clone());
+            let match_anything = format_input(&ambiguity.match_anything);
+            let match_anything = match match_anything_locations.is_empty() {
+                true => match_anything,
+                false => format!("{match_anything} ({})", match_anything_locations.join(", ")),
+            };
+            println!("{location}: ambiguity after `{}`: {match_anything} also matches `{literal}`", format_path(command.command, &ambiguity.path));
+            num_ambiguities += 1;
+        }
+        for member in dfa.get_unreachable_exclusive_members() {
+            println!("{}: `{member}` from @exclusive(...) in `{}` is never offered: another member of its group always precedes it", args.usage_file_path, command.command);
+            num_unreachable += 1;
+        }
+    }
+
+    if num_ambiguities > 0 {
+        anyhow::bail!("Ambiguities found: {num_ambiguities}.  Shell scripts always pick the literal while `complgen complete` considers both.");
+    }
+    if num_unreachable > 0 {
+        anyhow::bail!("Unreachable @exclusive(...) members found: {num_unreachable}");
+    }
+    Ok(())
+}
+
+
 fn scrape() -> anyhow::Result<()> {
     let input: String = {
         let mut input = String::default();
@@ -243,12 +511,34 @@ fn scrape() -> anyhow::Result<()> {
 }
 
 
+fn init(args: &InitArgs) -> anyhow::Result<()> {
+    // Integrations keep working regardless of PATH
+    let complgen = std::env::current_exe().map(|path| path.to_string_lossy().to_string()).unwrap_or_else(|_| "complgen".to_string());
+    let mut stdout = std::io::stdout().lock();
+    match args.shell {
+        InitShell::Bash => init::write_bash_init(&mut stdout, &complgen, args.dir.as_deref())?,
+        InitShell::Fish => init::write_fish_init(&mut stdout, &complgen, args.dir.as_deref())?,
+        InitShell::Zsh => init::write_zsh_init(&mut stdout, &complgen, args.dir.as_deref())?,
+    }
+    Ok(())
+}
+
+
 fn main() -> anyhow::Result<()> {
     env_logger::init();
     let args = Cli::parse();
     match args.mode {
         Mode::Complete(args) => complete(&args)?,
         Mode::Compile(args) => compile(&args)?,
+        Mode::Check(args) => check(&args)?,
+        Mode::Serve(args) => {
+            let socket_path = match args.socket {
+                Some(socket) => std::path::PathBuf::from(socket),
+                None => serve::get_default_socket_path()?,
+            };
+            serve::serve(Path::new(&args.grammars_directory), &socket_path)?
+        },
+        Mode::Init(args) => init(&args)?,
         Mode::Scrape => scrape()?,
     };
     Ok(())
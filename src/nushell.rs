@@ -0,0 +1,332 @@
+use std::io::Write;
+
+use complgen::{StateId, Result};
+use hashbrown::HashMap;
+use ustr::{Ustr, UstrMap, ustr};
+use crate::dfa::DFA;
+
+
+// Nushell double quoted strings interpret backslash escapes, so both backslashes and double quotes need escaping.
+pub fn escape_nushell_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>, directories included
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let regex = crate::glob::to_regex(glob);
+    format!(r#"try {{ ls --all ($"($cur)*" | into glob) | where {{|f| $f.type == dir or ($f.name | path basename) =~ "{}" }} | get name }} catch {{ [] }}"#, escape_nushell_string(&format!("^(?:{regex})$")))
+}
+
+
+/// `literals`: a list of all literals; a literal's id is its index
+///
+/// `descriptions`: a list of literals' descriptions, indexed by literal's id.  Empty if there's no description.
+///
+/// `transitions`: a table with the columns:
+///   * from: source state number
+///   * literal: literal's id
+///   * to: destination state number
+///
+/// `prefix_transitions`: same as `transitions` but the literals only match the beginning of a word, e.g.
+/// `--color=`.  The rest of the word is matched starting at the destination state.  Longer prefixes come first.
+///
+/// `match_anything_transitions`: a table with the columns:
+///   * from: source state number
+///   * to: destination state number
+///
+/// An entry in the `match_anything_transitions` table indicates that there's a fallback transition that
+/// accepts any word
+///
+/// `bindings`: a table with the columns:
+///   * from: source state number of a match-anything transition
+///   * to: target state number of that transition
+///   * name: a name the word matched by the transition gets bound to
+///
+/// `once`: a table with the columns:
+///   * from: source state number of a literal (or prefix) transition
+///   * literal: literal id of that transition
+///   * id: id of an @once(...) option the transition stands for
+///
+/// Once a transition has been taken, none of the transitions sharing an id with it is offered anymore
+///
+/// `exclusive`: a table with the columns:
+///   * from: source state number of a literal (or prefix) transition
+///   * literal: literal id of that transition
+///   * tag: tag of an @exclusive(...) alternative the transition starts
+///
+/// `exclusions`: a table with the columns:
+///   * tag: tag of an @exclusive(...) alternative
+///   * excluding: tag of another alternative of the same @exclusive(...)
+///
+/// Once a transition has been taken, none of the transitions starting the other alternatives is offered anymore
+///
+/// `end_of_options_states`: a list of the numbers of states reached through <END_OF_OPTIONS>, i.e. after which only
+/// positionals follow
+///
+fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
+    let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id, literal, description.unwrap_or(ustr("")))).collect();
+
+    let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, literal, description)| ((*literal, *description), *id)).collect();
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| format!(r#""{}""#, escape_nushell_string(literal))), ", ");
+    writeln!(buffer, r#"    let literals = [{literals}]"#)?;
+    let descriptions: String = itertools::join(all_literals.iter().map(|(_, _, description)| format!(r#""{}""#, escape_nushell_string(description))), ", ");
+    writeln!(buffer, r#"    let descriptions = [{descriptions}]"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    let transitions = ["#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_literal_transitions_from(state);
+        for (input, description, to) in transitions {
+            let literal_id = literal_id_from_input_description.get(&(input, description)).unwrap();
+            writeln!(buffer, r#"        {{from: {state}, literal: {literal_id}, to: {to}}}"#)?;
+        }
+    }
+    writeln!(buffer, r#"    ]"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    let prefix_transitions = ["#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        for (prefix, to) in transitions {
+            let literal_id = literal_id_from_input_description.get(&(prefix, ustr(""))).unwrap();
+            writeln!(buffer, r#"        {{from: {state}, literal: {literal_id}, to: {to}}}"#)?;
+        }
+    }
+    writeln!(buffer, r#"    ]"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    let match_anything_transitions = ["#)?;
+    for (from, to) in dfa.get_match_anything_transitions() {
+        writeln!(buffer, r#"        {{from: {from}, to: {to}}}"#)?;
+    }
+    writeln!(buffer, r#"    ]"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    let bindings = ["#)?;
+    for (from, to, names) in dfa.get_binding_transitions() {
+        for name in names {
+            writeln!(buffer, r#"        {{from: {from}, to: {to}, name: "{name}"}}"#)?;
+        }
+    }
+    writeln!(buffer, r#"    ]"#)?;
+
+    writeln!(buffer, r#"    let once = ["#)?;
+    for (from, literal, description, ids) in dfa.get_once_transitions() {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        for id in ids {
+            writeln!(buffer, r#"        {{from: {from}, literal: {literal_id}, id: {id}}}"#)?;
+        }
+    }
+    writeln!(buffer, r#"    ]"#)?;
+
+    writeln!(buffer, r#"    let exclusive = ["#)?;
+    for (from, literal, description, tags) in dfa.get_exclusive_transitions() {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        for tag in tags {
+            writeln!(buffer, r#"        {{from: {from}, literal: {literal_id}, tag: {tag}}}"#)?;
+        }
+    }
+    writeln!(buffer, r#"    ]"#)?;
+
+    writeln!(buffer, r#"    let exclusions = ["#)?;
+    for (tag, others) in dfa.get_exclusions() {
+        for excluding in others {
+            writeln!(buffer, r#"        {{tag: {tag}, excluding: {excluding}}}"#)?;
+        }
+    }
+    writeln!(buffer, r#"    ]"#)?;
+
+    // Always defined as Nushell rejects references to undefined variables when parsing
+    writeln!(buffer, r#"    let end_of_options_states = [{}]"#, itertools::join(dfa.end_of_options_states.iter(), ", "))?;
+
+    Ok(())
+}
+
+
+fn write_commands_completion_code<W: Write>(buffer: &mut W, function_prefix: &str, command_transitions: &[(StateId, Ustr)], id_from_command: &UstrMap<usize>) -> Result<()> {
+    if command_transitions.is_empty() {
+        return Ok(());
+    }
+
+    write!(buffer, r#"
+    let output = match $final_state {{
+"#)?;
+    for (state, cmd) in command_transitions {
+        let id = id_from_command.get(cmd).unwrap();
+        writeln!(buffer, r#"        {state} => {{ {function_prefix}{id} $final_cur $complgen_words $bound_words }}"#)?;
+    }
+    write!(buffer, r#"        _ => {{ "" }}
+    }}
+    for line in ($output | to text | lines) {{
+        let fields = ($line | split row "\t")
+        let description = if ($fields | length) > 1 {{ $fields.1 }} else {{ "" }}
+        $completions = ($completions | append {{value: $"($final_prefix)($fields.0)", description: $description}})
+    }}
+"#)?;
+
+    Ok(())
+}
+
+
+pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DFA) -> Result<()> {
+    let command_transitions = dfa.get_command_transitions();
+    let id_from_command: UstrMap<usize> = command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_command {
+        write!(buffer, r#"def "_{command}_{id}" [cur: string, words: string, bound: record] {{
+    with-env ({{COMPLGEN_WORDS: $words}} | merge $bound) {{ ^sh -c "{}" _ $cur }}
+}}
+
+"#, escape_nushell_string(cmd))?;
+    }
+
+    let specialized_command_transitions = dfa.get_nushell_command_transitions();
+    let id_from_specialized_command: UstrMap<usize> = specialized_command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_specialized_command {
+        write!(buffer, r#"def "_{command}_spec_{id}" [cur: string, words: string, bound: record] {{
+    with-env ({{COMPLGEN_WORDS: $words}} | merge $bound) {{ {cmd} }}
+}}
+
+"#)?;
+    }
+
+    writeln!(buffer, r#"def "nu-complete {command}" [context: string] {{"#)?;
+
+    write_tables(buffer, dfa)?;
+
+    write!(buffer, r#"
+    # Whitespace within quotes doesn't separate words and the quotes themselves aren't part of them, e.g.
+    # `cmd "foo bar"` has the words `cmd` and `foo bar`.  A quote left open is closed by the end of the line.
+    mut words = ($context
+        | parse --regex r#'(?<word>(?:"(?:[^"\\]|\\.)*(?:"|$)|'[^']*(?:'|$)|`[^`]*(?:`|$)|[^\s"'`])+)'#
+        | get word
+        | each {{|word| $word | str replace --all --regex r#'"((?:[^"\\]|\\.)*)"?|'([^']*)'?|`([^`]*)`?'# '$1$2$3' }})
+    if $context =~ '\s$' {{
+        $words = ($words | append '')
+    }}
+    let cword = ($words | length) - 1
+
+    let complgen_words = ($words | skip 1 | first ($cword - 1) | str join "\n")
+
+    # Words bound to names, e.g. by <repo=PATH>, exported to external commands
+    mut bound_words = {{}}
+
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    mut given_once = []
+    mut given_exclusive = []
+
+    mut state = {starting_state}
+    mut word_index = 1
+    while $word_index < $cword {{
+        # Closures can't capture mutable variables
+        let current_state = $state
+        let word = ($words | get $word_index)
+
+        let matching = ($transitions | where {{|t| $t.from == $current_state and ($literals | get $t.literal) == $word }})
+        if not ($matching | is-empty) {{
+            let literal_id = ($matching | first | get literal)
+            $given_once = ($given_once ++ ($once | where {{|o| $o.from == $current_state and $o.literal == $literal_id }} | each {{|o| $o.id }}))
+            $given_exclusive = ($given_exclusive ++ ($exclusive | where {{|x| $x.from == $current_state and $x.literal == $literal_id }} | each {{|x| $x.tag }}))
+            $state = ($matching | first | get to)
+            $word_index += 1
+            continue
+        }}
+
+        let matching = ($prefix_transitions | where {{|t| $t.from == $current_state and ($word | str starts-with ($literals | get $t.literal)) }})
+        if not ($matching | is-empty) {{
+            let literal_id = ($matching | first | get literal)
+            let prefix = ($literals | get $literal_id)
+            $given_once = ($given_once ++ ($once | where {{|o| $o.from == $current_state and $o.literal == $literal_id }} | each {{|o| $o.id }}))
+            $given_exclusive = ($given_exclusive ++ ($exclusive | where {{|x| $x.from == $current_state and $x.literal == $literal_id }} | each {{|x| $x.tag }}))
+            $state = ($matching | first | get to)
+            $words = ($words | update $word_index ($word | str substring ($prefix | str length)..))
+            continue
+        }}
+
+        let matching = ($match_anything_transitions | where {{|t| $t.from == $current_state }})
+        if not ($matching | is-empty) {{
+            let next_state = ($matching | first | get to)
+            for binding in ($bindings | where {{|b| $b.from == $current_state and $b.to == $next_state }}) {{
+                $bound_words = ($bound_words | upsert $binding.name $word)
+            }}
+            $state = $next_state
+            $word_index += 1
+            continue
+        }}
+
+        return []
+    }}
+"#, starting_state = dfa.starting_state)?;
+
+    // Literals like `--color=` only match the beginning of a word.  Strip them off the completed word one by one
+    // so that the remainder can be completed and put them back in front of every completion.
+    write!(buffer, r#"
+    let completed_word = ($words | get $cword)
+    mut completions = []
+    mut cur = $completed_word
+    mut word_prefix = ""
+    let given_once = $given_once
+    let given_exclusive = $given_exclusive
+    let excluded_tags = ($exclusions | where {{|e| $e.excluding in $given_exclusive }} | each {{|e| $e.tag }})
+    loop {{
+        let current_state = $state
+        let current_word = $cur
+        let state_literal_ids = (
+            ($transitions | where {{|t| $t.from == $current_state }} | each {{|t| $t.literal }})
+            ++ ($prefix_transitions | where {{|t| $t.from == $current_state }} | each {{|t| $t.literal }})
+        )
+
+        for literal_id in $state_literal_ids {{
+            let literal = ($literals | get $literal_id)
+            # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+            if ($word_prefix =~ '^-[^-]') and ($literal | str length) == 1 and ($word_prefix | str substring 1.. | str contains $literal) {{
+                continue
+            }}
+            # Options from @once(...) aren't offered again once given
+            if ($word_prefix | is-empty) and not ($once | where {{|o| $o.from == $current_state and $o.literal == $literal_id and $o.id in $given_once }} | is-empty) {{
+                continue
+            }}
+            # Alternatives of an @exclusive(...) aren't offered once another one of it has been given
+            if ($word_prefix | is-empty) and not ($exclusive | where {{|x| $x.from == $current_state and $x.literal == $literal_id and $x.tag in $excluded_tags }} | is-empty) {{
+                continue
+            }}
+            # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+            if ($word_prefix | is-empty) and ($current_state in $end_of_options_states) and ($literal | str starts-with '-') {{
+                continue
+            }}
+            $completions = ($completions | append {{value: $"($word_prefix)($literal)", description: ($descriptions | get $literal_id)}})
+        }}
+
+        let matching = ($prefix_transitions | where {{|t| $t.from == $current_state and ($current_word | str starts-with ($literals | get $t.literal)) }})
+        if ($matching | is-empty) {{
+            break
+        }}
+        let prefix = ($literals | get ($matching | first | get literal))
+        $state = ($matching | first | get to)
+        $word_prefix = $"($word_prefix)($prefix)"
+        $cur = ($cur | str substring ($prefix | str length)..)
+    }}
+
+    let final_state = $state
+    let final_cur = $cur
+    let final_prefix = $word_prefix
+"#)?;
+
+    write_commands_completion_code(buffer, &format!("_{command}_"), &command_transitions, &id_from_command)?;
+    write_commands_completion_code(buffer, &format!("_{command}_spec_"), &specialized_command_transitions, &id_from_specialized_command)?;
+
+    // The same completion may be reachable both as a literal and as a prefix, e.g. in short options clusters, in
+    // which case the one having a description wins.
+    write!(buffer, r#"
+    $completions
+        | where {{|c| $c.value | str starts-with $completed_word }}
+        | sort-by --reverse description
+        | uniq-by value
+        | sort-by value
+}}
+
+extern "{command}" [...args: string@"nu-complete {command}"]
+"#)?;
+
+    Ok(())
+}
@@ -0,0 +1,373 @@
+use std::io::Write;
+
+use complgen::{StateId, Result};
+use hashbrown::HashMap;
+use ustr::{Ustr, UstrMap, ustr};
+use crate::dfa::DFA;
+
+
+/// For use within single quotes.  PowerShell also treats typographic single quotes as quotes.
+pub fn escape_powershell_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            result.push(c);
+        }
+        result.push(c);
+    }
+    result
+}
+
+
+/// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>, directories included
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let regex = crate::glob::to_regex(glob);
+    format!(r#"[System.Management.Automation.CompletionCompleters]::CompleteFilename("$1") | Where-Object {{ $_.ResultType -eq 'ProviderContainer' -or $_.ListItemText -cmatch '{}' }} | ForEach-Object {{ $_.CompletionText }}"#, escape_powershell_string(&format!("^(?:{regex})$")))
+}
+
+
+/// `literals`: an array of all literals; a literal's id is its index
+///
+/// `descriptions`: a hashtable where:
+///   * key: literal's id
+///   * value: literal's description
+///
+/// `transitions`: a hashtable where:
+///   * key: source state number
+///   * value: a hashtable mapping a literal id to the destination state number
+///
+/// `prefix_transitions`: same as `transitions` but the keys are literals that only match the beginning of a
+/// word, e.g. `--color=`.  The rest of the word is matched starting at the target state.
+///
+/// `match_anything_transitions`: a hashtable where:
+///  * key: state number
+///  * value: state number
+///
+/// An entry in the `match_anything_transitions` hashtable indicates that there's a fallback transition that
+/// accepts any word
+///
+/// `bindings`: a hashtable where:
+///  * key: source and target state numbers of a match-anything transition, separated by a comma
+///  * value: an array of names the word matched by the transition gets bound to
+///
+/// `once`: a hashtable where:
+///  * key: state number and literal (or prefix) id, separated by a comma
+///  * value: an array of ids of the @once(...) options the transition stands for
+///
+/// Once a transition has been taken, none of the transitions sharing an id with it is offered anymore
+///
+/// `exclusive`: a hashtable where:
+///  * key: state number and literal (or prefix) id, separated by a comma
+///  * value: an array of tags of the @exclusive(...) alternatives the transition starts
+///
+/// `exclusions`: a hashtable where:
+///  * key: tag of an @exclusive(...) alternative
+///  * value: an array of tags of the other alternatives of the same @exclusive(...)
+///
+/// Once a transition has been taken, none of the transitions starting the other alternatives is offered anymore
+///
+/// `end_of_options_states`: a hashtable where:
+///  * key: number of a state reached through <END_OF_OPTIONS>, i.e. after which only positionals follow
+///  * value: $true
+///
+fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
+    let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id, literal, description.unwrap_or(ustr("")))).collect();
+
+    let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, literal, description)| ((*literal, *description), *id)).collect();
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| format!("'{}'", escape_powershell_string(literal))), ", ");
+    writeln!(buffer, r#"    $literals = @({literals})"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    $descriptions = @{{"#)?;
+    for (id, _, description) in all_literals.iter() {
+        if !description.is_empty() {
+            writeln!(buffer, r#"        {id} = '{}'"#, escape_powershell_string(description))?;
+        }
+    }
+    writeln!(buffer, r#"    }}"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    $transitions = @{{"#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_literal_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(input, description, to)| format!("{} = {}", literal_id_from_input_description.get(&(input, description)).unwrap(), to)), "; ");
+        writeln!(buffer, r#"        {state} = @{{ {state_transitions} }}"#)?;
+    }
+    writeln!(buffer, r#"    }}"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    $prefix_transitions = @{{"#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(prefix, to)| format!("{} = {}", literal_id_from_input_description.get(&(prefix, ustr(""))).unwrap(), to)), "; ");
+        writeln!(buffer, r#"        {state} = @{{ {state_transitions} }}"#)?;
+    }
+    writeln!(buffer, r#"    }}"#)?;
+    writeln!(buffer)?;
+
+    let match_anything_transitions = itertools::join(dfa.get_match_anything_transitions().into_iter().map(|(from, to)| format!("{from} = {to}")), "; ");
+    writeln!(buffer, r#"    $match_anything_transitions = @{{ {match_anything_transitions} }}"#)?;
+
+    let bindings = itertools::join(dfa.get_binding_transitions().into_iter().map(|(from, to, names)| format!("'{from},{to}' = @({})", itertools::join(names.iter().map(|name| format!("'{name}'")), ", "))), "; ");
+    writeln!(buffer, r#"    $bindings = @{{ {bindings} }}"#)?;
+
+    let once = itertools::join(dfa.get_once_transitions().into_iter().map(|(from, literal, description, ids)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!("'{from},{literal_id}' = @({})", itertools::join(ids, ", "))
+    }), "; ");
+    writeln!(buffer, r#"    $once = @{{ {once} }}"#)?;
+
+    let exclusive = itertools::join(dfa.get_exclusive_transitions().into_iter().map(|(from, literal, description, tags)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!("'{from},{literal_id}' = @({})", itertools::join(tags, ", "))
+    }), "; ");
+    writeln!(buffer, r#"    $exclusive = @{{ {exclusive} }}"#)?;
+    let exclusions = itertools::join(dfa.get_exclusions().into_iter().map(|(tag, others)| format!("{tag} = @({})", itertools::join(others, ", "))), "; ");
+    writeln!(buffer, r#"    $exclusions = @{{ {exclusions} }}"#)?;
+
+    let end_of_options_states = itertools::join(dfa.end_of_options_states.iter().map(|state| format!("{state} = $true")), "; ");
+    writeln!(buffer, r#"    $end_of_options_states = @{{ {end_of_options_states} }}"#)?;
+
+    Ok(())
+}
+
+
+fn write_commands_completion_code<W: Write>(buffer: &mut W, function_prefix: &str, command_transitions: &[(StateId, Ustr)], id_from_command: &UstrMap<usize>) -> Result<()> {
+    if command_transitions.is_empty() {
+        return Ok(());
+    }
+
+    let id_from_state = itertools::join(command_transitions.iter().map(|(state, cmd)| format!("{state} = {}", id_from_command.get(cmd).unwrap())), "; ");
+    write!(buffer, r#"
+    $command_ids = @{{ {id_from_state} }}
+    if ($command_ids.ContainsKey($state)) {{
+        # Environment variables are process-wide so don't leave them behind
+        $env:COMPLGEN_WORDS = $complgen_words
+        $saved_env = @{{}}
+        foreach ($name in $bound_words.Keys) {{
+            $saved_env[$name] = [System.Environment]::GetEnvironmentVariable($name)
+            [System.Environment]::SetEnvironmentVariable($name, $bound_words[$name])
+        }}
+        $lines = @(& "{function_prefix}$($command_ids[$state])" $cur)
+        foreach ($name in $saved_env.Keys) {{
+            [System.Environment]::SetEnvironmentVariable($name, $saved_env[$name])
+        }}
+        Remove-Item Env:COMPLGEN_WORDS -ErrorAction SilentlyContinue
+        foreach ($line in $lines) {{
+            $completion, $description = "$line" -split "`t", 2
+            Add-Completion "$word_prefix$completion" $description
+        }}
+    }}
+"#)?;
+
+    Ok(())
+}
+
+
+pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DFA) -> Result<()> {
+    let command_transitions = dfa.get_command_transitions();
+    let id_from_command: UstrMap<usize> = command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    // Generic commands are meant for POSIX shells, hence `sh`.  Arguments are passed to it as is, even on
+    // PowerShell versions that would otherwise mangle embedded quotes.
+    for (cmd, id) in &id_from_command {
+        write!(buffer, r#"function _{command}_{id} {{
+    $PSNativeCommandArgumentPassing = 'Standard'
+    sh -c '{}' _ $args[0]
+}}
+
+"#, escape_powershell_string(cmd))?;
+    }
+
+    let specialized_command_transitions = dfa.get_powershell_command_transitions();
+    let id_from_specialized_command: UstrMap<usize> = specialized_command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_specialized_command {
+        write!(buffer, r#"function _{command}_spec_{id} {{
+    $1 = $args[0]
+    {cmd}
+}}
+
+"#)?;
+    }
+
+    write!(buffer, r#"Register-ArgumentCompleter -Native -CommandName '{}' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+
+"#, escape_powershell_string(command))?;
+
+    write_tables(buffer, dfa)?;
+
+    write!(buffer, r#"
+    # Quoted words are taken without their quotes, e.g. "foo bar" as foo bar
+    $words = @($commandAst.CommandElements | Where-Object {{ $_.Extent.StartOffset -lt $cursorPosition }} | ForEach-Object {{
+        if ($_ -is [System.Management.Automation.Language.StringConstantExpressionAst]) {{ $_.Value }} else {{ $_.Extent.Text }}
+    }})
+    if ($wordToComplete -eq '') {{
+        $words += ''
+    }}
+    $cword = $words.Count - 1
+
+    $complgen_words = ($words | Select-Object -Skip 1 -First ($cword - 1)) -join "`n"
+
+    # Words bound to names, e.g. by <repo=PATH>, exported to external commands
+    $bound_words = @{{}}
+
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    $given_once = @{{}}
+    $given_exclusive = @{{}}
+
+    # Prefixes like --color= get stripped off while matching, so leave the words as typed alone
+    $remaining_words = @($words)
+
+    $state = {starting_state}
+    $word_index = 1
+    while ($word_index -lt $cword) {{
+        $word = $remaining_words[$word_index]
+
+        if ($transitions.ContainsKey($state)) {{
+            $state_transitions = $transitions[$state]
+            $word_matched = $false
+            foreach ($literal_id in $state_transitions.Keys) {{
+                if ($literals[$literal_id] -ceq $word) {{
+                    foreach ($id in $once["$state,$literal_id"]) {{
+                        $given_once[$id] = $true
+                    }}
+                    foreach ($tag in $exclusive["$state,$literal_id"]) {{
+                        $given_exclusive[$tag] = $true
+                    }}
+                    $state = $state_transitions[$literal_id]
+                    $word_index += 1
+                    $word_matched = $true
+                    break
+                }}
+            }}
+            if ($word_matched) {{
+                continue
+            }}
+        }}
+
+        if ($prefix_transitions.ContainsKey($state)) {{
+            $longest_prefix = ''
+            $longest_prefix_id = $null
+            $longest_prefix_to = $null
+            foreach ($entry in $prefix_transitions[$state].GetEnumerator()) {{
+                $prefix = $literals[$entry.Key]
+                if ($word.StartsWith($prefix, [System.StringComparison]::Ordinal) -and $prefix.Length -gt $longest_prefix.Length) {{
+                    $longest_prefix = $prefix
+                    $longest_prefix_id = $entry.Key
+                    $longest_prefix_to = $entry.Value
+                }}
+            }}
+            if ($null -ne $longest_prefix_to) {{
+                foreach ($id in $once["$state,$longest_prefix_id"]) {{
+                    $given_once[$id] = $true
+                }}
+                foreach ($tag in $exclusive["$state,$longest_prefix_id"]) {{
+                    $given_exclusive[$tag] = $true
+                }}
+                $state = $longest_prefix_to
+                $remaining_words[$word_index] = $word.Substring($longest_prefix.Length)
+                continue
+            }}
+        }}
+
+        if ($match_anything_transitions.ContainsKey($state)) {{
+            $next_state = $match_anything_transitions[$state]
+            if ($bindings.ContainsKey("$state,$next_state")) {{
+                foreach ($name in $bindings["$state,$next_state"]) {{
+                    $bound_words[$name] = $word
+                }}
+            }}
+            $state = $next_state
+            $word_index += 1
+            continue
+        }}
+
+        return
+    }}
+
+    # Completion text -> description.  The same completion may be reachable both as a literal and as a prefix,
+    # e.g. in short options clusters, in which case the one having a description wins.
+    $completions = [ordered]@{{}}
+    function Add-Completion($completion, $description) {{
+        if (-not $completions.Contains($completion) -or $description) {{
+            $completions[$completion] = $description
+        }}
+    }}
+"#, starting_state = dfa.starting_state)?;
+
+    // Literals like `--color=` only match the beginning of a word.  Strip them off the completed word one by one
+    // so that the remainder can be completed and put them back in front of every completion.
+    write!(buffer, r#"
+    $cur = $words[$cword]
+    $word_prefix = ''
+    while ($true) {{
+        $state_literal_ids = @()
+        if ($transitions.ContainsKey($state)) {{
+            $state_literal_ids += @($transitions[$state].Keys)
+        }}
+
+        $longest_prefix = ''
+        $longest_prefix_to = $null
+        if ($prefix_transitions.ContainsKey($state)) {{
+            $state_literal_ids += @($prefix_transitions[$state].Keys)
+            foreach ($entry in $prefix_transitions[$state].GetEnumerator()) {{
+                $prefix = $literals[$entry.Key]
+                if ($cur.StartsWith($prefix, [System.StringComparison]::Ordinal) -and $prefix.Length -gt $longest_prefix.Length) {{
+                    $longest_prefix = $prefix
+                    $longest_prefix_to = $entry.Value
+                }}
+            }}
+        }}
+
+        foreach ($literal_id in $state_literal_ids) {{
+            $literal = $literals[$literal_id]
+            # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+            if ($word_prefix -cmatch '^-[^-]' -and $literal.Length -eq 1 -and $word_prefix.Substring(1).Contains($literal)) {{
+                continue
+            }}
+            # Options from @once(...) aren't offered again once given
+            if (-not $word_prefix -and @($once["$state,$literal_id"] | Where-Object {{ $given_once.ContainsKey($_) }}).Count -gt 0) {{
+                continue
+            }}
+            # Alternatives of an @exclusive(...) aren't offered once another one of it has been given
+            if (-not $word_prefix -and @($exclusive["$state,$literal_id"] | ForEach-Object {{ $exclusions[$_] }} | Where-Object {{ $given_exclusive.ContainsKey($_) }}).Count -gt 0) {{
+                continue
+            }}
+            # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+            if (-not $word_prefix -and $end_of_options_states.ContainsKey($state) -and $literal.StartsWith('-')) {{
+                continue
+            }}
+            Add-Completion "$word_prefix$literal" $descriptions[$literal_id]
+        }}
+
+        if ($null -eq $longest_prefix_to) {{
+            break
+        }}
+        $state = $longest_prefix_to
+        $word_prefix += $longest_prefix
+        $cur = $cur.Substring($longest_prefix.Length)
+    }}
+"#)?;
+
+    write_commands_completion_code(buffer, &format!("_{command}_"), &command_transitions, &id_from_command)?;
+    write_commands_completion_code(buffer, &format!("_{command}_spec_"), &specialized_command_transitions, &id_from_specialized_command)?;
+
+    write!(buffer, r#"
+    foreach ($entry in $completions.GetEnumerator()) {{
+        if (-not $entry.Key.StartsWith($wordToComplete, [System.StringComparison]::Ordinal)) {{
+            continue
+        }}
+        $tooltip = if ($entry.Value) {{ $entry.Value }} else {{ $entry.Key }}
+        [System.Management.Automation.CompletionResult]::new($entry.Key, $entry.Key, 'ParameterValue', $tooltip)
+    }}
+}}
+"#)?;
+
+    Ok(())
+}
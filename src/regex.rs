@@ -4,25 +4,62 @@ use std::rc::Rc;
 use std::collections::{BTreeMap, BTreeSet};
 
 use bumpalo::Bump;
-use ustr::{Ustr, UstrMap};
+use ustr::{Ustr, UstrMap, ustr};
 use roaring::RoaringBitmap;
+use complgen::{Error, Result};
 
-use crate::grammar::{Expr, Specialization};
+use crate::grammar::{Expr, ExprKind, Span, Specialization, CommandAnnotations, ClusterMember, END_OF_OPTIONS, get_cluster_member};
 
 pub type Position = u32;
 
+// The binding is the name the matched word gets bound to, e.g. `repo` in <repo=PATH>
 #[derive(Debug, Clone, Copy)]
 pub enum MatchAnythingInput {
-    Nonterminal(Ustr, Option<Specialization>),
-    Command(Ustr),
+    Nonterminal(Ustr, Option<Specialization>, Option<Ustr>),
+    Command(Ustr, Option<Ustr>, CommandAnnotations),
+}
+
+// Unlike Input, tells apart what gets completed, e.g. different commands.  The specialization is left out as it
+// follows from the nonterminal's name.
+impl PartialEq for MatchAnythingInput {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nonterminal(left_name, _, left_binding), Self::Nonterminal(right_name, _, right_binding)) => (left_name, left_binding) == (right_name, right_binding),
+            (Self::Command(left_cmd, left_binding, left_annotations), Self::Command(right_cmd, right_binding, right_annotations)) => (left_cmd, left_binding, left_annotations) == (right_cmd, right_binding, right_annotations),
+            _ => false,
+        }
+    }
+}
+
+
+impl Eq for MatchAnythingInput {}
+
+
+impl std::hash::Hash for MatchAnythingInput {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Nonterminal(name, _, binding) => (0, name, binding).hash(state),
+            Self::Command(cmd, binding, annotations) => (1, cmd, binding, annotations).hash(state),
+        }
+    }
+}
+
+
+impl MatchAnythingInput {
+    pub fn get_binding(&self) -> Option<Ustr> {
+        match self {
+            MatchAnythingInput::Nonterminal(_, _, binding) => *binding,
+            MatchAnythingInput::Command(_, binding, _) => *binding,
+        }
+    }
 }
 
 impl std::fmt::Display for MatchAnythingInput {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MatchAnythingInput::Nonterminal(name, None) => write!(f, "{name}"),
-            MatchAnythingInput::Nonterminal(name, Some(_)) => write!(f, "{name}@shell"),
-            MatchAnythingInput::Command(cmd) => write!(f, "{{{cmd}}}"),
+            MatchAnythingInput::Nonterminal(name, None, _) => write!(f, "{name}"),
+            MatchAnythingInput::Nonterminal(name, Some(_), _) => write!(f, "{name}@shell"),
+            MatchAnythingInput::Command(cmd, ..) => write!(f, "{{{cmd}}}"),
         }
     }
 }
@@ -31,6 +68,7 @@ impl std::fmt::Display for MatchAnythingInput {
 #[derive(Debug, Clone, Copy)]
 pub enum Input {
     Literal(Ustr, Option<Ustr>),
+    Prefix(Ustr), // Matches the beginning of a word.  The remainder of that word is the next input.
     Any(MatchAnythingInput),
 }
 
@@ -39,9 +77,9 @@ impl PartialEq for Input {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Literal(left_name, left_description), Self::Literal(right_name, right_description)) => left_name == right_name && left_description == right_description,
+            (Self::Prefix(left), Self::Prefix(right)) => left == right,
             (Self::Any(..), Self::Any(..)) => true,
-            (Self::Literal(..), Self::Any(..)) => false,
-            (Self::Any(..), Self::Literal(..)) => false,
+            _ => false,
         }
     }
 }
@@ -57,6 +95,7 @@ impl std::hash::Hash for Input {
                 name.hash(state);
                 description.hash(state);
             },
+            Input::Prefix(prefix) => prefix.hash(state),
             Input::Any(_) => {},
         }
     }
@@ -67,9 +106,12 @@ impl Ord for Input {
     fn cmp(&self, other: &Self) -> Ordering {
         match (self, other) {
             (Input::Literal(left_name, left_description), Input::Literal(right_name, right_description)) => (left_name, left_description).cmp(&(right_name, right_description)),
-            (Input::Literal(_, _), Input::Any(_)) => Ordering::Less,
-            (Input::Any(_), Input::Literal(_, _)) => Ordering::Greater,
+            (Input::Literal(..), _) => Ordering::Less,
+            (Input::Prefix(_), Input::Literal(..)) => Ordering::Greater,
+            (Input::Prefix(left), Input::Prefix(right)) => left.cmp(right),
+            (Input::Prefix(_), Input::Any(_)) => Ordering::Less,
             (Input::Any(_), Input::Any(_)) => Ordering::Equal,
+            (Input::Any(_), _) => Ordering::Greater,
         }
     }
 }
@@ -86,9 +128,17 @@ impl Input {
     pub fn matches_anything(&self) -> bool {
         match self {
             Input::Literal(..) => false,
+            Input::Prefix(_) => false,
             Input::Any(_) => true,
         }
     }
+
+    pub fn get_binding(&self) -> Option<Ustr> {
+        match self {
+            Input::Literal(..) | Input::Prefix(_) => None,
+            Input::Any(any) => any.get_binding(),
+        }
+    }
 }
 
 
@@ -96,6 +146,7 @@ impl std::fmt::Display for Input {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Input::Literal(literal, _) => write!(f, r#"{literal}"#),
+            Input::Prefix(prefix) => write!(f, r#"{prefix}"#),
             Input::Any(any) => write!(f, "{}", any),
         }
     }
@@ -252,56 +303,210 @@ impl<'a> AugmentedRegexNode<'a> {
 }
 
 
-fn do_from_expr<'a>(e: &Expr, specs: &UstrMap<Specialization>, arena: &'a Bump, symbols: &mut HashSet<Input>, input_from_position: &mut Vec<Input>) -> AugmentedRegexNode<'a> {
-    match e {
-        Expr::Terminal(term, description) => {
-            let result = AugmentedRegexNode::Terminal(*term, Position::try_from(input_from_position.len()).unwrap());
-            let input = Input::Literal(*term, *description);
-            input_from_position.push(input.clone());
-            symbols.insert(input);
-            result
+// What gets recorded about positions while building the regex, see AugmentedRegex
+#[derive(Default)]
+struct PositionTable {
+    inputs: Vec<Input>,
+    spans: Vec<Option<Span>>,
+    once: RoaringBitmap,
+    exclusive_tags: BTreeMap<Position, Vec<u32>>,
+    exclusive_groups: Vec<Vec<u32>>,
+    end_of_options: RoaringBitmap,
+}
+
+
+impl PositionTable {
+    // Positions end up as DFA states, hence the error
+    fn next_position(&self) -> Result<Position> {
+        Position::try_from(self.inputs.len()).map_err(|_| Error::TooManyStates(Position::MAX.into()))
+    }
+
+    fn push(&mut self, input: Input, span: Option<Span>) -> Result<Position> {
+        let position = self.next_position()?;
+        self.inputs.push(input);
+        self.spans.push(span);
+        Ok(position)
+    }
+
+    // Each alternative of an @exclusive(...) gets a tag of its own, attached to the positions it can start at.
+    // Alternatives only starting with match-anything inputs, e.g. <FILE>, are left out as there's no telling
+    // whether they've been given.
+    fn add_exclusive_group(&mut self, alternatives: &[AugmentedRegexNode]) {
+        let alternatives: Vec<Vec<Position>> = alternatives.iter()
+            .map(|alternative| alternative.firstpos().into_iter().filter(|pos| !self.inputs[usize::try_from(*pos).unwrap()].matches_anything()).collect())
+            .filter(|positions: &Vec<Position>| !positions.is_empty())
+            .collect();
+        if alternatives.len() < 2 {
+            return;
+        }
+        let first_tag = u32::try_from(self.exclusive_groups.iter().map(Vec::len).sum::<usize>()).unwrap();
+        let mut group: Vec<u32> = Default::default();
+        for (tag, positions) in (first_tag..).zip(alternatives) {
+            for pos in positions {
+                self.exclusive_tags.entry(pos).or_default().push(tag);
+            }
+            group.push(tag);
+        }
+        self.exclusive_groups.push(group);
+    }
+}
+
+
+fn do_from_terminal<'a>(term: Ustr, description: Option<Ustr>, span: Option<&Span>, symbols: &mut HashSet<Input>, positions: &mut PositionTable) -> Result<AugmentedRegexNode<'a>> {
+    let input = Input::Literal(term, description);
+    let result = AugmentedRegexNode::Terminal(term, positions.push(input, span.cloned())?);
+    symbols.insert(input);
+    Ok(result)
+}
+
+
+fn do_from_prefix<'a>(prefix: Ustr, span: Option<&Span>, symbols: &mut HashSet<Input>, positions: &mut PositionTable) -> Result<AugmentedRegexNode<'a>> {
+    let input = Input::Prefix(prefix);
+    let result = AugmentedRegexNode::Terminal(prefix, positions.push(input, span.cloned())?);
+    symbols.insert(input);
+    Ok(result)
+}
+
+
+// The options that can end a cluster, i.e. all of them, followed by their arguments, if any.
+fn do_cluster_ends<'a>(dash: &str, members: &[ClusterMember], span: Option<&Span>, specs: &UstrMap<Specialization>, arena: &'a Bump, symbols: &mut HashSet<Input>, positions: &mut PositionTable) -> Result<Vec<AugmentedRegexNode<'a>>> {
+    let mut result: Vec<AugmentedRegexNode> = Default::default();
+    for (c, description, argument) in members {
+        let literal = do_from_terminal(ustr(&format!("{dash}{c}")), *description, span, symbols, positions)?;
+        match argument {
+            Some(argument) => {
+                let argument = arena.alloc(do_from_expr(argument, specs, arena, symbols, positions)?);
+                result.push(AugmentedRegexNode::Cat(arena.alloc(literal), argument));
+            },
+            None => result.push(literal),
+        }
+    }
+    Ok(result)
+}
+
+
+// The options that can be followed by more options within the same cluster, i.e. the ones without an argument.
+fn do_cluster_prefixes<'a>(dash: &str, members: &[ClusterMember], span: Option<&Span>, symbols: &mut HashSet<Input>, positions: &mut PositionTable) -> Result<AugmentedRegexNode<'a>> {
+    let mut result: Vec<AugmentedRegexNode> = Default::default();
+    for (c, _, argument) in members {
+        if argument.is_none() {
+            result.push(do_from_prefix(ustr(&format!("{dash}{c}")), span, symbols, positions)?);
+        }
+    }
+    Ok(AugmentedRegexNode::Or(result))
+}
+
+
+fn do_from_expr<'a>(e: &Expr, specs: &UstrMap<Specialization>, arena: &'a Bump, symbols: &mut HashSet<Input>, positions: &mut PositionTable) -> Result<AugmentedRegexNode<'a>> {
+    match &e.kind {
+        ExprKind::Terminal(term, description) => do_from_terminal(*term, *description, e.span.as_ref(), symbols, positions),
+        ExprKind::Nonterminal(name, _) if *name == END_OF_OPTIONS && !specs.contains_key(name) => {
+            let result = do_from_terminal(ustr("--"), None, e.span.as_ref(), symbols, positions)?;
+            positions.end_of_options.extend(result.firstpos());
+            Ok(result)
         },
-        Expr::Nonterminal(name) => {
-            let result = AugmentedRegexNode::Nonterminal(Position::try_from(input_from_position.len()).unwrap());
+        ExprKind::Nonterminal(name, binding) => {
             let specialization = specs.get(name);
-            let input = Input::Any(MatchAnythingInput::Nonterminal(*name, specialization.copied()));
-            input_from_position.push(input.clone());
+            let input = Input::Any(MatchAnythingInput::Nonterminal(*name, specialization.copied(), *binding));
+            let result = AugmentedRegexNode::Nonterminal(positions.push(input, e.span.clone())?);
             symbols.insert(input);
-            result
+            Ok(result)
         },
-        Expr::Command(code) => {
-            let result = AugmentedRegexNode::Command(*code, Position::try_from(input_from_position.len()).unwrap());
-            let input = Input::Any(MatchAnythingInput::Command(*code));
-            input_from_position.push(input.clone());
+        ExprKind::Command(code, binding, annotations) => {
+            let input = Input::Any(MatchAnythingInput::Command(*code, *binding, *annotations));
+            let result = AugmentedRegexNode::Command(*code, positions.push(input, e.span.clone())?);
             symbols.insert(input);
-            result
+            Ok(result)
+        },
+        ExprKind::Prefix(prefix, subexpr) => {
+            let prefix_regex = arena.alloc(do_from_prefix(*prefix, e.span.as_ref(), symbols, positions)?);
+            let subregex = arena.alloc(do_from_expr(subexpr, specs, arena, symbols, positions)?);
+            Ok(AugmentedRegexNode::Cat(prefix_regex, subregex))
         },
-        Expr::Sequence(subexprs) => {
-            let mut left_regex = do_from_expr(&subexprs[0], specs, arena, symbols, input_from_position);
+        ExprKind::Cluster(members) => {
+            // -x | -f ARG | -x(x)*(x | f ARG) where the parenthesized parts are word continuations
+            let members: Vec<ClusterMember> = members.iter().map(|member| get_cluster_member(member).unwrap()).collect();
+            let mut alternatives = do_cluster_ends("-", &members, e.span.as_ref(), specs, arena, symbols, positions)?;
+            if members.iter().any(|(_, _, argument)| argument.is_none()) {
+                let first = arena.alloc(do_cluster_prefixes("-", &members, e.span.as_ref(), symbols, positions)?);
+                let middle = arena.alloc(do_cluster_prefixes("", &members, e.span.as_ref(), symbols, positions)?);
+                let middle = arena.alloc(AugmentedRegexNode::Star(middle));
+                let last = arena.alloc(AugmentedRegexNode::Or(do_cluster_ends("", &members, e.span.as_ref(), specs, arena, symbols, positions)?));
+                let rest = arena.alloc(AugmentedRegexNode::Cat(middle, last));
+                alternatives.push(AugmentedRegexNode::Cat(first, rest));
+            }
+            Ok(AugmentedRegexNode::Or(alternatives))
+        },
+        ExprKind::Sequence(subexprs) => {
+            let mut left_regex = do_from_expr(&subexprs[0], specs, arena, symbols, positions)?;
             for right_expr in &subexprs[1..] {
-                let right_regex = arena.alloc(do_from_expr(right_expr, specs, arena, symbols, input_from_position));
+                let right_regex = arena.alloc(do_from_expr(right_expr, specs, arena, symbols, positions)?);
                 left_regex = AugmentedRegexNode::Cat(arena.alloc(left_regex), right_regex);
             }
-            left_regex
+            Ok(left_regex)
+        },
+        ExprKind::Alternative(subexprs) => {
+            let mut subregexes: Vec<AugmentedRegexNode> = Default::default();
+            for e in subexprs {
+                let subregex = do_from_expr(e, specs, arena, symbols, positions)?;
+                subregexes.push(subregex);
+            }
+            Ok(AugmentedRegexNode::Or(subregexes))
         },
-        Expr::Alternative(subexprs) => {
+        ExprKind::Exclusive(subexprs) => {
             let mut subregexes: Vec<AugmentedRegexNode> = Default::default();
             for e in subexprs {
-                let subregex = do_from_expr(e, specs, arena, symbols, input_from_position);
+                let subregex = do_from_expr(e, specs, arena, symbols, positions)?;
                 subregexes.push(subregex);
             }
-            AugmentedRegexNode::Or(subregexes)
+            positions.add_exclusive_group(&subregexes);
+            Ok(AugmentedRegexNode::Or(subregexes))
         },
-        Expr::Optional(subexpr) => {
-            let subregex = do_from_expr(subexpr, specs, arena, symbols, input_from_position);
-            AugmentedRegexNode::Or(vec![subregex, AugmentedRegexNode::Epsilon])
+        ExprKind::Optional(subexpr) => {
+            let subregex = do_from_expr(subexpr, specs, arena, symbols, positions)?;
+            Ok(AugmentedRegexNode::Or(vec![subregex, AugmentedRegexNode::Epsilon]))
+        }
+        ExprKind::Many1(subexpr) => {
+            let subregex = arena.alloc(do_from_expr(subexpr, specs, arena, symbols, positions)?);
+            let star = arena.alloc(AugmentedRegexNode::Star(subregex));
+            Ok(AugmentedRegexNode::Cat(subregex, star))
         }
-        Expr::Many1(subexpr) => {
-            let subregex = arena.alloc(do_from_expr(subexpr, specs, arena, symbols, input_from_position));
+        ExprKind::Once(subexpr) => {
+            let subregex = arena.alloc(do_from_expr(subexpr, specs, arena, symbols, positions)?);
+            positions.once.extend(subregex.firstpos());
             let star = arena.alloc(AugmentedRegexNode::Star(subregex));
-            AugmentedRegexNode::Cat(subregex, star)
+            Ok(AugmentedRegexNode::Cat(subregex, star))
+        },
+        ExprKind::Repeat(subexpr, min, max) => do_from_repeat(subexpr, *min, *max, specs, arena, symbols, positions),
+    }
+}
+
+
+// x{2,4} becomes x x (x (x)?)? and x{2,} becomes x x x*.  Unlike in x x* for x..., every occurrence of x gets
+// positions of its own so that they can be counted.
+fn do_from_repeat<'a>(e: &Expr, min: u32, max: Option<u32>, specs: &UstrMap<Specialization>, arena: &'a Bump, symbols: &mut HashSet<Input>, positions: &mut PositionTable) -> Result<AugmentedRegexNode<'a>> {
+    let mut factors: Vec<AugmentedRegexNode> = Default::default();
+    for _ in 0..min {
+        factors.push(do_from_expr(e, specs, arena, symbols, positions)?);
+    }
+    match max {
+        Some(max) => {
+            let optionals: Vec<AugmentedRegexNode> = (min..max).map(|_| do_from_expr(e, specs, arena, symbols, positions)).collect::<Result<_>>()?;
+            let tail = optionals.into_iter().rev().fold(None, |tail, optional| {
+                let subregex = match tail {
+                    Some(tail) => AugmentedRegexNode::Cat(arena.alloc(optional), arena.alloc(tail)),
+                    None => optional,
+                };
+                Some(AugmentedRegexNode::Or(vec![subregex, AugmentedRegexNode::Epsilon]))
+            });
+            factors.extend(tail);
+        },
+        None => {
+            let subregex = arena.alloc(do_from_expr(e, specs, arena, symbols, positions)?);
+            factors.push(AugmentedRegexNode::Star(subregex));
         },
     }
+    Ok(factors.into_iter().reduce(|left, right| AugmentedRegexNode::Cat(arena.alloc(left), arena.alloc(right))).unwrap_or(AugmentedRegexNode::Epsilon))
 }
 
 
@@ -310,24 +515,37 @@ pub struct AugmentedRegex<'a> {
     pub root: AugmentedRegexNode<'a>,
     pub input_symbols: Rc<HashSet<Input>>,
     pub input_from_position: Vec<Input>,
+    pub span_from_position: Vec<Option<Span>>, // Where in the grammar each position comes from, if known
     pub endmarker_position: Position,
+    // Positions the words matched by @once(...) can start at.  Every one of them stands for an option that isn't
+    // to be offered again once given, e.g. -a and -b in @once(-a | -b), including any copies of it in the DFA.
+    pub once_positions: RoaringBitmap,
+    // Tags of the @exclusive(...) alternatives each position can start, see PositionTable::add_exclusive_group()
+    pub exclusive_tags: BTreeMap<Position, Vec<u32>>,
+    pub exclusive_groups: Vec<Vec<u32>>, // Tags of the alternatives of each @exclusive(...)
+    pub end_of_options_positions: RoaringBitmap, // Positions of the `--` words <END_OF_OPTIONS> stands for
 }
 
 
 impl<'a> AugmentedRegex<'a> {
-    pub fn from_expr(e: &Expr, specs: &UstrMap<Specialization>, arena: &'a Bump) -> Self {
+    pub fn from_expr(e: &Expr, specs: &UstrMap<Specialization>, arena: &'a Bump) -> Result<Self> {
         let mut input_symbols: HashSet<Input> = Default::default();
-        let mut input_from_position: Vec<Input> = Default::default();
-        let regex = arena.alloc(do_from_expr(e, specs, arena, &mut input_symbols, &mut input_from_position));
-        let endmarker_position = Position::try_from(input_from_position.len()).unwrap();
+        let mut positions = PositionTable::default();
+        let regex = arena.alloc(do_from_expr(e, specs, arena, &mut input_symbols, &mut positions)?);
+        let endmarker_position = positions.next_position()?;
         let endmarker = arena.alloc(AugmentedRegexNode::EndMarker(endmarker_position));
         let root = AugmentedRegexNode::Cat(regex, endmarker);
-        Self {
+        Ok(Self {
             root,
             input_symbols: Rc::new(input_symbols),
             endmarker_position,
-            input_from_position,
-        }
+            input_from_position: positions.inputs,
+            span_from_position: positions.spans,
+            once_positions: positions.once,
+            exclusive_tags: positions.exclusive_tags,
+            exclusive_groups: positions.exclusive_groups,
+            end_of_options_positions: positions.end_of_options,
+        })
     }
 
     pub fn firstpos(&self) -> BTreeSet<Position> {
@@ -337,6 +555,24 @@ impl<'a> AugmentedRegex<'a> {
     pub fn followpos(&self) -> BTreeMap<Position, RoaringBitmap> {
         self.root.followpos()
     }
+
+    /// The positions making up the DFA state `path` leads to from the starting one, the same way `DFA::from_regex()`
+    /// computes them.
+    pub fn get_positions_after(&self, followpos: &BTreeMap<Position, RoaringBitmap>, path: &[Input]) -> RoaringBitmap {
+        let mut positions = RoaringBitmap::from_iter(self.firstpos());
+        for input in path {
+            let mut next = RoaringBitmap::new();
+            for pos in &positions {
+                if self.input_from_position.get(usize::try_from(pos).unwrap()) == Some(input) {
+                    if let Some(follow) = followpos.get(&pos) {
+                        next |= follow;
+                    }
+                }
+            }
+            positions = next;
+        }
+        positions
+    }
 }
 
 
@@ -410,4 +646,19 @@ mod tests {
         assert_eq!(fp.get(&2), Some(&RoaringBitmap::from_iter([1,2,3])));
         assert_eq!(fp.get(&1), Some(&RoaringBitmap::from_iter([1,2,3])));
     }
+
+    #[test]
+    fn locates_positions_in_the_grammar() {
+        use crate::grammar::{Grammar, ValidGrammar};
+        let grammar = Grammar::parse("cargo (build --release | <TARGET> --verbose);").unwrap();
+        let validated = ValidGrammar::from_grammar(&grammar).unwrap();
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&validated.commands[0].expr, &validated.specializations, &arena).unwrap();
+        let followpos = regex.followpos();
+        let locate = |path: &[Input]| -> Vec<String> {
+            regex.get_positions_after(&followpos, path).iter().filter_map(|pos| regex.span_from_position.get(usize::try_from(pos).unwrap())?.as_ref().map(|span| span.location())).collect()
+        };
+        assert_eq!(locate(&[]), vec!["<stdin>:1:8", "<stdin>:1:26"]);
+        assert_eq!(locate(&[Input::Literal(ustr("build"), None)]), vec!["<stdin>:1:14"]);
+    }
 }
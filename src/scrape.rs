@@ -3,7 +3,7 @@ use std::rc::Rc;
 use nom::{IResult, character::complete::{char, anychar, multispace1}, bytes::complete::{tag_no_case, tag, take_till, take_while1, is_not, take_while}, error::context, branch::alt, combinator::fail, multi::many1};
 use ustr::ustr;
 
-use crate::grammar::Expr;
+use crate::grammar::{Expr, ExprKind};
 
 
 #[derive(Debug, Clone, PartialEq)]
@@ -48,8 +48,8 @@ fn terminal(input: &str) -> IResult<&str, &str> {
 
 fn terminal_expr(input: &str) -> IResult<&str, Expr> {
     let (input, term) = context("terminal", terminal)(input)?;
-    let expr = Expr::Terminal(ustr(term), None);
-    Ok((input, expr))
+    let expr = ExprKind::Terminal(ustr(term), None);
+    Ok((input, expr.into()))
 }
 
 
@@ -80,7 +80,7 @@ fn nonterminal(input: &str) -> IResult<&str, &str> {
 
 fn nonterminal_expr(input: &str) -> IResult<&str, Expr> {
     let (input, nonterm) = context("nonterminal", nonterminal)(input)?;
-    Ok((input, Expr::Nonterminal(ustr(nonterm))))
+    Ok((input, ExprKind::Nonterminal(ustr(nonterm), None).into()))
 }
 
 
@@ -90,7 +90,7 @@ fn optional_expr(input: &str) -> IResult<&str, Expr> {
     let (input, expr) = usage_expr(input)?;
     let (input, _) = multispace0_except_newline(input)?;
     let (input, _) = char(']')(input)?;
-    Ok((input, Expr::Optional(Rc::new(expr))))
+    Ok((input, ExprKind::Optional(Rc::new(expr)).into()))
 }
 
 
@@ -109,7 +109,7 @@ fn unary_expr(input: &str) -> IResult<&str, Expr> {
     ))(input)?;
 
     if let Ok((input, ())) = many1_tag(input) {
-        return Ok((input, Expr::Many1(Rc::new(e))));
+        return Ok((input, ExprKind::Many1(Rc::new(e)).into()));
     }
 
     Ok((input, e))
@@ -127,7 +127,7 @@ fn sequence_expr(input: &str) -> IResult<&str, Expr> {
     let mut factors: Vec<Expr> = vec![left];
     loop {
         let Ok((pos, right)) = do_sequence_expr(input) else { break };
-        if let Expr::Sequence(v) = right {
+        if let ExprKind::Sequence(v) = right.kind {
             factors.extend(v.into_iter().map(|rc| Rc::into_inner(rc).unwrap()));
         }
         else {
@@ -138,7 +138,7 @@ fn sequence_expr(input: &str) -> IResult<&str, Expr> {
     let result = if factors.len() == 1 {
         factors.into_iter().next().unwrap()
     } else {
-        Expr::Sequence(factors.into_iter().map(Rc::new).collect())
+        ExprKind::Sequence(factors.into_iter().map(Rc::new).collect()).into()
     };
     Ok((input, result))
 }
@@ -194,10 +194,10 @@ fn short_option_long_option_description(input: &str) -> IResult<&str, (&str, &st
 fn short_option_long_option_description_expr(input: &str) -> IResult<&str, Expr> {
     let (input, (short, long, description)) = short_option_long_option_description(input)?;
     let d = ustr(description);
-    let s = Expr::Terminal(ustr(short), Some(d));
-    let l = Expr::Terminal(ustr(long), Some(d));
-    let a = Expr::Alternative(vec![Rc::new(s), Rc::new(l)]);
-    Ok((input, a))
+    let s = ExprKind::Terminal(ustr(short), Some(d));
+    let l = ExprKind::Terminal(ustr(long), Some(d));
+    let a = ExprKind::Alternative(vec![s.into(), l.into()]);
+    Ok((input, a.into()))
 }
 
 fn short_option_long_option_argument_description_expr(input: &str) -> IResult<&str, Expr> {
@@ -216,13 +216,13 @@ fn short_option_long_option_argument_description_expr(input: &str) -> IResult<&s
     let (input, _) = newline_or_eof(input)?;
 
     let d = ustr(description);
-    let s = Expr::Terminal(ustr(short), Some(d));
-    let l = Expr::Terminal(ustr(long), Some(d));
-    let alt = Expr::Alternative(vec![Rc::new(s), Rc::new(l)]);
-    let arg = Expr::Nonterminal(ustr(arg));
-    let s = Expr::Sequence(vec![Rc::new(alt), Rc::new(arg)]);
+    let s = ExprKind::Terminal(ustr(short), Some(d));
+    let l = ExprKind::Terminal(ustr(long), Some(d));
+    let alt = ExprKind::Alternative(vec![s.into(), l.into()]);
+    let arg = ExprKind::Nonterminal(ustr(arg), None);
+    let s = ExprKind::Sequence(vec![alt.into(), arg.into()]);
 
-    Ok((input, s))
+    Ok((input, s.into()))
 }
 
 // e.g. "  -I                        equivalent to --binary-files=without-match"
@@ -232,7 +232,7 @@ fn short_option_description_expr(input: &str) -> IResult<&str, Expr> {
     let (input, _) = multispace1_except_newline(input)?;
     let (input, descr) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
-    Ok((input, Expr::Terminal(ustr(short), Some(ustr(descr)))))
+    Ok((input, ExprKind::Terminal(ustr(short), Some(ustr(descr))).into()))
 }
 
 
@@ -246,11 +246,11 @@ fn short_option_argument_description_expr(input: &str) -> IResult<&str, Expr> {
     let (input, descr) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
 
-    let t = Expr::Terminal(ustr(short), Some(ustr(descr)));
-    let a = Expr::Nonterminal(ustr(arg));
-    let s = Expr::Sequence(vec![Rc::new(t), Rc::new(a)]);
+    let t = ExprKind::Terminal(ustr(short), Some(ustr(descr)));
+    let a = ExprKind::Nonterminal(ustr(arg), None);
+    let s = ExprKind::Sequence(vec![t.into(), a.into()]);
 
-    Ok((input, s))
+    Ok((input, s.into()))
 }
 
 
@@ -261,8 +261,8 @@ fn long_option_description_expr(input: &str) -> IResult<&str, Expr> {
     let (input, _) = multispace1_except_newline(input)?;
     let (input, descr) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
-    let expr = Expr::Terminal(ustr(long), Some(ustr(descr)));
-    Ok((input, expr))
+    let expr = ExprKind::Terminal(ustr(long), Some(ustr(descr)));
+    Ok((input, expr.into()))
 }
 
 // e.g. "--foo=BAR Description"
@@ -277,8 +277,8 @@ fn long_option_argument_description_expr(input: &str) -> IResult<&str, Expr> {
     let (input, _) = multispace1_except_newline(input)?;
     let (input, description) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
-    let expr = Expr::Sequence(vec![Rc::new(Expr::Terminal(ustr(long), Some(ustr(description)))), Rc::new(Expr::Nonterminal(ustr(arg)))]);
-    Ok((input, expr))
+    let expr = ExprKind::Sequence(vec![ExprKind::Terminal(ustr(long), Some(ustr(description))).into(), ExprKind::Nonterminal(ustr(arg), None).into()]);
+    Ok((input, expr.into()))
 }
 
 
@@ -288,7 +288,7 @@ fn optional_nonterminal_expr(input: &str) -> IResult<&str, Expr> {
     let (input, nonterm) = nonterminal_expr(input)?;
     let (input, _) = multispace0_except_newline(input)?;
     let (input, _) = char(']')(input)?;
-    Ok((input, Expr::Optional(Rc::new(nonterm))))
+    Ok((input, ExprKind::Optional(Rc::new(nonterm)).into()))
 }
 
 fn optional_equals_nonterminal_expr(input: &str) -> IResult<&str, Expr> {
@@ -298,7 +298,7 @@ fn optional_equals_nonterminal_expr(input: &str) -> IResult<&str, Expr> {
     let (input, nonterm) = nonterminal_expr(input)?;
     let (input, _) = multispace0_except_newline(input)?;
     let (input, _) = char(']')(input)?;
-    Ok((input, Expr::Optional(Rc::new(nonterm))))
+    Ok((input, ExprKind::Optional(Rc::new(nonterm)).into()))
 }
 
 
@@ -316,11 +316,11 @@ fn short_option_long_option_optional_argument_description_expr(input: &str) -> I
     let (input, _) = multispace1_except_newline(input)?;
     let (input, description) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
-    let s = Rc::new(Expr::Terminal(ustr(short), Some(ustr(description))));
-    let l = Rc::new(Expr::Terminal(ustr(long), Some(ustr(description))));
+    let s = ExprKind::Terminal(ustr(short), Some(ustr(description))).into();
+    let l = ExprKind::Terminal(ustr(long), Some(ustr(description))).into();
     let o = Rc::new(opt_arg);
-    let expr = Expr::Sequence(vec![Rc::new(Expr::Alternative(vec![s, l])), o]);
-    Ok((input, expr))
+    let expr = ExprKind::Sequence(vec![ExprKind::Alternative(vec![s, l]).into(), o]);
+    Ok((input, expr.into()))
 }
 
 
@@ -334,10 +334,10 @@ fn short_option_long_option_many1_description_expr(input: &str) -> IResult<&str,
     let (input, _) = multispace1_except_newline(input)?;
     let (input, description) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
-    let s = Rc::new(Expr::Terminal(ustr(short), Some(ustr(description))));
-    let l = Rc::new(Expr::Many1(Rc::new(Expr::Terminal(ustr(long), Some(ustr(description))))));
-    let expr = Expr::Alternative(vec![s, l]);
-    Ok((input, expr))
+    let s = ExprKind::Terminal(ustr(short), Some(ustr(description))).into();
+    let l = ExprKind::Many1(ExprKind::Terminal(ustr(long), Some(ustr(description))).into()).into();
+    let expr = ExprKind::Alternative(vec![s, l]);
+    Ok((input, expr.into()))
 }
 
 fn long_option_optional_argument_description_expr(input: &str) -> IResult<&str, Expr> {
@@ -351,10 +351,10 @@ fn long_option_optional_argument_description_expr(input: &str) -> IResult<&str,
     let (input, _) = multispace1_except_newline(input)?;
     let (input, description) = description(input)?;
     let (input, _) = newline_or_eof(input)?;
-    let l = Rc::new(Expr::Terminal(ustr(long), Some(ustr(description))));
+    let l = ExprKind::Terminal(ustr(long), Some(ustr(description))).into();
     let a = Rc::new(opt_arg);
-    let expr = Expr::Sequence(vec![l, a]);
-    Ok((input, expr))
+    let expr = ExprKind::Sequence(vec![l, a]);
+    Ok((input, expr.into()))
 }
 
 fn option_line(input: &str) -> IResult<&str, Expr> {
@@ -435,23 +435,33 @@ fn escape_description(s: &str) -> String {
 
 
 fn do_pretty_print(e: &Expr) -> String {
-    match e {
-        Expr::Terminal(term, None) => format!(r#"{term}"#),
-        Expr::Terminal(term, Some(descr)) => format!(r#"{term} "{}""#, escape_description(descr)),
-        Expr::Nonterminal(nonterm) => format!(r#"<{nonterm}>"#),
-        Expr::Command(cmd) => format!(r#"{{{{{{ {cmd} }}}}}}"#),
-        Expr::Sequence(subexprs) => itertools::join(subexprs.iter().map(|e| do_pretty_print(e)), " "),
-        Expr::Alternative(subexprs) => itertools::join(subexprs.iter().map(|e| do_pretty_print(e)), " | "),
-        Expr::Optional(subexpr) => format!(r#"[{}]"#, do_pretty_print(subexpr)),
-        Expr::Many1(subexpr) => format!(r#"{} ..."#, do_pretty_print(subexpr)),
+    match &e.kind {
+        ExprKind::Terminal(term, None) => format!(r#"{term}"#),
+        ExprKind::Terminal(term, Some(descr)) => format!(r#"{term} "{}""#, escape_description(descr)),
+        ExprKind::Nonterminal(nonterm, _) => format!(r#"<{nonterm}>"#),
+        ExprKind::Command(cmd, ..) => format!(r#"{{{{{{ {cmd} }}}}}}"#),
+        ExprKind::Prefix(prefix, subexpr) => match &subexpr.kind {
+            ExprKind::Terminal(..) | ExprKind::Nonterminal(..) | ExprKind::Command(..) => format!(r#"{prefix}{}"#, do_pretty_print(subexpr)),
+            _ => format!(r#"{prefix}({})"#, do_pretty_print(subexpr)),
+        },
+        ExprKind::Cluster(members) => format!(r#"@cluster({})"#, itertools::join(members.iter().map(|e| do_pretty_print(e)), " | ")),
+        ExprKind::Once(subexpr) => format!(r#"@once({})"#, do_pretty_print(subexpr)),
+        ExprKind::Exclusive(members) => format!(r#"@exclusive({})"#, itertools::join(members.iter().map(|e| do_pretty_print(e)), " | ")),
+        ExprKind::Sequence(subexprs) => itertools::join(subexprs.iter().map(|e| do_pretty_print(e)), " "),
+        ExprKind::Alternative(subexprs) => itertools::join(subexprs.iter().map(|e| do_pretty_print(e)), " | "),
+        ExprKind::Optional(subexpr) => format!(r#"[{}]"#, do_pretty_print(subexpr)),
+        ExprKind::Many1(subexpr) => format!(r#"{} ..."#, do_pretty_print(subexpr)),
+        ExprKind::Repeat(subexpr, min, Some(max)) if min == max => format!(r#"{}{{{min}}}"#, do_pretty_print(subexpr)),
+        ExprKind::Repeat(subexpr, min, Some(max)) => format!(r#"{}{{{min},{max}}}"#, do_pretty_print(subexpr)),
+        ExprKind::Repeat(subexpr, min, None) => format!(r#"{}{{{min},}}"#, do_pretty_print(subexpr)),
     }
 }
 
 
 pub fn pretty_print(exprs: &[Statement]) {
     fn pp_option_line(e: &Expr) {
-        match e {
-            Expr::Alternative(subexprs) => {
+        match &e.kind {
+            ExprKind::Alternative(subexprs) => {
                 for subexpr in subexprs {
                     println!(" | {}", do_pretty_print(subexpr));
                 }
@@ -508,52 +518,52 @@ mod tests {
 
     #[test]
     fn parses_long_option_argument_description() {
-        use Expr::*;
+        use ExprKind::*;
         const INPUT: &str = r#"--name <string> Name of the project"#;
         let (rest, expr) = long_option_argument_description_expr(INPUT).unwrap();
         assert_eq!(rest, "");
-        assert_eq!(expr, Sequence(vec![Rc::new(Terminal(ustr("--name"), Some(ustr("Name of the project")))), Rc::new(Nonterminal(ustr("string")))]));
+        assert_eq!(expr, Sequence(vec![Terminal(ustr("--name"), Some(ustr("Name of the project"))).into(), Nonterminal(ustr("string"), None).into()]).into());
     }
 
     #[test]
     fn parses_short_option_long_option_argument_description() {
-        use Expr::*;
+        use ExprKind::*;
         const INPUT: &str = r#"-n, --name <string> Name of the project"#;
         let (rest, expr) = option_line(INPUT).unwrap();
         assert_eq!(rest, "");
-        assert_eq!(expr, Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-n"), Some(ustr("Name of the project")))), Rc::new(Terminal(ustr("--name"), Some(ustr("Name of the project"))))])), Rc::new(Nonterminal(ustr("string")))]));
+        assert_eq!(expr, Sequence(vec![Alternative(vec![Terminal(ustr("-n"), Some(ustr("Name of the project"))).into(), Terminal(ustr("--name"), Some(ustr("Name of the project"))).into()]).into(), Nonterminal(ustr("string"), None).into()]).into());
     }
 
     #[test]
     fn parses_long_option_optional_argument_description() {
-        use Expr::*;
+        use ExprKind::*;
         const INPUT: &str = r#"--name [<string>] Name of the project"#;
         let (rest, expr) = option_line(INPUT).unwrap();
         assert_eq!(rest, "");
-        assert_eq!(expr, Sequence(vec![Rc::new(Terminal(ustr("--name"), Some(ustr("Name of the project")))), Rc::new(Optional(Rc::new(Nonterminal(ustr("string")))))]));
+        assert_eq!(expr, Sequence(vec![Terminal(ustr("--name"), Some(ustr("Name of the project"))).into(), Optional(Nonterminal(ustr("string"), None).into()).into()]).into());
     }
 
     #[test]
     fn parses_long_option_optional_equals_argument_description() {
-        use Expr::*;
+        use ExprKind::*;
         const INPUT: &str = r#"      --timings[=<FMTS>]        Timing output formats (unstable) (comma separated): html, json"#;
         let (rest, expr) = option_line(INPUT).unwrap();
         assert_eq!(rest, "");
-        assert_eq!(expr, Sequence(vec![Rc::new(Terminal(ustr("--timings"), Some(ustr("Timing output formats (unstable) (comma separated): html, json")))), Rc::new(Optional(Rc::new(Nonterminal(ustr("FMTS")))))]));
+        assert_eq!(expr, Sequence(vec![Terminal(ustr("--timings"), Some(ustr("Timing output formats (unstable) (comma separated): html, json"))).into(), Optional(Nonterminal(ustr("FMTS"), None).into()).into()]).into());
     }
 
     #[test]
     fn parses_usage_line() {
-        use Expr::*;
+        use ExprKind::*;
         const INPUT: &str = r#"Usage: grep [OPTION]... PATTERNS [FILE]..."#;
         let (rest, e) = usage_line(INPUT).unwrap();
         assert_eq!(rest, "");
-        assert_eq!(e, Sequence(vec![Rc::new(Terminal(ustr("grep"), None)), Rc::new(Many1(Rc::new(Optional(Rc::new(Nonterminal(ustr("OPTION"))))))), Rc::new(Nonterminal(ustr("PATTERNS"))), Rc::new(Many1(Rc::new(Optional(Rc::new(Nonterminal(ustr("FILE")))))))]));
+        assert_eq!(e, Sequence(vec![Terminal(ustr("grep"), None).into(), Many1(Optional(Nonterminal(ustr("OPTION"), None).into()).into()).into(), Nonterminal(ustr("PATTERNS"), None).into(), Many1(Optional(Nonterminal(ustr("FILE"), None).into()).into()).into()]).into());
     }
 
     #[test]
     fn parses_grep_help() {
-        use Expr::*;
+        use ExprKind::*;
         use Statement::*;
         const INPUT: &str = r#"
 Usage: ggrep [OPTION]... PATTERNS [FILE]...
@@ -577,40 +587,40 @@ Pattern selection and interpretation:
         let Ok(("", expr)) = usage(INPUT) else { panic!("parse error") };
         assert_eq!(expr, [
             UsageLine(
-                Rc::new(Sequence(vec![Rc::new(Terminal(ustr("ggrep"), None)), Rc::new(Many1(Rc::new(Optional(Rc::new(Nonterminal(ustr("OPTION"))))))), Rc::new(Nonterminal(ustr("PATTERNS"))), Rc::new(Many1(Rc::new(Optional(Rc::new(Nonterminal(ustr("FILE")))))))])),
+                Sequence(vec![Terminal(ustr("ggrep"), None).into(), Many1(Optional(Nonterminal(ustr("OPTION"), None).into()).into()).into(), Nonterminal(ustr("PATTERNS"), None).into(), Many1(Optional(Nonterminal(ustr("FILE"), None).into()).into()).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-E"), Some(ustr("are extended regular expressions")))), Rc::new(Terminal(ustr("--extended-regexp"), Some(ustr("are extended regular expressions"))))])), Rc::new(Nonterminal(ustr("PATTERNS")))])),
+                Sequence(vec![Alternative(vec![Terminal(ustr("-E"), Some(ustr("are extended regular expressions"))).into(), Terminal(ustr("--extended-regexp"), Some(ustr("are extended regular expressions"))).into()]).into(), Nonterminal(ustr("PATTERNS"), None).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-F"), Some(ustr("are strings")))), Rc::new(Terminal(ustr("--fixed-strings"), Some(ustr("are strings"))))])), Rc::new(Nonterminal(ustr("PATTERNS")))])),
+                Sequence(vec![Alternative(vec![Terminal(ustr("-F"), Some(ustr("are strings"))).into(), Terminal(ustr("--fixed-strings"), Some(ustr("are strings"))).into()]).into(), Nonterminal(ustr("PATTERNS"), None).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-G"), Some(ustr("are basic regular expressions")))), Rc::new(Terminal(ustr("--basic-regexp"), Some(ustr("are basic regular expressions"))))])), Rc::new(Nonterminal(ustr("PATTERNS")))])),
+                Sequence(vec![Alternative(vec![Terminal(ustr("-G"), Some(ustr("are basic regular expressions"))).into(), Terminal(ustr("--basic-regexp"), Some(ustr("are basic regular expressions"))).into()]).into(), Nonterminal(ustr("PATTERNS"), None).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-P"), Some(ustr("are Perl regular expressions")))), Rc::new(Terminal(ustr("--perl-regexp"), Some(ustr("are Perl regular expressions"))))])), Rc::new(Nonterminal(ustr("PATTERNS")))])),
+                Sequence(vec![Alternative(vec![Terminal(ustr("-P"), Some(ustr("are Perl regular expressions"))).into(), Terminal(ustr("--perl-regexp"), Some(ustr("are Perl regular expressions"))).into()]).into(), Nonterminal(ustr("PATTERNS"), None).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-e"), Some(ustr("use PATTERNS for matching")))), Rc::new(Terminal(ustr("--regexp"), Some(ustr("use PATTERNS for matching"))))])), Rc::new(Nonterminal(ustr("PATTERNS")))])),
+                Sequence(vec![Alternative(vec![Terminal(ustr("-e"), Some(ustr("use PATTERNS for matching"))).into(), Terminal(ustr("--regexp"), Some(ustr("use PATTERNS for matching"))).into()]).into(), Nonterminal(ustr("PATTERNS"), None).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Sequence(vec![Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-f"), Some(ustr("take PATTERNS from FILE")))), Rc::new(Terminal(ustr("--file"), Some(ustr("take PATTERNS from FILE"))))])), Rc::new(Nonterminal(ustr("FILE")))])),
+                Sequence(vec![Alternative(vec![Terminal(ustr("-f"), Some(ustr("take PATTERNS from FILE"))).into(), Terminal(ustr("--file"), Some(ustr("take PATTERNS from FILE"))).into()]).into(), Nonterminal(ustr("FILE"), None).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-i"), Some(ustr("ignore case distinctions in patterns and data")))), Rc::new(Terminal(ustr("--ignore-case"), Some(ustr("ignore case distinctions in patterns and data"))))])),
+                Alternative(vec![Terminal(ustr("-i"), Some(ustr("ignore case distinctions in patterns and data"))).into(), Terminal(ustr("--ignore-case"), Some(ustr("ignore case distinctions in patterns and data"))).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Terminal(ustr("--no-ignore-case"), Some(ustr("do not ignore case distinctions (default)")))),
+                Terminal(ustr("--no-ignore-case"), Some(ustr("do not ignore case distinctions (default)"))).into(),
             ),
             OptionLine(
-                Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-w"), Some(ustr("match only whole words")))), Rc::new(Terminal(ustr("--word-regexp"), Some(ustr("match only whole words"))))])),
+                Alternative(vec![Terminal(ustr("-w"), Some(ustr("match only whole words"))).into(), Terminal(ustr("--word-regexp"), Some(ustr("match only whole words"))).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-x"), Some(ustr("match only whole lines")))), Rc::new(Terminal(ustr("--line-regexp"), Some(ustr("match only whole lines"))))])),
+                Alternative(vec![Terminal(ustr("-x"), Some(ustr("match only whole lines"))).into(), Terminal(ustr("--line-regexp"), Some(ustr("match only whole lines"))).into()]).into(),
             ),
             OptionLine(
-                Rc::new(Alternative(vec![Rc::new(Terminal(ustr("-z"), Some(ustr("a data line ends in 0 byte, not newline")))), Rc::new(Terminal(ustr("--null-data"), Some(ustr("a data line ends in 0 byte, not newline"))))])),
+                Alternative(vec![Terminal(ustr("-z"), Some(ustr("a data line ends in 0 byte, not newline"))).into(), Terminal(ustr("--null-data"), Some(ustr("a data line ends in 0 byte, not newline"))).into()]).into(),
             ),
         ]);
     }
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context};
+use ustr::Ustr;
+
+use crate::complete::{self, Shell};
+use crate::dfa::DFA;
+use crate::grammar::{Grammar, ValidGrammar};
+
+
+// `complgen serve` keeps the automata of every grammar in a directory in memory and answers completion requests
+// over a Unix domain socket, sparing shells from spawning `complgen complete` on every TAB press.
+//
+// A request is the following lines, after which the client shuts down its writing side of the connection:
+//
+//   <current working directory of the shell>
+//   <completed command, e.g. grep or /usr/bin/grep>
+//   <shell: bash, fish, zsh, powershell or nushell>
+//   <completed word index>
+//   <word>
+//   <word>
+//   ...
+//
+// i.e. the same arguments `complgen complete` takes.  The response is exactly what `complgen complete` would
+// output, except for zsh, which gets `<completion>\t<description>` lines instead of code to evaluate.  The server
+// closes the connection afterwards.  Erroneous requests get an empty response and an error message on the
+// server's stderr.
+//
+// Requests are served one at a time.  Grammars get reloaded whenever they or any of the files they include
+// change, and picked up when they get added to the directory.
+
+
+struct LoadedGrammar {
+    // The grammar file first, followed by the ones it includes
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    dfas: Vec<(Ustr, DFA)>,
+}
+
+
+fn get_modification_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+
+impl LoadedGrammar {
+    // A broken grammar still gets remembered so that it's not reparsed on every request until it's fixed
+    fn load(path: &Path) -> Self {
+        let mut files = vec![(path.to_path_buf(), get_modification_time(path))];
+        let dfas = match load_dfas(path) {
+            Ok((grammar, dfas)) => {
+                files.extend(grammar.get_included_files().iter().map(|path| (path.clone(), get_modification_time(path))));
+                dfas
+            },
+            Err(e) => {
+                eprintln!("{}: {:?}", path.display(), e);
+                vec![]
+            },
+        };
+        Self { files, dfas }
+    }
+
+    fn is_stale(&self) -> bool {
+        self.files.iter().any(|(path, modified)| get_modification_time(path) != *modified)
+    }
+}
+
+
+fn load_dfas(path: &Path) -> anyhow::Result<(Grammar, Vec<(Ustr, DFA)>)> {
+    let grammar = crate::parse_grammar_file(&path.to_string_lossy())?;
+    let validated = ValidGrammar::from_grammar(&grammar)?;
+    let dfas = crate::get_dfas(&validated)?;
+    Ok((grammar, dfas))
+}
+
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    cwd: PathBuf,
+    command: String,
+    shell: Shell,
+    completed_word_index: usize,
+    words: Vec<String>,
+}
+
+
+fn get_shell(name: &str) -> Option<Shell> {
+    match name {
+        "bash" => Some(Shell::Bash),
+        "fish" => Some(Shell::Fish),
+        "zsh" => Some(Shell::Zsh),
+        "powershell" => Some(Shell::Powershell),
+        "nushell" => Some(Shell::Nushell),
+        _ => None,
+    }
+}
+
+
+fn parse_request(request: &str) -> anyhow::Result<Request> {
+    let request = request.strip_suffix('\n').unwrap_or(request);
+    let mut lines = request.split('\n');
+    let mut next = |what: &str| lines.next().ok_or_else(|| anyhow!("Request is missing the {}", what));
+    let cwd = PathBuf::from(next("working directory")?);
+    let command = next("command")?.to_string();
+    let shell = next("shell")?;
+    let shell = get_shell(shell).ok_or_else(|| anyhow!("Unknown shell: {}", shell))?;
+    let completed_word_index = next("completed word index")?.parse().context("Invalid completed word index")?;
+    let words = lines.map(|word| word.to_string()).collect();
+    Ok(Request { cwd, command, shell, completed_word_index, words })
+}
+
+
+pub struct Server {
+    directory: PathBuf,
+    grammars: BTreeMap<PathBuf, LoadedGrammar>, // Sorted so that it's deterministic which grammar defines a command
+}
+
+
+impl Server {
+    pub fn new(directory: &Path) -> anyhow::Result<Self> {
+        let directory = directory.canonicalize().context(directory.to_string_lossy().to_string())?;
+        let mut server = Self { directory, grammars: Default::default() };
+        server.refresh()?;
+        Ok(server)
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let mut paths: Vec<PathBuf> = Default::default();
+        for entry in std::fs::read_dir(&self.directory).context(self.directory.to_string_lossy().to_string())? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "usage") {
+                paths.push(path);
+            }
+        }
+        self.grammars.retain(|path, _| paths.contains(path));
+        for path in paths {
+            if self.grammars.get(&path).is_some_and(|grammar| !grammar.is_stale()) {
+                continue;
+            }
+            log::debug!("Loading {}", path.display());
+            let grammar = LoadedGrammar::load(&path);
+            self.grammars.insert(path, grammar);
+        }
+        Ok(())
+    }
+
+    fn get_dfa(&self, command: &str) -> Option<&DFA> {
+        self.grammars.values().flat_map(|grammar| &grammar.dfas).find(|(name, _)| *name == command).map(|(_, dfa)| dfa)
+    }
+
+    fn respond(&mut self, request: &str, output: &mut Vec<u8>) -> anyhow::Result<()> {
+        let request = parse_request(request)?;
+        self.refresh()?;
+        // Shells pass the command as typed, possibly along with its path
+        let command = Path::new(&request.command).file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let Some(dfa) = self.get_dfa(&command) else {
+            bail!("No grammar for command: {}", command);
+        };
+        // Completions coming from external commands, e.g. files, are relative to the shell's directory
+        let words: Vec<&str> = request.words.iter().map(|word| word.as_str()).collect();
+        if request.shell == Shell::Zsh {
+            for (completion, description) in complete::get_completions(dfa, &words, request.completed_word_index, request.shell, &request.cwd) {
+                writeln!(output, "{}\t{}", completion, description)?;
+            }
+            return Ok(());
+        }
+        complete::write_completions(output, dfa, &words, request.completed_word_index, request.shell, &request.cwd)
+    }
+
+    fn handle(&mut self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        let mut request: String = Default::default();
+        stream.read_to_string(&mut request)?;
+        let mut response: Vec<u8> = Default::default();
+        self.respond(&request, &mut response)?;
+        stream.write_all(&response)?;
+        Ok(())
+    }
+}
+
+
+// XDG_RUNTIME_DIR is private to the user.  A shared directory like /tmp would let anyone else put a socket
+// there first and have their responses run in our shells.
+pub fn get_default_socket_path() -> anyhow::Result<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) if !directory.is_empty() => Ok(PathBuf::from(directory).join("complgen.sock")),
+        _ => bail!("XDG_RUNTIME_DIR isn't set, pass the socket path explicitly with --socket"),
+    }
+}
+
+
+// Other users shouldn't be able to make us run commands on their behalf, not even right after the socket gets
+// created and before its permissions could be changed
+fn bind_private(socket_path: &Path) -> anyhow::Result<UnixListener> {
+    // SAFETY: umask() can't fail and only affects the permissions of files created afterwards
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket_path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.context(socket_path.to_string_lossy().to_string())?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+
+pub fn serve(directory: &Path, socket_path: &Path) -> anyhow::Result<()> {
+    let mut server = Server::new(directory)?;
+
+    if UnixStream::connect(socket_path).is_ok() {
+        bail!("Another server is already listening on {}", socket_path.display());
+    }
+    if socket_path.exists() {
+        std::fs::remove_file(socket_path).context(socket_path.to_string_lossy().to_string())?;
+    }
+    let listener = bind_private(socket_path)?;
+    eprintln!("Listening on {}", socket_path.display());
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                continue;
+            },
+        };
+        // A stuck client mustn't block everyone else
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        if let Err(e) = server.handle(&mut stream) {
+            eprintln!("{:?}", e);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(server: &mut Server, lines: &[&str]) -> String {
+        request_in(server, &std::env::current_dir().unwrap(), lines)
+    }
+
+    fn request_in(server: &mut Server, cwd: &Path, lines: &[&str]) -> String {
+        let (mut client, mut server_side) = UnixStream::pair().unwrap();
+        for line in std::iter::once(cwd.to_str().unwrap()).chain(lines.iter().copied()) {
+            writeln!(client, "{}", line).unwrap();
+        }
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        server.handle(&mut server_side).unwrap();
+        drop(server_side);
+        let mut response: String = Default::default();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request = parse_request("/home/user\n/usr/bin/grep\nbash\n1\n--color\n\n").unwrap();
+        assert_eq!(request, Request {
+            cwd: PathBuf::from("/home/user"),
+            command: "/usr/bin/grep".to_string(),
+            shell: Shell::Bash,
+            completed_word_index: 1,
+            words: vec!["--color".to_string(), "".to_string()],
+        });
+        assert!(parse_request("/home/user\ngrep\ntcsh\n0\n").is_err());
+        assert!(parse_request("/home/user\ngrep\n").is_err());
+    }
+
+    #[test]
+    fn reloads_changed_grammars() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage_file_path = dir.path().join("grep.usage");
+        std::fs::write(&usage_file_path, "grep --color=(always | never);").unwrap();
+        let mut server = Server::new(dir.path()).unwrap();
+        assert_eq!(request(&mut server, &["/usr/bin/grep", "bash", "2", "--color", "=", "a"]), "always\n");
+
+        std::fs::write(&usage_file_path, "grep --color=(always | auto | never);").unwrap();
+        let file = std::fs::File::options().write(true).open(&usage_file_path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(request(&mut server, &["grep", "bash", "2", "--color", "=", "a"]), "always\nauto\n");
+
+        std::fs::write(dir.path().join("ls.usage"), "ls --all;").unwrap();
+        assert_eq!(request(&mut server, &["ls", "fish", "0", ""]), "--all\t\n");
+    }
+
+    #[test]
+    fn runs_commands_in_the_requested_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cmd.usage"), "cmd (<PATH> | --pwd {{{ pwd }}});").unwrap();
+        std::fs::write(dir.path().join("foo"), "").unwrap();
+        let mut server = Server::new(dir.path()).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(request_in(&mut server, dir.path(), &["cmd", "bash", "0", "f"]), "foo\n");
+        let response = request_in(&mut server, dir.path(), &["cmd", "bash", "1", "--pwd", ""]);
+        assert_eq!(response, format!("{}\n", dir.path().canonicalize().unwrap().display()));
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+    }
+
+    #[test]
+    fn responds_to_zsh_with_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ls.usage"), r#"ls --all "list hidden files";"#).unwrap();
+        let mut server = Server::new(dir.path()).unwrap();
+        assert_eq!(request(&mut server, &["ls", "zsh", "0", ""]), "--all\tlist hidden files\n");
+    }
+
+    #[test]
+    fn creates_private_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("complgen.sock");
+        let _listener = bind_private(&socket_path).unwrap();
+        let mode = std::fs::metadata(&socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
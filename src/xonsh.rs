@@ -0,0 +1,326 @@
+use std::io::Write;
+
+use complgen::{StateId, Result};
+use hashbrown::HashMap;
+use ustr::{Ustr, UstrMap, ustr};
+use crate::dfa::DFA;
+
+
+pub fn escape_python_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+
+// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>, directories included
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let regex = crate::glob::to_regex(glob);
+    format!("[p + os.sep if os.path.isdir(p) else p for p in glob.glob(glob.escape(cur) + '*') if os.path.isdir(p) or re.fullmatch('{}', os.path.basename(p))]", escape_python_string(&regex))
+}
+
+
+/// `literals`: a list of all literals; a literal's id is its index
+///
+/// `descriptions`: a dict where:
+///   * key: literal's id
+///   * value: literal's description
+///
+/// `transitions`: a dict where:
+///   * key: source state number
+///   * value: a dict mapping a literal id to the destination state number
+///
+/// `prefix_transitions`: a dict where:
+///   * key: source state number
+///   * value: a list of `(literal id, destination state number)` tuples, longer prefixes first
+///
+/// Prefix literals like `--color=` only match the beginning of a word.  The rest of the word is matched starting
+/// at the destination state.
+///
+/// `match_anything_transitions`: a dict where:
+///   * key: state number
+///   * value: state number
+///
+/// An entry in the `match_anything_transitions` dict indicates that there's a fallback transition that accepts
+/// any word
+///
+/// `bindings`: a dict where:
+///   * key: (source, target) state numbers of a fallback transition
+///   * value: a list of names the word matched by the transition is bound to
+///
+/// `once`: a dict where:
+///   * key: (state number, literal (or prefix) id) of a transition
+///   * value: a list of ids of the @once(...) options the transition stands for
+///
+/// Once a transition has been taken, none of the transitions sharing an id with it is offered anymore
+///
+/// `exclusive`: a dict where:
+///   * key: (state number, literal (or prefix) id) of a transition
+///   * value: a list of tags of the @exclusive(...) alternatives the transition starts
+///
+/// `exclusions`: a dict where:
+///   * key: tag of an @exclusive(...) alternative
+///   * value: a list of tags of the other alternatives of the same @exclusive(...)
+///
+/// Once a transition has been taken, none of the transitions starting the other alternatives is offered anymore
+///
+/// `end_of_options_states`: a set of the numbers of states reached through <END_OF_OPTIONS>, i.e. after which only
+/// positionals follow
+///
+fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
+    let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id, literal, description.unwrap_or(ustr("")))).collect();
+
+    let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, literal, description)| ((*literal, *description), *id)).collect();
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| format!("'{}'", escape_python_string(literal))), ", ");
+    writeln!(buffer, r#"    literals = [{literals}]"#)?;
+
+    let descriptions: String = itertools::join(all_literals.iter().filter(|(_, _, description)| !description.is_empty()).map(|(id, _, description)| format!("{id}: '{}'", escape_python_string(description))), ", ");
+    writeln!(buffer, r#"    descriptions = {{{descriptions}}}"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    transitions = {{"#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_literal_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(input, description, to)| format!("{}: {}", literal_id_from_input_description.get(&(input, description)).unwrap(), to)), ", ");
+        writeln!(buffer, r#"        {state}: {{{state_transitions}}},"#)?;
+    }
+    writeln!(buffer, r#"    }}"#)?;
+    writeln!(buffer)?;
+
+    writeln!(buffer, r#"    prefix_transitions = {{"#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(prefix, to)| format!("({}, {})", literal_id_from_input_description.get(&(prefix, ustr(""))).unwrap(), to)), ", ");
+        writeln!(buffer, r#"        {state}: [{state_transitions}],"#)?;
+    }
+    writeln!(buffer, r#"    }}"#)?;
+    writeln!(buffer)?;
+
+    let match_anything_transitions = itertools::join(dfa.get_match_anything_transitions().into_iter().map(|(from, to)| format!("{from}: {to}")), ", ");
+    writeln!(buffer, r#"    match_anything_transitions = {{{match_anything_transitions}}}"#)?;
+
+    let bindings = itertools::join(dfa.get_binding_transitions().into_iter().map(|(from, to, names)| format!("({from}, {to}): [{}]", itertools::join(names.iter().map(|name| format!("'{name}'")), ", "))), ", ");
+    writeln!(buffer, r#"    bindings = {{{bindings}}}"#)?;
+
+    let once = itertools::join(dfa.get_once_transitions().into_iter().map(|(from, literal, description, ids)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!("({from}, {literal_id}): [{}]", itertools::join(ids, ", "))
+    }), ", ");
+    writeln!(buffer, r#"    once = {{{once}}}"#)?;
+
+    let exclusive = itertools::join(dfa.get_exclusive_transitions().into_iter().map(|(from, literal, description, tags)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!("({from}, {literal_id}): [{}]", itertools::join(tags, ", "))
+    }), ", ");
+    writeln!(buffer, r#"    exclusive = {{{exclusive}}}"#)?;
+    let exclusions = itertools::join(dfa.get_exclusions().into_iter().map(|(tag, others)| format!("{tag}: [{}]", itertools::join(others, ", "))), ", ");
+    writeln!(buffer, r#"    exclusions = {{{exclusions}}}"#)?;
+    // An empty set has to be spelled out as {} would be a dict
+    let end_of_options_states = if dfa.end_of_options_states.is_empty() { "set()".to_string() } else { format!("{{{}}}", itertools::join(dfa.end_of_options_states.iter(), ", ")) };
+    writeln!(buffer, r#"    end_of_options_states = {end_of_options_states}"#)?;
+
+    Ok(())
+}
+
+
+fn write_commands_completion_code<W: Write>(buffer: &mut W, table_name: &str, function_prefix: &str, command_transitions: &[(StateId, Ustr)], id_from_command: &UstrMap<usize>) -> Result<()> {
+    if command_transitions.is_empty() {
+        return Ok(());
+    }
+
+    let function_from_state = itertools::join(command_transitions.iter().map(|(state, cmd)| format!("{state}: {function_prefix}{}", id_from_command.get(cmd).unwrap())), ", ");
+    write!(buffer, r#"
+    {table_name} = {{{function_from_state}}}
+    if state in {table_name}:
+        output = {table_name}[state](cur, complgen_words, bound_words)
+        lines = output.splitlines() if isinstance(output, str) else output
+        for line in lines:
+            completion, _, description = line.partition('\t')
+            add_completion(word_prefix + completion, description)
+"#)?;
+
+    Ok(())
+}
+
+
+pub fn write_preamble<W: Write>(buffer: &mut W) -> Result<()> {
+    write!(buffer, r#"import glob
+import os
+import re
+import subprocess
+
+from xonsh.completers.completer import add_one_completer
+from xonsh.completers.tools import RichCompletion, contextual_command_completer_for
+
+
+"#)?;
+    Ok(())
+}
+
+
+// Command names may contain characters like `-` that can't be part of Python identifiers.  Every other character
+// becomes `_` followed by its code in hex, `_` included, so that e.g. foo-admin and foo_admin don't collide.
+fn make_identifier(command: &str) -> String {
+    let mut result = String::with_capacity(command.len());
+    for c in command.chars() {
+        if c.is_ascii_alphanumeric() {
+            result.push(c);
+        } else {
+            result.push_str(&format!("_{:02x}", u32::from(c)));
+        }
+    }
+    result
+}
+
+
+pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DFA) -> Result<()> {
+    let identifier = make_identifier(command);
+
+    // Generic commands are meant for POSIX shells, hence `sh`
+    let command_transitions = dfa.get_command_transitions();
+    let id_from_command: UstrMap<usize> = command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_command {
+        write!(buffer, r#"def _{identifier}_{id}(cur, words, bound):
+    return subprocess.run(['sh', '-c', '{}', '_', cur], stdout=subprocess.PIPE, text=True, env=dict(os.environ, COMPLGEN_WORDS=words, **bound)).stdout
+
+
+"#, escape_python_string(cmd))?;
+    }
+
+    let specialized_command_transitions = dfa.get_xonsh_command_transitions();
+    let id_from_specialized_command: UstrMap<usize> = specialized_command_transitions.iter().enumerate().map(|(id, (_, cmd))| (*cmd, id)).collect();
+    for (cmd, id) in &id_from_specialized_command {
+        write!(buffer, r#"def _{identifier}_spec_{id}(cur, words, bound):
+    with ${{...}}.swap(COMPLGEN_WORDS=words, **bound):
+        return ({cmd})
+
+
+"#)?;
+    }
+
+    write!(buffer, r#"@contextual_command_completer_for('{}')
+def _complgen_{identifier}(context):
+"#, escape_python_string(command))?;
+
+    write_tables(buffer, dfa)?;
+
+    write!(buffer, r#"
+    words = [arg.value for arg in context.args[:context.arg_index]] + [context.prefix]
+    cword = len(words) - 1
+
+    complgen_words = '\n'.join(words[1:cword])
+
+    # Words matched by bound nonterminals, e.g. <repo=PATH>, exported to external commands under their names
+    bound_words = {{}}
+
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    given_once = set()
+    given_exclusive = set()
+
+    state = {starting_state}
+    word_index = 1
+    while word_index < cword:
+        word = words[word_index]
+
+        transition = next(((literal_id, to) for literal_id, to in transitions.get(state, {{}}).items() if literals[literal_id] == word), None)
+        if transition is not None:
+            literal_id, to = transition
+            given_once.update(once.get((state, literal_id), []))
+            given_exclusive.update(exclusive.get((state, literal_id), []))
+            state = to
+            word_index += 1
+            continue
+
+        prefix_transition = next(((literal_id, to) for literal_id, to in prefix_transitions.get(state, []) if word.startswith(literals[literal_id])), None)
+        if prefix_transition is not None:
+            literal_id, to = prefix_transition
+            given_once.update(once.get((state, literal_id), []))
+            given_exclusive.update(exclusive.get((state, literal_id), []))
+            state = to
+            words[word_index] = word[len(literals[literal_id]):]
+            continue
+
+        if state in match_anything_transitions:
+            next_state = match_anything_transitions[state]
+            for name in bindings.get((state, next_state), []):
+                bound_words[name] = word
+            state = next_state
+            word_index += 1
+            continue
+
+        return set()
+
+    # Completion text -> description.  The same completion may be reachable both as a literal and as a prefix,
+    # e.g. in short options clusters, in which case the one having a description wins.
+    completions = {{}}
+    def add_completion(completion, description):
+        if completion not in completions or description:
+            completions[completion] = description
+"#, starting_state = dfa.starting_state)?;
+
+    // Literals like `--color=` only match the beginning of a word.  Strip them off the completed word one by one
+    // so that the remainder can be completed and put them back in front of every completion.
+    write!(buffer, r#"
+    cur = words[cword]
+    word_prefix = ''
+    while True:
+        state_literal_ids = list(transitions.get(state, {{}})) + [literal_id for literal_id, _ in prefix_transitions.get(state, [])]
+        for literal_id in state_literal_ids:
+            literal = literals[literal_id]
+            # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+            if re.match('^-[^-]', word_prefix) and len(literal) == 1 and literal in word_prefix[1:]:
+                continue
+            # Options from @once(...) aren't offered again once given
+            if not word_prefix and not given_once.isdisjoint(once.get((state, literal_id), [])):
+                continue
+            # Alternatives of an @exclusive(...) aren't offered once another one of it has been given
+            if not word_prefix and any(not given_exclusive.isdisjoint(exclusions[tag]) for tag in exclusive.get((state, literal_id), [])):
+                continue
+            # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+            if not word_prefix and state in end_of_options_states and literal.startswith('-'):
+                continue
+            add_completion(word_prefix + literal, descriptions.get(literal_id, ''))
+
+        prefix_transition = next(((literal_id, to) for literal_id, to in prefix_transitions.get(state, []) if cur.startswith(literals[literal_id])), None)
+        if prefix_transition is None:
+            break
+        literal_id, state = prefix_transition
+        word_prefix += literals[literal_id]
+        cur = cur[len(literals[literal_id]):]
+"#)?;
+
+    write_commands_completion_code(buffer, "commands", &format!("_{identifier}_"), &command_transitions, &id_from_command)?;
+    write_commands_completion_code(buffer, "specialized_commands", &format!("_{identifier}_spec_"), &specialized_command_transitions, &id_from_specialized_command)?;
+
+    // Completions ending with `=` expect a value right after them, hence no trailing space
+    write!(buffer, r#"
+    return {{
+        RichCompletion(completion, description=description, append_space=not completion.endswith('='))
+        for completion, description in completions.items()
+        if completion.startswith(words[cword])
+    }}
+
+
+add_one_completer('{}', _complgen_{identifier}, 'start')
+"#, escape_python_string(command))?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn makes_distinct_identifiers() {
+        assert_eq!(make_identifier("foo"), "foo");
+        assert_eq!(make_identifier("foo-admin"), "foo_2dadmin");
+        assert_eq!(make_identifier("foo_admin"), "foo_5fadmin");
+        assert_eq!(make_identifier("g++"), "g_2b_2b");
+    }
+}
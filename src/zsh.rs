@@ -9,8 +9,81 @@ use crate::dfa::DFA;
 // Array indexes in ZSH start from 1 (!)
 
 
+// Shared between all completion scripts, hence not prefixed with the command name.  Sets `command_output` in the
+// caller, which also provides `bound_words`.
+const RUN_FUNCTION: &str = r#"# Runs the function $1 completing $PREFIX, giving up on it after $2 seconds and reusing the output of a
+# successful run for $3 seconds, if given.  Errors are only shown if COMPLGEN_DEBUG is set.
+_complgen_run () {
+    zmodload zsh/datetime zsh/system
+    local function_name=$1 timeout=$2 cache_ttl=$3
+    typeset -gA _complgen_cached_output _complgen_cached_at
+    # Commands like `git branch` depend on the working directory
+    local cache_key=$PWD$'\x1f'$function_name$'\x1f'$PREFIX$'\x1f'$COMPLGEN_WORDS$'\x1f'${bound_words[*]}
+    # Not using arithmetic on the arrays as it would evaluate the key once more
+    local cached_at=${_complgen_cached_at[$cache_key]-}
+    if [[ -n $cache_ttl && -n $cached_at ]] && (( EPOCHSECONDS - cached_at < cache_ttl )); then
+        command_output=${_complgen_cached_output[$cache_key]}
+        return
+    fi
+
+    local fd
+    exec {fd}< <(
+        set -- "$function_name" "$PREFIX"
+        [[ ${#bound_words} -eq 0 ]] || export "${bound_words[@]}"
+        [[ -v COMPLGEN_DEBUG ]] || exec 2>/dev/null
+        "$@" && print -n '\0'
+    )
+    local -F deadline=$(( EPOCHREALTIME + ${timeout:-0} ))
+    local chunk
+    local -i read_status
+    command_output=
+    while true; do
+        if [[ -n $timeout ]]; then
+            local -F remaining=$(( deadline - EPOCHREALTIME ))
+            if (( remaining <= 0 )); then
+                read_status=4
+                break
+            fi
+            sysread -t $remaining -i $fd chunk
+        else
+            sysread -i $fd chunk
+        fi
+        read_status=$?
+        (( read_status == 0 )) || break
+        command_output+=$chunk
+    done
+    exec {fd}<&-
+
+    # sysread returns 4 on timeout and 5 on end of input
+    if (( read_status == 4 )); then
+        command_output=
+        return
+    fi
+    local succeeded_output=${command_output%$'\0'}
+    if (( ${#succeeded_output} < ${#command_output} )); then
+        command_output=$succeeded_output
+        if [[ -n $cache_ttl ]]; then
+            _complgen_cached_output[$cache_key]=$command_output
+            _complgen_cached_at[$cache_key]=$EPOCHSECONDS
+        fi
+    fi
+}
+"#;
+
+
+/// For use within double quotes, where `\`, `"`, `` ` `` and `$` are special
 pub fn escape_zsh_string(s: &str) -> String {
-    s.replace("\"", "\\\"").replace("`", "\\`").replace("$", "\\$")
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('`', "\\`").replace('$', "\\$")
+}
+
+
+/// Completes the paths matching `glob`, e.g. from <PATH:*.{yml,yaml}>, directories included
+pub fn make_glob_completion_command(glob: &str) -> String {
+    let pattern = match crate::glob::expand_braces(glob).iter().map(|pattern| crate::glob::escape_pattern(pattern)).collect::<Vec<_>>().as_slice() {
+        [pattern] => pattern.clone(),
+        patterns => format!("({})", patterns.join("|")),
+    };
+    format!("_path_files -/; _path_files -g '{}'", pattern.replace('\'', r"'\''"))
 }
 
 
@@ -25,17 +98,48 @@ pub fn escape_zsh_string(s: &str) -> String {
 /// The initializer value above indicates there are 3 transitions from the state of id `key`.
 /// First of which is a transition on word `add`, to the state 23.
 ///
+/// `prefix_transitions`: same as `transitions` but the keys are literals that only match the beginning of a
+/// word, e.g. `--color=`.  The rest of the word is matched starting at the target state.
+///
 /// `match_anything_transitions`: an associative array where:
 ///  * key: state number
 ///  * value: state number
 /// An entry in the `match_anything_transitions` array indicates that there's a fallback transition that
 /// accepts any word
 ///
+/// `bindings`: an associative array where:
+///  * key: source and target state numbers of a match-anything transition, separated by a comma
+///  * value: space-separated names the word matched by the transition gets bound to
+///
+/// `once`: an associative array where:
+///  * key: state number and literal (or prefix) id, separated by a comma
+///  * value: space-separated ids of the @once(...) options the transition stands for
+///
+/// Once a transition has been taken, none of the transitions sharing an id with it is offered anymore
+///
+/// `exclusive`: an associative array where:
+///  * key: state number and literal (or prefix) id, separated by a comma
+///  * value: space-separated tags of the @exclusive(...) alternatives the transition starts
+///
+/// `exclusions`: an associative array where:
+///  * key: tag of an @exclusive(...) alternative
+///  * value: space-separated tags of the other alternatives of the same @exclusive(...)
+///
+/// Once a transition has been taken, none of the transitions starting the other alternatives is offered anymore
+///
+/// `end_of_options_states`: an associative array where:
+///  * key: number of a state reached through <END_OF_OPTIONS>, i.e. after which only positionals follow
+///  * value: 1
+///
+/// `command_timeouts`, `command_cache_ttls`: associative arrays where:
+///  * key: command id
+///  * value: seconds, from @timeout(...) and @cache(...) in the grammar
+///
 fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
     let all_literals: Vec<(usize, Ustr, Ustr)> = dfa.get_all_literals().into_iter().enumerate().map(|(id, (literal, description))| (id + 1, literal, description.unwrap_or(ustr("")))).collect();
 
     let literal_id_from_input_description: HashMap<(Ustr, Ustr), usize> = all_literals.iter().map(|(id, input, description)| ((*input, *description), *id)).collect();
-    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| literal), " ");
+    let literals: String = itertools::join(all_literals.iter().map(|(_, literal, _)| format!(r#""{}""#, escape_zsh_string(literal))), " ");
     writeln!(buffer, r#"    local -a literals=({literals})"#)?;
     writeln!(buffer, "")?;
 
@@ -50,7 +154,7 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
 
     writeln!(buffer, r#"    local -A transitions"#)?;
     for state in dfa.get_all_states() {
-        let transitions = dfa.get_literal_transitions_from(StateId::try_from(state).unwrap());
+        let transitions = dfa.get_literal_transitions_from(state);
         if transitions.is_empty() {
             continue;
         }
@@ -61,10 +165,43 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
 
     writeln!(buffer, "")?;
 
+    writeln!(buffer, r#"    local -A prefix_transitions"#)?;
+    for state in dfa.get_all_states() {
+        let transitions = dfa.get_prefix_transitions_from(state);
+        if transitions.is_empty() {
+            continue;
+        }
+        let state_transitions: String = itertools::join(transitions.into_iter().map(|(prefix, to)| format!("[{}]={}", literal_id_from_input_description.get(&(prefix, ustr(""))).unwrap(), to + 1)), " ");
+        writeln!(buffer, r#"    prefix_transitions[{}]="({state_transitions})""#, state + 1)?;
+    }
+
+    writeln!(buffer)?;
+
     writeln!(buffer, r#"    local -A match_anything_transitions"#)?;
     let match_anything_transitions = itertools::join(dfa.get_match_anything_transitions().into_iter().map(|(from, to)| format!("[{}]={}", from + 1, to + 1)), " ");
     writeln!(buffer, r#"    match_anything_transitions=({match_anything_transitions})"#)?;
 
+    writeln!(buffer, r#"    local -A bindings"#)?;
+    let bindings = itertools::join(dfa.get_binding_transitions().into_iter().map(|(from, to, names)| format!(r#"[{},{}]="{}""#, from + 1, to + 1, itertools::join(names, " "))), " ");
+    writeln!(buffer, r#"    bindings=({bindings})"#)?;
+
+    let once = itertools::join(dfa.get_once_transitions().into_iter().map(|(from, literal, description, ids)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!(r#"[{},{literal_id}]="{}""#, from + 1, itertools::join(ids, " "))
+    }), " ");
+    writeln!(buffer, r#"    local -A once=({once})"#)?;
+
+    let exclusive = itertools::join(dfa.get_exclusive_transitions().into_iter().map(|(from, literal, description, tags)| {
+        let literal_id = literal_id_from_input_description.get(&(literal, description)).unwrap();
+        format!(r#"[{},{literal_id}]="{}""#, from + 1, itertools::join(tags, " "))
+    }), " ");
+    writeln!(buffer, r#"    local -A exclusive=({exclusive})"#)?;
+    let exclusions = itertools::join(dfa.get_exclusions().into_iter().map(|(tag, others)| format!(r#"[{tag}]="{}""#, itertools::join(others, " "))), " ");
+    writeln!(buffer, r#"    local -A exclusions=({exclusions})"#)?;
+
+    let end_of_options_states = itertools::join(dfa.end_of_options_states.iter().map(|state| format!("[{}]=1", state + 1)), " ");
+    writeln!(buffer, r#"    local -A end_of_options_states=({end_of_options_states})"#)?;
+
     Ok(())
 }
 
@@ -72,7 +209,7 @@ fn write_tables<W: Write>(buffer: &mut W, dfa: &DFA) -> Result<()> {
 fn write_specialized_commands<W: Write>(buffer: &mut W, command: &str, dfa: &DFA) -> Result<UstrMap<usize>> {
     let id_from_specialized_command: UstrMap<usize> = dfa.get_zsh_command_transitions().into_iter().enumerate().map(|(id, (_, cmd))| (cmd, id)).collect();
     for (cmd, id) in &id_from_specialized_command {
-        write!(buffer, r#"_{command}_{id} () {{
+        write!(buffer, r#"_{command}_spec_{id} () {{
     {cmd}
 }}
 
@@ -92,7 +229,10 @@ fn write_specialized_commands_completion_code<W: Write>(buffer: &mut W, command:
         write!(buffer, r#"
     if [[ -v "specialized_commands[$state]" ]]; then
         local command_id=${{specialized_commands[$state]}}
-        _{command}_${{command_id}} ${{words[$CURRENT]}}
+        () {{
+            [[ $# -le 2 ]] || local -x "${{@[3,-1]}}"
+            $1 "$2"
+        }} _{command}_spec_${{command_id}} "$PREFIX" "${{bound_words[@]}}"
     fi
 "#)?;
     }
@@ -113,25 +253,44 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
 
     let id_from_specialized_command = write_specialized_commands(buffer, command, dfa)?;
 
+    writeln!(buffer, "{RUN_FUNCTION}")?;
+
     write!(buffer, r#"_{command} () {{
 "#)?;
 
     write_tables(buffer, dfa)?;
 
     write!(buffer, r#"
+    local -x COMPLGEN_WORDS=${{(pj:\n:)words[2,CURRENT-1]}}
+
+    # Words bound to names, e.g. by <repo=PATH>, as assignments to export to external commands
+    local -a bound_words=()
+
+    # Ids of the @once(...) options and tags of the @exclusive(...) alternatives matched so far
+    local -A given_once=()
+    local -A given_exclusive=()
+    local id tag excluding_tag
+
     local state={starting_state}
     local word_index=2
     while [[ $word_index -lt $CURRENT ]]; do
+        local word=${{words[$word_index]}}
+
         if [[ -v "transitions[$state]" ]]; then
             local state_transitions_initializer=${{transitions[$state]}}
             local -A state_transitions
             eval "state_transitions=$state_transitions_initializer"
 
-            local word=${{words[$word_index]}}
             local word_matched=0
             for literal_id in {{1..$#literals}}; do
-                if [[ ${{literals[$literal_id]}} = $word ]]; then
+                if [[ ${{literals[$literal_id]}} = "$word" ]]; then
                     if [[ -v "state_transitions[$literal_id]" ]]; then
+                        for id in ${{=once[$state,$literal_id]-}}; do
+                            given_once[$id]=1
+                        done
+                        for tag in ${{=exclusive[$state,$literal_id]-}}; do
+                            given_exclusive[$tag]=1
+                        done
                         state=${{state_transitions[$literal_id]}}
                         word_index=$((word_index + 1))
                         word_matched=1
@@ -144,8 +303,41 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
             fi
         fi
 
+        if [[ -v "prefix_transitions[$state]" ]]; then
+            local state_prefix_transitions_initializer=${{prefix_transitions[$state]}}
+            local -A state_prefix_transitions
+            eval "state_prefix_transitions=$state_prefix_transitions_initializer"
+
+            local longest_prefix_id=
+            local longest_prefix=
+            for literal_id in ${{(k)state_prefix_transitions}}; do
+                local prefix=${{literals[$literal_id]}}
+                if [[ $word = "$prefix"* && ${{#prefix}} -gt ${{#longest_prefix}} ]]; then
+                    longest_prefix_id=$literal_id
+                    longest_prefix=$prefix
+                fi
+            done
+            if [[ -n $longest_prefix_id ]]; then
+                for id in ${{=once[$state,$longest_prefix_id]-}}; do
+                    given_once[$id]=1
+                done
+                for tag in ${{=exclusive[$state,$longest_prefix_id]-}}; do
+                    given_exclusive[$tag]=1
+                done
+                state=${{state_prefix_transitions[$longest_prefix_id]}}
+                words[$word_index]=${{word#"$longest_prefix"}}
+                continue
+            fi
+        fi
+
         if [[ -v "match_anything_transitions[$state]" ]]; then
-            state=${{match_anything_transitions[$state]}}
+            local next_state=${{match_anything_transitions[$state]}}
+            if [[ -v "bindings[$state,$next_state]" ]]; then
+                for name in ${{=bindings[$state,$next_state]}}; do
+                    bound_words+=("$name=$word")
+                done
+            fi
+            state=$next_state
             word_index=$((word_index + 1))
             continue
         fi
@@ -154,42 +346,120 @@ pub fn write_completion_script<W: Write>(buffer: &mut W, command: &str, dfa: &DF
     done
 "#, starting_state = dfa.starting_state + 1)?;
 
+    // Move literals like `--color=` from $PREFIX to $IPREFIX one by one so that zsh only completes the remainder
+    // of the word
     write!(buffer, r#"
-    if [[ -v "transitions[$state]" ]]; then
-        local state_transitions_initializer=${{transitions[$state]}}
-        local -A state_transitions
-        eval "state_transitions=$state_transitions_initializer"
+    local word_prefix=
+    while true; do
+        local -a args=()
+        local -a descrs=()
+        local -a prefixes=()
+
+        if [[ -v "transitions[$state]" ]]; then
+            local state_transitions_initializer=${{transitions[$state]}}
+            local -A state_transitions
+            eval "state_transitions=$state_transitions_initializer"
+
+            for literal_id in ${{(k)state_transitions}}; do
+                local literal=${{literals[$literal_id]}}
+                # Within a short options cluster, e.g. -xv, only offer the options that aren't part of it yet
+                if [[ $word_prefix = -[^-]* && ${{#literal}} -eq 1 && ${{word_prefix[2,-1]}} = *"$literal"* ]]; then
+                    continue
+                fi
+                # Options from @once(...) aren't offered again once given
+                if [[ -z $word_prefix ]]; then
+                    for id in ${{=once[$state,$literal_id]-}}; do
+                        [[ -v "given_once[$id]" ]] && continue 2
+                    done
+                fi
+                # Alternatives of an @exclusive(...) aren't offered once another one of it has been given
+                if [[ -z $word_prefix ]]; then
+                    for tag in ${{=exclusive[$state,$literal_id]-}}; do
+                        for excluding_tag in ${{=exclusions[$tag]}}; do
+                            [[ -v "given_exclusive[$excluding_tag]" ]] && continue 3
+                        done
+                    done
+                fi
+                # Only positionals follow <END_OF_OPTIONS>, i.e. `--`
+                if [[ -z $word_prefix && -v "end_of_options_states[$state]" && $literal = -* ]]; then
+                    continue
+                fi
+                if [[ -v "descriptions[$literal_id]" ]]; then
+                    args+=("$literal")
+                    descrs+=("$literal (${{descriptions[$literal_id]}})")
+                else
+                    args+=("$literal")
+                    descrs+=("$literal")
+                fi
+            done
+        fi
+
+        local longest_prefix_id=
+        local longest_prefix=
+        if [[ -v "prefix_transitions[$state]" ]]; then
+            local state_prefix_transitions_initializer=${{prefix_transitions[$state]}}
+            local -A state_prefix_transitions
+            eval "state_prefix_transitions=$state_prefix_transitions_initializer"
+
+            for literal_id in ${{(k)state_prefix_transitions}}; do
+                local prefix=${{literals[$literal_id]}}
+                local ruled_out=0
+                if [[ -z $word_prefix ]]; then
+                    for id in ${{=once[$state,$literal_id]-}}; do
+                        [[ -v "given_once[$id]" ]] && ruled_out=1
+                    done
+                    for tag in ${{=exclusive[$state,$literal_id]-}}; do
+                        for excluding_tag in ${{=exclusions[$tag]}}; do
+                            [[ -v "given_exclusive[$excluding_tag]" ]] && ruled_out=1
+                        done
+                    done
+                    [[ -v "end_of_options_states[$state]" && $prefix = -* ]] && ruled_out=1
+                fi
+                if [[ ${{args[(Ie)$prefix]}} -eq 0 && $ruled_out -eq 0 && ! ( $word_prefix = -[^-]* && ${{#prefix}} -eq 1 && ${{word_prefix[2,-1]}} = *"$prefix"* ) ]]; then
+                    prefixes+=("$prefix")
+                fi
+                if [[ $PREFIX = "$prefix"* && ${{#prefix}} -gt ${{#longest_prefix}} ]]; then
+                    longest_prefix_id=$literal_id
+                    longest_prefix=$prefix
+                fi
+            done
+        fi
 
-        local -a args
-        local -a descrs
-        for literal_id in ${{(k)state_transitions}}; do
-            if [[ -v "descriptions[$literal_id]" ]]; then
-                args+=(${{literals[$literal_id]}})
-                descrs+=("${{literals[$literal_id]}} (${{descriptions[$literal_id]}})")
-            else
-                args+=(${{literals[$literal_id]}})
-                descrs+=(${{literals[$literal_id]}})
-            fi
-        done
         local joined=${{(j::)descrs}}
         if [[ -z $joined ]]; then
             compadd -a args
         else
             compadd -d descrs -a args
         fi
-    fi
+        compadd -S '' -a prefixes
+
+        if [[ -z $longest_prefix_id ]]; then
+            break
+        fi
+        state=${{state_prefix_transitions[$longest_prefix_id]}}
+        word_prefix+=$longest_prefix
+        IPREFIX+=$longest_prefix
+        PREFIX=${{PREFIX#"$longest_prefix"}}
+    done
 "#)?;
 
     let command_id_from_state: HashMap<StateId, usize> = dfa.get_command_transitions().into_iter().map(|(state, cmd)| (state, *id_from_command.get(&cmd).unwrap())).collect();
     if !command_id_from_state.is_empty() {
         let commands_array_initializer = itertools::join(command_id_from_state.into_iter().map(|(state, id)| format!("[{}]={id}", state + 1)), " ");
         writeln!(buffer, r#"    local -A commands=({commands_array_initializer})"#)?;
+        let annotations = dfa.get_command_annotations();
+        let timeouts = itertools::join(id_from_command.iter().filter_map(|(cmd, id)| Some(format!("[{id}]={}", annotations.get(cmd)?.get_timeout_seconds()?))), " ");
+        writeln!(buffer, r#"    local -A command_timeouts=({timeouts})"#)?;
+        let cache_ttls = itertools::join(id_from_command.iter().filter_map(|(cmd, id)| Some(format!("[{id}]={}", annotations.get(cmd)?.get_cache_ttl_seconds()?))), " ");
+        writeln!(buffer, r#"    local -A command_cache_ttls=({cache_ttls})"#)?;
         write!(buffer, r#"
     if [[ -v "commands[$state]" ]]; then
         local command_id=${{commands[$state]}}
         local -a args
         local -a descrs
-        local -a command_completions=("${{(@f)$(_{command}_${{command_id}} ${{words[$CURRENT]}})}}")
+        local command_output
+        _complgen_run _{command}_${{command_id}} "${{command_timeouts[$command_id]-}}" "${{command_cache_ttls[$command_id]-}}"
+        local -a command_completions=("${{(@f)command_output}}")
         for line in ${{command_completions[@]}}; do
             local a=$(echo "$line" | cut -f1)
             args+=($a)
@@ -220,3 +490,72 @@ compdef _{command} {command}
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use crate::grammar::ExprKind;
+    use crate::regex::AugmentedRegex;
+
+    use super::*;
+
+    // compdef and compadd only work within the completion system, hence the stubs
+    fn get_completions(literals: &[String]) -> Vec<String> {
+        let expr = ExprKind::Alternative(literals.iter().map(|literal| ExprKind::Terminal(ustr(literal), None).into()).collect());
+        let arena = Bump::new();
+        let regex = AugmentedRegex::from_expr(&expr.into(), &UstrMap::default(), &arena).unwrap();
+        let dfa = DFA::from_regex(&regex).unwrap().minimize();
+        let mut script: Vec<u8> = Default::default();
+        write_completion_script(&mut script, "cmd", &dfa).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let output = Command::new("zsh").arg("-f").arg("-c").arg(format!(r#"compdef () {{ : }}
+compadd () {{
+    while [[ $# -gt 0 ]]; do
+        if [[ $1 = -a ]]; then
+            local -a values=("${{(@P)2}}")
+            (( $#values )) && printf '%s\0' "${{values[@]}}"
+            shift
+        fi
+        shift
+    done
+}}
+{script}
+words=(cmd '')
+CURRENT=2
+PREFIX=''
+IPREFIX=''
+_cmd
+"#)).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut completions: Vec<String> = stdout.split_terminator('\0').map(|s| s.to_string()).collect();
+        completions.sort();
+        completions
+    }
+
+    #[test]
+    #[ignore = "requires zsh"]
+    fn completes_literals_containing_whitespace_and_globs() {
+        let literals = ["foo bar".to_string(), "*".to_string(), "it's".to_string(), "$HOME".to_string()];
+        assert_eq!(get_completions(&literals), vec!["$HOME", "*", "foo bar", "it's"]);
+    }
+
+    proptest! {
+        #[test]
+        #[ignore = "requires zsh"]
+        fn escaping_round_trips(s in "[^\\x00]*") {
+            let output = Command::new("zsh").arg("-f").arg("-c").arg(format!(r#"printf %s "{}""#, escape_zsh_string(&s))).output().unwrap();
+            prop_assert_eq!(String::from_utf8(output.stdout).unwrap(), s);
+        }
+
+        #[test]
+        #[ignore = "requires zsh"]
+        fn completes_arbitrary_literals(literal in "[^\\x00]{1,16}") {
+            prop_assert_eq!(get_completions(std::slice::from_ref(&literal)), vec![literal]);
+        }
+    }
+}